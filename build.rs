@@ -1,5 +1,12 @@
 fn main() {
+    // The tray icons are Windows resources; other targets have no tray
+    if std::env::var("CARGO_CFG_TARGET_OS").as_deref() != Ok("windows") {
+        return;
+    }
+
     let mut res = winres::WindowsResource::new();
     res.set_icon("src/icon.ico");
+    // Shown in the tray while expansion is paused
+    res.set_icon_with_id("src/icon_paused.ico", "2");
     res.compile().expect("Failed to compile Windows resources");
 }
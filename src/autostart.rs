@@ -0,0 +1,124 @@
+// src/autostart.rs
+//
+// The tray's "Start with Windows": a value under the current user's Run key
+// that launches this executable at sign-in, with the `--config` it was
+// started with. If the exe has moved since the value was written, the user
+// is offered to point it at the running copy.
+
+use std::env;
+use std::io;
+use std::path::Path;
+use std::ptr;
+
+use winapi::shared::minwindef::{DWORD, HKEY};
+use winapi::shared::winerror::{ERROR_FILE_NOT_FOUND, ERROR_SUCCESS};
+use winapi::um::winnt::{KEY_QUERY_VALUE, KEY_SET_VALUE, REG_SZ};
+use winapi::um::winreg::{
+    RegCloseKey, RegDeleteValueW, RegGetValueW, RegOpenKeyExW, RegSetValueExW, HKEY_CURRENT_USER, RRF_RT_REG_SZ,
+};
+
+use crate::keyboard_hook::encode_wide;
+use text_expander::config;
+
+const RUN_KEY: &str = "Software\\Microsoft\\Windows\\CurrentVersion\\Run";
+const VALUE_NAME: &str = "text_expander";
+
+/// The command line Windows runs at sign-in, or `None` if not registered.
+pub fn registered_command() -> Option<String> {
+    let key = RunKey::open(KEY_QUERY_VALUE).ok()?;
+    let name = encode_wide(VALUE_NAME);
+    let mut size: DWORD = 0;
+    unsafe {
+        let status =
+            RegGetValueW(key.0, ptr::null(), name.as_ptr(), RRF_RT_REG_SZ, ptr::null_mut(), ptr::null_mut(), &mut size);
+        if status != ERROR_SUCCESS as i32 {
+            return None;
+        }
+        let mut buffer = vec![0u16; size as usize / 2];
+        let status = RegGetValueW(
+            key.0,
+            ptr::null(),
+            name.as_ptr(),
+            RRF_RT_REG_SZ,
+            ptr::null_mut(),
+            buffer.as_mut_ptr().cast(),
+            &mut size,
+        );
+        if status != ERROR_SUCCESS as i32 {
+            return None;
+        }
+        let text = &buffer[..buffer.iter().position(|&c| c == 0).unwrap_or(buffer.len())];
+        Some(String::from_utf16_lossy(text))
+    }
+}
+
+/// This executable, quoted, plus the `--config` it was given (made absolute,
+/// since sign-in starts it in another directory).
+pub fn current_command() -> Result<String, Box<dyn std::error::Error>> {
+    let mut command = quote(&env::current_exe()?);
+    if let Some(path) = config::config_arg()? {
+        let path = std::path::absolute(&path)?;
+        command.push_str(&format!(" --config {}", quote(&path)));
+    }
+    Ok(command)
+}
+
+fn quote(path: &Path) -> String {
+    format!("\"{}\"", path.display())
+}
+
+/// Register `current_command` to run at sign-in, replacing any older entry.
+pub fn enable() -> Result<(), Box<dyn std::error::Error>> {
+    let command = encode_wide(&current_command()?);
+    let key = RunKey::open(KEY_SET_VALUE)?;
+    let name = encode_wide(VALUE_NAME);
+    let status = unsafe {
+        RegSetValueExW(
+            key.0,
+            name.as_ptr(),
+            0,
+            REG_SZ,
+            command.as_ptr().cast(),
+            (command.len() * 2) as DWORD,
+        )
+    };
+    check(status)
+}
+
+/// Stop starting at sign-in. Not being registered is fine.
+pub fn disable() -> Result<(), Box<dyn std::error::Error>> {
+    let key = RunKey::open(KEY_SET_VALUE)?;
+    let name = encode_wide(VALUE_NAME);
+    let status = unsafe { RegDeleteValueW(key.0, name.as_ptr()) };
+    if status == ERROR_FILE_NOT_FOUND as i32 {
+        return Ok(());
+    }
+    check(status)
+}
+
+fn check(status: i32) -> Result<(), Box<dyn std::error::Error>> {
+    if status == ERROR_SUCCESS as i32 {
+        Ok(())
+    } else {
+        Err(io::Error::from_raw_os_error(status).into())
+    }
+}
+
+/// The Run key, closed on drop.
+struct RunKey(HKEY);
+
+impl RunKey {
+    fn open(access: DWORD) -> Result<RunKey, Box<dyn std::error::Error>> {
+        let path = encode_wide(RUN_KEY);
+        let mut key: HKEY = ptr::null_mut();
+        let status = unsafe { RegOpenKeyExW(HKEY_CURRENT_USER, path.as_ptr(), 0, access, &mut key) };
+        check(status)?;
+        Ok(RunKey(key))
+    }
+}
+
+impl Drop for RunKey {
+    fn drop(&mut self) {
+        unsafe { RegCloseKey(self.0) };
+    }
+}
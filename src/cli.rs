@@ -0,0 +1,486 @@
+// src/cli.rs
+//
+// Command-line modes that do one job on expansions.toml and exit instead of
+// installing the hooks: `list`, `add`, `check`, `--import-espanso`, and
+// `--simulate`.
+// `--config <path>` picks the file for all of them, as it does when running.
+
+use std::fs;
+use std::path::Path;
+
+use toml_edit::DocumentMut;
+
+use crate::config::{self, ExpansionFile, Snippet, SnippetInsert};
+use crate::engine::ExpansionEngine;
+use crate::stats::Stats;
+use crate::{counters, espanso, simulate, stats, validate};
+
+pub const USAGE: &str = "\
+Usage: text_expander [--config <path>] [command]
+
+With no command, runs in the tray and expands as you type.
+
+Commands:
+  list                                        Print every trigger and its expansion
+  add <trigger> <expansion> [--case-insensitive]
+                                              Add a snippet to the config file
+  check                                       Report problems in the config file and snippets.d
+  stats                                       Show how often each trigger expanded and what it saved
+  counter [<name> <last value>|<name> reset]  List the {{counter:...}} values, or set or reset one
+  --import-espanso <file>                     Merge an espanso match file into the config file
+  --simulate <script>                         Replay a keystroke script and print what each key does
+  help                                        Show this message";
+
+/// Expansions longer than this are cut short by `list`.
+const PREVIEW_CHARS: usize = 60;
+
+/// `list` pads triggers to the longest one, up to this many characters.
+const MAX_TRIGGER_WIDTH: usize = 20;
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Command {
+    /// No command: install the hooks and expand.
+    Run,
+    List,
+    Add { trigger: String, expansion: String, case_insensitive: bool },
+    Check,
+    Stats,
+    /// List the counters (no `name`), or set `name`'s last value; a `None`
+    /// value resets it.
+    Counter { name: Option<String>, value: Option<i64> },
+    ImportEspanso(String),
+    /// Replay a keystroke script (`simulate::parse_script`).
+    Simulate(String),
+    Help,
+}
+
+/// Read the command from the arguments after the program name. The error is
+/// what to print, usage included.
+pub fn parse_args(args: impl IntoIterator<Item = String>) -> Result<Command, String> {
+    let mut positional = Vec::new();
+    let mut case_insensitive = false;
+    let mut import = None;
+    let mut simulate = None;
+
+    let mut args = args.into_iter();
+    while let Some(arg) = args.next() {
+        match arg.as_str() {
+            // Read by `config::resolve_config_path`
+            "--config" => {
+                args.next();
+            }
+            _ if arg.starts_with("--config=") => {}
+            "--case-insensitive" => case_insensitive = true,
+            "--import-espanso" => import = Some(args.next().ok_or("--import-espanso needs a file")?),
+            _ if arg.starts_with("--import-espanso=") => import = Some(arg["--import-espanso=".len()..].to_string()),
+            "--simulate" => simulate = Some(args.next().ok_or("--simulate needs a script")?),
+            _ if arg.starts_with("--simulate=") => simulate = Some(arg["--simulate=".len()..].to_string()),
+            "-h" | "--help" => return Ok(Command::Help),
+            _ if arg.starts_with("--") => return Err(format!("Unknown option {:?}\n\n{}", arg, USAGE)),
+            _ => positional.push(arg),
+        }
+    }
+
+    if let Some(file) = import {
+        return match positional.is_empty() && simulate.is_none() {
+            true => Ok(Command::ImportEspanso(file)),
+            false => Err(format!("--import-espanso can't be combined with a command\n\n{}", USAGE)),
+        };
+    }
+    if let Some(script) = simulate {
+        return match positional.is_empty() {
+            true => Ok(Command::Simulate(script)),
+            false => Err(format!("--simulate can't be combined with a command\n\n{}", USAGE)),
+        };
+    }
+
+    let mut positional = positional.into_iter();
+    let command = match positional.next().as_deref() {
+        None => Command::Run,
+        Some("list") => Command::List,
+        Some("check") => Command::Check,
+        Some("stats") => Command::Stats,
+        Some("help") => Command::Help,
+        Some("add") => match (positional.next(), positional.next()) {
+            (Some(trigger), Some(expansion)) => Command::Add { trigger, expansion, case_insensitive },
+            _ => return Err(format!("add needs a trigger and an expansion\n\n{}", USAGE)),
+        },
+        Some("counter") => match (positional.next(), positional.next().as_deref()) {
+            (None, _) => Command::Counter { name: None, value: None },
+            (Some(name), Some("reset")) => Command::Counter { name: Some(name), value: None },
+            (Some(name), Some(value)) => match value.parse() {
+                Ok(value) => Command::Counter { name: Some(name), value: Some(value) },
+                Err(_) => return Err(format!("{:?} is not a number or reset\n\n{}", value, USAGE)),
+            },
+            (Some(_), None) => return Err(format!("counter needs a last value or reset after the name\n\n{}", USAGE)),
+        },
+        Some(other) => return Err(format!("Unknown command {:?}\n\n{}", other, USAGE)),
+    };
+    if let Some(extra) = positional.next() {
+        return Err(format!("Unexpected argument {:?}\n\n{}", extra, USAGE));
+    }
+    if case_insensitive && !matches!(command, Command::Add { .. }) {
+        return Err(format!("--case-insensitive only goes with add\n\n{}", USAGE));
+    }
+    Ok(command)
+}
+
+/// Carry out a command other than `Run`, printing its results.
+pub fn run(command: Command) -> Result<(), Box<dyn std::error::Error>> {
+    match command {
+        Command::Run => {}
+        Command::Help => println!("{}", USAGE),
+        Command::List => {
+            for line in list(&config::load_expansion_table()?) {
+                println!("{}", line);
+            }
+        }
+        Command::Add { trigger, expansion, case_insensitive } => {
+            let path = config::resolve_config_path()?;
+            match add_snippet(&fs::read_to_string(&path)?, &trigger, &expansion, case_insensitive)? {
+                Some(updated) => {
+                    fs::write(&path, updated)?;
+                    println!("Added {:?} to {}", trigger, path.display());
+                }
+                None => println!("{:?} is already in {} with that expansion", trigger, path.display()),
+            }
+        }
+        Command::Check => {
+            let path = config::resolve_config_path()?;
+            let contents = fs::read_to_string(&path)?;
+            let mut problems = located(validate::check(&contents), &path);
+            let includes = config::included_files(&path);
+            if !includes.is_empty() {
+                let delimiters = config::parse_expansion_file(&contents)
+                    .map(|file| file.settings.delimiters.punctuation)
+                    .unwrap_or_default();
+                for include in &includes {
+                    problems.extend(located(validate::check_included(&fs::read_to_string(include)?, &delimiters), include));
+                }
+            }
+            for problem in &problems {
+                println!("{}", problem);
+            }
+            let checked = format!("{} and {} included file(s)", path.display(), includes.len());
+            let checked = if includes.is_empty() { path.display().to_string() } else { checked };
+            if !problems.is_empty() {
+                return Err(format!("{} problem(s) in {}", problems.len(), checked).into());
+            }
+            println!("{}: no problems found", checked);
+        }
+        Command::Stats => {
+            let file = config::load_expansion_table()?;
+            for line in stats_table(&stats::read(), &file) {
+                println!("{}", line);
+            }
+        }
+        Command::Counter { name, value } => {
+            config::load_expansion_table()?;
+            match name {
+                None if counters::snapshot().is_empty() => println!("No counter has been used yet"),
+                None => {
+                    for (name, value) in counters::snapshot() {
+                        println!("{:<20} {}", name, value);
+                    }
+                }
+                Some(name) => {
+                    counters::set(&name, value)?;
+                    match value {
+                        Some(value) => println!("{:?} set: the next value follows {}", name, value),
+                        None => println!("{:?} reset: it starts over at its start value", name),
+                    }
+                }
+            }
+        }
+        Command::ImportEspanso(source) => import_espanso(&source)?,
+        Command::Simulate(script) => {
+            let steps = simulate::parse_script(&fs::read_to_string(&script)?)?;
+            let mut engine = ExpansionEngine::new(config::load_expansion_table()?);
+            for line in simulate::run(&mut engine, &steps) {
+                println!("{}", line);
+            }
+        }
+    }
+    Ok(())
+}
+
+/// `problems` marked as found in `path`.
+fn located(mut problems: Vec<validate::Problem>, path: &Path) -> Vec<validate::Problem> {
+    for problem in &mut problems {
+        problem.file = Some(path.to_path_buf());
+    }
+    problems
+}
+
+/// One line per trigger, sorted, global tables first and then each app
+/// profile under its own heading. case_insensitive triggers are marked `*`.
+pub fn list(file: &ExpansionFile) -> Vec<String> {
+    let mut lines = Vec::new();
+
+    let mut global: Vec<(&String, char, String)> = Vec::new();
+    global.extend(file.case_sensitive.iter().map(|(trigger, snippet)| (trigger, ' ', preview(snippet))));
+    global.extend(file.case_insensitive.iter().map(|(trigger, snippet)| (trigger, '*', preview(snippet))));
+    global.extend(file.macros.iter().map(|(trigger, steps)| (trigger, ' ', format!("macro, {} steps", steps.len()))));
+    push_entries(&mut lines, global);
+
+    let mut apps: Vec<_> = file.app.iter().collect();
+    apps.sort_by_key(|(app, _)| *app);
+    for (app, profile) in apps {
+        lines.push(String::new());
+        lines.push(format!("[app.{:?}]", app));
+        let mut entries: Vec<(&String, char, String)> = Vec::new();
+        entries.extend(profile.case_sensitive.iter().map(|(trigger, snippet)| (trigger, ' ', preview(snippet))));
+        entries.extend(profile.case_insensitive.iter().map(|(trigger, snippet)| (trigger, '*', preview(snippet))));
+        push_entries(&mut lines, entries);
+    }
+
+    lines.push(String::new());
+    lines.push("* case-insensitive".to_string());
+    lines
+}
+
+fn push_entries(lines: &mut Vec<String>, mut entries: Vec<(&String, char, String)>) {
+    entries.sort_by(|a, b| a.0.cmp(b.0).then(a.1.cmp(&b.1)));
+    let width = entries.iter().map(|(trigger, _, _)| trigger.chars().count()).max().unwrap_or(0).min(MAX_TRIGGER_WIDTH);
+    lines.extend(
+        entries
+            .into_iter()
+            .map(|(trigger, marker, preview)| format!("{:<width$} {} {}", trigger, marker, preview, width = width)),
+    );
+}
+
+/// A snippet's expansion on one line, shortened to `PREVIEW_CHARS`.
+fn preview(snippet: &Snippet) -> String {
+    let text = match &snippet.options.command {
+        Some(command) => format!("$ {}", command.cmd),
+        None => snippet.replace.replace("\r\n", "\n").replace('\n', "\\n").replace('\t', "\\t"),
+    };
+    if text.chars().count() <= PREVIEW_CHARS {
+        return text;
+    }
+    let mut shortened: String = text.chars().take(PREVIEW_CHARS - 3).collect();
+    shortened.push_str("...");
+    shortened
+}
+
+/// One line per trigger that has expanded, most keystrokes saved first, then
+/// the totals. Triggers no longer in `file` are marked `-`.
+pub fn stats_table(stats: &Stats, file: &ExpansionFile) -> Vec<String> {
+    if stats.triggers.is_empty() {
+        return vec!["No expansions counted yet".to_string()];
+    }
+
+    let mut rows: Vec<_> = stats.triggers.iter().collect();
+    rows.sort_by(|a, b| b.1.chars_saved.cmp(&a.1.chars_saved).then(a.0.cmp(b.0)));
+    let width = rows.iter().map(|(trigger, _)| trigger.chars().count()).max().unwrap_or(0).clamp(7, MAX_TRIGGER_WIDTH);
+
+    let mut lines = vec![format!("{:<width$}   {:>8} {:>10}", "trigger", "uses", "saved", width = width)];
+    let mut removed = false;
+    for (trigger, counts) in &rows {
+        let marker = if defines_trigger(file, trigger) { ' ' } else { '-' };
+        removed |= marker == '-';
+        lines.push(format!(
+            "{:<width$} {} {:>8} {:>10}",
+            trigger,
+            marker,
+            thousands(counts.expansions as i64),
+            thousands(counts.chars_saved),
+            width = width
+        ));
+    }
+
+    let uses: u64 = rows.iter().map(|(_, counts)| counts.expansions).sum();
+    let saved: i64 = rows.iter().map(|(_, counts)| counts.chars_saved).sum();
+    let since = stats.since.as_deref().and_then(|day| chrono::NaiveDate::parse_from_str(day, "%Y-%m-%d").ok());
+    lines.push(String::new());
+    lines.push(match since {
+        Some(day) => format!(
+            "{} expansions, saved ~{} keystrokes since {}",
+            thousands(uses as i64),
+            thousands(saved),
+            day.format("%b %-d, %Y")
+        ),
+        None => format!("{} expansions, saved ~{} keystrokes", thousands(uses as i64), thousands(saved)),
+    });
+    if removed {
+        lines.push("- no longer in the config".to_string());
+    }
+    lines
+}
+
+/// Whether `trigger`, as counted, is still defined anywhere in `file`.
+fn defines_trigger(file: &ExpansionFile, trigger: &str) -> bool {
+    let in_tables = |case_sensitive: &std::collections::HashMap<String, Snippet>,
+                     case_insensitive: &std::collections::HashMap<String, Snippet>| {
+        case_sensitive.contains_key(trigger) || case_insensitive.contains_key(trigger)
+    };
+    in_tables(&file.case_sensitive, &file.case_insensitive)
+        || file.macros.contains_key(trigger)
+        || file.app.values().any(|profile| in_tables(&profile.case_sensitive, &profile.case_insensitive))
+}
+
+/// `n` with commas between groups of three digits.
+fn thousands(n: i64) -> String {
+    let digits = n.unsigned_abs().to_string();
+    let groups: Vec<&str> = digits.as_bytes().rchunks(3).rev().map(|group| std::str::from_utf8(group).unwrap()).collect();
+    let grouped = groups.join(",");
+    if n < 0 { format!("-{}", grouped) } else { grouped }
+}
+
+/// expansions.toml text `contents` with the snippet added, or `None` if it
+/// is already there with the same expansion. A snippet that validation
+/// would skip is refused.
+pub fn add_snippet(
+    contents: &str,
+    trigger: &str,
+    expansion: &str,
+    case_insensitive: bool,
+) -> Result<Option<String>, Box<dyn std::error::Error>> {
+    let delimiters = config::parse_expansion_file(contents)?.settings.delimiters.punctuation;
+    if let Some(problem) = validate::trigger_problem(trigger, &delimiters) {
+        return Err(problem.into());
+    }
+
+    let mut doc: DocumentMut = contents.parse()?;
+    match config::insert_snippet(&mut doc, case_insensitive, trigger, expansion, false)? {
+        SnippetInsert::Added => {}
+        SnippetInsert::Unchanged => return Ok(None),
+        SnippetInsert::Conflict => {
+            return Err(format!("{:?} already has a different expansion; edit the file to change it", trigger).into())
+        }
+    }
+
+    let updated = doc.to_string();
+    let problems = config::parse_expansion_file(&updated)?.problems;
+    if let Some(problem) = problems.iter().find(|problem| problem.skipped.as_ref().is_some_and(|entry| entry.trigger == trigger)) {
+        return Err(problem.message.clone().into());
+    }
+    Ok(Some(updated))
+}
+
+/// Merge an espanso match file into the config file and report what
+/// couldn't be converted. A running instance picks the new snippets up
+/// through hot reload.
+fn import_espanso(source: &str) -> Result<(), Box<dyn std::error::Error>> {
+    let path = config::resolve_config_path()?;
+    let conversion = espanso::convert(&fs::read_to_string(source)?)?;
+    let (merged, summary) = espanso::merge(&fs::read_to_string(&path)?, &conversion.snippets)?;
+    if summary.added > 0 {
+        // Never write a file the expander would refuse to load
+        config::parse_expansion_file(&merged)?;
+        fs::write(&path, merged)?;
+    }
+
+    println!("Imported {} snippets into {} ({} already there)", summary.added, path.display(), summary.unchanged);
+    for trigger in &summary.conflicts {
+        println!("Kept the existing expansion for {}", trigger);
+    }
+    for skipped in &conversion.skipped {
+        println!("Skipped {}", skipped);
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn args(line: &str) -> Result<Command, String> {
+        parse_args(line.split_whitespace().map(String::from))
+    }
+
+    #[test]
+    fn commands_parse_around_config_flags() {
+        assert_eq!(args(""), Ok(Command::Run));
+        assert_eq!(args("--config x.toml"), Ok(Command::Run));
+        assert_eq!(args("--config x.toml list"), Ok(Command::List));
+        assert_eq!(
+            args("add btw by_the_way --case-insensitive --config=x.toml"),
+            Ok(Command::Add { trigger: "btw".into(), expansion: "by_the_way".into(), case_insensitive: true })
+        );
+        assert_eq!(args("--import-espanso base.yml"), Ok(Command::ImportEspanso("base.yml".into())));
+        assert_eq!(args("--simulate=repro.txt --config x.toml"), Ok(Command::Simulate("repro.txt".into())));
+        assert!(args("--simulate repro.txt list").is_err());
+        assert!(args("add btw").is_err());
+        assert_eq!(args("counter"), Ok(Command::Counter { name: None, value: None }));
+        assert_eq!(args("counter invoice 41"), Ok(Command::Counter { name: Some("invoice".into()), value: Some(41) }));
+        assert_eq!(args("counter invoice reset"), Ok(Command::Counter { name: Some("invoice".into()), value: None }));
+        assert!(args("counter invoice").is_err() && args("counter invoice next").is_err());
+        assert!(args("check --case-insensitive").is_err());
+        assert!(args("lsit").unwrap_err().starts_with("Unknown command \"lsit\""));
+    }
+
+    #[test]
+    fn add_keeps_the_file_and_refuses_to_overwrite() {
+        let contents = "# notes\n[case_sensitive]\n\"btw\" = \"by the way\"\n\n[case_insensitive]\n";
+        let updated = add_snippet(contents, "Omw", "on my way", true).unwrap().unwrap();
+        assert!(updated.starts_with("# notes\n"));
+        assert!(updated.ends_with("[case_insensitive]\n\"omw\" = \"on my way\"\n"));
+
+        assert_eq!(add_snippet(contents, "btw", "by the way", false).unwrap(), None);
+        assert!(add_snippet(contents, "btw", "between", false).is_err());
+        assert!(add_snippet(contents, " btw", "x", false).is_err());
+        assert!(add_snippet(contents, "bad", "{{nope}}", false).is_err());
+    }
+
+    #[test]
+    fn list_sorts_and_marks_case_insensitive_triggers() {
+        let file = config::parse_expansion_file(
+            r#"
+            [case_sensitive]
+            "sig" = "Regards,\nAnn"
+            "btw" = "by the way"
+
+            [case_insensitive]
+            "omw" = "on my way"
+
+            [app."Outlook.exe".case_sensitive]
+            "sig" = "Best"
+
+            [app."olk.exe, WINWORD.EXE".case_insensitive]
+            "TY" = "Thank you"
+            "#,
+        )
+        .unwrap();
+        assert_eq!(
+            list(&file),
+            [
+                "btw   by the way",
+                "omw * on my way",
+                "sig   Regards,\\nAnn",
+                "",
+                "[app.\"olk.exe\"]",
+                "ty * Thank you",
+                "",
+                "[app.\"outlook.exe\"]",
+                "sig   Best",
+                "",
+                "[app.\"winword.exe\"]",
+                "ty * Thank you",
+                "",
+                "* case-insensitive",
+            ]
+        );
+    }
+
+    #[test]
+    fn stats_sort_by_keystrokes_saved_and_flag_removed_triggers() {
+        let file = config::parse_expansion_file("[case_sensitive]\n\"btw\" = \"by the way\"\n\"sig\" = \"Regards\"\n").unwrap();
+        let mut stats = Stats { since: Some("2026-01-03".into()), ..Stats::default() };
+        stats.triggers.insert("btw".into(), stats::TriggerStats { expansions: 1200, chars_saved: 8400 });
+        stats.triggers.insert("sig".into(), stats::TriggerStats { expansions: 10, chars_saved: 40 });
+        stats.triggers.insert("gone".into(), stats::TriggerStats { expansions: 3, chars_saved: 32760 });
+        assert_eq!(
+            stats_table(&stats, &file),
+            [
+                "trigger       uses      saved",
+                "gone    -        3     32,760",
+                "btw          1,200      8,400",
+                "sig             10         40",
+                "",
+                "1,213 expansions, saved ~41,200 keystrokes since Jan 3, 2026",
+                "- no longer in the config",
+            ]
+        );
+    }
+}
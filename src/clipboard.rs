@@ -0,0 +1,348 @@
+// src/clipboard.rs
+//
+// User-clipboard snapshot/restore for clipboard-mode expansions.
+//
+// Expansions are serialized on the processing thread, but filling a form fires
+// several of them back to back. Snapshotting per expansion would capture the
+// previous expansion's text, so the user's clipboard is saved only on the first
+// expansion of a burst and restored once after the burst goes idle. The
+// bookkeeping for that is `text_expander::clipboard_burst`.
+//
+// Another app (often a clipboard manager) can hold the clipboard open for a
+// moment, so every open, read, and write is retried for up to
+// `RETRY_TIMEOUT` before giving up.
+//
+// The timings around a paste come from `[settings]` (`set_timing`): how long
+// to wait for a write to show up, how long the target gets to read it, and
+// how long a burst must be idle before the restore.
+//
+// Off Windows there is no clipboard sequence number, so `sequence_number` is
+// a fingerprint of the clipboard's text instead (see there).
+
+#[cfg(windows)]
+use std::ptr;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::Mutex;
+use std::thread;
+use std::time::{Duration, Instant};
+
+use arboard::{Clipboard, ImageData};
+use text_expander::clipboard_burst::{self, Burst, ClipboardAccess};
+#[cfg(windows)]
+use winapi::um::winuser::{
+    CloseClipboard, EnumClipboardFormats, GetClipboardSequenceNumber, OpenClipboard,
+    CF_LOCALE, CF_OEMTEXT, CF_TEXT, CF_UNICODETEXT,
+};
+
+/// How long a write may take to show up in the clipboard sequence number
+/// (`clipboard_ready_timeout_ms`) before the expansion gives up on pasting.
+static READY_TIMEOUT_MS: AtomicU64 = AtomicU64::new(200);
+
+/// How long the target app gets to read the clipboard after Ctrl+V
+/// (`paste_settle_ms`).
+static PASTE_SETTLE_MS: AtomicU64 = AtomicU64::new(150);
+
+/// How long the processing thread waits after the last expansion before the
+/// burst is considered over and the user's clipboard is restored
+/// (`clipboard_restore_delay_ms`).
+static BURST_IDLE_MS: AtomicU64 = AtomicU64::new(300);
+
+/// How often the sequence number is checked while waiting for a write.
+const READY_POLL_INTERVAL: Duration = Duration::from_millis(2);
+
+/// How long a busy clipboard is retried, and how often.
+const RETRY_TIMEOUT: Duration = Duration::from_millis(500);
+const RETRY_INTERVAL: Duration = Duration::from_millis(10);
+
+static BURST: Mutex<Option<Burst>> = Mutex::new(None);
+
+/// Take the paste timings from `[settings]`. Called by the processing thread
+/// whenever the config is loaded.
+pub fn set_timing(ready_timeout_ms: u64, paste_settle_ms: u64, restore_delay_ms: u64) {
+    READY_TIMEOUT_MS.store(ready_timeout_ms, Ordering::SeqCst);
+    PASTE_SETTLE_MS.store(paste_settle_ms, Ordering::SeqCst);
+    BURST_IDLE_MS.store(restore_delay_ms, Ordering::SeqCst);
+}
+
+/// How long to leave the expansion on the clipboard after sending Ctrl+V.
+pub fn paste_settle() -> Duration {
+    Duration::from_millis(PASTE_SETTLE_MS.load(Ordering::SeqCst))
+}
+
+/// Run `operation` again while another app has the clipboard open, until
+/// `RETRY_TIMEOUT` runs out. Other errors are returned at once.
+fn retry<T>(mut operation: impl FnMut() -> Result<T, arboard::Error>) -> Result<T, arboard::Error> {
+    let deadline = Instant::now() + RETRY_TIMEOUT;
+    loop {
+        match operation() {
+            Err(arboard::Error::ClipboardOccupied) if Instant::now() < deadline => thread::sleep(RETRY_INTERVAL),
+            result => return result,
+        }
+    }
+}
+
+/// A clipboard handle, retrying while the clipboard is busy.
+pub fn open() -> Result<Clipboard, arboard::Error> {
+    retry(Clipboard::new)
+}
+
+/// An arboard handle as `clipboard_burst` sees it.
+struct System<'a>(&'a mut Clipboard);
+
+impl ClipboardAccess for System<'_> {
+    fn text(&mut self) -> String {
+        retry(|| self.0.get_text()).unwrap_or_default()
+    }
+
+    fn set_text(&mut self, text: &str) -> Result<(), Box<dyn std::error::Error>> {
+        Ok(retry(|| self.0.set_text(text))?)
+    }
+
+    fn sequence(&self) -> u32 {
+        sequence_number()
+    }
+
+    #[cfg(windows)]
+    fn wait_for_change(&self, before: u32) -> Option<u32> {
+        wait_for_sequence_change(before)
+    }
+
+    // arboard's writes have landed once they return, and writing the text
+    // that was already there leaves the fingerprint as it was
+    #[cfg(not(windows))]
+    fn wait_for_change(&self, _before: u32) -> Option<u32> {
+        Some(sequence_number())
+    }
+}
+
+/// Changes whenever anyone writes the clipboard: `GetClipboardSequenceNumber`.
+#[cfg(windows)]
+fn sequence_number() -> u32 {
+    unsafe { GetClipboardSequenceNumber() }
+}
+
+/// A hash of the clipboard's text, standing in for Windows' sequence number.
+/// Copying the same text again doesn't change it, so a burst can't tell that
+/// apart from its own write and restores over it.
+#[cfg(not(windows))]
+fn sequence_number() -> u32 {
+    use std::hash::{Hash, Hasher};
+
+    let text = open().and_then(|mut clipboard| retry(|| clipboard.get_text())).unwrap_or_default();
+    let mut hasher = std::collections::hash_map::DefaultHasher::new();
+    text.hash(&mut hasher);
+    hasher.finish() as u32
+}
+
+/// Put `text`, and `html` if the snippet has a formatted form, on the
+/// clipboard for pasting.
+pub fn set_expansion_text(
+    clipboard: &mut Clipboard,
+    text: &str,
+    html: Option<&str>,
+) -> Result<(), Box<dyn std::error::Error>> {
+    set_expansion(clipboard, |clipboard| match html {
+        #[cfg(windows)]
+        Some(html) => crate::windows_input::set_clipboard_text_and_html(text, html),
+        #[cfg(not(windows))]
+        Some(html) => Ok(retry(|| clipboard.set_html(html, Some(text)))?),
+        None => Ok(retry(|| clipboard.set_text(text))?),
+    })
+}
+
+/// Put an `image` snippet's picture on the clipboard for pasting.
+pub fn set_expansion_image(clipboard: &mut Clipboard, image: ImageData) -> Result<(), Box<dyn std::error::Error>> {
+    set_expansion(clipboard, |clipboard| Ok(retry(|| clipboard.set_image(image.clone()))?))
+}
+
+/// Replace the clipboard with an expansion using `write`
+/// (`clipboard_burst::write_job`). The user's clipboard is only snapshotted
+/// when no burst is in progress (idle → busy transition).
+///
+/// The write has to be visible before the caller pastes. The sequence number
+/// bumps on every write, so waiting for it needs no clipboard open or text
+/// comparison. If it never does, pasting would insert whatever was there
+/// before, so the caller is told to type the text instead.
+fn set_expansion(
+    clipboard: &mut Clipboard,
+    write: impl FnOnce(&mut Clipboard) -> Result<(), Box<dyn std::error::Error>>,
+) -> Result<(), Box<dyn std::error::Error>> {
+    let mut burst = BURST.lock().unwrap();
+    let started = burst.is_none();
+    clipboard_burst::write_job(&mut burst, &mut System(clipboard), Instant::now(), |system| write(system.0))?;
+    if started {
+        crate::debug_println!("Clipboard burst started, saved user clipboard");
+    }
+    Ok(())
+}
+
+/// Snapshot the user's clipboard text if no burst is in progress. True if
+/// this started one.
+fn start_burst(burst: &mut Option<Burst>, clipboard: &mut Clipboard) -> bool {
+    let started = clipboard_burst::begin(burst, &mut System(clipboard), Instant::now());
+    if started {
+        crate::debug_println!("Clipboard burst started, saved user clipboard");
+    }
+    started
+}
+
+/// Copy the selection in the focused app with `copy` (Ctrl+C) and read it
+/// back. `None` if nothing was selected: the sequence number didn't change
+/// within `clipboard_ready_timeout_ms` (off Windows, also when the selection
+/// is the text already on the clipboard), or what was copied isn't text. The
+/// user's clipboard is snapshotted first, as for an expansion, and the
+/// restore after the burst puts it back.
+pub fn copy_selection(
+    clipboard: &mut Clipboard,
+    copy: impl FnOnce() -> Result<(), Box<dyn std::error::Error>>,
+) -> Result<Option<String>, Box<dyn std::error::Error>> {
+    let mut burst = BURST.lock().unwrap();
+    let started = start_burst(&mut burst, clipboard);
+
+    let sequence_before = sequence_number();
+    let copied = copy().map(|()| wait_for_sequence_change(sequence_before));
+    let Ok(Some(sequence_after_copy)) = copied else {
+        // The clipboard is as it was; a burst started here has nothing to
+        // put back
+        if started {
+            *burst = None;
+        }
+        return copied.map(|_| None);
+    };
+
+    if let Some(burst) = burst.as_mut() {
+        burst.wrote(sequence_after_copy);
+        burst.finish_job(Instant::now());
+    }
+    Ok(retry(|| clipboard.get_text()).ok())
+}
+
+/// Poll the clipboard sequence number until it differs from `before`, for up
+/// to `READY_TIMEOUT_MS`. The new number, or `None` on timeout.
+fn wait_for_sequence_change(before: u32) -> Option<u32> {
+    let deadline = Instant::now() + Duration::from_millis(READY_TIMEOUT_MS.load(Ordering::SeqCst));
+    loop {
+        let sequence = sequence_number();
+        if sequence != before {
+            return Some(sequence);
+        }
+        if Instant::now() >= deadline {
+            return None;
+        }
+        thread::sleep(READY_POLL_INTERVAL);
+    }
+}
+
+/// `OpenClipboard`, retried while another app has it open. The caller closes
+/// it with `CloseClipboard` if this returns true.
+#[cfg(windows)]
+pub fn open_raw() -> bool {
+    let deadline = Instant::now() + RETRY_TIMEOUT;
+    while unsafe { OpenClipboard(ptr::null_mut()) } == 0 {
+        if Instant::now() >= deadline {
+            return false;
+        }
+        thread::sleep(RETRY_INTERVAL);
+    }
+    true
+}
+
+/// True if the user's clipboard holds anything a text-only snapshot would
+/// lose: an image, an Excel range, rich text, files. Expansions then skip
+/// the clipboard entirely. Also true if the clipboard can't be opened.
+pub fn holds_non_text() -> bool {
+    // Mid-burst the clipboard holds our own text, and a burst only starts
+    // from a text-only clipboard
+    let current_sequence = sequence_number();
+    if BURST.lock().unwrap().as_ref().is_some_and(|b| b.holds_our_write(current_sequence)) {
+        return false;
+    }
+    holds_other_formats()
+}
+
+#[cfg(windows)]
+fn holds_other_formats() -> bool {
+    if !open_raw() {
+        return true;
+    }
+    unsafe {
+        let mut non_text = false;
+        let mut format = EnumClipboardFormats(0);
+        while format != 0 {
+            if !matches!(format, CF_TEXT | CF_OEMTEXT | CF_UNICODETEXT | CF_LOCALE) {
+                non_text = true;
+                break;
+            }
+            format = EnumClipboardFormats(format);
+        }
+
+        CloseClipboard();
+        non_text
+    }
+}
+
+/// arboard can't list what the clipboard holds, so only a picture without
+/// text is caught here. Rich text comes with a plain form and passes.
+#[cfg(not(windows))]
+fn holds_other_formats() -> bool {
+    let Ok(mut clipboard) = open() else {
+        return true;
+    };
+    retry(|| clipboard.get_text()).is_err() && retry(|| clipboard.get_image()).is_ok()
+}
+
+/// The user's clipboard text, for `%clip%`. While a burst is open the live
+/// clipboard holds our previous expansion, so the snapshot is used unless the
+/// user has copied something since. Empty if the clipboard holds no text.
+pub fn user_text() -> String {
+    let current_sequence = sequence_number();
+    if let Some(burst) = BURST.lock().unwrap().as_ref().filter(|b| b.holds_our_write(current_sequence)) {
+        return burst.original().to_string();
+    }
+
+    open()
+        .and_then(|mut clipboard| retry(|| clipboard.get_text()))
+        .unwrap_or_default()
+}
+
+/// Mark the current expansion as finished. Restoring is deferred until the
+/// burst has been idle for `clipboard_restore_delay_ms`.
+pub fn finish_job() {
+    if let Some(burst) = BURST.lock().unwrap().as_mut() {
+        burst.finish_job(Instant::now());
+    }
+}
+
+/// Time left before the pending restore is due, or `None` when no burst is
+/// in progress. The processing thread uses this as its receive timeout.
+pub fn time_until_restore() -> Option<Duration> {
+    let idle = Duration::from_millis(BURST_IDLE_MS.load(Ordering::SeqCst));
+    BURST.lock().unwrap().as_ref().map(|burst| burst.time_until_restore(idle, Instant::now()))
+}
+
+/// End the burst and put the user's clipboard back, unless they copied
+/// something new in the meantime. Setting the text clears any HTML or
+/// picture an expansion left there.
+pub fn restore_after_burst() {
+    let Some(burst) = BURST.lock().unwrap().take() else {
+        return;
+    };
+
+    let restored = match open() {
+        Ok(mut clipboard) => burst.restore(&mut System(&mut clipboard)),
+        Err(e) => Err(e.into()),
+    };
+    match restored {
+        Ok(true) => {
+            crate::debug_println!("Clipboard burst ended, restored user clipboard");
+        }
+        Ok(false) => {
+            crate::debug_println!("Clipboard changed during burst, not restoring");
+        }
+        Err(e) => {
+            crate::debug_println!("Failed to restore clipboard: {:?}", e);
+            crate::log_error!("Failed to restore clipboard: {}", e);
+            crate::keyboard_hook::notify_error(&format!("Restoring your clipboard failed: {}", e));
+        }
+    }
+}
@@ -0,0 +1,242 @@
+// src/clipboard_burst.rs
+//
+// The bookkeeping behind clipboard.rs's snapshot/restore: a burst of
+// clipboard-mode expansions saves the user's text once, at its first job,
+// and puts it back once after it goes idle, unless the user copied something
+// in between. Kept apart from the Win32 calls so it can run against a fake
+// clipboard.
+
+use std::time::{Duration, Instant};
+
+/// The parts of the system clipboard a burst touches.
+pub trait ClipboardAccess {
+    /// The clipboard's text; empty if it holds none or can't be read.
+    fn text(&mut self) -> String;
+    fn set_text(&mut self, text: &str) -> Result<(), Box<dyn std::error::Error>>;
+    /// `GetClipboardSequenceNumber` on Windows: changes on every write, by
+    /// anyone.
+    fn sequence(&self) -> u32;
+    /// The sequence number once it differs from `before`, or `None` if it
+    /// doesn't within `clipboard_ready_timeout_ms`.
+    fn wait_for_change(&self, before: u32) -> Option<u32>;
+}
+
+pub struct Burst {
+    /// The user's clipboard text from before the first expansion of the burst.
+    original: String,
+    /// Clipboard sequence number right after our most recent write.
+    /// If it changes before restore, the user copied something themselves.
+    sequence_after_set: u32,
+    last_job: Instant,
+}
+
+/// Start a burst in `burst` unless one is in progress, snapshotting the
+/// user's clipboard text. True if this started it.
+pub fn begin(burst: &mut Option<Burst>, clipboard: &mut impl ClipboardAccess, now: Instant) -> bool {
+    if burst.is_some() {
+        return false;
+    }
+    *burst = Some(Burst { original: clipboard.text(), sequence_after_set: 0, last_job: now });
+    true
+}
+
+/// Put one job's expansion on the clipboard with `write`, in the burst in
+/// progress or a new one. The write has to show up in the sequence number
+/// before the caller pastes; if it doesn't, or fails, the burst ends here and
+/// the user's text goes back over whatever landed, so the caller can type the
+/// expansion instead without the clipboard being lost.
+pub fn write_job<C: ClipboardAccess>(
+    burst: &mut Option<Burst>,
+    clipboard: &mut C,
+    now: Instant,
+    write: impl FnOnce(&mut C) -> Result<(), Box<dyn std::error::Error>>,
+) -> Result<(), Box<dyn std::error::Error>> {
+    let started = begin(burst, clipboard, now);
+    let sequence_before = clipboard.sequence();
+    let written = write(clipboard).and_then(|()| {
+        let sequence = clipboard.wait_for_change(sequence_before);
+        sequence.ok_or_else(|| "the clipboard did not take the expansion in time".into())
+    });
+
+    match written {
+        Ok(sequence) => {
+            if let Some(burst) = burst.as_mut() {
+                burst.wrote(sequence);
+            }
+            Ok(())
+        }
+        Err(error) => {
+            let Some(ended) = burst.take() else {
+                return Err(error);
+            };
+            // Unless the user copied something since our last write, which
+            // the snapshot would wrongly replace
+            let restored = if started || ended.holds_our_write(sequence_before) {
+                clipboard.set_text(&ended.original)
+            } else {
+                Ok(())
+            };
+            match restored {
+                Ok(()) => Err(error),
+                Err(restore_error) => {
+                    Err(format!("{}, and restoring the clipboard failed: {}", error, restore_error).into())
+                }
+            }
+        }
+    }
+}
+
+impl Burst {
+    /// Note a write of ours that left the clipboard at `sequence`.
+    pub fn wrote(&mut self, sequence: u32) {
+        self.sequence_after_set = sequence;
+    }
+
+    /// Note that a job finished at `now`; the idle wait starts over.
+    pub fn finish_job(&mut self, now: Instant) {
+        self.last_job = now;
+    }
+
+    /// The clipboard at `sequence` still holds our last write.
+    pub fn holds_our_write(&self, sequence: u32) -> bool {
+        self.sequence_after_set == sequence
+    }
+
+    /// The snapshot of the user's clipboard text.
+    pub fn original(&self) -> &str {
+        &self.original
+    }
+
+    /// Time left at `now` before the burst has been idle for `idle`.
+    pub fn time_until_restore(&self, idle: Duration, now: Instant) -> Duration {
+        idle.saturating_sub(now.saturating_duration_since(self.last_job))
+    }
+
+    /// Put the snapshot back unless the clipboard changed since our last
+    /// write. True if it was put back.
+    pub fn restore(self, clipboard: &mut impl ClipboardAccess) -> Result<bool, Box<dyn std::error::Error>> {
+        if !self.holds_our_write(clipboard.sequence()) {
+            return Ok(false);
+        }
+        clipboard.set_text(&self.original)?;
+        Ok(true)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// A clipboard that counts reads and keeps every write.
+    struct FakeClipboard {
+        text: String,
+        sequence: u32,
+        reads: usize,
+        writes: Vec<String>,
+        /// Writes land, but too late for `wait_for_change`.
+        slow: bool,
+    }
+
+    impl FakeClipboard {
+        fn holding(text: &str) -> Self {
+            FakeClipboard { text: text.to_string(), sequence: 1, reads: 0, writes: Vec::new(), slow: false }
+        }
+
+        /// The user copies `text` in another app.
+        fn user_copies(&mut self, text: &str) {
+            self.text = text.to_string();
+            self.sequence += 1;
+        }
+    }
+
+    impl ClipboardAccess for FakeClipboard {
+        fn text(&mut self) -> String {
+            self.reads += 1;
+            self.text.clone()
+        }
+
+        fn set_text(&mut self, text: &str) -> Result<(), Box<dyn std::error::Error>> {
+            self.text = text.to_string();
+            self.sequence += 1;
+            self.writes.push(text.to_string());
+            Ok(())
+        }
+
+        fn sequence(&self) -> u32 {
+            self.sequence
+        }
+
+        fn wait_for_change(&self, before: u32) -> Option<u32> {
+            (!self.slow && self.sequence != before).then_some(self.sequence)
+        }
+    }
+
+    /// Paste `text` in `burst` as clipboard.rs does, finishing the job.
+    fn job(
+        burst: &mut Option<Burst>,
+        clipboard: &mut FakeClipboard,
+        text: &str,
+        now: Instant,
+    ) -> Result<(), Box<dyn std::error::Error>> {
+        write_job(burst, clipboard, now, |clipboard| clipboard.set_text(text))?;
+        burst.as_mut().unwrap().finish_job(now);
+        Ok(())
+    }
+
+    #[test]
+    fn a_burst_saves_once_and_restores_once() {
+        let start = Instant::now();
+        let idle = Duration::from_millis(300);
+        let mut clipboard = FakeClipboard::holding("user's text");
+        let mut burst = None;
+
+        for (i, text) in ["one", "two", "three"].into_iter().enumerate() {
+            job(&mut burst, &mut clipboard, text, start + Duration::from_millis(100 * i as u64)).unwrap();
+            assert_eq!(burst.as_ref().unwrap().original(), "user's text");
+        }
+        assert_eq!(clipboard.reads, 1, "saved once, at the first job");
+
+        let last_job = start + Duration::from_millis(200);
+        let burst = burst.take().unwrap();
+        assert_eq!(burst.time_until_restore(idle, last_job + Duration::from_millis(100)), Duration::from_millis(200));
+        assert_eq!(burst.time_until_restore(idle, last_job + idle), Duration::ZERO);
+
+        assert!(burst.restore(&mut clipboard).unwrap());
+        assert_eq!(clipboard.writes, ["one", "two", "three", "user's text"], "restored once, at the end");
+        assert_eq!(clipboard.text, "user's text");
+    }
+
+    #[test]
+    fn a_copy_during_the_burst_is_not_overwritten() {
+        let now = Instant::now();
+        let mut clipboard = FakeClipboard::holding("user's text");
+        let mut burst = None;
+
+        job(&mut burst, &mut clipboard, "one", now).unwrap();
+        clipboard.user_copies("copied since");
+        let burst = burst.take().unwrap();
+        assert!(!burst.holds_our_write(clipboard.sequence()));
+
+        assert!(!burst.restore(&mut clipboard).unwrap());
+        assert_eq!(clipboard.text, "copied since");
+        assert_eq!(clipboard.writes, ["one"]);
+    }
+
+    #[test]
+    fn a_write_that_shows_up_late_still_gets_the_user_text_back() {
+        let now = Instant::now();
+        let mut clipboard = FakeClipboard::holding("user's text");
+        let mut burst = None;
+
+        job(&mut burst, &mut clipboard, "one", now).unwrap();
+        clipboard.slow = true;
+        assert!(job(&mut burst, &mut clipboard, "two", now).is_err());
+        assert!(burst.is_none(), "the burst ends with the failed job");
+        assert_eq!(clipboard.writes, ["one", "two", "user's text"]);
+
+        // The next job snapshots the user's text again, not an expansion
+        clipboard.slow = false;
+        job(&mut burst, &mut clipboard, "three", now).unwrap();
+        assert_eq!(burst.as_ref().unwrap().original(), "user's text");
+    }
+}
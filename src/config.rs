@@ -0,0 +1,1198 @@
+// src/config.rs
+//
+// expansions.toml schema, loading, and adding snippets to the file.
+
+use std::collections::hash_map::Entry;
+use std::collections::{BTreeSet, HashMap};
+use std::env;
+use std::fs;
+use std::path::{Path, PathBuf};
+use std::sync::OnceLock;
+use std::thread;
+use std::time::{Duration, SystemTime};
+
+use chrono::NaiveDate;
+use serde::{Deserialize, Deserializer};
+use toml_edit::{DocumentMut, InlineTable, Item, Key, Table, Value};
+
+use crate::dates;
+use crate::logging::LogLevel;
+use crate::macros::{self, MacroStep};
+use crate::patterns::{self, RegexTriggers};
+use crate::template;
+use crate::transforms::{self, SelectionTransform};
+use crate::trie::TriggerTrie;
+use crate::validate;
+
+#[derive(Debug, Deserialize)]
+pub struct ExpansionFile {
+    #[serde(default)]
+    pub case_sensitive: HashMap<String, Snippet>,
+    #[serde(default)]
+    pub case_insensitive: HashMap<String, Snippet>,
+    /// `[[snippet]]` entries, moved into the two tables above as soon as
+    /// the file is parsed.
+    #[serde(default, rename = "snippet")]
+    pub listed_snippets: Vec<ListedSnippet>,
+    #[serde(default)]
+    pub settings: Settings,
+    #[serde(default, alias = "date_triggers")]
+    pub date_expansion: DateExpansion,
+    /// Trigger → scripted steps, matched case-sensitively (see macros.rs).
+    #[serde(default)]
+    pub macros: HashMap<String, Vec<MacroStep>>,
+    /// Pattern → template, e.g. `'rx(\d+)x(\d+)' = "Take $1 tablets $2 times
+    /// daily"` (see patterns.rs).
+    #[serde(default)]
+    pub regex: HashMap<String, String>,
+    /// Hotkey → selection transform, e.g. `"Ctrl+Alt+U" = "upper"` (see
+    /// transforms.rs). Like `settings`, only read from the main file.
+    #[serde(default)]
+    pub transforms: HashMap<String, String>,
+    /// Per-application overlays keyed by executable name, e.g.
+    /// `[app."OUTLOOK.EXE".case_insensitive]`. Keys are lowercased at load,
+    /// and one naming several programs, comma-separated, is split.
+    #[serde(default)]
+    pub app: HashMap<String, AppProfile>,
+    /// Built at load: `instant` snippets keyed by their last character, so
+    /// the per-keystroke check only looks at triggers that can end there.
+    #[serde(skip)]
+    pub instant_index: HashMap<char, Vec<SuffixTrigger>>,
+    /// Built at load: the other `word = false` snippets, keyed the same way
+    /// for the delimiter check.
+    #[serde(skip)]
+    pub mid_word_index: HashMap<char, Vec<SuffixTrigger>>,
+    /// Built at load: every trigger of the tables, app profiles, and macros,
+    /// for finding the ones typed and asking whether typed text could still
+    /// become one (see trie.rs).
+    #[serde(skip)]
+    pub triggers: TriggerTrie,
+    /// Built at load: `regex`, compiled.
+    #[serde(skip)]
+    pub regex_triggers: RegexTriggers,
+    /// Built at load: the most characters a trigger can be typed as (see
+    /// `longest_trigger_chars`). The engine's buffer keeps no more than that.
+    #[serde(skip)]
+    pub longest_trigger_chars: usize,
+    /// Built at load: `transforms`, parsed.
+    #[serde(skip)]
+    pub selection_transforms: Vec<SelectionTransform>,
+    /// Found at load (see validate.rs). Entries they mark `skipped` were
+    /// left out of the tables.
+    #[serde(skip)]
+    pub problems: Vec<validate::Problem>,
+}
+
+/// A `[[snippet]]` entry: its trigger and table, beside the same fields as
+/// a snippet table (`replace`, `word`, `keep_delimiter`, ...).
+#[derive(Debug, Deserialize)]
+pub struct ListedSnippet {
+    pub trigger: String,
+    /// Which table the trigger goes in; case_insensitive if unset.
+    #[serde(default)]
+    pub case_sensitive: bool,
+    #[serde(flatten)]
+    pub snippet: Snippet,
+}
+
+/// A snippet matched against the end of the buffer rather than all of it:
+/// an `instant` one, or a `word = false` one.
+#[derive(Debug, Clone)]
+pub struct SuffixTrigger {
+    /// As stored in its table (lowercased for case_insensitive).
+    pub trigger: String,
+    pub case_sensitive: bool,
+    /// The snippet's `word`: whether the trigger must start a word.
+    pub word: bool,
+    /// The `[app."..."]` profile it belongs to, `None` for the global tables.
+    pub app: Option<String>,
+}
+
+/// Snippets that apply only while a given program has focus, layered over
+/// the global tables.
+#[derive(Debug, Default, Clone, Deserialize)]
+pub struct AppProfile {
+    #[serde(default)]
+    pub case_sensitive: HashMap<String, Snippet>,
+    #[serde(default)]
+    pub case_insensitive: HashMap<String, Snippet>,
+}
+
+impl ExpansionFile {
+    /// Layer an included file's snippets, macros, and app profiles over
+    /// these. A trigger defined again replaces the earlier definition, with a
+    /// warning. The included file's settings are ignored; they come from the
+    /// main file.
+    fn merge_included(&mut self, included: ExpansionFile, path: &Path) {
+        merge_entries(&mut self.case_sensitive, included.case_sensitive, path, "case_sensitive");
+        merge_entries(&mut self.case_insensitive, included.case_insensitive, path, "case_insensitive");
+        merge_entries(&mut self.macros, included.macros, path, "macros");
+        merge_entries(&mut self.regex, included.regex, path, "regex");
+        for (app, profile) in included.app {
+            let existing = self.app.entry(app.clone()).or_default();
+            merge_entries(&mut existing.case_sensitive, profile.case_sensitive, path, &format!("app.{:?}.case_sensitive", app));
+            merge_entries(&mut existing.case_insensitive, profile.case_insensitive, path, &format!("app.{:?}.case_insensitive", app));
+        }
+        self.problems.extend(included.problems);
+    }
+
+    /// Get a parsed file (with any included files merged in) ready for
+    /// matching: fold the case-insensitive triggers, inline references, index
+    /// the suffix triggers, build the trigger trie, compile the regex triggers and
+    /// transforms, and check the settings.
+    fn prepare(&mut self) -> Result<(), Box<dyn std::error::Error>> {
+        self.fold_case_insensitive();
+        self.resolve_snippet_references()?;
+        self.index_suffix_triggers();
+        self.index_triggers();
+        self.regex_triggers = patterns::compile(&self.regex)?;
+        self.selection_transforms = transforms::compile(&self.transforms)?;
+        self.longest_trigger_chars = self.longest_trigger_chars();
+        self.date_expansion.validate()?;
+        if self.settings.cursor_marker.is_empty() {
+            return Err("[settings] cursor_marker can't be empty".into());
+        }
+        let settings = &mut self.settings;
+        let patterns = settings.excluded_apps.iter_mut().chain(&mut settings.excluded_titles);
+        let patterns = patterns.chain(&mut settings.excluded_classes).chain(&mut settings.only_apps);
+        for pattern in patterns.chain(&mut settings.remote_apps).chain(&mut settings.remote_window_classes) {
+            *pattern = pattern.to_lowercase();
+        }
+        Ok(())
+    }
+
+    /// Move the `[[snippet]]` entries into their tables, in file order. One
+    /// whose trigger is already there replaces it (`validate::lint` reports
+    /// it).
+    fn move_listed_snippets(&mut self) {
+        for listed in self.listed_snippets.drain(..) {
+            let snippets = if listed.case_sensitive { &mut self.case_sensitive } else { &mut self.case_insensitive };
+            snippets.insert(listed.trigger, listed.snippet);
+        }
+    }
+
+    /// Drop the entries `problems` skip. Run before folding, while triggers
+    /// and app names are still as written.
+    fn remove_skipped_entries(&mut self) {
+        for entry in self.problems.iter().filter_map(|problem| problem.skipped.as_ref()) {
+            if entry.table == "macros" {
+                self.macros.remove(&entry.trigger);
+                continue;
+            }
+            let (case_sensitive, case_insensitive) = match &entry.app {
+                Some(app) => match self.app.get_mut(app) {
+                    Some(profile) => (&mut profile.case_sensitive, &mut profile.case_insensitive),
+                    None => continue,
+                },
+                None => (&mut self.case_sensitive, &mut self.case_insensitive),
+            };
+            let snippets = if entry.table == "case_sensitive" { case_sensitive } else { case_insensitive };
+            snippets.remove(&entry.trigger);
+        }
+    }
+
+    /// Lowercase the case-insensitive triggers (global and per-app) and the
+    /// app names once at load, so matching only has to lowercase what was
+    /// typed, and give each program of a comma-separated profile name
+    /// (`[app."outlook.exe, olk.exe"]`) its own copy. Triggers that collide
+    /// after folding are resolved in sorted order and reported.
+    fn fold_case_insensitive(&mut self) {
+        fold_triggers(&mut self.case_insensitive);
+
+        let mut apps: Vec<(String, AppProfile)> = self.app.drain().collect();
+        apps.sort_by(|a, b| a.0.cmp(&b.0));
+        for (app, mut profile) in apps {
+            fold_triggers(&mut profile.case_insensitive);
+            for name in app.split(',').map(str::trim).filter(|name| !name.is_empty()) {
+                match self.app.entry(name.to_lowercase()) {
+                    Entry::Vacant(vacant) => {
+                        vacant.insert(profile.clone());
+                    }
+                    Entry::Occupied(mut occupied) => {
+                        println!("Warning: [app.{:?}] names {:?}, which another app profile also names", app, name);
+                        let existing = occupied.get_mut();
+                        existing.case_sensitive.extend(profile.case_sensitive.clone());
+                        existing.case_insensitive.extend(profile.case_insensitive.clone());
+                    }
+                }
+            }
+        }
+    }
+
+    /// Index every `instant` snippet, and every other `word = false` one, by
+    /// its last character. Run after folding, so case_insensitive keys are
+    /// already lowercase.
+    fn index_suffix_triggers(&mut self) {
+        let global = [(&self.case_sensitive, true), (&self.case_insensitive, false)]
+            .into_iter()
+            .map(|(snippets, case_sensitive)| (None, snippets, case_sensitive));
+        let apps = self.app.iter().flat_map(|(app, profile)| {
+            [(&profile.case_sensitive, true), (&profile.case_insensitive, false)]
+                .into_iter()
+                .map(move |(snippets, case_sensitive)| (Some(app.clone()), snippets, case_sensitive))
+        });
+
+        let mut instant: HashMap<char, Vec<SuffixTrigger>> = HashMap::new();
+        let mut mid_word: HashMap<char, Vec<SuffixTrigger>> = HashMap::new();
+        for (app, snippets, case_sensitive) in global.chain(apps) {
+            for (trigger, snippet) in snippets {
+                let index = match (snippet.options.instant, snippet.options.word) {
+                    (true, _) => &mut instant,
+                    (false, false) => &mut mid_word,
+                    (false, true) => continue,
+                };
+                if let Some(last) = trigger.chars().last() {
+                    index.entry(last).or_default().push(SuffixTrigger {
+                        trigger: trigger.clone(),
+                        case_sensitive,
+                        word: snippet.options.word,
+                        app: app.clone(),
+                    });
+                }
+            }
+        }
+        self.instant_index = instant;
+        self.mid_word_index = mid_word;
+    }
+
+    /// Inline `{{snippet:trigger}}` references into each expansion, so later
+    /// edits to the referenced snippet show up everywhere on the next load.
+    /// A snippet in an `[app."..."]` profile sees that profile's snippets
+    /// before the global ones. Run after folding.
+    fn resolve_snippet_references(&mut self) -> Result<(), Box<dyn std::error::Error>> {
+        let global = [(None, true, &self.case_sensitive), (None, false, &self.case_insensitive)];
+        let apps = self.app.iter().flat_map(|(app, profile)| {
+            [(Some(app), true, &profile.case_sensitive), (Some(app), false, &profile.case_insensitive)]
+        });
+
+        let mut resolved = Vec::new();
+        let mut problems = Vec::new();
+        for (app, case_sensitive, snippets) in global.into_iter().chain(apps) {
+            let profile = app.and_then(|app| self.app.get(app));
+            let referencing = snippets.iter().filter(|(_, snippet)| snippet.replace.contains(template::SNIPPET_REF_OPEN));
+            for (trigger, snippet) in referencing {
+                let lookup = |name: &str| self.snippet_text(profile, name);
+                let mut unknown = Vec::new();
+                let text = template::resolve_snippet_refs(trigger, &snippet.replace, &lookup, &mut unknown)
+                    .map_err(|e| format!("Snippet {:?}: {}", trigger, e))?;
+                problems.extend(unknown.into_iter().map(|reference| validate::Problem {
+                    file: None,
+                    line: None,
+                    column: None,
+                    message: format!("{:?}: {} doesn't name a snippet", trigger, reference),
+                    skipped: None,
+                }));
+                resolved.push((app.cloned(), case_sensitive, trigger.clone(), text));
+            }
+        }
+        self.problems.extend(problems);
+
+        for (app, case_sensitive, trigger, text) in resolved {
+            let (case_sensitive_table, case_insensitive_table) = match app.and_then(|app| self.app.get_mut(&app)) {
+                Some(profile) => (&mut profile.case_sensitive, &mut profile.case_insensitive),
+                None => (&mut self.case_sensitive, &mut self.case_insensitive),
+            };
+            let snippets = if case_sensitive { case_sensitive_table } else { case_insensitive_table };
+            if let Some(snippet) = snippets.get_mut(&trigger) {
+                snippet.replace = text;
+            }
+        }
+        Ok(())
+    }
+
+    /// The expansion a `{{snippet:name}}` reference names, as written:
+    /// `profile`'s tables first, then the global ones, case-sensitive before
+    /// case_insensitive. `cmd` snippets have no text until run, so they
+    /// count as unknown.
+    fn snippet_text<'a>(&'a self, profile: Option<&'a AppProfile>, name: &str) -> Option<&'a str> {
+        let lowered = name.to_lowercase();
+        let mut tables = Vec::new();
+        if let Some(profile) = profile {
+            tables.push((&profile.case_sensitive, name));
+            tables.push((&profile.case_insensitive, lowered.as_str()));
+        }
+        tables.push((&self.case_sensitive, name));
+        tables.push((&self.case_insensitive, lowered.as_str()));
+        tables
+            .into_iter()
+            .find_map(|(snippets, key)| snippets.get(key))
+            .filter(|snippet| snippet.options.command.is_none())
+            .map(|snippet| snippet.replace.as_str())
+    }
+
+    /// Put every trigger into the `triggers` trie. Run after folding.
+    fn index_triggers(&mut self) {
+        let profiles = || self.app.values();
+        let case_sensitive = self.case_sensitive.keys().chain(self.macros.keys());
+        let case_sensitive = case_sensitive.chain(profiles().flat_map(|profile| profile.case_sensitive.keys()));
+        let case_insensitive = self.case_insensitive.keys();
+        let case_insensitive = case_insensitive.chain(profiles().flat_map(|profile| profile.case_insensitive.keys()));
+        self.triggers = TriggerTrie::new(case_sensitive, case_insensitive);
+    }
+
+    /// The longest trigger of the tables, app profiles, and macros; a date
+    /// prefix with `dates::MAX_COUNT_CHARS`; or, if there are `[regex]`
+    /// triggers, `patterns::MAX_MATCH_CHARS`. In characters. Run after the
+    /// trigger trie and the regex triggers are built.
+    fn longest_trigger_chars(&self) -> usize {
+        let dates = self.date_expansion.prefixes.keys().map(|prefix| prefix.chars().count() + dates::MAX_COUNT_CHARS);
+        let regex = (!self.regex_triggers.is_empty()).then_some(patterns::MAX_MATCH_CHARS);
+        dates.chain(regex).fold(self.triggers.longest_chars(), usize::max)
+    }
+
+    /// The snippet a `SuffixTrigger` points at.
+    pub fn suffix_snippet(&self, suffix: &SuffixTrigger) -> Option<&Snippet> {
+        let (case_sensitive, case_insensitive) = match &suffix.app {
+            Some(app) => {
+                let profile = self.app.get(app)?;
+                (&profile.case_sensitive, &profile.case_insensitive)
+            }
+            None => (&self.case_sensitive, &self.case_insensitive),
+        };
+        let snippets = if suffix.case_sensitive { case_sensitive } else { case_insensitive };
+        snippets.get(&suffix.trigger)
+    }
+
+    /// The overlay for `exe_name` (already lowercase), if it has one.
+    pub fn app_profile(&self, exe_name: Option<&str>) -> Option<&AppProfile> {
+        self.app.get(exe_name?)
+    }
+}
+
+/// Move `from`'s entries into `into`, reporting each trigger `into` already had.
+fn merge_entries<T>(into: &mut HashMap<String, T>, from: HashMap<String, T>, path: &Path, table: &str) {
+    let mut entries: Vec<(String, T)> = from.into_iter().collect();
+    entries.sort_by(|a, b| a.0.cmp(&b.0));
+    for (trigger, entry) in entries {
+        if into.insert(trigger.clone(), entry).is_some() {
+            println!("Warning: {}: [{}] {:?} overrides an earlier definition", path.display(), table, trigger);
+            crate::log_warn!("{}: [{}] {:?} overrides an earlier definition", path.display(), table, trigger);
+        }
+    }
+}
+
+fn fold_triggers(snippets: &mut HashMap<String, Snippet>) {
+    let mut triggers: Vec<(String, Snippet)> = snippets.drain().collect();
+    triggers.sort_by(|a, b| a.0.cmp(&b.0));
+
+    for (trigger, snippet) in triggers {
+        let folded = trigger.to_lowercase();
+        if snippets.insert(folded, snippet).is_some() {
+            println!("Warning: case_insensitive trigger {:?} duplicates another trigger after lowercasing", trigger);
+        }
+    }
+}
+
+impl DateExpansion {
+    /// Reject a bad format at load rather than at the first date trigger.
+    fn validate(&self) -> Result<(), Box<dyn std::error::Error>> {
+        use std::fmt::Write;
+
+        let mut probe = String::new();
+        write!(probe, "{}", chrono::Local::now().format(&self.format))
+            .map_err(|_| format!("Invalid [date_expansion] format: {:?}", self.format))?;
+        Ok(())
+    }
+}
+
+/// One trigger's expansion. In TOML this is either a plain string
+/// (`"mtx" = "methotrexate"`), a table with per-snippet options
+/// (`"sig" = { replace = "...", priority = 5 }`), or a shell command whose
+/// output is inserted (`"gb" = { cmd = "git branch --show-current" }`).
+#[derive(Debug, Clone, Deserialize)]
+#[serde(from = "SnippetEntry")]
+pub struct Snippet {
+    pub replace: String,
+    pub options: SnippetOptions,
+}
+
+/// Per-snippet options. All optional; a plain-string snippet gets the defaults.
+#[derive(Debug, Clone, Deserialize)]
+#[serde(default)]
+pub struct SnippetOptions {
+    /// Final tiebreak when several snippets match the same typed text.
+    pub priority: i32,
+    /// Whether the typed trigger is replaced or kept in front of the expansion.
+    pub insert_mode: InsertMode,
+    /// For case_insensitive triggers, mirror the typed capitalization
+    /// (`Btw` → `By the way`, `BTW` → `BY THE WAY`). Turn off for URLs/code.
+    pub propagate_case: bool,
+    /// Overrides `[settings] injection` for this snippet.
+    pub injection: Option<Injection>,
+    /// Overrides `[settings] typing_delay_ms` for this snippet.
+    pub typing_delay_ms: Option<u64>,
+    /// Formatted form of `replace`, put on the clipboard alongside it so
+    /// Outlook and Word paste bold and links while plain-text apps paste
+    /// `replace`. Clipboard injection only; never recased.
+    pub html: Option<String>,
+    /// Fire as soon as the trigger's last character is typed, with no
+    /// Space/Enter. Nothing is added after the expansion; end `replace` with
+    /// a space to get one.
+    #[serde(alias = "immediate")]
+    pub instant: bool,
+    /// The trigger must start a word: the character before it is not a
+    /// letter or digit, or the trigger itself starts with one that isn't.
+    /// With `false` it also fires at the end of a longer word (`takeq4h`),
+    /// and only the trigger is deleted.
+    #[serde(alias = "word_boundary")]
+    pub word: bool,
+    /// Drop line breaks at the end of `replace`, which can send a chat
+    /// message or submit a form. Unset: only when the snippet is pasted.
+    pub strip_trailing_newline: Option<bool>,
+    /// Ask before expanding, for long or destructive snippets. The trigger
+    /// stays until the user agrees; no leaves it as typed.
+    pub confirm: bool,
+    /// Re-emit the Space/Enter/Tab that fired the trigger after the
+    /// expansion. Off replaces the delimiter along with the trigger.
+    pub keep_delimiter: bool,
+    /// Treat `$1`, `$2`, ... `$0` in the expansion as tab stops: the caret
+    /// starts at `$1` and Tab moves on. Opt-in, since `$10` is usually money.
+    pub tab_stops: bool,
+    /// The snippet's group, switched on and off from the tray's Groups menu.
+    /// Unset: `groups::DEFAULT_GROUP`.
+    pub group: Option<String>,
+    /// Set for `cmd` snippets, whose `replace` is `template::COMMAND_OUTPUT`.
+    #[serde(skip)]
+    pub command: Option<ShellCommand>,
+    /// Set for `image` snippets: the picture pasted in place of `replace`,
+    /// which is empty.
+    #[serde(skip)]
+    pub image: Option<PathBuf>,
+}
+
+/// A snippet's `cmd`: run on trigger, its stdout is the expansion.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ShellCommand {
+    pub cmd: String,
+    /// The expansion is abandoned if the command runs longer than this.
+    pub timeout_ms: u64,
+    /// Inserted instead when the command fails or times out, rather than
+    /// leaving the trigger as typed.
+    pub fallback: Option<String>,
+}
+
+fn default_command_timeout_ms() -> u64 {
+    2000
+}
+
+impl Default for SnippetOptions {
+    fn default() -> Self {
+        SnippetOptions {
+            priority: 0,
+            insert_mode: InsertMode::Replace,
+            propagate_case: true,
+            injection: None,
+            typing_delay_ms: None,
+            html: None,
+            instant: false,
+            word: true,
+            strip_trailing_newline: None,
+            confirm: false,
+            keep_delimiter: true,
+            tab_stops: false,
+            group: None,
+            command: None,
+            image: None,
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum InsertMode {
+    /// Delete the trigger, then insert the expansion and the delimiter.
+    Replace,
+    /// Keep the trigger, then insert the expansion and the delimiter.
+    Append,
+    /// Keep the trigger, then insert the delimiter and the expansion.
+    AppendAfterDelimiter,
+}
+
+/// How expansion text gets into the target app.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Injection {
+    /// Put the text on the clipboard and send Ctrl+V. Best for rich-text
+    /// editors; the user's clipboard is restored afterwards.
+    Clipboard,
+    /// Type the text as KEYEVENTF_UNICODE key events. Leaves the clipboard
+    /// alone and works in apps that block paste.
+    Unicode,
+    /// Like `Unicode`, but one character at a time, `typing_delay_ms`
+    /// apart, for apps (remote sessions, validating web forms) that drop
+    /// characters from a burst.
+    Typed,
+}
+
+#[derive(Deserialize)]
+#[serde(untagged)]
+enum SnippetEntry {
+    Text(String),
+    Table {
+        #[serde(alias = "text")]
+        replace: String,
+        #[serde(flatten)]
+        options: SnippetOptions,
+    },
+    Command {
+        cmd: String,
+        #[serde(default = "default_command_timeout_ms")]
+        timeout_ms: u64,
+        fallback: Option<String>,
+        #[serde(flatten)]
+        options: SnippetOptions,
+    },
+    Image {
+        image: PathBuf,
+        #[serde(flatten)]
+        options: SnippetOptions,
+    },
+}
+
+impl From<SnippetEntry> for Snippet {
+    fn from(entry: SnippetEntry) -> Self {
+        match entry {
+            SnippetEntry::Text(replace) => Snippet { replace, options: SnippetOptions::default() },
+            SnippetEntry::Table { replace, options } => Snippet { replace, options },
+            // Command output is inserted as-is, never recased
+            SnippetEntry::Command { cmd, timeout_ms, fallback, options } => Snippet {
+                replace: template::COMMAND_OUTPUT.to_string(),
+                options: SnippetOptions {
+                    propagate_case: false,
+                    command: Some(ShellCommand { cmd, timeout_ms, fallback }),
+                    ..options
+                },
+            },
+            // A picture can only be pasted
+            SnippetEntry::Image { image, options } => Snippet {
+                replace: String::new(),
+                options: SnippetOptions {
+                    propagate_case: false,
+                    injection: Some(Injection::Clipboard),
+                    image: Some(image),
+                    ..options
+                },
+            },
+        }
+    }
+}
+
+/// Optional `[settings]` table. Every field has a default so existing
+/// expansion files keep working unchanged.
+#[derive(Debug, Deserialize)]
+#[serde(default)]
+pub struct Settings {
+    /// Offer near-match completion when Tab is pressed after a partial or
+    /// slightly misspelled trigger.
+    pub tab_completion: bool,
+    /// What a Tab completion inserts in place of the typed fragment.
+    pub tab_completion_insert: TabCompletionInsert,
+    /// A second Tab within this window cycles to the next candidate.
+    pub tab_cycle_timeout_ms: u64,
+    /// A repeat of the delimiter that fired an expansion, pressed within this
+    /// many milliseconds of it, is dropped as a reflexive double-tap. 0 disables.
+    pub delimiter_debounce_ms: u32,
+    /// A key pressed this many seconds after the previous one starts a fresh
+    /// buffer, so a half-typed trigger doesn't fire long after. 0 disables.
+    pub idle_reset_secs: u64,
+    /// Chord that pauses/resumes expansion, e.g. "Ctrl+Alt+E". "" disables it.
+    #[serde(deserialize_with = "deserialize_hotkey")]
+    pub toggle_hotkey: Option<Hotkey>,
+    /// Chord that opens the quick-search popup, e.g. "Ctrl+Shift+Space".
+    /// "" disables it.
+    #[serde(deserialize_with = "deserialize_hotkey")]
+    pub search_hotkey: Option<Hotkey>,
+    /// Chord that inserts the last expansion again, as it was inserted, e.g.
+    /// "Ctrl+Alt+.". "" disables it.
+    #[serde(deserialize_with = "deserialize_hotkey")]
+    pub repeat_hotkey: Option<Hotkey>,
+    /// Executable names (e.g. "keepass.exe") where nothing is tracked or
+    /// expanded. Case-insensitive; lowercased at load. Defaults to
+    /// `CREDENTIAL_APPS`; setting it replaces that list.
+    pub excluded_apps: Vec<String>,
+    /// Window-title substrings excluded the same way, e.g. "VIM" for vim in
+    /// a terminal. Case-insensitive; lowercased at load.
+    pub excluded_titles: Vec<String>,
+    /// Window classes excluded the same way, e.g. "unitywndclass" for
+    /// games. Case-insensitive; lowercased at load.
+    pub excluded_classes: Vec<String>,
+    /// If set, the only executable names where anything is tracked or
+    /// expanded; the exclusions still apply inside them. Case-insensitive;
+    /// lowercased at load.
+    pub only_apps: Vec<String>,
+    /// Default injection backend; snippets can override it.
+    pub injection: Injection,
+    /// Pause between characters with `injection = "typed"`.
+    pub typing_delay_ms: u64,
+    /// How long the clipboard may take to show an expansion written to it
+    /// before the expansion is typed instead of pasted.
+    pub clipboard_ready_timeout_ms: u64,
+    /// How long the target app gets to read the clipboard after Ctrl+V. No
+    /// signal says it has, so slow apps (browser extensions) need more.
+    pub paste_settle_ms: u64,
+    /// How long no expansion must fire before the user's clipboard is put
+    /// back.
+    pub clipboard_restore_delay_ms: u64,
+    /// Keys that complete a trigger.
+    pub delimiters: Delimiters,
+    /// Where in an expansion the caret is left, e.g. "$|" (the default).
+    pub cursor_marker: String,
+    /// Treat a focused password box (an edit control with `ES_PASSWORD`, or
+    /// an element accessibility reports as protected, as browsers do) like
+    /// an excluded app. Off for apps that misreport either.
+    pub detect_password_fields: bool,
+    /// Suspend tracking and expansion while a remote-desktop or VM client
+    /// has focus, where the guest may run its own expander and injected
+    /// backspaces and pastes don't behave.
+    pub suppress_in_remote: bool,
+    /// Executable names treated as remote clients on top of `REMOTE_APPS`.
+    /// Case-insensitive; lowercased at load.
+    pub remote_apps: Vec<String>,
+    /// Window classes treated as remote clients on top of
+    /// `REMOTE_WINDOW_CLASSES`. Case-insensitive; lowercased at load.
+    pub remote_window_classes: Vec<String>,
+    /// Show a balloon from the tray icon for each expansion, with its
+    /// trigger and length. Failures always get one.
+    pub notify_on_expand: bool,
+    /// How much goes in text_expander.log next to this file;
+    /// `TEXT_EXPANDER_LOG` overrides it. Typed keys only at "trace".
+    pub log_level: LogLevel,
+}
+
+/// Password managers and the Windows credential prompt, excluded unless
+/// `excluded_apps` is set.
+pub const CREDENTIAL_APPS: &[&str] = &[
+    "credentialuibroker.exe",
+    "keepass.exe",
+    "keepassxc.exe",
+    "1password.exe",
+    "bitwarden.exe",
+];
+
+/// Remote-desktop and VM clients recognised by `suppress_in_remote`: Remote
+/// Desktop (old and new client), Hyper-V, Windows Sandbox, VMware,
+/// VirtualBox, and Citrix.
+pub const REMOTE_APPS: &[&str] = &[
+    "mstsc.exe",
+    "msrdc.exe",
+    "vmconnect.exe",
+    "windowssandboxclient.exe",
+    "vmware.exe",
+    "vmplayer.exe",
+    "virtualboxvm.exe",
+    "wfica32.exe",
+    "cdviewer.exe",
+];
+
+/// Window classes of the same clients, for when the process can't be
+/// queried; lowercase.
+pub const REMOTE_WINDOW_CLASSES: &[&str] = &["tscshellcontainerclass", "vmuiframe", "transparent windows client"];
+
+impl Default for Settings {
+    fn default() -> Self {
+        Settings {
+            tab_completion: false,
+            tab_completion_insert: TabCompletionInsert::Expansion,
+            tab_cycle_timeout_ms: 1500,
+            delimiter_debounce_ms: 150,
+            idle_reset_secs: 10,
+            toggle_hotkey: Some(Hotkey { vk: b'E' as u16, ctrl: true, alt: true, shift: false, win: false }),
+            search_hotkey: Some(Hotkey { vk: 0x20, ctrl: true, alt: false, shift: true, win: false }),
+            repeat_hotkey: Some(Hotkey { vk: 0xBE, ctrl: true, alt: true, shift: false, win: false }),
+            excluded_apps: CREDENTIAL_APPS.iter().map(|app| app.to_string()).collect(),
+            excluded_titles: Vec::new(),
+            excluded_classes: Vec::new(),
+            only_apps: Vec::new(),
+            injection: Injection::Clipboard,
+            typing_delay_ms: 20,
+            clipboard_ready_timeout_ms: 200,
+            paste_settle_ms: 150,
+            clipboard_restore_delay_ms: 300,
+            delimiters: Delimiters::default(),
+            cursor_marker: template::CURSOR_MARKER.to_string(),
+            detect_password_fields: true,
+            suppress_in_remote: false,
+            remote_apps: Vec::new(),
+            remote_window_classes: Vec::new(),
+            notify_on_expand: false,
+            log_level: LogLevel::Info,
+        }
+    }
+}
+
+/// Punctuation accepted in `delimiters`.
+const PUNCTUATION_DELIMITERS: &str = ".,;:?!()";
+
+/// Which keys complete a trigger, from e.g. `delimiters = ["space", "enter", "."]`.
+/// Space, Enter, and Tab are swallowed by the hook and re-emitted with the
+/// expansion. Punctuation reaches the app first, so it is deleted along with
+/// the trigger and retyped after the expansion (`btw.` → `by the way.`).
+#[derive(Debug, Clone, Deserialize)]
+#[serde(try_from = "Vec<String>")]
+pub struct Delimiters {
+    pub space: bool,
+    pub enter: bool,
+    pub tab: bool,
+    pub punctuation: Vec<char>,
+}
+
+impl Default for Delimiters {
+    fn default() -> Self {
+        Delimiters { space: true, enter: true, tab: false, punctuation: Vec::new() }
+    }
+}
+
+impl TryFrom<Vec<String>> for Delimiters {
+    type Error = String;
+
+    fn try_from(names: Vec<String>) -> Result<Self, Self::Error> {
+        let mut delimiters = Delimiters { space: false, enter: false, tab: false, punctuation: Vec::new() };
+        for name in names {
+            match name.to_ascii_lowercase().as_str() {
+                "space" => delimiters.space = true,
+                "enter" | "return" => delimiters.enter = true,
+                "tab" => delimiters.tab = true,
+                other => {
+                    let mut chars = other.chars();
+                    match (chars.next(), chars.next()) {
+                        (Some(c), None) if PUNCTUATION_DELIMITERS.contains(c) => delimiters.punctuation.push(c),
+                        _ => {
+                            return Err(format!(
+                                "unknown delimiter {:?} (expected space, enter, tab, or one of {})",
+                                name, PUNCTUATION_DELIMITERS
+                            ))
+                        }
+                    }
+                }
+            }
+        }
+        Ok(delimiters)
+    }
+}
+
+/// A modifier chord the keyboard hook watches for.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Hotkey {
+    pub vk: u16,
+    pub ctrl: bool,
+    pub alt: bool,
+    pub shift: bool,
+    pub win: bool,
+}
+
+impl Hotkey {
+    /// Parse "Ctrl+Alt+E"-style text. Empty or "none" means no hotkey.
+    /// Rejects chords the low-level hook can't see or that would fire
+    /// during normal typing.
+    pub fn parse(text: &str) -> Result<Option<Hotkey>, String> {
+        let text = text.trim();
+        if text.is_empty() || text.eq_ignore_ascii_case("none") {
+            return Ok(None);
+        }
+
+        let (mut ctrl, mut alt, mut shift, mut win) = (false, false, false, false);
+        let mut vk = None;
+        for part in text.split('+').map(str::trim) {
+            match part.to_ascii_lowercase().as_str() {
+                "ctrl" | "control" => ctrl = true,
+                "alt" => alt = true,
+                "shift" => shift = true,
+                "win" => win = true,
+                _ => {
+                    let key = macros::key_by_name(part)
+                        .filter(|key| !key.is_modifier())
+                        .ok_or_else(|| format!("hotkey {:?}: unknown key {:?}", text, part))?;
+                    if vk.replace(key.vk()).is_some() {
+                        return Err(format!("hotkey {:?}: only one non-modifier key is allowed", text));
+                    }
+                }
+            }
+        }
+
+        let vk = vk.ok_or_else(|| format!("hotkey {:?}: needs a key besides the modifiers", text))?;
+        if !(ctrl || alt || win) {
+            return Err(format!("hotkey {:?}: needs Ctrl, Alt, or Win so it can't fire while typing", text));
+        }
+        // Reserved by Windows; these never reach a low-level hook
+        let reserved = (ctrl && alt && vk == 0x2E) || (win && vk == b'L' as u16);
+        if reserved {
+            return Err(format!("hotkey {:?}: reserved by Windows", text));
+        }
+
+        Ok(Some(Hotkey { vk, ctrl, alt, shift, win }))
+    }
+}
+
+fn deserialize_hotkey<'de, D: Deserializer<'de>>(deserializer: D) -> Result<Option<Hotkey>, D::Error> {
+    let text = String::deserialize(deserializer)?;
+    Hotkey::parse(&text).map_err(serde::de::Error::custom)
+}
+
+fn deserialize_dates<'de, D: Deserializer<'de>>(deserializer: D) -> Result<BTreeSet<NaiveDate>, D::Error> {
+    let dates = Vec::<String>::deserialize(deserializer)?;
+    dates
+        .iter()
+        .map(|date| {
+            NaiveDate::parse_from_str(date, "%Y-%m-%d")
+                .map_err(|_| serde::de::Error::custom(format!("{:?} is not a YYYY-MM-DD date", date)))
+        })
+        .collect()
+}
+
+/// Optional `[date_expansion]` table (also spelled `[date_triggers]`):
+/// relative-date trigger prefixes and the output format. Defaults reproduce
+/// the original `/days`, `/wks`, `/mo` triggers with `M/D/YY` output.
+#[derive(Debug, Deserialize)]
+#[serde(default)]
+pub struct DateExpansion {
+    /// chrono strftime format for the resulting date.
+    pub format: String,
+    /// Trigger prefix → unit. Replaces the default set when given.
+    pub prefixes: HashMap<String, DateUnit>,
+    /// Dates (`YYYY-MM-DD`) that business days skip, as they do weekends.
+    #[serde(deserialize_with = "deserialize_dates")]
+    pub holidays: BTreeSet<NaiveDate>,
+}
+
+impl Default for DateExpansion {
+    fn default() -> Self {
+        let prefixes = [
+            ("/days", DateUnit::Days),
+            ("/wks", DateUnit::Weeks),
+            ("/mo", DateUnit::Months),
+            ("/mos", DateUnit::Months),
+            ("/yrs", DateUnit::Years),
+            ("/bdays", DateUnit::BusinessDays),
+        ];
+
+        DateExpansion {
+            format: "%-m/%-d/%y".to_string(),
+            prefixes: prefixes.into_iter().map(|(p, u)| (p.to_string(), u)).collect(),
+            holidays: BTreeSet::new(),
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum DateUnit {
+    #[serde(alias = "day")]
+    Days,
+    #[serde(alias = "week")]
+    Weeks,
+    #[serde(alias = "month")]
+    Months,
+    #[serde(alias = "year")]
+    Years,
+    /// Days counted Monday–Friday only.
+    #[serde(alias = "business_day")]
+    BusinessDays,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum TabCompletionInsert {
+    /// Replace the fragment with the candidate's expansion text.
+    Expansion,
+    /// Replace the fragment with the candidate's trigger, ready for Space/Enter.
+    Trigger,
+}
+
+/// How often the watcher thread checks the config file's modification time.
+pub const CONFIG_POLL_INTERVAL_MS: u64 = 2000;
+
+/// Environment variable that points at an expansions.toml.
+pub const CONFIG_ENV_VAR: &str = "TEXT_EXPANDER_CONFIG";
+
+/// Written to the per-user `expansions.toml` (`user_config_dir`) when no
+/// config file exists anywhere, so a fresh install starts with something to edit.
+const STARTER_TEMPLATE: &str = r#"# Text Expander snippets. Type a trigger, then Space or Enter.
+# See the expansions.toml shipped with the source for every option.
+
+[case_sensitive]
+"addr" = "123 Main Street"
+
+[case_insensitive]
+"btw" = "by the way"
+"ty" = "thank you"
+
+[macros]
+"nn" = ["delete(2)", 'date("%-m/%-d/%y: ")']
+"#;
+
+static CONFIG_PATH: OnceLock<PathBuf> = OnceLock::new();
+
+/// The file the live table was loaded from, once `load_expansion_table` has run.
+pub fn config_path() -> Option<&'static Path> {
+    CONFIG_PATH.get().map(PathBuf::as_path)
+}
+
+/// The path given with `--config <path>` or `--config=<path>`, if any.
+pub fn config_arg() -> Result<Option<PathBuf>, Box<dyn std::error::Error>> {
+    let mut args = env::args().skip(1);
+    while let Some(arg) = args.next() {
+        if arg == "--config" {
+            return Ok(Some(args.next().ok_or("--config needs a path")?.into()));
+        }
+        if let Some(path) = arg.strip_prefix("--config=") {
+            return Ok(Some(path.into()));
+        }
+    }
+    Ok(None)
+}
+
+/// Pick the config file: `--config <path>`, then `TEXT_EXPANDER_CONFIG`,
+/// then expansions.toml in the per-user config directory (`user_config_dir`),
+/// then expansions.toml next to the executable. A path given explicitly must
+/// exist. If none of the last two exist, a starter file is written to the
+/// per-user location; the error lists every path tried.
+pub fn resolve_config_path() -> Result<PathBuf, Box<dyn std::error::Error>> {
+    if let Some(path) = config_arg()? {
+        return existing(path, "--config");
+    }
+
+    if let Some(path) = env::var_os(CONFIG_ENV_VAR).filter(|p| !p.is_empty()) {
+        return existing(path.into(), CONFIG_ENV_VAR);
+    }
+
+    let user_path = user_config_dir().map(|dir| dir.join("expansions.toml"));
+    if let Some(path) = user_path.as_ref().filter(|p| p.is_file()) {
+        return Ok(path.clone());
+    }
+
+    let exe_path = env::current_exe()?
+        .parent()
+        .ok_or("Failed to get executable directory")?
+        .join("expansions.toml");
+    if exe_path.is_file() {
+        return Ok(exe_path);
+    }
+
+    let tried: Vec<String> = user_path.iter().chain([&exe_path]).map(|path| path.display().to_string()).collect();
+    let Some(path) = user_path else {
+        return Err(format!(
+            "No expansions.toml found (tried {}) and no per-user config directory is set; use --config or {}",
+            tried.join(", "),
+            CONFIG_ENV_VAR
+        )
+        .into());
+    };
+    let write_starter = || -> std::io::Result<()> {
+        if let Some(dir) = path.parent() {
+            fs::create_dir_all(dir)?;
+        }
+        fs::write(&path, STARTER_TEMPLATE)
+    };
+    write_starter().map_err(|e| {
+        format!("No expansions.toml found (tried {}), and creating {} failed: {}", tried.join(", "), path.display(), e)
+    })?;
+    println!("No expansions.toml found, created a starter file at: {:?}", path);
+
+    Ok(path)
+}
+
+/// `path` if it is a file, or an error naming where it came from.
+fn existing(path: PathBuf, source: &str) -> Result<PathBuf, Box<dyn std::error::Error>> {
+    if path.is_file() {
+        Ok(path)
+    } else {
+        Err(format!("Config file {} (from {}) does not exist", path.display(), source).into())
+    }
+}
+
+/// The per-user directory for text_expander's files: `%APPDATA%\text_expander`,
+/// or where `APPDATA` isn't set, `$XDG_CONFIG_HOME/text_expander` or
+/// `~/.config/text_expander`.
+fn user_config_dir() -> Option<PathBuf> {
+    let var = |name| env::var_os(name).filter(|value| !value.is_empty()).map(PathBuf::from);
+    let base = var("APPDATA").or_else(|| var("XDG_CONFIG_HOME")).or_else(|| Some(var("HOME")?.join(".config")))?;
+    Some(base.join("text_expander"))
+}
+
+pub fn load_expansion_table() -> Result<ExpansionFile, Box<dyn std::error::Error>> {
+    let path = resolve_config_path()?;
+    println!("Loading expansions from: {:?}", path);
+
+    let expansion_file = load_from_path(&path)?;
+    let _ = CONFIG_PATH.set(path);
+    Ok(expansion_file)
+}
+
+/// Re-read the file the live table came from.
+pub fn reload_expansion_table() -> Result<ExpansionFile, Box<dyn std::error::Error>> {
+    let path = config_path().ok_or("No config file has been loaded yet")?;
+    load_from_path(path)
+}
+
+/// Read and parse `path` and merge in its `snippets.d` files. An included
+/// file that can't be read or parsed is reported and left out. Problems that
+/// don't stop the load are logged as warnings and left in `problems` for the
+/// caller to show.
+fn load_from_path(path: &Path) -> Result<ExpansionFile, Box<dyn std::error::Error>> {
+    let mut expansion_file = read_entries(path, None)?;
+    let delimiters = expansion_file.settings.delimiters.punctuation.clone();
+    for include in included_files(path) {
+        match read_entries(&include, Some(&delimiters)) {
+            Ok(included) => expansion_file.merge_included(included, &include),
+            Err(error) => expansion_file.problems.push(validate::Problem {
+                file: None,
+                line: None,
+                column: None,
+                message: error.to_string(),
+                skipped: None,
+            }),
+        }
+    }
+    expansion_file.prepare().map_err(|e| format!("Failed to load {}: {}", path.display(), e))?;
+
+    for problem in &expansion_file.problems {
+        println!("Warning: {}", problem);
+        crate::log_warn!("{}", problem);
+    }
+    Ok(expansion_file)
+}
+
+/// One file's entries, before merging and `prepare`. Its problems name it.
+fn read_entries(path: &Path, delimiters: Option<&[char]>) -> Result<ExpansionFile, Box<dyn std::error::Error>> {
+    let contents = fs::read_to_string(path)
+        .map_err(|e| format!("Failed to read {}: {}", path.display(), e))?;
+    let mut expansion_file = parse_entries(&contents, delimiters).map_err(|e| {
+        format!("Failed to parse {}: {}", path.display(), validate::parse_problem(&contents, e.as_ref()))
+    })?;
+    for problem in &mut expansion_file.problems {
+        problem.file = Some(path.to_path_buf());
+    }
+    Ok(expansion_file)
+}
+
+/// Directory next to the config file whose `*.toml` files are merged into it.
+pub const INCLUDE_DIR: &str = "snippets.d";
+
+/// The `snippets.d/*.toml` files that go with the config file at `path`, in
+/// the order they are merged: by file name, later files winning.
+pub fn included_files(path: &Path) -> Vec<PathBuf> {
+    let Some(dir) = path.parent().map(|dir| dir.join(INCLUDE_DIR)) else {
+        return Vec::new();
+    };
+    let Ok(entries) = fs::read_dir(&dir) else {
+        return Vec::new();
+    };
+    let mut files: Vec<PathBuf> = entries
+        .filter_map(|entry| entry.ok().map(|entry| entry.path()))
+        .filter(|file| file.is_file() && file.extension().is_some_and(|ext| ext.eq_ignore_ascii_case("toml")))
+        .collect();
+    files.sort();
+    files
+}
+
+/// Parse expansions.toml text and prepare it for matching (see
+/// `ExpansionFile::prepare`). Entries validation skips are left out.
+pub fn parse_expansion_file(contents: &str) -> Result<ExpansionFile, Box<dyn std::error::Error>> {
+    let mut expansion_file = parse_entries(contents, None)?;
+    expansion_file.prepare()?;
+    Ok(expansion_file)
+}
+
+/// Deserialize one file and drop the entries validation skips. Triggers are
+/// checked against the punctuation `delimiters` given, else the file's own.
+pub(crate) fn parse_entries(contents: &str, delimiters: Option<&[char]>) -> Result<ExpansionFile, Box<dyn std::error::Error>> {
+    let mut expansion_file: ExpansionFile = toml::from_str(contents)?;
+    expansion_file.move_listed_snippets();
+    let delimiters = delimiters.unwrap_or(&expansion_file.settings.delimiters.punctuation);
+    expansion_file.problems = validate::lint(contents, delimiters);
+    expansion_file.remove_skipped_entries();
+    Ok(expansion_file)
+}
+
+/// What `insert_snippet` did with a trigger.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum SnippetInsert {
+    Added,
+    /// Already there with the same expansion.
+    Unchanged,
+    /// Already there with a different expansion, which is kept.
+    Conflict,
+}
+
+/// Add a snippet to expansions.toml opened with toml_edit, keeping the
+/// file's comments and layout. case_insensitive triggers are lowercased as
+/// at load. `instant` writes a `{ replace = ..., instant = true }` table.
+pub fn insert_snippet(
+    doc: &mut DocumentMut,
+    case_insensitive: bool,
+    trigger: &str,
+    replace: &str,
+    instant: bool,
+) -> Result<SnippetInsert, Box<dyn std::error::Error>> {
+    let table_name = if case_insensitive { "case_insensitive" } else { "case_sensitive" };
+    let table = doc
+        .entry(table_name)
+        .or_insert_with(|| Item::Table(Table::new()))
+        .as_table_mut()
+        .ok_or_else(|| format!("[{}] is not a table", table_name))?;
+    let trigger = if case_insensitive { trigger.to_lowercase() } else { trigger.to_string() };
+
+    if let Some(existing) = table.get(&trigger) {
+        let same = match existing.as_value() {
+            Some(Value::String(text)) => text.value() == replace,
+            Some(Value::InlineTable(entry)) => entry.get("replace").and_then(Value::as_str) == Some(replace),
+            _ => false,
+        };
+        return Ok(if same { SnippetInsert::Unchanged } else { SnippetInsert::Conflict });
+    }
+
+    let value = if instant {
+        let mut entry = InlineTable::new();
+        entry.insert("replace", Value::from(replace));
+        entry.insert("instant", Value::from(true));
+        Value::InlineTable(entry)
+    } else {
+        Value::from(replace)
+    };
+    // Quoted like the rest of the file, not as a bare key
+    let key: Key = Value::from(trigger.as_str()).to_string().parse()?;
+    table.insert_formatted(&key, Item::Value(value));
+    Ok(SnippetInsert::Added)
+}
+
+/// Poll the live config file and its `snippets.d` files from a background
+/// thread and reload when any of them changes, appears, or goes away.
+/// `on_change` gets the new table or the load error; returning `false` means
+/// "not now" and the reload is retried on the next poll.
+pub fn watch_for_changes<F>(mut on_change: F)
+where
+    F: FnMut(Result<ExpansionFile, Box<dyn std::error::Error>>) -> bool + Send + 'static,
+{
+    let Some(path) = config_path() else {
+        return;
+    };
+
+    thread::spawn(move || {
+        let mut last_seen = modification_times(path);
+        loop {
+            thread::sleep(Duration::from_millis(CONFIG_POLL_INTERVAL_MS));
+
+            // Missing mid-save (editors that write via rename); try again later
+            let Some(current) = modification_times(path) else {
+                continue;
+            };
+            if last_seen.as_ref() == Some(&current) {
+                continue;
+            }
+
+            println!("Config changed, reloading: {:?}", path);
+            if on_change(load_from_path(path)) {
+                last_seen = Some(current);
+            }
+        }
+    });
+}
+
+/// The config file's modification time followed by each included file's,
+/// or `None` while the config file itself is missing.
+fn modification_times(path: &Path) -> Option<Vec<(PathBuf, SystemTime)>> {
+    let modified = |path: &Path| fs::metadata(path).and_then(|m| m.modified()).ok();
+    let mut times = vec![(path.to_path_buf(), modified(path)?)];
+    times.extend(included_files(path).into_iter().filter_map(|file| Some((file.clone(), modified(&file)?))));
+    Some(times)
+}
@@ -0,0 +1,87 @@
+// src/counters.rs
+//
+// `{{counter:name}}` values, kept in counters.toml next to the config file
+// so numbering carries on across restarts. Every increment rewrites the file
+// through a temporary file and a rename, so a crash mid-write leaves the
+// previous values intact.
+
+use std::collections::BTreeMap;
+use std::fs;
+use std::path::PathBuf;
+use std::sync::Mutex;
+
+use crate::config;
+
+const COUNTERS_FILE: &str = "counters.toml";
+
+/// Last value handed out per counter name. `None` until first loaded.
+static COUNTERS: Mutex<Option<BTreeMap<String, i64>>> = Mutex::new(None);
+
+fn state_path() -> Option<PathBuf> {
+    Some(config::config_path()?.parent()?.join(COUNTERS_FILE))
+}
+
+/// Read counters.toml. Called at startup; a missing file means no counter
+/// has been used yet, and an unreadable one is reported and started over.
+pub fn load() {
+    *COUNTERS.lock().unwrap() = Some(read_state());
+}
+
+fn read_state() -> BTreeMap<String, i64> {
+    let Some(path) = state_path() else {
+        return BTreeMap::new();
+    };
+    let Ok(contents) = fs::read_to_string(&path) else {
+        return BTreeMap::new();
+    };
+    toml::from_str(&contents).unwrap_or_else(|e| {
+        println!("Warning: ignoring unreadable {}: {}", path.display(), e);
+        BTreeMap::new()
+    })
+}
+
+/// The next value of counter `name`: `start` the first time, then the last
+/// value plus `step`. Saved before it is returned.
+pub fn next_value(name: &str, start: i64, step: i64) -> i64 {
+    let mut counters = COUNTERS.lock().unwrap();
+    let counters = counters.get_or_insert_with(read_state);
+    let value = counters.get(name).map_or(start, |last| last.saturating_add(step));
+    counters.insert(name.to_string(), value);
+    if let Err(e) = save(counters) {
+        println!("Error: could not save counters: {}", e);
+    }
+    value
+}
+
+/// Every counter and its last value, by name.
+pub fn snapshot() -> Vec<(String, i64)> {
+    let mut counters = COUNTERS.lock().unwrap();
+    counters.get_or_insert_with(read_state).iter().map(|(name, value)| (name.clone(), *value)).collect()
+}
+
+/// Make `value` the last value of counter `name`, so the next one follows
+/// it, or with `None` forget the counter so it starts over at its `start`.
+pub fn set(name: &str, value: Option<i64>) -> Result<(), Box<dyn std::error::Error>> {
+    let mut counters = COUNTERS.lock().unwrap();
+    let counters = counters.get_or_insert_with(read_state);
+    match value {
+        Some(value) => counters.insert(name.to_string(), value),
+        None => counters.remove(name),
+    };
+    save(counters)
+}
+
+/// Forget every counter, so each starts over at its `start` value.
+pub fn reset_all() -> Result<(), Box<dyn std::error::Error>> {
+    let mut counters = COUNTERS.lock().unwrap();
+    let counters = counters.insert(BTreeMap::new());
+    save(counters)
+}
+
+fn save(counters: &BTreeMap<String, i64>) -> Result<(), Box<dyn std::error::Error>> {
+    let path = state_path().ok_or("No config file has been loaded yet")?;
+    let temp = path.with_extension("toml.tmp");
+    fs::write(&temp, toml::to_string(counters)?)?;
+    fs::rename(&temp, &path)?;
+    Ok(())
+}
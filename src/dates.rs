@@ -0,0 +1,100 @@
+// src/dates.rs
+//
+// Relative-date triggers: a configured prefix followed by a signed count,
+// e.g. "/days40", "/wks-2", "/bdays10". Prefixes, the output format, and the
+// holidays business days skip come from the `[date_expansion]` table in
+// expansions.toml.
+
+use std::collections::BTreeSet;
+use std::fmt::Write;
+
+use chrono::{DateTime, Datelike, Local, Months, NaiveDate, TimeDelta, Weekday};
+
+use crate::config::{DateExpansion, DateUnit};
+
+/// Longest count after a prefix the typed word is kept for: a sign and
+/// seven digits, well past any date anyone means.
+pub const MAX_COUNT_CHARS: usize = 8;
+
+/// Checks the buffer for a relative-date trigger and returns the formatted
+/// date, or `None` if the buffer isn't one.
+pub fn handle_date_expansion(buffer: &str, config: &DateExpansion) -> Option<String> {
+    // Longest prefix first so e.g. "/mos" is never read as "/mo" + "s..."
+    let mut prefixes: Vec<(&String, &DateUnit)> = config.prefixes.iter().collect();
+    prefixes.sort_by(|a, b| b.0.len().cmp(&a.0.len()).then_with(|| a.0.cmp(b.0)));
+
+    let (unit, count) = prefixes.into_iter().find_map(|(prefix, unit)| {
+        let count = buffer.strip_prefix(prefix.as_str())?.parse::<i64>().ok()?;
+        Some((*unit, count))
+    })?;
+
+    let date = offset_date(Local::now(), unit, count, &config.holidays)?;
+
+    let mut formatted = String::new();
+    if write!(formatted, "{}", date.format(&config.format)).is_err() {
+        crate::debug_println!("Invalid date_expansion format: {}", config.format);
+        return None;
+    }
+
+    crate::debug_println!("Date expansion {:?} {} -> {}", unit, count, formatted);
+    Some(formatted)
+}
+
+/// True if `typed` is the start of a date trigger: part of a prefix, or a
+/// prefix followed by a sign and/or digits.
+pub fn could_start_date_trigger(typed: &str, config: &DateExpansion) -> bool {
+    config.prefixes.keys().any(|prefix| {
+        if prefix.starts_with(typed) {
+            return true;
+        }
+        let Some(count) = typed.strip_prefix(prefix.as_str()) else {
+            return false;
+        };
+        let digits = count.strip_prefix(['-', '+']).unwrap_or(count);
+        digits.chars().all(|c| c.is_ascii_digit())
+    })
+}
+
+/// Move `from` by `count` units. Months and years clamp to the end of a
+/// shorter month (Jan 31 + 1 month = Feb 28/29). Business days skip
+/// weekends and `holidays`.
+pub fn offset_date(
+    from: DateTime<Local>,
+    unit: DateUnit,
+    count: i64,
+    holidays: &BTreeSet<NaiveDate>,
+) -> Option<DateTime<Local>> {
+    match unit {
+        DateUnit::Days => from.checked_add_signed(TimeDelta::try_days(count)?),
+        DateUnit::Weeks => from.checked_add_signed(TimeDelta::try_weeks(count)?),
+        DateUnit::Months => add_months(from, count),
+        DateUnit::Years => add_months(from, count.checked_mul(12)?),
+        DateUnit::BusinessDays => add_business_days(from, count, holidays),
+    }
+}
+
+fn add_months(from: DateTime<Local>, count: i64) -> Option<DateTime<Local>> {
+    let months = Months::new(u32::try_from(count.unsigned_abs()).ok()?);
+    if count >= 0 {
+        from.checked_add_months(months)
+    } else {
+        from.checked_sub_months(months)
+    }
+}
+
+/// Step one day at a time, counting only Monday–Friday days that aren't
+/// holidays.
+fn add_business_days(from: DateTime<Local>, count: i64, holidays: &BTreeSet<NaiveDate>) -> Option<DateTime<Local>> {
+    let step = TimeDelta::try_days(count.signum())?;
+    let mut date = from;
+    let mut remaining = count.unsigned_abs();
+
+    while remaining > 0 {
+        date = date.checked_add_signed(step)?;
+        if !matches!(date.weekday(), Weekday::Sat | Weekday::Sun) && !holidays.contains(&date.date_naive()) {
+            remaining -= 1;
+        }
+    }
+
+    Some(date)
+}
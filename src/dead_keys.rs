@@ -0,0 +1,56 @@
+// src/dead_keys.rs
+//
+// Dead-key composition for the key buffer. The hook translates keys without
+// touching the system's dead-key state (so the target app still composes
+// them), which means composing `´` + `e` into `é` for the buffer is done here.
+
+/// Each dead key's base letters and what they compose to, position by position.
+const COMPOSITIONS: &[(&[char], &str, &str)] = &[
+    (&['´', '\''], "aeiouycnszAEIOUYCNSZ", "áéíóúýćńśźÁÉÍÓÚÝĆŃŚŹ"),
+    (&['`'], "aeiouAEIOU", "àèìòùÀÈÌÒÙ"),
+    (&['^', 'ˆ'], "aeiouAEIOU", "âêîôûÂÊÎÔÛ"),
+    (&['~', '˜'], "anoANO", "ãñõÃÑÕ"),
+    (&['¨', '"'], "aeiouyAEIOUY", "äëïöüÿÄËÏÖÜŸ"),
+    (&['¸'], "cC", "çÇ"),
+    (&['ˇ'], "cdenrstzCDENRSTZ", "čďěňřšťžČĎĚŇŘŠŤŽ"),
+    (&['°', '˚'], "auAU", "åůÅŮ"),
+];
+
+/// The text a dead key followed by `typed` produces, matching what Windows
+/// does: the composed letter when there is one, the accent alone after
+/// Space, and otherwise the accent followed by the key's own text.
+pub fn compose(dead: char, typed: &str) -> String {
+    let mut chars = typed.chars();
+    let (Some(base), None) = (chars.next(), chars.next()) else {
+        return format!("{}{}", dead, typed);
+    };
+
+    if base == ' ' {
+        return dead.to_string();
+    }
+
+    COMPOSITIONS
+        .iter()
+        .filter(|(accents, _, _)| accents.contains(&dead))
+        .find_map(|(_, bases, composed)| {
+            let index = bases.chars().position(|c| c == base)?;
+            composed.chars().nth(index)
+        })
+        .map(String::from)
+        .unwrap_or_else(|| format!("{}{}", dead, base))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::compose;
+
+    #[test]
+    fn dead_keys_compose_like_windows() {
+        assert_eq!(compose('´', "e"), "é");
+        assert_eq!(compose('\'', "E"), "É");
+        assert_eq!(compose('~', "n"), "ñ");
+        assert_eq!(compose('¨', "a"), "ä");
+        assert_eq!(compose('^', " "), "^");
+        assert_eq!(compose('^', "x"), "^x");
+    }
+}
@@ -0,0 +1,2389 @@
+// src/engine.rs
+//
+// The expansion state machine, with no Win32 in it. main.rs turns hook
+// events into `KeyPress`es; `ExpansionEngine::handle` tracks the typed word
+// and returns the `Action`s the target app should see. Sending input, the
+// clipboard, and the foreground window lookup stay in the binary, so the
+// engine can be driven from tests with plain keystroke sequences.
+
+use std::collections::{BTreeSet, HashMap, VecDeque};
+use std::hash::{BuildHasher, DefaultHasher, Hash, Hasher, RandomState};
+use std::path::PathBuf;
+use std::time::{Duration, Instant, SystemTime};
+
+use chrono::{Datelike, Local};
+
+use crate::config::{
+    ExpansionFile, Injection, InsertMode, Settings, ShellCommand, Snippet, SnippetOptions, SuffixTrigger,
+    TabCompletionInsert,
+};
+use crate::dates;
+use crate::groups;
+use crate::macros::MacroStep;
+use crate::search::{self, SearchEntry};
+use crate::stats;
+use crate::template::{self, Choice};
+use crate::transforms::SelectionTransform;
+
+/// Near-match completion only considers one-edit-away triggers once the typed
+/// fragment is at least this long; shorter fragments are one edit away from
+/// too many of the two-letter triggers.
+const NEAR_MATCH_MIN_LEN: usize = 3;
+
+/// Backspace undoes the last expansion only if pressed within this long of it.
+const UNDO_TIMEOUT_MS: u64 = 3000;
+
+/// Expansions kept for `repeat_hotkey` and the tray's History menu.
+const HISTORY_LEN: usize = 10;
+
+/// Space or Enter. The hook always swallows these.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Delimiter {
+    Space,
+    Enter,
+}
+
+impl Delimiter {
+    /// The text the delimiter puts in the app.
+    fn text(self) -> &'static str {
+        match self {
+            Delimiter::Space => " ",
+            Delimiter::Enter => "\n",
+        }
+    }
+}
+
+/// A hook event, reduced to what the engine needs.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum InputEvent {
+    /// A printable key and the text it produced under the active layout
+    /// (`None` for a dead key or a Ctrl/Alt chord).
+    Char(Option<String>),
+    Delimiter(Delimiter),
+    Tab,
+    Backspace,
+    /// Forward delete.
+    Delete,
+    ArrowLeft,
+    ArrowRight,
+    /// Up/Down, Home/End, PageUp/PageDown, Escape: the caret left the word.
+    /// With Shift held they select instead, which resets all the same.
+    Navigation,
+    /// Shift+Left/Right: part of the word is selected, and typing would
+    /// replace it. The buffer doesn't model selections, so this resets it.
+    Select,
+    /// Any key with Ctrl, Alt, or Win held (Ctrl+A, Ctrl+V, Ctrl+Enter...):
+    /// the text before the caret may have changed.
+    Shortcut,
+    /// A mouse button went down; the caret may be anywhere now.
+    MouseClick,
+    /// Modifiers and other keys that don't edit text.
+    Other,
+}
+
+#[derive(Debug, Clone)]
+pub struct KeyPress {
+    pub event: InputEvent,
+    /// Hook timestamp in milliseconds, for delimiter debouncing.
+    pub time: u32,
+    /// The hook swallowed the key, so it only reaches the app through an
+    /// action.
+    pub swallowed: bool,
+}
+
+/// Something to do to the target app. A key's actions run in order.
+#[derive(Debug, Clone, PartialEq)]
+pub enum Action {
+    /// Send the swallowed key on unchanged.
+    PassThrough,
+    /// Backspace over this many characters.
+    DeleteChars(usize),
+    /// Insert snippet text. Placeholders and the cursor marker are still in
+    /// it; the caller resolves them.
+    InsertText(Insertion),
+    /// Type literal text as Unicode key events.
+    TypeText(String),
+    /// Run a `[macros]` entry. Its steps handle the trigger themselves.
+    RunMacro(Vec<MacroStep>),
+    /// Run a `cmd` snippet's command before touching the app. On success do
+    /// `then`, with `template::COMMAND_OUTPUT` in its text replaced by the
+    /// output; on failure or timeout do `otherwise`, which leaves the
+    /// trigger as typed.
+    RunCommand { command: ShellCommand, then: Vec<Action>, otherwise: Vec<Action> },
+    /// Ask before expanding a `confirm` snippet, before touching the app. On
+    /// yes do `then`; on no do `otherwise`, which leaves the trigger as typed.
+    Confirm { trigger: String, then: Vec<Action>, otherwise: Vec<Action> },
+    /// Move the caret this many characters left or right, to a tab stop.
+    CaretLeft(usize),
+    CaretRight(usize),
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct Insertion {
+    /// The snippet's trigger as stored, for notifications; `None` for a Tab
+    /// completion.
+    pub trigger: Option<String>,
+    pub text: String,
+    /// Pasted alongside `text` as CF_HTML with clipboard injection.
+    pub html: Option<String>,
+    /// An `image` snippet's picture, pasted before `text` (the kept
+    /// delimiter). `undo_text` is typed back if it can't be loaded.
+    pub image: Option<PathBuf>,
+    pub injection: Injection,
+    /// Pause between characters for `Injection::Typed`.
+    pub typing_delay_ms: u64,
+    /// Typed back if a Backspace undoes the insertion; `None` if it can't
+    /// be. Passed to `ExpansionEngine::record_expansion` once inserted.
+    pub undo_text: Option<String>,
+    /// `$1`, `$2`, ... are tab stops (`template::take_tab_stops`), handed
+    /// to `ExpansionEngine::start_tab_stops` once inserted.
+    pub tab_stops: bool,
+    /// `text` is a repeat from the history, inserted as it was the first
+    /// time: placeholders, fill-ins, and markers are not resolved again.
+    pub resolved: bool,
+}
+
+/// One finished expansion, kept in memory only.
+#[derive(Debug, Clone, PartialEq)]
+pub struct HistoryEntry {
+    pub trigger: String,
+    /// As inserted: placeholders resolved and markers stripped.
+    pub text: String,
+    pub injection: Injection,
+    pub typing_delay_ms: u64,
+    pub time: SystemTime,
+}
+
+pub struct ExpansionEngine {
+    key_buffer: String,
+    expansion_table: ExpansionFile,
+    /// Caret position within `key_buffer`, in chars (not bytes).
+    cursor_position: usize,
+    /// The buffer's first word lost its start to the length cap
+    /// (`cap_buffer`), so it can only end in a `word = false` trigger.
+    cut_mid_word: bool,
+    typing_state: TypingState,
+    tab_cycle: Option<TabCycle>,
+    /// Delimiter that fired the last expansion and its hook timestamp (ms),
+    /// for collapsing a reflexive double-tap into the same gesture.
+    last_delimiter: Option<(Delimiter, u32)>,
+    /// The expansion that just finished, while a Backspace can still undo it.
+    last_expansion: Option<LastExpansion>,
+    /// When the last key or click was handled, for `idle_reset_secs`.
+    last_input: Option<Instant>,
+    /// Tab stops of the last insertion not visited yet.
+    tab_stops: Option<TabStopSession>,
+    /// Next option of each `{{cycle:...}}`, by trigger and which cycle
+    /// placeholder of its expansion it is. Reset by a reload.
+    cycle_positions: HashMap<(String, usize), usize>,
+    /// For `{{choose:...}}`; seeded once per run, never zero.
+    rng_state: u64,
+    /// Groups switched off from the tray. Kept across reloads, even for
+    /// groups the new table doesn't have.
+    disabled_groups: BTreeSet<String>,
+    /// The last `HISTORY_LEN` expansions, newest first. Never saved.
+    history: VecDeque<HistoryEntry>,
+}
+
+/// Enough of a finished expansion to take it back.
+struct LastExpansion {
+    /// Characters the expansion put on screen (a line break counts once).
+    inserted_chars: usize,
+    /// What to type in its place: the trigger as typed and/or the delimiter.
+    undo_text: String,
+    finished: Instant,
+}
+
+/// Positions are Left presses from the end of the inserted text, which
+/// typing at the caret doesn't change for stops after it.
+struct TabStopSession {
+    /// Stops still to visit, next first.
+    stops: Vec<usize>,
+    caret: usize,
+    /// Net characters typed at the caret since Tab moved it there. Every
+    /// stop before the caret is that much further from the end.
+    typed: isize,
+}
+
+/// Tab completion in progress: what was inserted and what the next Tab offers.
+struct TabCycle {
+    /// Texts to insert, best candidate first.
+    candidates: Vec<String>,
+    index: usize,
+    /// Characters the last insertion put on screen (`\n` counts once).
+    inserted_chars: usize,
+    last_tab: Instant,
+}
+
+#[derive(Debug, Clone, Copy)]
+enum TypingState {
+    Typing,
+    Empty,
+    NoMatch,
+}
+
+impl ExpansionEngine {
+    pub fn new(expansion_table: ExpansionFile) -> Self {
+        ExpansionEngine {
+            key_buffer: String::with_capacity(buffer_bytes(&expansion_table)),
+            expansion_table,
+            cursor_position: 0,
+            cut_mid_word: false,
+            typing_state: TypingState::Empty,
+            tab_cycle: None,
+            last_delimiter: None,
+            last_expansion: None,
+            last_input: None,
+            tab_stops: None,
+            cycle_positions: HashMap::new(),
+            rng_state: RandomState::new().hash_one(SystemTime::now()) | 1,
+            disabled_groups: BTreeSet::new(),
+            history: VecDeque::new(),
+        }
+    }
+
+    pub fn settings(&self) -> &Settings {
+        &self.expansion_table.settings
+    }
+
+    /// The `[transforms]` hotkeys, in hotkey order.
+    pub fn selection_transforms(&self) -> &[SelectionTransform] {
+        &self.expansion_table.selection_transforms
+    }
+
+    /// The word typed so far, as tracked.
+    pub fn buffer(&self) -> &str {
+        &self.key_buffer
+    }
+
+    /// The snippets the quick-search popup lists.
+    pub fn search_entries(&self) -> Vec<SearchEntry> {
+        search::entries(&self.expansion_table)
+    }
+
+    /// Insert the global snippet stored under `trigger` at the caret, with
+    /// nothing typed to delete, for the quick-search popup. Empty if the
+    /// table no longer has it (reloaded while the popup was open).
+    pub fn insert_by_trigger(&mut self, trigger: &str) -> Vec<Action> {
+        let table = &self.expansion_table;
+        let Some(snippet) = table.case_sensitive.get(trigger).or_else(|| table.case_insensitive.get(trigger)) else {
+            return Vec::new();
+        };
+        let (text, options) = (snippet.replace.clone(), snippet.options.clone());
+        crate::log_info!("Inserting {:?} from search", trigger);
+        stats::record(trigger, 0, text.chars().count());
+        self.reset();
+
+        let text = self.resolve_choices(trigger, text);
+        let text = self.strip_trailing_newline(text, &options);
+        let inserted = SnippetOptions { insert_mode: InsertMode::Replace, keep_delimiter: false, ..options.clone() };
+        let mut job = ExpansionJob::new("", text, "", true, &inserted);
+        // Nothing typed to bring back
+        job.undo_text = None;
+        await_command(job.into_actions(Some(trigger), &self.expansion_table.settings), &options, Vec::new())
+    }
+
+    /// Forget the typed word and any pending Tab cycle, double-tap or undo.
+    /// The typed keys are zeroed, not just dropped.
+    pub fn reset(&mut self) {
+        self.key_buffer.clear();
+        scrub_spare_capacity(&mut self.key_buffer);
+        self.typing_state = TypingState::Empty;
+        self.cursor_position = 0;
+        self.cut_mid_word = false;
+        self.tab_cycle = None;
+        self.last_delimiter = None;
+        self.last_expansion = None;
+    }
+
+    /// Swap in a reloaded expansions.toml. The buffer is reset so a half-typed
+    /// trigger isn't matched against the new table.
+    pub fn replace_expansion_table(&mut self, expansion_table: ExpansionFile) {
+        self.expansion_table = expansion_table;
+        self.tab_stops = None;
+        self.cycle_positions.clear();
+        self.reset();
+        reserve_scrubbed(&mut self.key_buffer, buffer_bytes(&self.expansion_table));
+    }
+
+    /// Every group in the table, `groups::DEFAULT_GROUP` first, and whether
+    /// it is switched on.
+    pub fn groups(&self) -> Vec<(String, bool)> {
+        groups::names(&self.expansion_table)
+            .into_iter()
+            .map(|group| {
+                let enabled = !self.disabled_groups.contains(&group);
+                (group, enabled)
+            })
+            .collect()
+    }
+
+    pub fn disabled_groups(&self) -> &BTreeSet<String> {
+        &self.disabled_groups
+    }
+
+    pub fn set_disabled_groups(&mut self, disabled: BTreeSet<String>) {
+        self.disabled_groups = disabled;
+    }
+
+    /// Switch `group` off, or back on if it is off. True if it is on now.
+    pub fn toggle_group(&mut self, group: &str) -> bool {
+        let enabled = self.disabled_groups.remove(group);
+        if !enabled {
+            self.disabled_groups.insert(group.to_string());
+        }
+        enabled
+    }
+
+    /// Whether `snippet`'s group is switched on. Disabled snippets don't
+    /// fire or complete, but the search popup still lists them.
+    fn group_enabled(&self, snippet: &Snippet) -> bool {
+        !self.disabled_groups.contains(groups::group_of(snippet))
+    }
+
+    /// Remember an expansion as it was inserted, for repeating it.
+    pub fn record_history(&mut self, entry: HistoryEntry) {
+        self.history.push_front(entry);
+        self.history.truncate(HISTORY_LEN);
+    }
+
+    /// Newest first.
+    pub fn history(&self) -> &VecDeque<HistoryEntry> {
+        &self.history
+    }
+
+    pub fn clear_history(&mut self) {
+        self.history.clear();
+    }
+
+    /// Insert the `index`th most recent expansion again at the caret, exactly
+    /// as it was inserted. Empty if the history is shorter than that.
+    pub fn repeat_from_history(&mut self, index: usize) -> Vec<Action> {
+        let Some(entry) = self.history.get(index).cloned() else {
+            return Vec::new();
+        };
+        crate::log_info!("Repeating {:?}", entry.trigger);
+        self.reset();
+        vec![Action::InsertText(Insertion {
+            trigger: Some(entry.trigger),
+            text: entry.text,
+            html: None,
+            image: None,
+            injection: entry.injection,
+            typing_delay_ms: entry.typing_delay_ms,
+            undo_text: None,
+            tab_stops: false,
+            resolved: true,
+        })]
+    }
+
+    /// Arm Backspace-to-undo for an `Insertion` that put `inserted_chars`
+    /// characters on screen and finished at `now`.
+    pub fn record_expansion(&mut self, inserted_chars: usize, undo_text: String, now: Instant) {
+        self.last_expansion = Some(LastExpansion { inserted_chars, undo_text, finished: now });
+    }
+
+    /// Start a tab-stop session once an insertion with `tab_stops` is in and
+    /// the caret is `caret` Left presses from its end. `stops` are the ones
+    /// Tab still visits, in order, measured the same way.
+    pub fn start_tab_stops(&mut self, stops: Vec<usize>, caret: usize) {
+        self.tab_stops = (!stops.is_empty()).then_some(TabStopSession { stops, caret, typed: 0 });
+    }
+
+    /// Whether the hook should swallow an unmodified Tab: for Tab completion,
+    /// Tab as a delimiter, or a tab-stop session.
+    pub fn wants_tab(&self) -> bool {
+        let settings = &self.expansion_table.settings;
+        settings.tab_completion || settings.delimiters.tab || self.tab_stops.is_some()
+    }
+
+    /// End the undo window without touching the buffer.
+    pub fn forget_last_expansion(&mut self) {
+        self.last_expansion = None;
+    }
+
+    /// True if `delimiter` repeats the delimiter that just fired an expansion
+    /// within the debounce window, i.e. it is part of the same gesture and
+    /// should be dropped instead of re-injected. Only one repeat is collapsed.
+    pub fn take_debounced_delimiter(&mut self, delimiter: Delimiter, time: u32) -> bool {
+        let window = self.expansion_table.settings.delimiter_debounce_ms;
+        match self.last_delimiter.take() {
+            Some((last, last_time)) => window > 0 && last == delimiter && time.wrapping_sub(last_time) <= window,
+            None => false,
+        }
+    }
+
+    /// Feed one key (or click) through the state machine. `focused_app`
+    /// returns the focused program's exe name; it is only called when an
+    /// `[app."..."]` profile or an app-specific instant trigger needs it.
+    pub fn handle(&mut self, press: KeyPress, now: Instant, focused_app: &dyn Fn() -> Option<String>) -> Vec<Action> {
+        let KeyPress { event, time, swallowed } = press;
+
+        // After a pause the old buffer is stale: this key starts a new one.
+        // Only keys reaching `handle` count, so injected and replayed input
+        // doesn't keep a buffer alive.
+        let idle_reset = Duration::from_secs(self.expansion_table.settings.idle_reset_secs);
+        let idle = self.last_input.is_some_and(|last| now.saturating_duration_since(last) > idle_reset);
+        self.last_input = Some(now);
+        if !idle_reset.is_zero() && idle {
+            crate::debug_println!("Idle for over {:?}, buffer cleared", idle_reset);
+            self.reset();
+        }
+
+        // Backspace straight after an expansion undoes it; any other key ends
+        // the chance to undo it
+        let undo = self.last_expansion.take().filter(|last| {
+            event == InputEvent::Backspace
+                && now.saturating_duration_since(last.finished) < Duration::from_millis(UNDO_TIMEOUT_MS)
+        });
+        if let Some(last) = undo {
+            self.reset();
+            return undo_actions(last);
+        }
+
+        // A tab-stop session follows the caret through plain typing. Anything
+        // that moves it another way ends the session.
+        if let Some(session) = &mut self.tab_stops {
+            match &event {
+                InputEvent::Char(text) => session.typed += text.as_deref().map_or(0, |t| t.chars().count()) as isize,
+                InputEvent::Delimiter(_) => session.typed += 1,
+                InputEvent::Backspace => session.typed -= 1,
+                InputEvent::Tab | InputEvent::Other => {}
+                _ => self.tab_stops = None,
+            }
+        }
+
+        // Any key other than Tab accepts the current Tab completion, and any
+        // non-delimiter ends the double-tap window of the last expansion
+        if event != InputEvent::Tab {
+            self.tab_cycle = None;
+        }
+        if !matches!(event, InputEvent::Delimiter(_)) {
+            self.last_delimiter = None;
+        }
+
+        let actions = match event {
+            // Space/Enter are swallowed by the hook to prevent WM_CHAR ordering
+            // issues, so each one must come out as exactly one DelimiterAction.
+            // Backends that can't swallow them (rdev) handle them like
+            // punctuation: already in the app, deleted with the trigger.
+            InputEvent::Delimiter(delimiter) => {
+                let app = self.focused_app(focused_app);
+                match self.plan_delimiter(delimiter, time, swallowed, app.as_deref()) {
+                    DelimiterAction::Collapse => {
+                        crate::debug_println!("Collapsed repeated {:?} into the previous expansion", delimiter);
+                        Vec::new()
+                    }
+                    DelimiterAction::Reinject => pass_through(swallowed),
+                    DelimiterAction::Expand(completion) => self.fire(*completion, delimiter.text(), !swallowed),
+                }
+            }
+
+            InputEvent::Backspace => {
+                self.pop_from_buffer();
+                self.typing_state = TypingState::Typing;
+                crate::debug_println!("{:?}", &self.key_buffer);
+                Vec::new()
+            }
+
+            // Forward delete inside the tracked word; at its end it eats text
+            // we never saw
+            InputEvent::Delete => {
+                if self.cursor_at_end() {
+                    self.reset();
+                } else {
+                    let index = self.byte_index(self.cursor_position);
+                    self.key_buffer.remove(index);
+                    scrub_spare_capacity(&mut self.key_buffer);
+                    crate::debug_println!("{:?}", &self.key_buffer);
+                }
+                Vec::new()
+            }
+
+            // Cursor movement
+            InputEvent::ArrowLeft => {
+                self.decrement_cursor_position();
+                Vec::new()
+            }
+            InputEvent::ArrowRight => {
+                if self.cursor_at_end() {
+                    self.reset();
+                } else {
+                    self.cursor_position += 1;
+                }
+                Vec::new()
+            }
+
+            InputEvent::Tab => self.handle_tab(swallowed, now, focused_app),
+
+            InputEvent::Navigation | InputEvent::Select | InputEvent::MouseClick => {
+                self.reset();
+                Vec::new()
+            }
+
+            // A swallowed Ctrl+Enter/Ctrl+Space goes through unexpanded
+            InputEvent::Shortcut => {
+                self.reset();
+                pass_through(swallowed)
+            }
+
+            InputEvent::Char(text) => self.handle_char(text, focused_app),
+
+            InputEvent::Other => Vec::new(),
+        };
+
+        // An expansion typed at a tab stop replaces the session's layout
+        let edits = actions.iter().any(|action| {
+            matches!(
+                action,
+                Action::DeleteChars(_)
+                    | Action::InsertText(_)
+                    | Action::TypeText(_)
+                    | Action::RunMacro(_)
+                    | Action::RunCommand { .. }
+            )
+        });
+        if edits {
+            self.tab_stops = None;
+        }
+        actions
+    }
+
+    /// Tab: the next tab stop during a session, else an exact trigger when
+    /// Tab is a delimiter, else near-match completion when enabled, else a
+    /// navigation key. The hook only swallows Tab when one of these is on
+    /// (`wants_tab`).
+    fn handle_tab(&mut self, swallowed: bool, now: Instant, focused_app: &dyn Fn() -> Option<String>) -> Vec<Action> {
+        if !swallowed {
+            self.tab_stops = None;
+            self.reset();
+            return Vec::new();
+        }
+
+        if let Some(session) = self.tab_stops.take() {
+            self.reset();
+            return self.next_tab_stop(session);
+        }
+
+        // Tab as a delimiter: an exact trigger expands as on Space
+        if self.expansion_table.settings.delimiters.tab && matches!(self.typing_state, TypingState::Typing) {
+            let app = self.focused_app(focused_app);
+            if let Some(completion) = self.check_for_completion(app.as_deref()) {
+                self.reset();
+                return self.fire(completion, "\t", false);
+            }
+        }
+
+        let completion = if self.expansion_table.settings.tab_completion {
+            self.next_tab_completion(now)
+        } else {
+            None
+        };
+        if let Some((delete_count, text)) = completion {
+            crate::debug_println!("Tab completion: {}", text);
+            crate::log_info!("Tab completion");
+            let job = ExpansionJob {
+                delete_count,
+                text,
+                undo_text: None,
+                injection: None,
+                typing_delay_ms: None,
+                html: None,
+                image: None,
+                tab_stops: false,
+            };
+            return job.into_actions(None, &self.expansion_table.settings);
+        }
+
+        self.reset();
+        vec![Action::PassThrough]
+    }
+
+    /// Move from the session's caret to its next stop. The session continues
+    /// while stops remain.
+    fn next_tab_stop(&mut self, session: TabStopSession) -> Vec<Action> {
+        let TabStopSession { stops, caret, typed } = session;
+        let shift = |stop: usize| if stop > caret { stop.saturating_add_signed(typed) } else { stop };
+        let mut stops = stops.into_iter().map(shift);
+        let Some(next) = stops.next() else {
+            return Vec::new();
+        };
+        self.start_tab_stops(stops.collect(), next);
+
+        crate::debug_println!("Tab stop: {} from the end (caret was {})", next, caret);
+        if next > caret {
+            vec![Action::CaretLeft(next - caret)]
+        } else if next < caret {
+            vec![Action::CaretRight(caret - next)]
+        } else {
+            Vec::new()
+        }
+    }
+
+    fn handle_char(&mut self, text: Option<String>, focused_app: &dyn Fn() -> Option<String>) -> Vec<Action> {
+        if matches!(self.typing_state, TypingState::NoMatch) {
+            self.reset();
+        }
+
+        // Punctuation delimiter: the character is already in the app, so
+        // it is deleted with the trigger and retyped after the expansion.
+        // Macros assume a swallowed delimiter and are left alone.
+        let punctuation = text.as_deref().filter(|c| {
+            let mut chars = c.chars();
+            chars.next().is_some_and(|c| self.expansion_table.settings.delimiters.punctuation.contains(&c))
+                && chars.next().is_none()
+        });
+        if let Some(delimiter) = punctuation.filter(|_| matches!(self.typing_state, TypingState::Typing)) {
+            let app = self.focused_app(focused_app);
+            let completion = self
+                .check_for_completion(app.as_deref())
+                .filter(|completion| matches!(completion.action, MatchAction::Expand(..)));
+            if let Some(completion) = completion {
+                self.reset();
+                return self.fire(completion, delimiter, true);
+            }
+        }
+
+        self.typing_state = TypingState::Typing;
+        if let Some(c) = text {
+            crate::debug_println!("Char to push: '{}', len: {}, bytes: {:?}", c, c.len(), c.as_bytes());
+            self.push_to_buffer(&c);
+            self.prune_buffer();
+            crate::debug_println!("{:?}", &self.key_buffer);
+        }
+
+        // Instant triggers fire on their last character. It has already
+        // reached the app, so exactly the trigger is deleted.
+        if let Some((trigger, typed, text, options)) = self.check_for_instant_trigger(focused_app) {
+            crate::debug_println!("Instant trigger: {:?}", typed);
+            crate::log_info!("Expanding instant trigger {:?}", trigger);
+            stats::record(&trigger, trigger.chars().count(), text.chars().count());
+            self.reset();
+            let text = self.resolve_choices(&trigger, text);
+            let text = self.strip_trailing_newline(text, &options);
+            let job = ExpansionJob::new(&typed, text, "", true, &options);
+            let actions = job.into_actions(Some(&trigger), &self.expansion_table.settings);
+            return await_confirmation(&trigger, await_command(actions, &options, Vec::new()), &options, Vec::new());
+        }
+
+        Vec::new()
+    }
+
+    /// The exe name for `[app."..."]` profiles, looked up only while typing a
+    /// word and only if any profile exists. The app focused when the
+    /// delimiter is pressed picks the profile.
+    fn focused_app(&self, lookup: &dyn Fn() -> Option<String>) -> Option<String> {
+        if matches!(self.typing_state, TypingState::Typing) && !self.expansion_table.app.is_empty() {
+            lookup()
+        } else {
+            None
+        }
+    }
+
+    /// The actions for a matched completion fired by `separator`.
+    /// `separator_in_app` is set for punctuation delimiters, which were not
+    /// swallowed and have already reached the app: one more character is
+    /// deleted, and the separator is retyped with the expansion.
+    fn fire(&mut self, completion: Completion, separator: &str, separator_in_app: bool) -> Vec<Action> {
+        crate::debug_println!("Found match: {:?} ({:?})", completion.trigger, completion.score);
+        crate::log_info!("Expanding {:?}", completion.trigger);
+        // The builtin date triggers aren't snippets of the user's, so aren't counted
+        let counted = completion.score.source != SnippetSource::Builtin;
+        let trigger_chars = completion.trigger.chars().count();
+        match completion.action {
+            MatchAction::Expand(text, options) => {
+                if counted {
+                    stats::record(&completion.trigger, trigger_chars, text.chars().count());
+                }
+                let text = self.resolve_choices(&completion.trigger, text);
+                let text = self.strip_trailing_newline(text, &options);
+                let job = ExpansionJob::new(&completion.typed, text, separator, !separator_in_app, &options);
+                let actions = job.into_actions(Some(&completion.trigger), &self.expansion_table.settings);
+                let actions = await_command(actions, &options, pass_through(!separator_in_app));
+                await_confirmation(&completion.trigger, actions, &options, pass_through(!separator_in_app))
+            }
+            MatchAction::RunMacro(steps) => {
+                stats::record(&completion.trigger, trigger_chars, trigger_chars);
+                // Its steps delete the trigger; only a swallowed delimiter fires
+                // one (see `handle_char`)
+                vec![Action::RunMacro(steps)]
+            }
+        }
+    }
+
+    /// Pick the options of `trigger`'s `{{choose:...}}`, `{{cycle:...}}`, and
+    /// `{{choose_daily:...}}` placeholders. Each cycle placeholder keeps its
+    /// own place; a daily one picks by the date, trigger, and placeholder.
+    fn resolve_choices(&mut self, trigger: &str, text: String) -> String {
+        if !text.contains("{{") {
+            return text;
+        }
+        template::substitute_choices(&text, |kind, ordinal, count| match kind {
+            Choice::Choose => (self.next_random() % count as u64) as usize,
+            Choice::Daily => {
+                let mut hasher = DefaultHasher::new();
+                (Local::now().date_naive().num_days_from_ce(), trigger, ordinal).hash(&mut hasher);
+                (hasher.finish() % count as u64) as usize
+            }
+            Choice::Cycle => {
+                let position = self.cycle_positions.entry((trigger.to_string(), ordinal)).or_insert(0);
+                let index = *position % count;
+                *position += 1;
+                index
+            }
+        })
+    }
+
+    /// `text` without its trailing line breaks if the snippet strips them:
+    /// by default when it is pasted, where a pasted newline can send a chat
+    /// message or submit a form.
+    fn strip_trailing_newline(&self, text: String, options: &SnippetOptions) -> String {
+        let injection = options.injection.unwrap_or(self.expansion_table.settings.injection);
+        if options.strip_trailing_newline.unwrap_or(injection == Injection::Clipboard) {
+            text.trim_end_matches(['\r', '\n']).to_string()
+        } else {
+            text
+        }
+    }
+
+    /// xorshift64: plenty for picking a greeting.
+    fn next_random(&mut self) -> u64 {
+        let mut x = self.rng_state;
+        x ^= x << 13;
+        x ^= x >> 7;
+        x ^= x << 17;
+        self.rng_state = x;
+        x
+    }
+
+    /// Decide what a Space/Enter does and update the typing state to match.
+    fn plan_delimiter(
+        &mut self,
+        delimiter: Delimiter,
+        time: u32,
+        swallowed: bool,
+        app: Option<&str>,
+    ) -> DelimiterAction {
+        // A repeat that wasn't swallowed has reached the app already
+        if self.take_debounced_delimiter(delimiter, time) && swallowed {
+            return DelimiterAction::Collapse;
+        }
+
+        let delimiters = &self.expansion_table.settings.delimiters;
+        let enabled = match delimiter {
+            Delimiter::Space => delimiters.space,
+            Delimiter::Enter => delimiters.enter,
+        };
+
+        match self.typing_state {
+            TypingState::Typing => {
+                // Macros assume a swallowed delimiter, as with punctuation
+                let completion = if enabled { self.check_for_completion(app) } else { None };
+                let completion = completion.filter(|completion| {
+                    swallowed || matches!(completion.action, MatchAction::Expand(..))
+                });
+                if let Some(completion) = completion {
+                    self.reset();
+                    self.last_delimiter = Some((delimiter, time));
+                    return DelimiterAction::Expand(Box::new(completion));
+                }
+
+                if delimiter == Delimiter::Space {
+                    self.push_to_buffer(" ");
+                    if !self.phrase_continues() {
+                        self.typing_state = TypingState::NoMatch;
+                    }
+                } else {
+                    self.reset();
+                }
+            }
+            TypingState::NoMatch => self.reset(),
+            TypingState::Empty => {}
+        }
+
+        DelimiterAction::Reinject
+    }
+
+    /// After a Space that fired nothing: true if the text before it could
+    /// still grow into a phrase trigger (one with spaces in it), which keeps
+    /// the buffer going through the space. The text in front of the phrase
+    /// is dropped.
+    fn phrase_continues(&mut self) -> bool {
+        if !self.cursor_at_end() {
+            return false;
+        }
+        let starts = self.word_starts();
+        if self.expansion_table.triggers.scan(&self.key_buffer, &starts).partial.is_empty() {
+            return false;
+        }
+        self.prune_buffer();
+        true
+    }
+
+    fn push_to_buffer(&mut self, c: &str) {
+        let index = self.byte_index(self.cursor_position);
+        reserve_scrubbed(&mut self.key_buffer, c.len());
+        self.key_buffer.insert_str(index, c);
+        self.cursor_position += c.chars().count();
+        self.cap_buffer();
+    }
+
+    /// Keep the buffer within one character of the longest trigger (one
+    /// more for a near match with an extra letter), dropping from the
+    /// front: at a word boundary if that leaves it short enough, otherwise
+    /// mid-word, and then what's left can only end in a `word = false`
+    /// trigger.
+    fn cap_buffer(&mut self) {
+        let limit = self.expansion_table.longest_trigger_chars + 1;
+        let length = self.key_buffer.chars().count();
+        if length <= limit {
+            return;
+        }
+
+        let excess = self.byte_index(length - limit);
+        let word_start = self.word_starts().into_iter().find(|&start| start >= excess);
+        let cut = word_start.unwrap_or(excess);
+        let dropped = self.key_buffer[..cut].chars().count();
+        self.key_buffer.drain(..cut);
+        scrub_spare_capacity(&mut self.key_buffer);
+        self.cursor_position = self.cursor_position.saturating_sub(dropped);
+        self.cut_mid_word = word_start.is_none();
+    }
+
+    /// Drop leading text that can no longer be part of a trigger: the buffer
+    /// is cut at the earliest word boundary after which the text could still
+    /// become a trigger (per the trigger trie and the date prefixes). Never
+    /// inside a word, which would let "staff" fire `ff`. If nothing could,
+    /// the buffer is left alone: a Backspace may still fix a typo, and Tab
+    /// completion wants the whole fragment.
+    fn prune_buffer(&mut self) {
+        // Editing mid-word: the caret isn't at the end of the buffer
+        if !self.cursor_at_end() {
+            return;
+        }
+
+        let table = &self.expansion_table;
+        let starts = self.word_starts();
+        let partial = table.triggers.scan(&self.key_buffer, &starts).partial;
+        let could_start = |start: usize| {
+            partial.contains(&start) || dates::could_start_date_trigger(&self.key_buffer[start..], &table.date_expansion)
+        };
+
+        let start = starts.into_iter().find(|&start| could_start(start));
+        if let Some(start) = start.filter(|&start| start > 0) {
+            self.key_buffer.drain(..start);
+            scrub_spare_capacity(&mut self.key_buffer);
+            self.cursor_position = self.key_buffer.chars().count();
+            self.cut_mid_word = false;
+        }
+    }
+
+    /// Byte offsets of the word boundaries in the buffer, first to last: its
+    /// start (unless `cut_mid_word`), and every character that isn't a letter
+    /// or digit or follows one that isn't.
+    fn word_starts(&self) -> Vec<usize> {
+        let mut boundaries = if self.cut_mid_word { Vec::new() } else { vec![0] };
+        let mut previous = None;
+        for (index, c) in self.key_buffer.char_indices() {
+            let after_separator = previous.is_some_and(|p: char| !p.is_alphanumeric());
+            if index > 0 && (after_separator || !c.is_alphanumeric()) {
+                boundaries.push(index);
+            }
+            previous = Some(c);
+        }
+        boundaries
+    }
+
+    fn pop_from_buffer(&mut self) {
+        if self.cursor_position > 0 && !self.key_buffer.is_empty() {
+            self.cursor_position -= 1;
+            let index = self.byte_index(self.cursor_position);
+            self.key_buffer.remove(index);
+            scrub_spare_capacity(&mut self.key_buffer);
+        }
+    }
+
+    /// Byte offset of the `char_index`th char, or the end of the buffer.
+    fn byte_index(&self, char_index: usize) -> usize {
+        self.key_buffer.char_indices().nth(char_index).map_or(self.key_buffer.len(), |(index, _)| index)
+    }
+
+    fn cursor_at_end(&self) -> bool {
+        self.cursor_position >= self.key_buffer.chars().count()
+    }
+
+    fn decrement_cursor_position(&mut self) {
+        if self.cursor_position > 0 {
+            self.cursor_position -= 1;
+        }
+        if self.cursor_position == 0 {
+            self.reset();
+        }
+    }
+
+    /// Collect every snippet that could claim the typed text and pick the best
+    /// by `MatchScore`. Ties on score fall back to the trigger text, so the
+    /// winner never depends on table iteration order. `app` is the focused
+    /// program's exe name; its `[app."..."]` profile outranks the global
+    /// tables. Triggers are matched against the text before the caret: a
+    /// trigger, a macro, or a date must be all of it from some word boundary on
+    /// (the longest such match wins, so leading junk the buffer kept doesn't
+    /// matter; the trigger trie finds them in one pass), and `word = false`
+    /// snippets may end a longer word. `[regex]` triggers are only tried if no
+    /// snippet or macro matched, longest word first. What matched is all that
+    /// gets deleted (`Completion::typed`).
+    fn check_for_completion(&self, app: Option<&str>) -> Option<Completion> {
+        let caret = self.byte_index(self.cursor_position);
+        let buffer = &self.key_buffer[..caret];
+        let table = &self.expansion_table;
+        // Word boundaries before the caret, longest suffix first
+        let starts: Vec<usize> = self.word_starts().into_iter().filter(|&start| start < caret).collect();
+
+        let mut candidates = Vec::new();
+
+        // Only the suffixes the trie says are triggers are looked up, in the
+        // tables that kind of trigger lives in
+        let profile = table.app_profile(app);
+        for found in table.triggers.scan(buffer, &starts).candidates {
+            let typed = &buffer[found.start..];
+            let (global, in_profile) = if found.case_sensitive {
+                (&table.case_sensitive, profile.map(|profile| &profile.case_sensitive))
+            } else {
+                (&table.case_insensitive, profile.map(|profile| &profile.case_insensitive))
+            };
+            let user_tables = [(Some(global), SnippetSource::User), (in_profile, SnippetSource::App)];
+            // case_insensitive triggers were lowercased at load, so fold the
+            // typed text
+            let key = if found.case_sensitive { typed.to_string() } else { typed.to_lowercase() };
+            for (snippets, source) in user_tables {
+                let found_snippet = snippets
+                    .and_then(|snippets| snippets.get_key_value(&key))
+                    .filter(|(_, snippet)| self.group_enabled(snippet));
+                if let Some((trigger, snippet)) = found_snippet {
+                    candidates.push(snippet_completion(trigger, typed, snippet, found.case_sensitive, source));
+                }
+            }
+
+            let steps = if found.case_sensitive { table.macros.get_key_value(typed) } else { None };
+            if let Some((trigger, steps)) = steps {
+                candidates.push(Completion {
+                    trigger: trigger.clone(),
+                    typed: typed.to_string(),
+                    score: MatchScore {
+                        trigger_length: typed.chars().count(),
+                        source: SnippetSource::User,
+                        exact_case: true,
+                        priority: 0,
+                        case_sensitive: true,
+                    },
+                    action: MatchAction::RunMacro(steps.clone()),
+                });
+            }
+        }
+
+        let lookup_app = || app.map(str::to_string);
+        let mid_word = self
+            .find_suffix_trigger(&table.mid_word_index, &lookup_app)
+            .and_then(|(suffix, typed)| Some((suffix, typed, table.suffix_snippet(suffix)?)));
+        if let Some((suffix, typed, snippet)) = mid_word {
+            let source = if suffix.app.is_some() { SnippetSource::App } else { SnippetSource::User };
+            candidates.push(snippet_completion(&suffix.trigger, typed, snippet, suffix.case_sensitive, source));
+        }
+
+        if candidates.is_empty() {
+            let regex_match = starts.iter().find_map(|&start| {
+                let typed = &buffer[start..];
+                table.regex_triggers.find(typed).map(|(trigger, text)| (trigger, typed, text))
+            });
+            if let Some((trigger, typed, text)) = regex_match {
+                candidates.push(Completion {
+                    trigger: trigger.pattern.clone(),
+                    typed: typed.to_string(),
+                    score: MatchScore {
+                        trigger_length: typed.chars().count(),
+                        source: SnippetSource::User,
+                        exact_case: true,
+                        priority: 0,
+                        case_sensitive: true,
+                    },
+                    action: MatchAction::Expand(text, SnippetOptions::default()),
+                });
+            }
+        }
+
+        let date = starts.iter().find_map(|&start| {
+            let typed = &buffer[start..];
+            dates::handle_date_expansion(typed, &table.date_expansion).map(|date| (typed, date))
+        });
+        if let Some((typed, date)) = date {
+            candidates.push(Completion {
+                trigger: typed.to_string(),
+                typed: typed.to_string(),
+                score: MatchScore {
+                    trigger_length: typed.chars().count(),
+                    source: SnippetSource::Builtin,
+                    exact_case: true,
+                    priority: 0,
+                    case_sensitive: true,
+                },
+                action: MatchAction::Expand(date, SnippetOptions::default()),
+            });
+        }
+
+        candidates.sort_by(|a, b| b.score.cmp(&a.score).then_with(|| a.trigger.cmp(&b.trigger)));
+        if let Some(runner_up) = candidates.get(1) {
+            crate::log_debug!("Match runner-up: {:?} ({:?})", runner_up.trigger, runner_up.score);
+        }
+
+        candidates.into_iter().next()
+    }
+
+    /// The `instant` snippet the buffer now ends with, if any: its trigger as
+    /// stored, the trigger as typed, the expansion text, and the snippet's
+    /// options.
+    fn check_for_instant_trigger(
+        &self,
+        lookup_app: &dyn Fn() -> Option<String>,
+    ) -> Option<(String, String, String, SnippetOptions)> {
+        let table = &self.expansion_table;
+        let (instant, typed) = self.find_suffix_trigger(&table.instant_index, lookup_app)?;
+        let snippet = table.suffix_snippet(instant)?;
+        let text = if !instant.case_sensitive && snippet.options.propagate_case {
+            propagate_case(typed, &snippet.replace)
+        } else {
+            snippet.replace.clone()
+        };
+        Some((instant.trigger.clone(), typed.to_string(), text, snippet.options.clone()))
+    }
+
+    /// The trigger in `index` the text before the caret ends with, and the
+    /// part of it the trigger was typed as. Only triggers ending in the last
+    /// typed character are looked at, and a `word` trigger must start a
+    /// word. Longest trigger wins, then the focused app's profile, then the
+    /// case-sensitive table.
+    fn find_suffix_trigger<'a>(
+        &'a self,
+        index: &'a HashMap<char, Vec<SuffixTrigger>>,
+        lookup_app: &dyn Fn() -> Option<String>,
+    ) -> Option<(&'a SuffixTrigger, &'a str)> {
+        // Editing mid-word, what was just typed ends at the caret
+        let buffer = &self.key_buffer[..self.byte_index(self.cursor_position)];
+        if index.is_empty() {
+            return None;
+        }
+
+        let last = buffer.chars().last()?;
+        let folded_last = last.to_lowercase().last()?;
+        let mut buckets = vec![last];
+        if folded_last != last {
+            buckets.push(folded_last);
+        }
+
+        let mut focused_app: Option<Option<String>> = None;
+        let mut best = None;
+        for suffix in buckets.iter().filter_map(|c| index.get(c)).flatten() {
+            let length = suffix.trigger.chars().count();
+            let Some((start, _)) = buffer.char_indices().rev().nth(length - 1) else {
+                continue;
+            };
+            let typed = &buffer[start..];
+            let matched = if suffix.case_sensitive {
+                typed == suffix.trigger
+            } else {
+                typed.to_lowercase() == suffix.trigger
+            };
+            let word_start = starts_word(&buffer[..start], typed) && !(start == 0 && self.cut_mid_word);
+            if !matched || (suffix.word && !word_start) {
+                continue;
+            }
+            if !self.expansion_table.suffix_snippet(suffix).is_some_and(|snippet| self.group_enabled(snippet)) {
+                continue;
+            }
+            if let Some(app) = &suffix.app {
+                let focused = focused_app.get_or_insert_with(lookup_app);
+                if focused.as_deref() != Some(app.as_str()) {
+                    continue;
+                }
+            }
+
+            let rank = (length, suffix.app.is_some(), suffix.case_sensitive);
+            if best.as_ref().is_none_or(|(best_rank, _, _)| rank > *best_rank) {
+                best = Some((rank, suffix, typed));
+            }
+        }
+
+        best.map(|(_, suffix, typed)| (suffix, typed))
+    }
+
+    /// Advance Tab completion: cycle to the next candidate if a cycle is still
+    /// live, otherwise start one from the near matches of the typed fragment.
+    /// Returns the number of characters to delete and the text to insert.
+    fn next_tab_completion(&mut self, now: Instant) -> Option<(usize, String)> {
+        let timeout = Duration::from_millis(self.expansion_table.settings.tab_cycle_timeout_ms);
+        let insert = self.expansion_table.settings.tab_completion_insert;
+
+        if let Some(cycle) = self.tab_cycle.as_mut() {
+            if cycle.candidates.len() > 1 && now.saturating_duration_since(cycle.last_tab) < timeout {
+                cycle.index = (cycle.index + 1) % cycle.candidates.len();
+                cycle.last_tab = now;
+                let delete_count = cycle.inserted_chars;
+                let text = cycle.candidates[cycle.index].clone();
+                cycle.inserted_chars = text.chars().count();
+                if insert == TabCompletionInsert::Trigger {
+                    self.key_buffer.clear();
+                    reserve_scrubbed(&mut self.key_buffer, text.len());
+                    self.key_buffer.push_str(&text);
+                    scrub_spare_capacity(&mut self.key_buffer);
+                    self.cursor_position = text.chars().count();
+                }
+                return Some((delete_count, text));
+            }
+            self.tab_cycle = None;
+            return None;
+        }
+
+        if !matches!(self.typing_state, TypingState::Typing) {
+            return None;
+        }
+
+        let candidates: Vec<String> = self
+            .find_near_matches()
+            .into_iter()
+            .map(|(trigger, expansion)| match insert {
+                TabCompletionInsert::Expansion => expansion,
+                TabCompletionInsert::Trigger => trigger,
+            })
+            .collect();
+        let first = candidates.first()?.clone();
+        let delete_count = self.key_buffer.chars().count();
+
+        self.reset();
+        if insert == TabCompletionInsert::Trigger {
+            // Leave the trigger in the buffer so Space/Enter expands it
+            self.push_to_buffer(&first);
+            self.typing_state = TypingState::Typing;
+        }
+        self.tab_cycle = Some(TabCycle {
+            inserted_chars: first.chars().count(),
+            candidates,
+            index: 0,
+            last_tab: now,
+        });
+
+        Some((delete_count, first))
+    }
+
+    /// Triggers that nearly match the buffer, best first: the one trigger the
+    /// buffer is a unique prefix of, then triggers exactly one edit away.
+    /// A case_insensitive snippet's expansion takes the typed fragment's case
+    /// as it would when typed out in full.
+    fn find_near_matches(&self) -> Vec<(String, String)> {
+        if self.cut_mid_word {
+            return Vec::new();
+        }
+        let buffer = self.key_buffer.as_str();
+        let lowered = buffer.to_lowercase();
+        let table = &self.expansion_table;
+
+        let entries = table.case_sensitive.iter().map(|entry| (entry, buffer, true))
+            .chain(table.case_insensitive.iter().map(|entry| (entry, lowered.as_str(), false)))
+            .filter(|((_, snippet), _, _)| self.group_enabled(snippet));
+
+        let mut prefixed = Vec::new();
+        let mut one_edit = Vec::new();
+        for ((trigger, snippet), typed, case_sensitive) in entries {
+            let expansion = || {
+                if !case_sensitive && snippet.options.propagate_case {
+                    propagate_case(buffer, &snippet.replace)
+                } else {
+                    snippet.replace.clone()
+                }
+            };
+            if trigger.starts_with(typed) {
+                prefixed.push((trigger.clone(), expansion()));
+            } else if typed.chars().count() >= NEAR_MATCH_MIN_LEN && within_one_edit(typed, trigger) {
+                one_edit.push((trigger.clone(), expansion()));
+            }
+        }
+        one_edit.sort();
+
+        let mut candidates = Vec::new();
+        if prefixed.len() == 1 {
+            candidates.append(&mut prefixed);
+        }
+        candidates.extend(one_edit);
+        candidates
+    }
+}
+
+fn pass_through(swallowed: bool) -> Vec<Action> {
+    if swallowed { vec![Action::PassThrough] } else { Vec::new() }
+}
+
+/// Hold a `cmd` snippet's actions until its command has run. `otherwise`
+/// sends on whatever the hook swallowed, so a failed command leaves the
+/// trigger and delimiter as the user typed them.
+fn await_command(actions: Vec<Action>, options: &SnippetOptions, otherwise: Vec<Action>) -> Vec<Action> {
+    match &options.command {
+        Some(command) => vec![Action::RunCommand { command: command.clone(), then: actions, otherwise }],
+        None => actions,
+    }
+}
+
+/// Hold a `confirm` snippet's actions (its command included) until the user
+/// agrees. `otherwise` is as for `await_command`: nothing has been deleted yet.
+fn await_confirmation(trigger: &str, actions: Vec<Action>, options: &SnippetOptions, otherwise: Vec<Action>) -> Vec<Action> {
+    if options.confirm {
+        vec![Action::Confirm { trigger: trigger.to_string(), then: actions, otherwise }]
+    } else {
+        actions
+    }
+}
+
+/// Take back the last expansion: delete what it inserted and type the
+/// trigger and delimiter back. The user's Backspace reached the app before
+/// we saw it and already removed the last inserted character.
+fn undo_actions(last: LastExpansion) -> Vec<Action> {
+    crate::debug_println!("Undoing expansion: {} characters -> {:?}", last.inserted_chars, last.undo_text);
+    crate::log_info!("Undoing the last expansion");
+    let mut actions = Vec::new();
+    let delete_count = last.inserted_chars.saturating_sub(1);
+    if delete_count > 0 {
+        actions.push(Action::DeleteChars(delete_count));
+    }
+    actions.push(Action::TypeText(last.undo_text));
+    actions
+}
+
+/// What a Space/Enter turns into. A swallowed one reaches the target app
+/// exactly once: as part of the expansion text, as a synthetic key tap, or,
+/// for a reflexive double-tap, folded into the expansion it repeats.
+#[derive(Debug)]
+enum DelimiterAction {
+    /// Fire this match. The job or macro accounts for the delimiter.
+    Expand(Box<Completion>),
+    /// No expansion: send the delimiter on (`Action::PassThrough`).
+    Reinject,
+    /// Repeat of the delimiter that just fired an expansion; dropped.
+    Collapse,
+}
+
+/// How many backspaces remove a trigger of `trigger_chars` characters (0 for
+/// jobs that keep it): the trigger, plus the delimiter after it unless the
+/// hook swallowed it. Space/Enter/Tab never reach the app; a punctuation
+/// delimiter has, and sits after the trigger. Instant triggers have no
+/// delimiter, so count as swallowed.
+fn delete_trigger(trigger_chars: usize, delimiter_swallowed: bool) -> usize {
+    if delimiter_swallowed { trigger_chars } else { trigger_chars + 1 }
+}
+
+/// One expansion's effect on the target app: delete `delete_count` characters
+/// before the caret, then insert `text`.
+struct ExpansionJob {
+    delete_count: usize,
+    text: String,
+    /// Typed back if a Backspace undoes the expansion; `None` if it can't be.
+    undo_text: Option<String>,
+    /// Per-snippet backend; `None` uses `[settings] injection`.
+    injection: Option<Injection>,
+    /// Per-snippet `typing_delay_ms`; `None` uses the `[settings]` one.
+    typing_delay_ms: Option<u64>,
+    html: Option<String>,
+    image: Option<PathBuf>,
+    tab_stops: bool,
+}
+
+impl ExpansionJob {
+    /// Build the job for a snippet whose trigger was typed as `typed` and
+    /// fired by `separator`. The separator is re-emitted as part of the text
+    /// unless the snippet drops it; if it wasn't swallowed it is deleted
+    /// first (see `delete_trigger`). Undo always restores the separator the
+    /// user typed.
+    fn new(typed: &str, expansion: String, separator: &str, delimiter_swallowed: bool, options: &SnippetOptions) -> Self {
+        let kept = if options.keep_delimiter { separator } else { "" };
+        let (delete_count, undo_text) = match options.insert_mode {
+            InsertMode::Replace => {
+                (delete_trigger(typed.chars().count(), delimiter_swallowed), format!("{}{}", typed, separator))
+            }
+            InsertMode::Append | InsertMode::AppendAfterDelimiter => {
+                (delete_trigger(0, delimiter_swallowed), separator.to_string())
+            }
+        };
+        // A kept Enter would be just whitespace in HTML
+        let with_separator = |expansion: &str, kept: &str| match options.insert_mode {
+            InsertMode::AppendAfterDelimiter => format!("{}{}", kept, expansion),
+            _ => format!("{}{}", expansion, kept),
+        };
+        let text = with_separator(&expansion, kept);
+        let html = options.html.as_deref().map(|html| with_separator(html, &kept.replace('\n', "<br>")));
+
+        ExpansionJob {
+            delete_count,
+            text,
+            undo_text: Some(undo_text),
+            injection: options.injection,
+            typing_delay_ms: options.typing_delay_ms,
+            html,
+            image: options.image.clone(),
+            tab_stops: options.tab_stops,
+        }
+    }
+
+    /// Append-mode jobs delete nothing, so they get no `DeleteChars`.
+    fn into_actions(self, trigger: Option<&str>, settings: &Settings) -> Vec<Action> {
+        let mut actions = Vec::new();
+        if self.delete_count > 0 {
+            actions.push(Action::DeleteChars(self.delete_count));
+        }
+        actions.push(Action::InsertText(Insertion {
+            trigger: trigger.map(str::to_string),
+            text: self.text,
+            html: self.html,
+            image: self.image,
+            injection: self.injection.unwrap_or(settings.injection),
+            typing_delay_ms: self.typing_delay_ms.unwrap_or(settings.typing_delay_ms),
+            undo_text: self.undo_text,
+            tab_stops: self.tab_stops,
+            resolved: false,
+        }));
+        actions
+    }
+}
+
+/// Where a candidate snippet came from, lowest precedence first.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+enum SnippetSource {
+    /// The `[date_expansion]` relative-date triggers.
+    Builtin,
+    /// expansions.toml
+    User,
+    /// The focused app's `[app."..."]` profile in expansions.toml
+    App,
+}
+
+/// How a candidate ranks against other candidates for the same typed text.
+/// Fields compare in declaration order, so the derived `Ord` is the
+/// precedence: longer trigger, then source, then exact case over folded
+/// case, then the snippet's `priority`, then the case-sensitive table.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+struct MatchScore {
+    trigger_length: usize,
+    source: SnippetSource,
+    exact_case: bool,
+    priority: i32,
+    case_sensitive: bool,
+}
+
+#[derive(Debug)]
+enum MatchAction {
+    /// Insert this text according to the snippet's options.
+    Expand(String, SnippetOptions),
+    /// Run a `[macros]` entry. Its steps handle the trigger themselves.
+    RunMacro(Vec<MacroStep>),
+}
+
+#[derive(Debug)]
+struct Completion {
+    trigger: String,
+    /// The end of the buffer that matched `trigger`, as typed; what gets
+    /// deleted.
+    typed: String,
+    score: MatchScore,
+    action: MatchAction,
+}
+
+/// The candidate for `snippet`, whose `trigger` was typed as `typed`.
+fn snippet_completion(
+    trigger: &str,
+    typed: &str,
+    snippet: &Snippet,
+    case_sensitive: bool,
+    source: SnippetSource,
+) -> Completion {
+    let expansion = if !case_sensitive && snippet.options.propagate_case {
+        propagate_case(typed, &snippet.replace)
+    } else {
+        snippet.replace.clone()
+    };
+    Completion {
+        trigger: trigger.to_string(),
+        typed: typed.to_string(),
+        score: MatchScore {
+            trigger_length: typed.chars().count(),
+            source,
+            exact_case: trigger == typed,
+            priority: snippet.options.priority,
+            case_sensitive,
+        },
+        action: MatchAction::Expand(expansion, snippet.options.clone()),
+    }
+}
+
+/// Zero what `text`'s allocation holds past its contents, so keys dropped
+/// from the buffer don't stay in memory until it is reused.
+fn scrub_spare_capacity(text: &mut String) {
+    // SAFETY: only bytes past the length are written; the string stays valid
+    let bytes = unsafe { text.as_mut_vec() };
+    for byte in bytes.spare_capacity_mut() {
+        byte.write(0);
+    }
+    // Keep the writes from being dropped as dead stores
+    std::hint::black_box(bytes);
+}
+
+/// Bytes the capped buffer can need: `longest_trigger_chars + 1` characters
+/// of up to four bytes each, and one more being typed before the cap.
+fn buffer_bytes(table: &ExpansionFile) -> usize {
+    (table.longest_trigger_chars + 2) * 4
+}
+
+/// Make room for `additional` more bytes in `text` without leaving keys
+/// behind in freed memory: instead of letting `String` reallocate, copy into
+/// a bigger allocation and zero the old one before it is dropped.
+fn reserve_scrubbed(text: &mut String, additional: usize) {
+    let needed = text.len() + additional;
+    if needed <= text.capacity() {
+        return;
+    }
+    let mut grown = String::with_capacity(needed.max(text.capacity() * 2));
+    grown.push_str(text);
+    let mut old = std::mem::replace(text, grown);
+    old.clear();
+    scrub_spare_capacity(&mut old);
+}
+
+/// Whether text typed after `before` starts a word: `before` doesn't end in
+/// a letter or digit, or `typed` doesn't start with one (`see;sig`).
+fn starts_word(before: &str, typed: &str) -> bool {
+    let ends_word = before.chars().last().is_none_or(|c| !c.is_alphanumeric());
+    ends_word || typed.chars().next().is_some_and(|c| !c.is_alphanumeric())
+}
+
+/// Apply the capitalization of a typed case-insensitive trigger to its
+/// expansion. Only letters decide the style, so `/Btw` counts as capitalized.
+/// A lone capital letter capitalizes rather than upper-cases, and
+/// capitalizing leaves expansions that start with a non-letter untouched.
+fn propagate_case(typed: &str, expansion: &str) -> String {
+    let letters: Vec<char> = typed.chars().filter(|c| c.is_alphabetic()).collect();
+    let Some(first) = letters.first() else {
+        return expansion.to_string();
+    };
+
+    if letters.len() > 1 && letters.iter().all(|c| c.is_uppercase()) {
+        return expansion.to_uppercase();
+    }
+
+    if first.is_uppercase() && !letters[1..].iter().any(|c| c.is_uppercase()) {
+        let mut chars = expansion.chars();
+        if let Some(initial) = chars.next().filter(|c| c.is_alphabetic()) {
+            return initial.to_uppercase().chain(chars).collect();
+        }
+    }
+
+    expansion.to_string()
+}
+
+/// True if `a` becomes `b` with exactly one insertion, deletion, or substitution.
+fn within_one_edit(a: &str, b: &str) -> bool {
+    let a: Vec<char> = a.chars().collect();
+    let b: Vec<char> = b.chars().collect();
+    let (short, long) = if a.len() <= b.len() { (&a, &b) } else { (&b, &a) };
+
+    match long.len() - short.len() {
+        0 => short.iter().zip(long.iter()).filter(|(x, y)| x != y).count() == 1,
+        1 => {
+            let prefix = short.iter().zip(long.iter()).take_while(|(x, y)| x == y).count();
+            short[prefix..] == long[prefix + 1..]
+        }
+        _ => false,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::config;
+
+    const CONFIG: &str = r#"
+        [settings]
+        tab_completion = true
+        delimiters = ["space", "enter", ".", ")"]
+
+        [case_sensitive]
+        "btw" = "by the way"
+        "sig" = { replace = "Regards", keep_delimiter = false }
+        ";addr" = { replace = "1 Main St", instant = true }
+        "brb" = { replace = "be right back", instant = true }
+        "~~" = { replace = "me@example.com", immediate = true }
+        "mgmt@@" = { replace = "management@", instant = true, word = false }
+        "q4h" = { replace = "every 4 hours", word = false }
+        "as per my" = "as I said in my"
+        "müde🙂" = "tired"
+
+        [case_insensitive]
+        "omw" = "on my way"
+
+        [macros]
+        "ff" = ["delete(2)", "key(ShiftLeft+End)", "key(Delete)"]
+
+        [regex]
+        'rx(\d+)x(\d+)' = "Take $1 tablets $2 times daily"
+    "#;
+
+    fn engine(config: &str) -> ExpansionEngine {
+        ExpansionEngine::new(config::parse_expansion_file(config).unwrap())
+    }
+
+    fn engine_in(typing_state: TypingState, buffer: &str) -> ExpansionEngine {
+        let mut engine = engine(
+            r#"
+            [case_sensitive]
+            "btw" = "by the way"
+
+            [case_insensitive]
+            "#,
+        );
+        engine.push_to_buffer(buffer);
+        engine.typing_state = typing_state;
+        engine
+    }
+
+    /// Keystrokes spelled as text: Space/Enter/Tab as themselves, `\u{8}`
+    /// for Backspace, `⌦` for Delete, `←`/`→` for the arrows, `↑` for any navigation key,
+    /// `¤` for a mouse click, `^` for a Ctrl shortcut, anything else a
+    /// printable character.
+    fn keys(typed: &str) -> Vec<InputEvent> {
+        typed
+            .chars()
+            .map(|c| match c {
+                ' ' => InputEvent::Delimiter(Delimiter::Space),
+                '\n' => InputEvent::Delimiter(Delimiter::Enter),
+                '\t' => InputEvent::Tab,
+                '\u{8}' => InputEvent::Backspace,
+                '⌦' => InputEvent::Delete,
+                '←' => InputEvent::ArrowLeft,
+                '→' => InputEvent::ArrowRight,
+                '↑' => InputEvent::Navigation,
+                '⇧' => InputEvent::Select,
+                '¤' => InputEvent::MouseClick,
+                '^' => InputEvent::Shortcut,
+                c => InputEvent::Char(Some(c.to_string())),
+            })
+            .collect()
+    }
+
+    /// Feed `typed` a second apart (outside every debounce window) with the
+    /// hook's swallowing rules, arming undo or tab stops after each insertion
+    /// the way main.rs does, and describe the actions.
+    fn run(engine: &mut ExpansionEngine, typed: &str) -> Vec<String> {
+        run_spaced(engine, typed, Duration::from_secs(1))
+    }
+
+    /// `run`, with the keys `gap` apart.
+    fn run_spaced(engine: &mut ExpansionEngine, typed: &str, gap: Duration) -> Vec<String> {
+        let start = Instant::now();
+        let mut described = Vec::new();
+        for (i, event) in keys(typed).into_iter().enumerate() {
+            let now = start + gap * i as u32;
+            let swallowed = match event {
+                InputEvent::Delimiter(_) => true,
+                InputEvent::Tab => engine.wants_tab(),
+                _ => false,
+            };
+            let press = KeyPress { event, time: (gap * i as u32).as_millis() as u32, swallowed };
+            for action in engine.handle(press, now, &|| None) {
+                described.push(match action {
+                    Action::PassThrough => "pass".to_string(),
+                    Action::DeleteChars(count) => format!("delete {}", count),
+                    Action::InsertText(insertion) => {
+                        if insertion.tab_stops {
+                            let (_, stops) = crate::template::take_tab_stops(&insertion.text);
+                            engine.start_tab_stops(stops[1..].to_vec(), stops[0]);
+                        } else if let Some(undo_text) = insertion.undo_text {
+                            engine.record_expansion(insertion.text.chars().count(), undo_text, now);
+                        }
+                        format!("insert {:?}", insertion.text)
+                    }
+                    Action::TypeText(text) => format!("type {:?}", text),
+                    Action::RunMacro(steps) => format!("macro of {}", steps.len()),
+                    Action::RunCommand { command, .. } => format!("command {:?}", command.cmd),
+                    Action::Confirm { trigger, .. } => format!("confirm {:?}", trigger),
+                    Action::CaretLeft(count) => format!("left {}", count),
+                    Action::CaretRight(count) => format!("right {}", count),
+                });
+            }
+        }
+        described
+    }
+
+    #[test]
+    fn keystroke_sequences() {
+        let cases: &[(&str, &str, &[&str])] = &[
+            ("trigger + Space", "btw ", &["delete 3", "insert \"by the way \""]),
+            ("trigger + Enter", "btw\n", &["delete 3", "insert \"by the way\\n\""]),
+            ("non-trigger", "xyz ", &["pass"]),
+            ("empty Space", " ", &["pass"]),
+            ("trigger after a non-match", "xyz btw ", &["pass", "delete 3", "insert \"by the way \""]),
+            ("trigger glued to a non-match", "xyz\u{8}\u{8}\u{8}btw ", &["delete 3", "insert \"by the way \""]),
+            ("Backspace fixes a typo", "btx\u{8}w ", &["delete 3", "insert \"by the way \""]),
+            ("Backspace after NoMatch resumes typing", "bt \u{8}w ", &["pass", "delete 3", "insert \"by the way \""]),
+            ("case-insensitive capitalized", "Omw ", &["delete 3", "insert \"On my way \""]),
+            ("case-insensitive upper", "OMW ", &["delete 3", "insert \"ON MY WAY \""]),
+            ("case-sensitive wrong case", "BTW ", &["pass"]),
+            ("keep_delimiter = false", "sig\n", &["delete 3", "insert \"Regards\""]),
+            ("insert mid-word", "bw←t→ ", &["delete 3", "insert \"by the way \""]),
+            ("Delete in the middle", "btxw←←⌦→ ", &["delete 3", "insert \"by the way \""]),
+            ("Delete at the end resets", "btw⌦ ", &["pass"]),
+            ("Delete on an empty buffer", "⌦btw ", &["delete 3", "insert \"by the way \""]),
+            ("Left to the start resets", "b←tw ", &["pass"]),
+            ("Right past the end resets", "btw→ ", &["pass"]),
+            ("navigation resets", "bt↑w ", &["pass"]),
+            ("Shift+arrow selection resets", "bt⇧w ", &["pass"]),
+            ("trigger typed over a selection", "xyz⇧⇧⇧btw ", &["delete 3", "insert \"by the way \""]),
+            ("mouse click resets", "bt¤w ", &["pass"]),
+            ("shortcut mid-trigger resets", "bt^w ", &["pass"]),
+            ("shortcut before a trigger", "xyz^btw ", &["delete 3", "insert \"by the way \""]),
+            ("trigger typed over select-all", "xyz^btw.", &["delete 4", "insert \"by the way.\""]),
+            ("instant trigger", ";addr", &["delete 5", "insert \"1 Main St\""]),
+            ("punctuation delimiter", "btw.", &["delete 4", "insert \"by the way.\""]),
+            ("punctuation after a non-trigger", "xyz.", &[]),
+            ("closing parenthesis", "(btw)", &["delete 4", "insert \"by the way)\""]),
+            ("macro", "ff ", &["macro of 3"]),
+            ("punctuation doesn't fire a macro", "ff.", &[]),
+            ("Tab completes a prefix", "bt\t", &["delete 2", "insert \"by the way\""]),
+            ("Tab completion follows the typed case", "Om\t", &["delete 2", "insert \"On my way\""]),
+            ("Tab with nothing to complete", "qq\t", &["pass"]),
+            (
+                "Backspace undoes",
+                "btw \u{8}",
+                &["delete 3", "insert \"by the way \"", "delete 10", "type \"btw \""],
+            ),
+            ("non-ASCII trigger", "müde🙂 ", &["delete 5", "insert \"tired \""]),
+            ("backspace in a non-ASCII trigger", "müx\u{8}de🙂🙂\u{8} ", &["delete 5", "insert \"tired \""]),
+            ("arrows through a non-ASCII trigger", "müe🙂←←d→→ ", &["delete 5", "insert \"tired \""]),
+            ("trigger ending at the caret", "btwx← ", &["delete 3", "insert \"by the way \""]),
+            ("trigger typed mid-buffer after a space", "a xyz←←←sig ", &["pass", "delete 3", "insert \"Regards\""]),
+            ("instant trigger typed mid-buffer", "a xyz←←←brb", &["pass", "delete 3", "insert \"be right back\""]),
+            ("Right past a non-ASCII end resets", "müde🙂→ ", &["pass"]),
+            ("typo after a trigger fixed", "btw,\u{8} ", &["delete 3", "insert \"by the way \""]),
+            ("trigger after punctuation", "xyz,btw ", &["delete 3", "insert \"by the way \""]),
+            ("trigger inside a word", "xbtw ", &["pass"]),
+            ("word = false trigger ending a word", "takeq4h ", &["delete 3", "insert \"every 4 hours \""]),
+            ("word = false trigger before punctuation", "takeq4h.", &["delete 4", "insert \"every 4 hours.\""]),
+            ("instant trigger inside a word", "xbrb", &[]),
+            ("instant trigger after punctuation", "(brb", &["delete 3", "insert \"be right back\""]),
+            ("immediate trigger after a word", "mail me~~", &["pass", "delete 2", "insert \"me@example.com\""]),
+            ("phrase trigger", "so as per my ", &["pass", "pass", "pass", "delete 9", "insert \"as I said in my \""]),
+            ("phrase trigger cut short", "as per the ", &["pass", "pass", "pass"]),
+            ("regex trigger", "rx2x3 ", &["delete 5", "insert \"Take 2 tablets 3 times daily \""]),
+            ("regex trigger after punctuation", "see,rx2x3\n", &["delete 5", "insert \"Take 2 tablets 3 times daily\\n\""]),
+            ("regex trigger inside a word", "xrx2x3 ", &["pass"]),
+            ("word = false instant trigger", "textmgmt@@", &["delete 6", "insert \"management@\""]),
+            ("undo window ends on the next key", "btw x\u{8}", &["delete 3", "insert \"by the way \""]),
+        ];
+
+        for (name, typed, expected) in cases {
+            let actions = run(&mut engine(CONFIG), typed);
+            assert_eq!(actions, *expected, "{}: {:?}", name, typed);
+        }
+    }
+
+    #[test]
+    fn the_longest_trigger_ending_at_the_caret_matches() {
+        let mut engine = engine(
+            r#"
+            [case_sensitive]
+            "btw" = "by the way"
+            ";btw" = "incidentally"
+
+            [case_insensitive]
+            "omw" = "on my way"
+            "#,
+        );
+        // Set the buffer directly: typing would have pruned the junk
+        let mut matched = |buffer: &str, caret: usize| {
+            engine.reset();
+            engine.push_to_buffer(buffer);
+            engine.cursor_position = caret;
+            engine.check_for_completion(None).map(|completion| (completion.trigger, completion.typed))
+        };
+        let found = |trigger: &str, typed: &str| Some((trigger.to_string(), typed.to_string()));
+
+        assert_eq!(matched("zz yy btw", 9), found("btw", "btw"), "after junk with spaces");
+        assert_eq!(matched("zz yy;btw", 9), found(";btw", ";btw"), "the longest suffix");
+        assert_eq!(matched("zz xbtw", 7), None, "inside a word");
+        assert_eq!(matched("zz (Omw", 7), found("omw", "Omw"));
+        assert_eq!(matched("zz btwxyz", 6), found("btw", "btw"), "up to the caret");
+        assert_eq!(matched("zz btwxyz", 5), None);
+        assert_eq!(run(&mut engine, "zz;btw "), ["delete 4", "insert \"incidentally \""]);
+    }
+
+    #[test]
+    fn case_insensitive_triggers_with_punctuation_match_any_case() {
+        let mut engine = engine(
+            r#"
+            [case_sensitive]
+            "ty" = "Thank you"
+
+            [case_insensitive]
+            ";Sig" = "best regards"
+            "/addr" = "address: 1 Main St"
+            "TY" = "thanks"
+            "#,
+        );
+        let cases = [
+            (";sig ", "delete 4", "insert \"best regards \""),
+            (";SIG ", "delete 4", "insert \"BEST REGARDS \""),
+            (";Sig ", "delete 4", "insert \"Best regards \""),
+            (";sIg ", "delete 4", "insert \"best regards \""),
+            ("/addr\n", "delete 5", "insert \"address: 1 Main St\\n\""),
+            ("/ADDR ", "delete 5", "insert \"ADDRESS: 1 MAIN ST \""),
+            ("/Addr ", "delete 5", "insert \"Address: 1 Main St \""),
+            ("/aDdR ", "delete 5", "insert \"address: 1 Main St \""),
+            // In both tables: the exact case-sensitive entry wins, the folded
+            // one takes any other case
+            ("ty ", "delete 2", "insert \"Thank you \""),
+            ("Ty ", "delete 2", "insert \"Thanks \""),
+            ("TY ", "delete 2", "insert \"THANKS \""),
+        ];
+        for (typed, delete, insert) in cases {
+            assert_eq!(run(&mut engine, typed), [delete, insert], "{:?}", typed);
+        }
+    }
+
+    #[test]
+    fn buffer_keeps_only_what_could_still_be_a_trigger() {
+        let cases = [
+            ("a prefix", "bt", "bt"),
+            ("a non-trigger word", "staff", "staff"),
+            ("a word then a prefix", "(xyz,bt", "bt"),
+            ("a trigger starting with punctuation", "see;ad", ";ad"),
+            ("case-insensitive", "so,OM", "OM"),
+            ("a date trigger", "due:/days-1", "/days-1"),
+            ("nothing that could be a trigger", "xyz,", "xyz,"),
+        ];
+
+        for (name, typed, expected) in cases {
+            let mut engine = engine(CONFIG);
+            run(&mut engine, typed);
+            assert_eq!(engine.key_buffer, expected, "{}", name);
+        }
+    }
+
+    #[test]
+    fn a_key_after_the_idle_timeout_starts_a_fresh_buffer() {
+        let start = Instant::now();
+        let press = |event| KeyPress { event, time: 0, swallowed: true };
+        let space = || press(InputEvent::Delimiter(Delimiter::Space));
+
+        let mut enabled = engine(CONFIG);
+        for (i, event) in keys("btw").into_iter().enumerate() {
+            enabled.handle(press(event), start + Duration::from_secs(i as u64), &|| None);
+        }
+        let late = enabled.handle(space(), start + Duration::from_secs(15), &|| None);
+        assert_eq!(late, [Action::PassThrough]);
+
+        let mut disabled = engine(&CONFIG.replace("[settings]", "[settings]\nidle_reset_secs = 0"));
+        for event in keys("btw") {
+            disabled.handle(press(event), start, &|| None);
+        }
+        let late = disabled.handle(space(), start + Duration::from_secs(600), &|| None);
+        assert_eq!(late.len(), 2, "disabled timeout still reset: {:?}", late);
+    }
+
+    #[test]
+    fn the_buffer_never_holds_more_than_the_longest_trigger() {
+        let config = r#"
+            [case_sensitive]
+            "btw" = "by the way"
+            "q4h" = { replace = "every 4 hours", word = false }
+
+            [case_insensitive]
+
+            [date_triggers]
+            prefixes = { "/d" = "day" }
+            "#;
+        let mut engine = engine(config);
+        // "/d" and a count is the longest; one more for a near match
+        let limit = 2 + dates::MAX_COUNT_CHARS + 1;
+        assert_eq!(engine.expansion_table.longest_trigger_chars + 1, limit);
+
+        // Sized up front, so typing never moves it and leaves keys behind
+        let allocation = engine.key_buffer.as_ptr();
+        let words = "the quick brown fox jumps over the lazy dog 🙂".repeat(10);
+        let burst = words + &"🙂".repeat(limit) + &"x".repeat(60);
+        assert!(burst.chars().count() >= 500);
+        for c in burst.chars() {
+            run(&mut engine, &c.to_string());
+            assert!(engine.key_buffer.chars().count() <= limit, "{:?}", engine.key_buffer);
+            assert!(engine.cursor_position <= engine.key_buffer.chars().count());
+            assert_eq!(engine.key_buffer.as_ptr(), allocation, "the buffer was reallocated");
+        }
+
+        // Cut mid-word, the rest of the word is no trigger, but may end in a
+        // `word = false` one
+        assert_eq!(run(&mut engine, "btw "), ["pass"]);
+        assert_eq!(run(&mut engine, &format!("{}q4h ", "y".repeat(30))), ["delete 3", "insert \"every 4 hours \""]);
+        assert_eq!(run(&mut engine, "btw "), ["delete 3", "insert \"by the way \""], "a new word after the cut");
+
+        // Nothing typed stays behind in the buffer's memory
+        run(&mut engine, "secret");
+        engine.reset();
+        let spare = unsafe { engine.key_buffer.as_mut_vec() }.spare_capacity_mut();
+        assert!(spare.iter().all(|byte| unsafe { byte.assume_init() } == 0));
+
+        // A reload with a longer trigger raises the limit, and the room
+        engine.replace_expansion_table(config::parse_expansion_file(CONFIG).unwrap());
+        let allocation = engine.key_buffer.as_ptr();
+        run(&mut engine, &"z".repeat(40));
+        assert_eq!(engine.key_buffer.chars().count(), 40, "[regex] keeps up to patterns::MAX_MATCH_CHARS");
+        assert_eq!(engine.key_buffer.as_ptr(), allocation, "the buffer was reallocated");
+
+        // Growing past the room copies the text into a new allocation
+        let mut text = String::with_capacity(4);
+        text.push_str("abc");
+        reserve_scrubbed(&mut text, 10);
+        assert!(text.capacity() >= 13);
+        assert_eq!(text, "abc");
+    }
+
+    #[test]
+    fn a_swallowed_shortcut_passes_through_unexpanded() {
+        let mut engine = engine(CONFIG);
+        run(&mut engine, "btw");
+        let ctrl_enter = KeyPress { event: InputEvent::Shortcut, time: 0, swallowed: true };
+        assert_eq!(engine.handle(ctrl_enter, Instant::now(), &|| None), [Action::PassThrough]);
+        assert_eq!(engine.key_buffer, "");
+    }
+
+    #[test]
+    fn unswallowed_tab_resets_without_passing_through() {
+        let mut engine = engine("[case_sensitive]\n\"btw\" = \"by the way\"\n[case_insensitive]\n");
+        assert_eq!(run(&mut engine, "bt\tw "), ["pass"]);
+    }
+
+    #[test]
+    fn each_expansion_path_deletes_exactly_what_is_on_screen() {
+        let cases = [
+            ("normal", "btw ", "delete 3"),
+            ("punctuation delimiter", "btw.", "delete 4"),
+            ("instant", ";addr", "delete 5"),
+            ("date", "/days1 ", "delete 6"),
+            ("date by punctuation", "/days1.", "delete 7"),
+        ];
+        for (name, typed, expected) in cases {
+            let actions = run(&mut engine(CONFIG), typed);
+            assert_eq!(actions.first().map(String::as_str), Some(expected), "{}", name);
+        }
+        assert_eq!(run(&mut engine(CONFIG), "ff "), ["macro of 3"], "macros delete their own trigger");
+
+        let append = SnippetOptions { insert_mode: InsertMode::Append, ..SnippetOptions::default() };
+        assert_eq!(ExpansionJob::new("sig", "Regards".to_string(), ".", false, &append).delete_count, 1);
+    }
+
+    #[test]
+    fn typed_injection_takes_its_delay_from_the_snippet_or_settings() {
+        let mut engine = engine(
+            r#"
+            [settings]
+            injection = "typed"
+            typing_delay_ms = 35
+
+            [case_sensitive]
+            "btw" = "by the way"
+            "emr" = { replace = "Plan:", typing_delay_ms = 80 }
+
+            [case_insensitive]
+            "#,
+        );
+        let mut delays = Vec::new();
+        for event in keys("btw emr ") {
+            let swallowed = matches!(event, InputEvent::Delimiter(_));
+            for action in engine.handle(KeyPress { event, time: 0, swallowed }, Instant::now(), &|| None) {
+                if let Action::InsertText(insertion) = action {
+                    delays.push((insertion.injection, insertion.typing_delay_ms));
+                }
+            }
+        }
+        assert_eq!(delays, [(Injection::Typed, 35), (Injection::Typed, 80)]);
+    }
+
+    #[test]
+    fn html_snippets_carry_their_formatted_form_and_the_kept_delimiter() {
+        let mut engine = engine(
+            r#"
+            [case_sensitive]
+            "sig" = { text = "Zach, Example Corp", html = "<b>Zach</b>, <a href='https://example.com'>Example Corp</a>" }
+
+            [case_insensitive]
+            "#,
+        );
+        let mut inserted = Vec::new();
+        for event in keys("sig\n") {
+            let swallowed = matches!(event, InputEvent::Delimiter(_));
+            for action in engine.handle(KeyPress { event, time: 0, swallowed }, Instant::now(), &|| None) {
+                if let Action::InsertText(insertion) = action {
+                    inserted.push((insertion.text, insertion.html));
+                }
+            }
+        }
+        let html = "<b>Zach</b>, <a href='https://example.com'>Example Corp</a><br>";
+        assert_eq!(inserted, [("Zach, Example Corp\n".to_string(), Some(html.to_string()))]);
+    }
+
+    #[test]
+    fn pasted_snippets_drop_their_trailing_newline_and_confirm_asks_first() {
+        let config = r#"
+            [case_sensitive]
+            "note" = "Call back\n"
+            "keep" = { replace = "Call back\n", strip_trailing_newline = true, injection = "unicode" }
+            "typed" = { replace = "Call back\n", injection = "unicode" }
+            "raw" = { replace = "Call back\n", strip_trailing_newline = false }
+            "tmpl" = { replace = "Long template", confirm = true }
+
+            [case_insensitive]
+        "#;
+        // Nothing is deleted until the user agrees; no leaves "tmpl" and
+        // sends on the swallowed Space
+        let mut confirming = engine(config);
+        let actions: Vec<Action> = keys("tmpl ")
+            .into_iter()
+            .flat_map(|event| {
+                let swallowed = matches!(event, InputEvent::Delimiter(_));
+                confirming.handle(KeyPress { event, time: 0, swallowed }, Instant::now(), &|| None)
+            })
+            .collect();
+        let [Action::Confirm { trigger, then, otherwise }] = &actions[..] else {
+            panic!("expected a confirmation, got {:?}", actions);
+        };
+        assert_eq!(trigger, "tmpl");
+        assert_eq!(then[0], Action::DeleteChars(4));
+        assert_eq!(otherwise, &[Action::PassThrough]);
+
+        let mut engine = engine(config);
+        assert_eq!(run(&mut engine, "note "), ["delete 4", "insert \"Call back \""]);
+        assert_eq!(run(&mut engine, "keep "), ["delete 4", "insert \"Call back \""]);
+        assert_eq!(run(&mut engine, "typed "), ["delete 5", "insert \"Call back\\n \""]);
+        assert_eq!(run(&mut engine, "raw\n"), ["delete 3", "insert \"Call back\\n\\n\""]);
+    }
+
+    #[test]
+    fn disabled_groups_stop_firing_until_switched_back_on() {
+        let mut engine = engine(
+            r#"
+            [settings]
+            tab_completion = true
+
+            [case_sensitive]
+            "btw" = "by the way"
+            "mtx" = { replace = "methotrexate", group = "medical" }
+            "pt;" = { replace = "patient", group = "medical", instant = true }
+
+            [case_insensitive]
+            "#,
+        );
+        assert_eq!(engine.groups(), [("default".to_string(), true), ("medical".to_string(), true)]);
+
+        assert!(!engine.toggle_group("medical"));
+        assert_eq!(run(&mut engine, "mtx "), ["pass"]);
+        assert_eq!(run(&mut engine, "pt;"), Vec::<String>::new());
+        assert_eq!(run(&mut engine, " mt\t"), ["pass", "pass"], "no Tab completion either");
+        assert_eq!(run(&mut engine, " btw "), ["pass", "delete 3", "insert \"by the way \""]);
+
+        // A reload keeps the group off
+        engine.replace_expansion_table(config::parse_expansion_file("[case_sensitive]\n\"mtx\" = { replace = \"MTX\", group = \"medical\" }\n").unwrap());
+        assert_eq!(run(&mut engine, "mtx "), ["pass"]);
+        assert!(engine.toggle_group("medical"));
+        assert_eq!(run(&mut engine, "mtx "), ["delete 3", "insert \"MTX \""]);
+    }
+
+    #[test]
+    fn history_keeps_the_last_expansions_as_inserted() {
+        let mut engine = engine(CONFIG);
+        for n in 0..12 {
+            engine.record_history(HistoryEntry {
+                trigger: "date".to_string(),
+                text: format!("Jan {}", n + 1),
+                injection: Injection::Unicode,
+                typing_delay_ms: 0,
+                time: SystemTime::now(),
+            });
+        }
+        assert_eq!(engine.history().len(), HISTORY_LEN);
+        assert_eq!(engine.history()[0].text, "Jan 12");
+
+        let [Action::InsertText(insertion)] = &engine.repeat_from_history(1)[..] else {
+            panic!("expected one insertion");
+        };
+        assert_eq!((insertion.text.as_str(), insertion.resolved), ("Jan 11", true));
+        assert_eq!((insertion.injection, insertion.undo_text.as_deref()), (Injection::Unicode, None));
+        assert!(engine.repeat_from_history(HISTORY_LEN).is_empty());
+
+        engine.clear_history();
+        assert!(engine.repeat_from_history(0).is_empty());
+    }
+
+    #[test]
+    fn keep_delimiter_controls_the_re_emitted_separator() {
+        let kept = SnippetOptions::default();
+        let dropped = SnippetOptions { keep_delimiter: false, ..SnippetOptions::default() };
+
+        let job = ExpansionJob::new("sig", "Regards".to_string(), "\n", true, &kept);
+        assert_eq!((job.delete_count, job.text.as_str()), (3, "Regards\n"));
+
+        let job = ExpansionJob::new("sig", "Regards".to_string(), "\n", true, &dropped);
+        assert_eq!((job.delete_count, job.text.as_str()), (3, "Regards"));
+        assert_eq!(job.undo_text.as_deref(), Some("sig\n"));
+
+        let append = SnippetOptions { insert_mode: InsertMode::AppendAfterDelimiter, ..dropped };
+        let job = ExpansionJob::new("sig", "Regards".to_string(), " ", true, &append);
+        assert_eq!((job.delete_count, job.text.as_str()), (0, "Regards"));
+    }
+
+    #[test]
+    fn precedence_ignores_the_order_snippets_are_written_in() {
+        // Each fixture's tables are tried as written and reversed
+        let fixtures: &[(&str, &[&str], &str, &str)] = &[
+            (
+                "app beats user, whatever the priority",
+                &[
+                    "[case_sensitive]\n\"ty\" = { replace = \"user\", priority = 9 }",
+                    "[case_insensitive]",
+                    "[app.\"mail.exe\".case_sensitive]\n\"ty\" = \"app\"",
+                ],
+                "ty",
+                "app",
+            ),
+            (
+                "exact case beats folded case, whatever the priority",
+                &[
+                    "[case_sensitive]\n\"TY\" = \"exact\"",
+                    "[case_insensitive]\n\"ty\" = { replace = \"folded\", priority = 9 }",
+                ],
+                "TY",
+                "exact",
+            ),
+            (
+                "priority breaks an otherwise equal tie",
+                &[
+                    "[case_sensitive]\n\"ty\" = \"low\"",
+                    "[case_insensitive]\n\"ty\" = { replace = \"high\", priority = 1 }",
+                ],
+                "ty",
+                "high",
+            ),
+        ];
+        for (name, tables, typed, expected) in fixtures {
+            let reversed: Vec<&str> = tables.iter().rev().copied().collect();
+            for order in [tables.to_vec(), reversed] {
+                let mut engine = engine(&order.join("\n\n"));
+                engine.push_to_buffer(typed);
+                let winner = engine.check_for_completion(Some("mail.exe")).map(|completion| completion.action);
+                let won = matches!(&winner, Some(MatchAction::Expand(text, _)) if text == expected);
+                assert!(won, "{}: {:?}", name, winner);
+            }
+        }
+    }
+
+    #[test]
+    fn insert_modes_place_the_expansion_around_the_delimiter() {
+        let config = r#"
+            [case_sensitive]
+            "rp" = { replace = "Regards", insert_mode = "replace" }
+            "ap" = { replace = "Regards", insert_mode = "append" }
+            "ad" = { replace = "Regards", insert_mode = "append_after_delimiter" }
+
+            [case_insensitive]
+        "#;
+        let cases: &[(&str, &[&str])] = &[
+            ("rp ", &["delete 2", "insert \"Regards \""]),
+            ("rp\n", &["delete 2", "insert \"Regards\\n\""]),
+            // The append modes keep the trigger, so nothing is deleted
+            ("ap ", &["insert \"Regards \""]),
+            ("ap\n", &["insert \"Regards\\n\""]),
+            ("ad ", &["insert \" Regards\""]),
+            ("ad\n", &["insert \"\\nRegards\""]),
+        ];
+        for (typed, expected) in cases {
+            assert_eq!(run(&mut engine(config), typed), *expected, "{:?}", typed);
+        }
+    }
+
+    #[test]
+    fn plain_and_table_snippets_parse_with_default_options() {
+        let table = config::parse_expansion_file(
+            r#"
+            [case_sensitive]
+            "addr" = "1 Main St"
+            "sig" = { replace = "Regards", keep_delimiter = false }
+            "logo" = { image = "C:/snippets/logo.png", injection = "unicode" }
+
+            [case_insensitive]
+            "#,
+        )
+        .unwrap();
+        assert!(table.case_sensitive["addr"].options.keep_delimiter);
+        assert!(!table.case_sensitive["sig"].options.keep_delimiter);
+        let logo = &table.case_sensitive["logo"].options;
+        assert_eq!(logo.image.as_deref(), Some(std::path::Path::new("C:/snippets/logo.png")));
+        assert_eq!(logo.injection, Some(Injection::Clipboard), "images can only be pasted");
+    }
+
+    /// Every (state, delimiter) pair either expands or re-injects; nothing is
+    /// silently eaten.
+    #[test]
+    fn every_delimiter_expands_or_is_reinjected() {
+        let cases = [
+            ("empty", TypingState::Empty, "", false),
+            ("after a non-match", TypingState::NoMatch, "xyz ", false),
+            ("typing a non-trigger", TypingState::Typing, "xyz", false),
+            ("typing a trigger", TypingState::Typing, "btw", true),
+        ];
+
+        for delimiter in [Delimiter::Space, Delimiter::Enter] {
+            for (name, state, buffer, expands) in cases {
+                let mut engine = engine_in(state, buffer);
+                let action = engine.plan_delimiter(delimiter, 1000, true, None);
+                let ok = match action {
+                    DelimiterAction::Expand(_) => expands,
+                    DelimiterAction::Reinject => !expands,
+                    DelimiterAction::Collapse => false,
+                };
+                assert!(ok, "{:?} {}: got {:?}", delimiter, name, action);
+            }
+        }
+    }
+
+    #[test]
+    fn only_one_repeat_of_the_firing_delimiter_collapses() {
+        let mut engine = engine_in(TypingState::Typing, "btw");
+        assert!(matches!(engine.plan_delimiter(Delimiter::Space, 1000, true, None), DelimiterAction::Expand(_)));
+        assert!(matches!(engine.plan_delimiter(Delimiter::Space, 1050, true, None), DelimiterAction::Collapse));
+        assert!(matches!(engine.plan_delimiter(Delimiter::Space, 1100, true, None), DelimiterAction::Reinject));
+    }
+
+    #[test]
+    fn a_different_or_late_delimiter_is_reinjected() {
+        let mut engine = engine_in(TypingState::Typing, "btw");
+        engine.plan_delimiter(Delimiter::Space, 1000, true, None);
+        assert!(matches!(engine.plan_delimiter(Delimiter::Enter, 1050, true, None), DelimiterAction::Reinject));
+
+        let mut engine = engine_in(TypingState::Typing, "btw");
+        engine.plan_delimiter(Delimiter::Space, 1000, true, None);
+        assert!(matches!(engine.plan_delimiter(Delimiter::Space, 5000, true, None), DelimiterAction::Reinject));
+    }
+
+    #[test]
+    fn tab_cycles_equally_close_candidates_until_the_timeout() {
+        let config = |timeout_ms: u64| {
+            format!(
+                r#"
+                [settings]
+                tab_completion = true
+                tab_cycle_timeout_ms = {}
+
+                [case_sensitive]
+                "cat" = "feline"
+                "cot" = "bed"
+                "cut" = "slice"
+
+                [case_insensitive]
+                "#,
+                timeout_ms
+            )
+        };
+
+        // Each Tab inside the window swaps in the next candidate, then wraps
+        let mut cycling = engine(&config(1500));
+        assert_eq!(
+            run(&mut cycling, "cxt\t\t\t\t"),
+            [
+                "delete 3", "insert \"feline\"",
+                "delete 6", "insert \"bed\"",
+                "delete 3", "insert \"slice\"",
+                "delete 5", "insert \"feline\"",
+            ]
+        );
+
+        // A reset ends the cycle, so the next Tab is an ordinary Tab
+        assert!(cycling.tab_cycle.is_some());
+        cycling.reset();
+        assert!(cycling.tab_cycle.is_none());
+        assert_eq!(run(&mut cycling, "\t"), ["pass"]);
+
+        // Past the timeout Tab passes through, and the next fragment starts over
+        let mut quick = engine(&config(500));
+        assert_eq!(
+            run(&mut quick, "cxt\t\tcxt\t"),
+            ["delete 3", "insert \"feline\"", "pass", "delete 3", "insert \"feline\""]
+        );
+        assert_eq!(
+            run_spaced(&mut quick, "cxt\t\t", Duration::from_millis(100)),
+            ["delete 3", "insert \"feline\"", "delete 6", "insert \"bed\""]
+        );
+    }
+
+    #[test]
+    fn keys_replayed_right_after_an_expansion_are_tracked() {
+        // main.rs replays what was typed while an expansion went out as
+        // unswallowed presses, a few milliseconds behind the delimiter
+        let mut delimited = engine(CONFIG);
+        assert_eq!(
+            run_spaced(&mut delimited, "btw sig\n", Duration::from_millis(20)),
+            ["delete 3", "insert \"by the way \"", "delete 3", "insert \"Regards\""]
+        );
+
+        let mut instant = engine(CONFIG);
+        assert_eq!(
+            run_spaced(&mut instant, "btw ;addr", Duration::from_millis(20)),
+            ["delete 3", "insert \"by the way \"", "delete 5", "insert \"1 Main St\""]
+        );
+    }
+
+    #[test]
+    fn an_unswallowed_delimiter_is_deleted_with_the_trigger() {
+        // The rdev backend can't swallow keys, so Space has reached the app
+        let mut engine = engine(CONFIG);
+        let mut press = |event| engine.handle(KeyPress { event, time: 0, swallowed: false }, Instant::now(), &|| None);
+
+        for event in keys("btw") {
+            press(event);
+        }
+        let actions = press(InputEvent::Delimiter(Delimiter::Space));
+        let expanded = matches!(&actions[..], [Action::DeleteChars(4), Action::InsertText(insertion)]
+            if insertion.text == "by the way ");
+        assert!(expanded, "{:?}", actions);
+
+        // Macros assume a swallowed delimiter and are left alone
+        for event in keys("ff") {
+            press(event);
+        }
+        assert!(press(InputEvent::Delimiter(Delimiter::Space)).is_empty());
+    }
+
+    #[test]
+    fn each_cycle_placeholder_rotates_until_a_reload() {
+        let config = r#"
+            [case_sensitive]
+            "hi" = "{{cycle:Hey|Hello}}!"
+            "yo" = "{{cycle:Hey|Hello}} {{choose:a|a}}"
+            "gm" = "{{choose_daily:Morning|Good morning|Hi|Hello|Hey}}"
+
+            [case_insensitive]
+        "#;
+        let mut engine = engine(config);
+        assert_eq!(
+            run(&mut engine, "hi hi yo hi "),
+            [
+                "delete 2", "insert \"Hey! \"",
+                "delete 2", "insert \"Hello! \"",
+                "delete 2", "insert \"Hey a \"",
+                "delete 2", "insert \"Hey! \"",
+            ]
+        );
+
+        engine.replace_expansion_table(config::parse_expansion_file(config).unwrap());
+        assert!(engine.cycle_positions.is_empty());
+        assert_eq!(run(&mut engine, "hi hi "), ["delete 2", "insert \"Hey! \"", "delete 2", "insert \"Hello! \""]);
+
+        // A daily choice picks the same option all day
+        let greeting = run(&mut engine, "gm ");
+        assert_eq!(run(&mut engine, "gm gm "), [greeting.clone(), greeting].concat());
+    }
+
+    #[test]
+    fn tab_walks_the_stops_around_what_was_typed() {
+        let config = r#"
+            [case_sensitive]
+            "dear" = { replace = "Dear $1,\n$2\nThanks", tab_stops = true }
+            "range" = { replace = "$2 to $1", tab_stops = true, keep_delimiter = false }
+
+            [case_insensitive]
+        "#;
+
+        // Forward: typing at a stop doesn't move the stops after it. The
+        // session ends at the last stop, and Tab is an ordinary key again.
+        let mut forward = engine(config);
+        assert_eq!(
+            run(&mut forward, "dear Al\thi\t\t"),
+            ["delete 4", "insert \"Dear $1,\\n$2\\nThanks \"", "right 2", "right 8"]
+        );
+
+        // Backward: the earlier stop moved by what was typed at the caret
+        let mut backward = engine(config);
+        assert_eq!(run(&mut backward, "range b\ta\t"), ["delete 5", "insert \"$2 to $1\"", "left 5", "right 5"]);
+
+        // Escape ends the session
+        let mut escaped = engine(config);
+        assert_eq!(run(&mut escaped, "range b↑\t"), ["delete 5", "insert \"$2 to $1\""]);
+        assert!(!escaped.wants_tab());
+    }
+
+    #[test]
+    fn command_snippets_wait_on_their_command() {
+        let mut engine = engine(
+            r#"
+            [settings]
+            delimiters = ["space", "."]
+
+            [case_sensitive]
+            "gb" = { cmd = "git branch --show-current" }
+            "now" = { cmd = "time /t", timeout_ms = 500, fallback = "(no time)", instant = true }
+
+            [case_insensitive]
+            "#,
+        );
+        let command = |cmd: &str, timeout_ms, fallback: Option<&str>| ShellCommand {
+            cmd: cmd.to_string(),
+            timeout_ms,
+            fallback: fallback.map(str::to_string),
+        };
+        let insert = |trigger: &str, text: &str, undo_text: &str| {
+            Action::InsertText(Insertion {
+                trigger: Some(trigger.to_string()),
+                text: text.to_string(),
+                html: None,
+                image: None,
+                injection: Injection::Clipboard,
+                typing_delay_ms: 20,
+                undo_text: Some(undo_text.to_string()),
+                tab_stops: false,
+                resolved: false,
+            })
+        };
+        let press = |engine: &mut ExpansionEngine, event, swallowed| {
+            engine.handle(KeyPress { event, time: 0, swallowed }, Instant::now(), &|| None)
+        };
+
+        // Nothing is deleted until the command has run; if it fails, the
+        // swallowed Space still has to reach the app
+        for event in keys("gb") {
+            press(&mut engine, event, false);
+        }
+        assert_eq!(
+            press(&mut engine, InputEvent::Delimiter(Delimiter::Space), true),
+            [Action::RunCommand {
+                command: command("git branch --show-current", 2000, None),
+                then: vec![Action::DeleteChars(2), insert("gb", "{{output}} ", "gb ")],
+                otherwise: vec![Action::PassThrough],
+            }]
+        );
+
+        // A punctuation delimiter is already in the app: nothing to send
+        for event in keys("gb") {
+            press(&mut engine, event, false);
+        }
+        let actions = press(&mut engine, InputEvent::Char(Some(".".to_string())), false);
+        assert!(matches!(&actions[..], [Action::RunCommand { otherwise, .. }] if otherwise.is_empty()));
+
+        let actions: Vec<Action> = keys("now").into_iter().flat_map(|event| press(&mut engine, event, false)).collect();
+        assert_eq!(
+            actions,
+            [Action::RunCommand {
+                command: command("time /t", 500, Some("(no time)")),
+                then: vec![Action::DeleteChars(3), insert("now", "{{output}}", "now")],
+                otherwise: Vec::new(),
+            }]
+        );
+    }
+}
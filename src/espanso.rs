@@ -0,0 +1,303 @@
+// src/espanso.rs
+//
+// `--import-espanso`: converts an espanso match file (YAML) into snippets
+// and merges them into expansions.toml. Matches that have no equivalent here
+// (regex triggers, forms, images, shell/script variables, ...) are reported
+// rather than dropped. Triggers already in the file are left alone, so
+// importing the same file twice changes nothing.
+
+use serde::Deserialize;
+use toml_edit::DocumentMut;
+
+use crate::config::{self, SnippetInsert};
+use crate::template::{CLIPBOARD_PLACEHOLDER, CURSOR_MARKER};
+
+/// espanso's cursor hint.
+const ESPANSO_CURSOR: &str = "$|$";
+
+#[derive(Deserialize, Default)]
+#[serde(default)]
+struct MatchFile {
+    matches: Vec<Match>,
+    global_vars: Vec<Var>,
+    imports: Vec<String>,
+}
+
+#[derive(Deserialize, Default)]
+#[serde(default)]
+struct Match {
+    trigger: Option<String>,
+    triggers: Vec<String>,
+    regex: Option<String>,
+    replace: Option<String>,
+    form: Option<String>,
+    image_path: Option<String>,
+    html: Option<String>,
+    markdown: Option<String>,
+    word: bool,
+    propagate_case: bool,
+    vars: Vec<Var>,
+}
+
+#[derive(Deserialize)]
+struct Var {
+    name: String,
+    #[serde(rename = "type")]
+    kind: String,
+    #[serde(default)]
+    params: VarParams,
+}
+
+#[derive(Deserialize, Default)]
+#[serde(default)]
+struct VarParams {
+    format: Option<String>,
+    offset: i64,
+    echo: Option<String>,
+    choices: Vec<String>,
+}
+
+/// One converted trigger.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ImportedSnippet {
+    pub trigger: String,
+    pub replace: String,
+    /// espanso's `propagate_case`: goes in `[case_insensitive]`, whose
+    /// snippets mirror the typed capitalization.
+    pub case_insensitive: bool,
+    /// espanso fires a trigger as soon as it is typed unless `word` is set;
+    /// `word` matches are left to fire on a delimiter.
+    pub instant: bool,
+}
+
+/// The result of converting a match file.
+#[derive(Debug, Default)]
+pub struct Conversion {
+    pub snippets: Vec<ImportedSnippet>,
+    /// What couldn't be converted, and why, one line each.
+    pub skipped: Vec<String>,
+}
+
+/// What merging changed in expansions.toml.
+#[derive(Debug, Default)]
+pub struct MergeSummary {
+    pub added: usize,
+    /// Already in the file with the same expansion.
+    pub unchanged: usize,
+    /// Triggers already in the file with a different expansion; the file's
+    /// version is kept.
+    pub conflicts: Vec<String>,
+}
+
+/// Convert the matches of an espanso YAML file.
+pub fn convert(yaml: &str) -> Result<Conversion, Box<dyn std::error::Error>> {
+    let file: MatchFile = serde_yaml::from_str(yaml)?;
+    let mut conversion = Conversion::default();
+
+    for import in &file.imports {
+        conversion.skipped.push(format!("imports: {:?} (import that file separately)", import));
+    }
+
+    for m in &file.matches {
+        let mut triggers: Vec<&String> = m.trigger.iter().chain(&m.triggers).collect();
+        let label = match (&m.regex, triggers.first()) {
+            (Some(regex), _) => format!("regex {:?}", regex),
+            (None, Some(trigger)) => format!("{:?}", trigger),
+            (None, None) => "match without a trigger".to_string(),
+        };
+
+        let replace = match translate_match(m, &file.global_vars) {
+            Ok(replace) => replace,
+            Err(reason) => {
+                conversion.skipped.push(format!("{}: {}", label, reason));
+                continue;
+            }
+        };
+
+        triggers.retain(|trigger| {
+            let problem = crate::validate::trigger_problem(trigger, &[]);
+            if let Some(problem) = &problem {
+                conversion.skipped.push(problem.clone());
+            }
+            problem.is_none()
+        });
+        conversion.snippets.extend(triggers.into_iter().map(|trigger| ImportedSnippet {
+            trigger: trigger.clone(),
+            replace: replace.clone(),
+            case_insensitive: m.propagate_case,
+            instant: !m.word,
+        }));
+    }
+
+    Ok(conversion)
+}
+
+/// The expansion text for `m`, or why it can't be converted.
+fn translate_match(m: &Match, global_vars: &[Var]) -> Result<String, String> {
+    if m.regex.is_some() {
+        return Err("regex triggers are not supported".to_string());
+    }
+    if m.form.is_some() {
+        return Err("forms are not supported".to_string());
+    }
+    if m.image_path.is_some() || m.html.is_some() || m.markdown.is_some() {
+        return Err("only plain-text replacements are supported".to_string());
+    }
+    let replace = m.replace.as_deref().ok_or("no replace text")?;
+
+    let mut out = String::with_capacity(replace.len());
+    let mut rest = replace;
+    while let Some(start) = rest.find("{{") {
+        out.push_str(&rest[..start]);
+        let after = &rest[start + 2..];
+        let end = after.find("}}").ok_or("unclosed {{ in replace")?;
+        let name = after[..end].trim();
+        let var = m
+            .vars
+            .iter()
+            .chain(global_vars)
+            .find(|var| var.name == name)
+            .ok_or_else(|| format!("unknown variable {:?}", name))?;
+        out.push_str(&translate_var(var)?);
+        rest = &after[end + 2..];
+    }
+    out.push_str(rest);
+
+    Ok(out.replace(ESPANSO_CURSOR, CURSOR_MARKER))
+}
+
+/// The placeholder (or literal text) standing in for an espanso variable.
+fn translate_var(var: &Var) -> Result<String, String> {
+    let params = &var.params;
+    match var.kind.as_str() {
+        "date" => {
+            let format = params.format.as_deref().ok_or_else(|| format!("date variable {:?} has no format", var.name))?;
+            if params.offset != 0 {
+                return Err(format!("date variable {:?} uses an offset", var.name));
+            }
+            if format.contains('}') {
+                return Err(format!("date variable {:?} has a '}}' in its format", var.name));
+            }
+            Ok(format!("{{date:{}}}", format))
+        }
+        "clipboard" => Ok(CLIPBOARD_PLACEHOLDER.to_string()),
+        "echo" => Ok(params.echo.clone().unwrap_or_default()),
+        "random" if !params.choices.is_empty() => {
+            let choices: Vec<String> = params.choices.iter().map(|choice| escape_choice(choice)).collect();
+            Ok(format!("{{{{choose:{}}}}}", choices.join("|")))
+        }
+        kind => Err(format!("{} variable {:?} is not supported", kind, var.name)),
+    }
+}
+
+/// Backslash-escape what `{{choose:...}}` treats specially.
+fn escape_choice(choice: &str) -> String {
+    let mut escaped = String::with_capacity(choice.len());
+    for c in choice.chars() {
+        if matches!(c, '\\' | '|' | '}') {
+            escaped.push('\\');
+        }
+        escaped.push(c);
+    }
+    escaped
+}
+
+/// Add `snippets` to the expansions.toml text `config`, keeping its comments
+/// and layout. Returns the new text and what changed.
+pub fn merge(config: &str, snippets: &[ImportedSnippet]) -> Result<(String, MergeSummary), Box<dyn std::error::Error>> {
+    let mut doc: DocumentMut = config.parse()?;
+    let mut summary = MergeSummary::default();
+
+    for snippet in snippets {
+        match config::insert_snippet(&mut doc, snippet.case_insensitive, &snippet.trigger, &snippet.replace, snippet.instant)? {
+            SnippetInsert::Added => summary.added += 1,
+            SnippetInsert::Unchanged => summary.unchanged += 1,
+            SnippetInsert::Conflict if snippet.case_insensitive => {
+                summary.conflicts.push(format!("[case_insensitive] {:?}", snippet.trigger.to_lowercase()))
+            }
+            SnippetInsert::Conflict => summary.conflicts.push(format!("[case_sensitive] {:?}", snippet.trigger)),
+        }
+    }
+
+    Ok((doc.to_string(), summary))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const MATCHES: &str = r#"
+global_vars:
+  - name: today
+    type: date
+    params:
+      format: "%Y-%m-%d"
+matches:
+  - trigger: ":brb"
+    replace: "be right back"
+  - triggers: [":ty", ":thx"]
+    replace: "Thanks, {{name}}! $|$"
+    word: true
+    propagate_case: true
+    vars:
+      - name: name
+        type: echo
+        params:
+          echo: "team"
+  - trigger: ":log"
+    replace: "{{today}}: {{greeting}}"
+    vars:
+      - name: greeting
+        type: random
+        params:
+          choices: ["hi", "a|b"]
+  - regex: ":add(?P<x>\\d+)"
+    replace: "{{x}}"
+  - trigger: ":ip"
+    replace: "{{ip}}"
+    vars:
+      - name: ip
+        type: shell
+        params:
+          cmd: "curl ifconfig.me"
+"#;
+
+    #[test]
+    fn espanso_matches_convert_or_are_reported() {
+        let conversion = convert(MATCHES).unwrap();
+        let converted: Vec<(&str, &str, bool, bool)> = conversion
+            .snippets
+            .iter()
+            .map(|s| (s.trigger.as_str(), s.replace.as_str(), s.case_insensitive, s.instant))
+            .collect();
+        assert_eq!(
+            converted,
+            [
+                (":brb", "be right back", false, true),
+                (":ty", "Thanks, team! $|", true, false),
+                (":thx", "Thanks, team! $|", true, false),
+                (":log", "{date:%Y-%m-%d}: {{choose:hi|a\\|b}}", false, true),
+            ]
+        );
+        assert_eq!(conversion.skipped.len(), 2);
+        assert!(conversion.skipped[0].starts_with("regex"));
+        assert!(conversion.skipped[1].contains("shell variable"));
+    }
+
+    #[test]
+    fn merging_twice_adds_nothing_new() {
+        let config = "# mine\n[case_sensitive]\n\":brb\" = \"be right back\"\n\":log\" = \"old\"\n\n[case_insensitive]\n";
+        let snippets = convert(MATCHES).unwrap().snippets;
+
+        let (merged, summary) = merge(config, &snippets).unwrap();
+        assert_eq!((summary.added, summary.unchanged), (2, 1));
+        assert_eq!(summary.conflicts, ["[case_sensitive] \":log\""]);
+        assert!(merged.starts_with("# mine\n"));
+        assert!(merged.contains("\":ty\" = \"Thanks, team! $|\""));
+        crate::config::parse_expansion_file(&merged).unwrap();
+
+        let (again, summary) = merge(&merged, &snippets).unwrap();
+        assert_eq!((summary.added, summary.unchanged, summary.conflicts.len()), (0, 3, 1));
+        assert_eq!(again, merged);
+    }
+}
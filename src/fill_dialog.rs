@@ -0,0 +1,271 @@
+// src/fill_dialog.rs
+//
+// The prompt for `{{fill:Name}}` (or `{{field:Name}}`) snippets: a label
+// (`template::field_label`) and edit box per field, OK and Cancel. Built
+// from plain Win32 controls and run modally on the processing thread with
+// its own message loop, while the keyboard hook lets every key through to it
+// (`FILL_DIALOG_OPEN`). `confirm` is the yes/no
+// message box for `confirm` snippets, run the same way.
+
+use std::sync::atomic::Ordering;
+use std::sync::{Mutex, Once};
+use std::thread;
+use std::time::Duration;
+use std::{mem, ptr};
+
+use winapi::shared::minwindef::{FALSE, LOWORD, LPARAM, LRESULT, UINT, WPARAM};
+use winapi::shared::windef::{HMENU, HWND, RECT};
+use winapi::um::libloaderapi::GetModuleHandleW;
+use winapi::um::processthreadsapi::GetCurrentThreadId;
+use winapi::um::wingdi::{GetStockObject, DEFAULT_GUI_FONT};
+use winapi::um::winuser::{
+    AdjustWindowRectEx, AttachThreadInput, CreateWindowExW, DefWindowProcW, DestroyWindow, DispatchMessageW,
+    GetForegroundWindow, GetMessageW, GetSystemMetrics, GetWindowTextLengthW, GetWindowTextW,
+    GetWindowThreadProcessId, IsDialogMessageW, MessageBoxW, PostQuitMessage, RegisterClassW, SendMessageW, SetFocus,
+    SetForegroundWindow, ShowWindow, TranslateMessage, BS_DEFPUSHBUTTON, BS_PUSHBUTTON, COLOR_BTNFACE,
+    ES_AUTOHSCROLL, IDCANCEL, IDOK, IDYES, MB_ICONQUESTION, MB_SETFOREGROUND, MB_TOPMOST, MB_YESNO, MSG, SM_CXSCREEN, SM_CYSCREEN, SS_RIGHT, SW_SHOW, WM_COMMAND,
+    WM_DESTROY, WM_SETFONT, WNDCLASSW, WS_CAPTION, WS_CHILD, WS_EX_CLIENTEDGE, WS_EX_CONTROLPARENT,
+    WS_EX_DLGMODALFRAME, WS_EX_TOPMOST, WS_POPUP, WS_SYSMENU, WS_TABSTOP, WS_VISIBLE,
+};
+
+use crate::keyboard_hook::{encode_wide, FILL_DIALOG_OPEN};
+use crate::template;
+
+const CLASS_NAME: &str = "TextExpanderFillDialog";
+
+// Layout, in pixels
+const MARGIN: i32 = 12;
+const LABEL_WIDTH: i32 = 110;
+const EDIT_WIDTH: i32 = 260;
+const ROW_HEIGHT: i32 = 24;
+const ROW_GAP: i32 = 8;
+const BUTTON_WIDTH: i32 = 80;
+const BUTTON_HEIGHT: i32 = 26;
+
+/// The edit boxes of the open dialog and, once OK is pressed, their text.
+/// HWNDs as integers so this can live in a static.
+struct DialogState {
+    edits: Vec<usize>,
+    values: Option<Vec<String>>,
+}
+
+static STATE: Mutex<DialogState> = Mutex::new(DialogState { edits: Vec::new(), values: None });
+
+static REGISTER_CLASS: Once = Once::new();
+
+/// Ask for a value for each of `fields`. Returns them in the same order, or
+/// `None` if the user pressed Escape or closed the dialog. Focus goes back to
+/// the window that had it before returning.
+pub fn prompt(fields: &[String]) -> Result<Option<Vec<String>>, Box<dyn std::error::Error>> {
+    let previous = unsafe { GetForegroundWindow() };
+    *STATE.lock().unwrap() = DialogState { edits: Vec::new(), values: None };
+
+    FILL_DIALOG_OPEN.store(true, Ordering::SeqCst);
+    let result = unsafe { run_dialog(fields) };
+    FILL_DIALOG_OPEN.store(false, Ordering::SeqCst);
+
+    return_focus(previous);
+    result?;
+    Ok(STATE.lock().unwrap().values.take())
+}
+
+/// Ask `question` with Yes and No, and give focus back as `prompt` does.
+pub fn confirm(question: &str) -> bool {
+    let previous = unsafe { GetForegroundWindow() };
+    let text = encode_wide(question);
+    let caption = encode_wide("Text Expander");
+
+    FILL_DIALOG_OPEN.store(true, Ordering::SeqCst);
+    let flags = MB_ICONQUESTION | MB_YESNO | MB_TOPMOST | MB_SETFOREGROUND;
+    let answer = unsafe { MessageBoxW(ptr::null_mut(), text.as_ptr(), caption.as_ptr(), flags) };
+    FILL_DIALOG_OPEN.store(false, Ordering::SeqCst);
+
+    return_focus(previous);
+    answer == IDYES
+}
+
+/// Back to the app the snippet was typed in before anything is injected.
+fn return_focus(previous: HWND) {
+    if !previous.is_null() {
+        unsafe { SetForegroundWindow(previous) };
+        thread::sleep(Duration::from_millis(50));
+    }
+}
+
+unsafe fn run_dialog(fields: &[String]) -> Result<(), Box<dyn std::error::Error>> {
+    unsafe {
+        let h_instance = GetModuleHandleW(ptr::null());
+        let class_name = encode_wide(CLASS_NAME);
+        REGISTER_CLASS.call_once(|| {
+            let wc = WNDCLASSW {
+                lpfnWndProc: Some(dialog_proc),
+                hInstance: h_instance,
+                lpszClassName: class_name.as_ptr(),
+                hbrBackground: (COLOR_BTNFACE + 1) as usize as _,
+                ..mem::zeroed()
+            };
+            RegisterClassW(&wc);
+        });
+
+        let rows = fields.len() as i32;
+        let client_width = MARGIN * 3 + LABEL_WIDTH + EDIT_WIDTH;
+        let buttons_top = MARGIN + rows * (ROW_HEIGHT + ROW_GAP);
+        let client_height = buttons_top + BUTTON_HEIGHT + MARGIN;
+
+        let style = WS_POPUP | WS_CAPTION | WS_SYSMENU;
+        let ex_style = WS_EX_DLGMODALFRAME | WS_EX_TOPMOST | WS_EX_CONTROLPARENT;
+        let mut rect = RECT { left: 0, top: 0, right: client_width, bottom: client_height };
+        AdjustWindowRectEx(&mut rect, style, FALSE, ex_style);
+        let (width, height) = (rect.right - rect.left, rect.bottom - rect.top);
+
+        let hwnd = CreateWindowExW(
+            ex_style,
+            class_name.as_ptr(),
+            encode_wide("Fill in snippet").as_ptr(),
+            style,
+            (GetSystemMetrics(SM_CXSCREEN) - width) / 2,
+            (GetSystemMetrics(SM_CYSCREEN) - height) / 2,
+            width,
+            height,
+            ptr::null_mut(),
+            ptr::null_mut(),
+            h_instance,
+            ptr::null_mut(),
+        );
+        if hwnd.is_null() {
+            return Err("Failed to create fill-in dialog".into());
+        }
+
+        let mut edits = Vec::with_capacity(fields.len());
+        for (row, field) in fields.iter().enumerate() {
+            let top = MARGIN + row as i32 * (ROW_HEIGHT + ROW_GAP);
+            let label = template::field_label(field);
+            add_control(hwnd, 0, "STATIC", &label, SS_RIGHT, (MARGIN, top + 4, LABEL_WIDTH, ROW_HEIGHT), 0);
+            let edit = add_control(
+                hwnd,
+                WS_EX_CLIENTEDGE,
+                "EDIT",
+                "",
+                WS_TABSTOP | ES_AUTOHSCROLL,
+                (MARGIN * 2 + LABEL_WIDTH, top, EDIT_WIDTH, ROW_HEIGHT),
+                0,
+            );
+            edits.push(edit as usize);
+        }
+
+        let cancel_left = client_width - MARGIN - BUTTON_WIDTH;
+        let ok_left = cancel_left - MARGIN - BUTTON_WIDTH;
+        let button_rect = |left| (left, buttons_top, BUTTON_WIDTH, BUTTON_HEIGHT);
+        add_control(hwnd, 0, "BUTTON", "OK", WS_TABSTOP | BS_DEFPUSHBUTTON, button_rect(ok_left), IDOK);
+        add_control(hwnd, 0, "BUTTON", "Cancel", WS_TABSTOP | BS_PUSHBUTTON, button_rect(cancel_left), IDCANCEL);
+
+        let first_edit = edits.first().copied();
+        STATE.lock().unwrap().edits = edits;
+
+        ShowWindow(hwnd, SW_SHOW);
+        bring_to_front(hwnd);
+        if let Some(edit) = first_edit {
+            SetFocus(edit as HWND);
+        }
+
+        // Until WM_DESTROY posts WM_QUIT. IsDialogMessageW gives the usual
+        // dialog keys: Tab between fields, Enter for OK, Escape for Cancel.
+        let mut msg: MSG = mem::zeroed();
+        while GetMessageW(&mut msg, ptr::null_mut(), 0, 0) > 0 {
+            if IsDialogMessageW(hwnd, &mut msg) == 0 {
+                TranslateMessage(&msg);
+                DispatchMessageW(&msg);
+            }
+        }
+        Ok(())
+    }
+}
+
+/// Create a child control with the dialog font. `rect` is left, top, width,
+/// height in client coordinates.
+pub(crate) unsafe fn add_control(
+    parent: HWND,
+    ex_style: u32,
+    class: &str,
+    text: &str,
+    style: u32,
+    rect: (i32, i32, i32, i32),
+    id: i32,
+) -> HWND {
+    unsafe {
+        let (left, top, width, height) = rect;
+        let control = CreateWindowExW(
+            ex_style,
+            encode_wide(class).as_ptr(),
+            encode_wide(text).as_ptr(),
+            WS_CHILD | WS_VISIBLE | style,
+            left,
+            top,
+            width,
+            height,
+            parent,
+            id as usize as HMENU,
+            GetModuleHandleW(ptr::null()),
+            ptr::null_mut(),
+        );
+        SendMessageW(control, WM_SETFONT, GetStockObject(DEFAULT_GUI_FONT as i32) as WPARAM, 1);
+        control
+    }
+}
+
+/// Windows only lets the process that owns the foreground window hand focus
+/// to a new one, so briefly share input state with that window's thread.
+pub(crate) unsafe fn bring_to_front(hwnd: HWND) {
+    unsafe {
+        let foreground_thread = GetWindowThreadProcessId(GetForegroundWindow(), ptr::null_mut());
+        let this_thread = GetCurrentThreadId();
+        let attached = foreground_thread != 0
+            && foreground_thread != this_thread
+            && AttachThreadInput(this_thread, foreground_thread, 1) != 0;
+        SetForegroundWindow(hwnd);
+        if attached {
+            AttachThreadInput(this_thread, foreground_thread, 0);
+        }
+    }
+}
+
+/// Text of every edit box, in field order.
+unsafe fn read_values() -> Vec<String> {
+    let state = STATE.lock().unwrap();
+    state
+        .edits
+        .iter()
+        .map(|&edit| unsafe {
+            let edit = edit as HWND;
+            let mut buf = vec![0u16; GetWindowTextLengthW(edit) as usize + 1];
+            let len = GetWindowTextW(edit, buf.as_mut_ptr(), buf.len() as i32);
+            String::from_utf16_lossy(&buf[..len.max(0) as usize])
+        })
+        .collect()
+}
+
+unsafe extern "system" fn dialog_proc(hwnd: HWND, msg: UINT, w_param: WPARAM, l_param: LPARAM) -> LRESULT {
+    unsafe {
+        match msg {
+            WM_COMMAND => match LOWORD(w_param as u32) as i32 {
+                IDOK => {
+                    let values = read_values();
+                    STATE.lock().unwrap().values = Some(values);
+                    DestroyWindow(hwnd);
+                    0
+                }
+                IDCANCEL => {
+                    DestroyWindow(hwnd);
+                    0
+                }
+                _ => DefWindowProcW(hwnd, msg, w_param, l_param),
+            },
+            WM_DESTROY => {
+                PostQuitMessage(0);
+                0
+            }
+            // WM_CLOSE (the title bar X) destroys the window: a cancel
+            _ => DefWindowProcW(hwnd, msg, w_param, l_param),
+        }
+    }
+}
@@ -0,0 +1,295 @@
+// src/foreground.rs
+//
+// Which application has focus, for `excluded_apps`, `only_apps`, per-app
+// profiles and `suppress_in_remote`, and whether the focused control is a
+// password box.
+//
+// Called for every keystroke, so the process lookup (OpenProcess +
+// QueryFullProcessImageNameW) and the window class are cached per
+// foreground window and only repeated when focus moves to a different window.
+// The MSAA password check is cached per focused control in the same way.
+
+use std::cell::Cell;
+use std::path::Path;
+use std::{mem, ptr};
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::Mutex;
+
+use winapi::ctypes::c_void;
+use winapi::shared::guiddef::{GUID, REFIID};
+use winapi::shared::minwindef::{DWORD, FALSE};
+use winapi::shared::windef::HWND;
+use winapi::shared::winerror::HRESULT;
+use winapi::shared::wtypes::{VARTYPE, VT_DISPATCH, VT_I4};
+use winapi::um::combaseapi::CoInitializeEx;
+use winapi::um::handleapi::CloseHandle;
+use winapi::um::oaidl::{IDispatchVtbl, VARIANT};
+use winapi::um::objbase::COINIT_MULTITHREADED;
+use winapi::um::oleauto::VariantClear;
+use winapi::um::processthreadsapi::OpenProcess;
+use winapi::um::winbase::QueryFullProcessImageNameW;
+use winapi::um::unknwnbase::IUnknown;
+use winapi::um::winnt::PROCESS_QUERY_LIMITED_INFORMATION;
+use winapi::um::winuser::{
+    GetClassNameW, GetForegroundWindow, GetGUIThreadInfo, GetWindowLongW, GetWindowTextW, GetWindowThreadProcessId,
+    CHILDID_SELF, ES_PASSWORD, GUITHREADINFO, GWL_STYLE, OBJID_CLIENT, STATE_SYSTEM_PROTECTED,
+};
+
+struct Cached {
+    /// Foreground HWND the name was looked up for, as an integer so the
+    /// cache can live in a static.
+    hwnd: usize,
+    exe_name: Option<String>,
+    class_name: String,
+}
+
+static CACHE: Mutex<Option<Cached>> = Mutex::new(None);
+
+/// Foreground and focus HWNDs the MSAA check last ran for, and whether the
+/// focused element was protected.
+static ACCESSIBLE_CACHE: Mutex<Option<(usize, usize, bool)>> = Mutex::new(None);
+
+/// The window the last expansion went into, as an integer so it can live in
+/// a static. The tray's History menu takes focus away from it.
+static LAST_TARGET: AtomicUsize = AtomicUsize::new(0);
+
+/// Lowercased executable file name of the focused window's process, e.g.
+/// `"keepass.exe"`. `None` if there is no foreground window or the process
+/// can't be queried.
+pub fn exe_name() -> Option<String> {
+    lookup(|cached| cached.exe_name.clone()).flatten()
+}
+
+/// Window class of the focused top-level window, e.g.
+/// `"TscShellContainerClass"` for Remote Desktop. Empty if there is none.
+pub fn class_name() -> String {
+    lookup(|cached| cached.class_name.clone()).unwrap_or_default()
+}
+
+/// Read from the cached lookup for the foreground window, refreshing it if
+/// focus has moved.
+fn lookup<T>(read: impl FnOnce(&Cached) -> T) -> Option<T> {
+    let hwnd = unsafe { GetForegroundWindow() };
+    if hwnd.is_null() {
+        return None;
+    }
+
+    let mut cache = CACHE.lock().unwrap();
+    if let Some(cached) = cache.as_ref().filter(|c| c.hwnd == hwnd as usize) {
+        return Some(read(cached));
+    }
+
+    let exe_name = unsafe { query_exe_name(hwnd) };
+    let class_name = unsafe { query_class_name(hwnd) };
+    crate::debug_println!("Foreground app: {:?} ({})", exe_name, class_name);
+    Some(read(cache.insert(Cached { hwnd: hwnd as usize, exe_name, class_name })))
+}
+
+/// Title of the focused window. Not cached: terminals and browsers change
+/// their title without changing window.
+pub fn window_title() -> String {
+    let mut buf = [0u16; 512];
+    let len = unsafe {
+        let hwnd = GetForegroundWindow();
+        if hwnd.is_null() {
+            return String::new();
+        }
+        GetWindowTextW(hwnd, buf.as_mut_ptr(), buf.len() as i32)
+    };
+    String::from_utf16_lossy(&buf[..len.max(0) as usize])
+}
+
+/// The control with keyboard focus in the foreground window is an edit box
+/// with `ES_PASSWORD`, or its focused accessible element is protected (how
+/// browsers and other custom-drawn UIs mark a password input). The style is
+/// read every time; the MSAA answer is reused until focus moves to another
+/// control, or `recheck` is set, since a browser moves focus between its
+/// fields without either HWND changing.
+pub fn password_field_focused(recheck: bool) -> bool {
+    unsafe {
+        let hwnd = GetForegroundWindow();
+        if hwnd.is_null() {
+            return false;
+        }
+        let thread = GetWindowThreadProcessId(hwnd, ptr::null_mut());
+
+        let mut info: GUITHREADINFO = mem::zeroed();
+        info.cbSize = mem::size_of::<GUITHREADINFO>() as DWORD;
+        if GetGUIThreadInfo(thread, &mut info) == 0 || info.hwndFocus.is_null() {
+            return false;
+        }
+        if GetWindowLongW(info.hwndFocus, GWL_STYLE) as u32 & ES_PASSWORD != 0 {
+            return true;
+        }
+
+        let (foreground, focus) = (hwnd as usize, info.hwndFocus as usize);
+        let mut cache = ACCESSIBLE_CACHE.lock().unwrap();
+        match *cache {
+            Some((cached_foreground, cached_focus, protected))
+                if !recheck && (cached_foreground, cached_focus) == (foreground, focus) =>
+            {
+                protected
+            }
+            _ => {
+                let protected = accessible_focus_protected(info.hwndFocus);
+                *cache = Some((foreground, focus, protected));
+                protected
+            }
+        }
+    }
+}
+
+/// `IAccessible` (oleacc.h), only as far as `get_accFocus`; winapi has no
+/// binding for it.
+#[repr(C)]
+struct IAccessible {
+    vtable: *const IAccessibleVtbl,
+}
+
+#[repr(C)]
+struct IAccessibleVtbl {
+    parent: IDispatchVtbl,
+    /// `get_accParent` .. `get_accRole`
+    _before_state: [usize; 7],
+    get_acc_state: unsafe extern "system" fn(*mut IAccessible, VARIANT, *mut VARIANT) -> HRESULT,
+    /// `get_accHelp` .. `get_accKeyboardShortcut`
+    _before_focus: [usize; 3],
+    get_acc_focus: unsafe extern "system" fn(*mut IAccessible, *mut VARIANT) -> HRESULT,
+}
+
+/// {618736E0-3C3D-11CF-810C-00AA00389B71}
+const IID_IACCESSIBLE: GUID = GUID {
+    Data1: 0x618736e0,
+    Data2: 0x3c3d,
+    Data3: 0x11cf,
+    Data4: [0x81, 0x0c, 0x00, 0xaa, 0x00, 0x38, 0x9b, 0x71],
+};
+
+#[link(name = "oleacc")]
+unsafe extern "system" {
+    fn AccessibleObjectFromWindow(hwnd: HWND, id: DWORD, riid: REFIID, object: *mut *mut c_void) -> HRESULT;
+}
+
+thread_local! {
+    static COM_INITIALIZED: Cell<bool> = const { Cell::new(false) };
+}
+
+/// The element with focus inside `hwnd` has `STATE_SYSTEM_PROTECTED`. Asked
+/// through MSAA, which Chromium and WPF answer for their own controls.
+unsafe fn accessible_focus_protected(hwnd: HWND) -> bool {
+    unsafe {
+        if !COM_INITIALIZED.get() {
+            CoInitializeEx(ptr::null_mut(), COINIT_MULTITHREADED);
+            COM_INITIALIZED.set(true);
+        }
+
+        let mut accessible: *mut IAccessible = ptr::null_mut();
+        let object = &mut accessible as *mut *mut IAccessible as *mut *mut c_void;
+        let found = AccessibleObjectFromWindow(hwnd, OBJID_CLIENT as DWORD, &IID_IACCESSIBLE, object) >= 0;
+        if !found || accessible.is_null() {
+            return false;
+        }
+
+        let mut focus: VARIANT = mem::zeroed();
+        let protected = if ((*(*accessible).vtable).get_acc_focus)(accessible, &mut focus) >= 0 {
+            match focus.n1.n2().vt as u32 {
+                // A child element without an object of its own, by id
+                VT_I4 => state_protected(accessible, focus),
+                VT_DISPATCH => {
+                    let dispatch = *focus.n1.n2().n3.pdispVal();
+                    let mut child: *mut IAccessible = ptr::null_mut();
+                    let object = &mut child as *mut *mut IAccessible as *mut *mut c_void;
+                    let found = !dispatch.is_null() && (*dispatch).QueryInterface(&IID_IACCESSIBLE, object) >= 0;
+                    let protected = found && !child.is_null() && state_protected(child, child_self());
+                    if !child.is_null() {
+                        (*(child as *mut IUnknown)).Release();
+                    }
+                    protected
+                }
+                _ => false,
+            }
+        } else {
+            false
+        };
+        VariantClear(&mut focus);
+        (*(accessible as *mut IUnknown)).Release();
+        protected
+    }
+}
+
+/// `CHILDID_SELF` as a VARIANT.
+fn child_self() -> VARIANT {
+    unsafe {
+        let mut child: VARIANT = mem::zeroed();
+        child.n1.n2_mut().vt = VT_I4 as VARTYPE;
+        *child.n1.n2_mut().n3.lVal_mut() = CHILDID_SELF;
+        child
+    }
+}
+
+unsafe fn state_protected(accessible: *mut IAccessible, child: VARIANT) -> bool {
+    unsafe {
+        let mut state: VARIANT = mem::zeroed();
+        let ok = ((*(*accessible).vtable).get_acc_state)(accessible, child, &mut state) >= 0;
+        let protected = ok
+            && state.n1.n2().vt as u32 == VT_I4
+            && *state.n1.n2().n3.lVal() as DWORD & STATE_SYSTEM_PROTECTED != 0;
+        VariantClear(&mut state);
+        protected
+    }
+}
+
+unsafe fn query_exe_name(hwnd: HWND) -> Option<String> {
+    unsafe {
+        let mut pid: DWORD = 0;
+        GetWindowThreadProcessId(hwnd, &mut pid);
+        if pid == 0 {
+            return None;
+        }
+
+        let process = OpenProcess(PROCESS_QUERY_LIMITED_INFORMATION, FALSE, pid);
+        if process.is_null() {
+            return None;
+        }
+
+        let mut buf = [0u16; 1024];
+        let mut size = buf.len() as DWORD;
+        let ok = QueryFullProcessImageNameW(process, 0, buf.as_mut_ptr(), &mut size);
+        CloseHandle(process);
+        if ok == 0 {
+            return None;
+        }
+
+        let path = String::from_utf16_lossy(&buf[..size as usize]);
+        Path::new(&path)
+            .file_name()
+            .map(|name| name.to_string_lossy().to_lowercase())
+    }
+}
+
+unsafe fn query_class_name(hwnd: HWND) -> String {
+    let mut buf = [0u16; 256];
+    let len = unsafe { GetClassNameW(hwnd, buf.as_mut_ptr(), buf.len() as i32) };
+    String::from_utf16_lossy(&buf[..len.max(0) as usize])
+}
+
+/// Note the focused window as the one the last expansion went into.
+pub fn remember_target() {
+    LAST_TARGET.store(unsafe { GetForegroundWindow() } as usize, Ordering::SeqCst);
+}
+
+/// Give focus back to the window `remember_target` noted, if any.
+pub fn refocus_last_target() {
+    let target = LAST_TARGET.load(Ordering::SeqCst) as HWND;
+    if !target.is_null() {
+        unsafe { crate::fill_dialog::bring_to_front(target) };
+    }
+}
+
+/// Forget the cached lookups. Called on mouse clicks, which is when a closed
+/// window's handle is most likely to have been reused by another process, and
+/// when a click may have moved focus to another field of a browser page.
+pub fn invalidate() {
+    *CACHE.lock().unwrap() = None;
+    *ACCESSIBLE_CACHE.lock().unwrap() = None;
+}
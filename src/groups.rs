@@ -0,0 +1,95 @@
+// src/groups.rs
+//
+// Snippet groups (`group = "medical"`), which the tray's Groups menu turns
+// on and off as a whole. Snippets without one are in `DEFAULT_GROUP`. The
+// engine keeps the set of disabled groups; it is saved to groups.toml next
+// to the config file on every change, so it outlives reloads and restarts.
+
+use std::collections::BTreeSet;
+use std::fs;
+use std::path::PathBuf;
+
+use serde::{Deserialize, Serialize};
+
+use crate::config::{self, ExpansionFile, Snippet};
+
+const GROUPS_FILE: &str = "groups.toml";
+
+/// The group of snippets that don't name one. Always listed.
+pub const DEFAULT_GROUP: &str = "default";
+
+#[derive(Debug, Default, Serialize, Deserialize)]
+struct SavedGroups {
+    #[serde(default)]
+    disabled: BTreeSet<String>,
+}
+
+fn state_path() -> Option<PathBuf> {
+    Some(config::config_path()?.parent()?.join(GROUPS_FILE))
+}
+
+pub fn group_of(snippet: &Snippet) -> &str {
+    snippet.options.group.as_deref().unwrap_or(DEFAULT_GROUP)
+}
+
+/// Every group a snippet in `file` belongs to, `DEFAULT_GROUP` first and the
+/// rest sorted.
+pub fn names(file: &ExpansionFile) -> Vec<String> {
+    let tables = [&file.case_sensitive, &file.case_insensitive]
+        .into_iter()
+        .chain(file.app.values().flat_map(|profile| [&profile.case_sensitive, &profile.case_insensitive]));
+    let named: BTreeSet<&str> =
+        tables.flat_map(|table| table.values().map(group_of)).filter(|group| *group != DEFAULT_GROUP).collect();
+    std::iter::once(DEFAULT_GROUP).chain(named).map(str::to_string).collect()
+}
+
+/// The groups switched off when groups.toml was last saved. A missing file
+/// means none, and an unreadable one is reported and ignored.
+pub fn read_disabled() -> BTreeSet<String> {
+    let Some(path) = state_path() else {
+        return BTreeSet::new();
+    };
+    let Ok(contents) = fs::read_to_string(&path) else {
+        return BTreeSet::new();
+    };
+    match toml::from_str::<SavedGroups>(&contents) {
+        Ok(saved) => saved.disabled,
+        Err(e) => {
+            println!("Warning: ignoring unreadable {}: {}", path.display(), e);
+            BTreeSet::new()
+        }
+    }
+}
+
+/// Write the disabled groups out, through a temporary file and a rename
+/// like counters.toml.
+pub fn save_disabled(disabled: &BTreeSet<String>) -> Result<(), Box<dyn std::error::Error>> {
+    let path = state_path().ok_or("No config file has been loaded yet")?;
+    let temp = path.with_extension("toml.tmp");
+    fs::write(&temp, toml::to_string(&SavedGroups { disabled: disabled.clone() })?)?;
+    fs::rename(&temp, &path)?;
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn groups_are_listed_default_first() {
+        let file = config::parse_expansion_file(
+            r#"
+            [case_sensitive]
+            "btw" = "by the way"
+            "mtx" = { replace = "methotrexate", group = "medical" }
+
+            [app."code.exe".case_insensitive]
+            "fn" = { replace = "function", group = "code" }
+            "#,
+        )
+        .unwrap();
+        assert_eq!(names(&file), ["default", "code", "medical"]);
+        assert_eq!(group_of(&file.case_sensitive["btw"]), DEFAULT_GROUP);
+        assert_eq!(names(&config::parse_expansion_file("").unwrap()), ["default"]);
+    }
+}
@@ -0,0 +1,177 @@
+// src/input_backend.rs
+//
+// What the expander needs from the OS, behind one trait: hook events for the
+// processing thread, and ways to get text and keys into the focused app.
+// main.rs only goes through `BACKEND`, and the engine only ever sees
+// `HookMessage`s turned into `InputEvent`s.
+//
+// Windows uses its own low-level hooks and SendInput (`WindowsBackend`).
+// Linux and macOS use rdev's `listen`/`simulate` (`rdev_backend.rs`), as does
+// Windows when built with the `rdev` feature.
+
+use std::sync::mpsc::Sender;
+
+use text_expander::keys::KeyId;
+
+#[cfg(all(windows, not(feature = "rdev")))]
+use crate::{keyboard_hook, windows_input};
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum MouseButton {
+    Left,
+    Right,
+    Middle,
+}
+
+/// What a hook saw of a key besides its `KeyId`, for
+/// `InputBackend::resolve_character`. Each backend only makes its own kind.
+#[derive(Debug)]
+#[allow(dead_code)]
+pub enum RawKey {
+    /// Virtual-key and scan code from the Windows hook, turned into text on
+    /// the processing thread so the hook returns quickly.
+    Codes { vk_code: u32, scan_code: u32 },
+    /// The text rdev already worked out for the key, if it typed any.
+    Text(Option<String>),
+}
+
+/// Off Windows there is no tray, so only keys and clicks are sent.
+#[derive(Debug)]
+#[cfg_attr(not(windows), allow(dead_code))]
+pub enum HookMessage {
+    KeyDown {
+        key: KeyId,
+        raw: RawKey,
+        /// The hook blocked this key from the target app; the processing
+        /// thread must re-inject it unless it consumes it. rdev can't block
+        /// keys, so its keys never are.
+        swallowed: bool,
+        /// Hook timestamp in milliseconds (KBDLLHOOKSTRUCT.time).
+        time: u32,
+        /// Ctrl, Alt, or Win was held with this (non-modifier) key, e.g.
+        /// Ctrl+A or Ctrl+V: the text around the caret may have changed.
+        /// Also set for AltGr, which the processing thread treats as typing
+        /// when the key resolves to a character.
+        shortcut: bool,
+        /// Shift was held: with an arrow key it extends a selection.
+        shift: bool,
+    },
+    MouseDown(MouseButton),
+    /// "Reload config" from the tray menu. Handled on the processing thread
+    /// between key events, so it never lands mid-expansion.
+    ReloadConfig,
+    /// A snippet picked in the search popup, which has given focus back to
+    /// the window it was opened over.
+    InsertSnippet { trigger: String },
+    /// A group clicked in the tray's Groups menu, to switch off or back on.
+    ToggleGroup { group: String },
+    /// Insert the `index`th most recent expansion again: the repeat hotkey
+    /// (0), or an entry of the tray's History menu, which took focus from
+    /// the app (`refocus`).
+    RepeatExpansion { index: usize, refocus: bool },
+    /// "Clear history" from the tray menu.
+    ClearHistory,
+    /// The `index`th `[transforms]` hotkey was pressed: replace the
+    /// selection in the focused app.
+    TransformSelection { index: usize },
+}
+
+pub trait InputBackend: Sync {
+    /// Install the hooks and send every key and click to `sender` until the
+    /// app quits.
+    fn run(&self, sender: Sender<HookMessage>) -> Result<(), Box<dyn std::error::Error>>;
+
+    /// The text a key typed, if any. Called on the processing thread.
+    fn resolve_character(&self, raw: &RawKey) -> Option<String>;
+
+    /// Backspace over `count` characters before the caret.
+    fn delete_chars(&self, count: usize) -> Result<(), Box<dyn std::error::Error>>;
+
+    /// Type `text` as key events, leaving the clipboard alone.
+    fn inject_text(&self, text: &str) -> Result<(), Box<dyn std::error::Error>>;
+
+    /// `inject_text` one character at a time, `delay_ms` apart, for apps
+    /// that drop fast input.
+    fn inject_text_slowly(&self, text: &str, delay_ms: u64) -> Result<(), Box<dyn std::error::Error>>;
+
+    /// Move the caret `count` characters left.
+    fn caret_left(&self, count: usize) -> Result<(), Box<dyn std::error::Error>>;
+
+    /// Move the caret `count` characters right.
+    fn caret_right(&self, count: usize) -> Result<(), Box<dyn std::error::Error>>;
+
+    /// Send the platform's paste shortcut (Ctrl+V; Cmd+V on macOS).
+    fn paste(&self) -> Result<(), Box<dyn std::error::Error>>;
+
+    /// Send the platform's copy shortcut (Ctrl+C; Cmd+C on macOS).
+    fn copy(&self) -> Result<(), Box<dyn std::error::Error>>;
+
+    /// Send on a key the hook swallowed or held back during an expansion.
+    fn resend_key(&self, key: KeyId) -> Result<(), Box<dyn std::error::Error>>;
+
+    /// Press `keys` in order, then release them in reverse (a macro's
+    /// `key(Shift+End)`).
+    fn send_chord(&self, keys: &[KeyId]) -> Result<(), Box<dyn std::error::Error>>;
+}
+
+/// Low-level hooks on the tray's message loop, and `SendInput`.
+#[cfg(all(windows, not(feature = "rdev")))]
+pub struct WindowsBackend;
+
+#[cfg(all(windows, not(feature = "rdev")))]
+impl InputBackend for WindowsBackend {
+    fn run(&self, sender: Sender<HookMessage>) -> Result<(), Box<dyn std::error::Error>> {
+        keyboard_hook::run_tray(sender, true)
+    }
+
+    fn resolve_character(&self, raw: &RawKey) -> Option<String> {
+        match raw {
+            RawKey::Codes { vk_code, scan_code } => keyboard_hook::resolve_character(*vk_code, *scan_code),
+            RawKey::Text(text) => text.clone(),
+        }
+    }
+
+    fn delete_chars(&self, count: usize) -> Result<(), Box<dyn std::error::Error>> {
+        windows_input::send_backspaces_fast(count)
+    }
+
+    fn inject_text(&self, text: &str) -> Result<(), Box<dyn std::error::Error>> {
+        windows_input::send_text_via_unicode(text)
+    }
+
+    fn inject_text_slowly(&self, text: &str, delay_ms: u64) -> Result<(), Box<dyn std::error::Error>> {
+        windows_input::send_text_typed(text, delay_ms)
+    }
+
+    fn caret_left(&self, count: usize) -> Result<(), Box<dyn std::error::Error>> {
+        windows_input::send_left_arrows(count)
+    }
+
+    fn caret_right(&self, count: usize) -> Result<(), Box<dyn std::error::Error>> {
+        windows_input::send_right_arrows(count)
+    }
+
+    fn paste(&self) -> Result<(), Box<dyn std::error::Error>> {
+        windows_input::send_ctrl_v()
+    }
+
+    fn copy(&self) -> Result<(), Box<dyn std::error::Error>> {
+        windows_input::send_ctrl_c()
+    }
+
+    fn resend_key(&self, key: KeyId) -> Result<(), Box<dyn std::error::Error>> {
+        windows_input::send_key_chord(&[key])
+    }
+
+    fn send_chord(&self, keys: &[KeyId]) -> Result<(), Box<dyn std::error::Error>> {
+        windows_input::send_key_chord(keys)
+    }
+}
+
+/// The backend for this platform and build.
+#[cfg(all(windows, not(feature = "rdev")))]
+pub const BACKEND: &dyn InputBackend = &WindowsBackend;
+
+/// The backend for this platform and build.
+#[cfg(any(not(windows), feature = "rdev"))]
+pub const BACKEND: &dyn InputBackend = &crate::rdev_backend::RdevBackend;
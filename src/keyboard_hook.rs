@@ -1,197 +1,172 @@
 // src/keyboard_hook.rs
 //
-// Custom lightweight WH_KEYBOARD_LL + WH_MOUSE_LL hooks.
+// Custom lightweight WH_KEYBOARD_LL + WH_MOUSE_LL hooks, and the tray icon.
 // Replaces rdev to avoid heavyweight hook callbacks that interfere with SendInput.
+// Builds with the `rdev` feature run only the tray from here (see rdev_backend.rs).
 
-use std::sync::atomic::Ordering;
+use std::sync::atomic::{AtomicBool, AtomicPtr, AtomicU32, Ordering};
 use std::sync::mpsc::Sender;
-use std::sync::OnceLock;
+use std::sync::{Mutex, OnceLock};
+use std::thread;
+use std::time::{Duration, Instant};
 use std::{mem, ptr};
 
-use winapi::shared::minwindef::{LPARAM, LRESULT, WPARAM};
-use winapi::shared::windef::{HHOOK, HWND, POINT};
+use winapi::shared::minwindef::{HINSTANCE, LPARAM, LRESULT, WPARAM};
+use winapi::shared::windef::{HHOOK, HICON, HWND, HWND__, POINT};
 use winapi::um::libloaderapi::GetModuleHandleW;
-use winapi::um::shellapi::{Shell_NotifyIconW, NOTIFYICONDATAW, NIF_ICON, NIF_MESSAGE, NIF_TIP, NIM_ADD, NIM_DELETE};
+use winapi::um::shellapi::{
+    Shell_NotifyIconW, NOTIFYICONDATAW, NIF_ICON, NIF_INFO, NIF_MESSAGE, NIF_TIP, NIIF_ERROR, NIIF_INFO, NIIF_NOSOUND,
+    NIM_ADD, NIM_DELETE, NIM_MODIFY,
+};
 use winapi::um::winuser::{
     AppendMenuW, CallNextHookEx, CreatePopupMenu, CreateWindowExW, DefWindowProcW,
-    DestroyMenu, DestroyWindow, DispatchMessageW, GetAsyncKeyState, GetCursorPos, GetKeyState,
-    GetMessageW, LoadIconW, PostQuitMessage, RegisterClassW, SetForegroundWindow,
+    DestroyMenu, DestroyWindow, DispatchMessageW, FindWindowExW, GetAsyncKeyState, GetCursorPos, GetKeyState,
+    GetMessageW, LoadIconW, MessageBoxW, PostMessageW, PostQuitMessage, RegisterClassW, SetForegroundWindow,
     SetWindowsHookExW, ToUnicode, TrackPopupMenu, TranslateMessage, UnhookWindowsHookEx,
-    HC_ACTION, KBDLLHOOKSTRUCT, MF_STRING, MSG, TPM_BOTTOMALIGN, TPM_LEFTALIGN,
-    VK_CAPITAL, VK_CONTROL, VK_MENU, VK_SHIFT, WH_KEYBOARD_LL, WH_MOUSE_LL, WM_APP, WM_COMMAND,
+    HC_ACTION, IDYES, KBDLLHOOKSTRUCT, MB_ICONERROR, MB_ICONINFORMATION, MB_ICONQUESTION, MB_ICONWARNING, MB_OK, MB_YESNO, MF_CHECKED, MF_GRAYED, MF_POPUP, MF_SEPARATOR, MF_STRING, MSG, TPM_BOTTOMALIGN, TPM_LEFTALIGN,
+    VK_CAPITAL, VK_CONTROL, VK_ESCAPE, VK_LCONTROL, VK_LWIN, VK_MENU, VK_RMENU, VK_RWIN, VK_SHIFT, WH_KEYBOARD_LL, WH_MOUSE_LL, WM_APP, WM_COMMAND,
     WM_DESTROY, WM_KEYDOWN, WM_LBUTTONDOWN, WM_MBUTTONDOWN, WM_RBUTTONDOWN, WM_SYSKEYDOWN,
     WNDCLASSW,
 };
 
-use crate::windows_input::SYNTHETIC_INPUT_TAG;
-use crate::GLOBAL_LISTENING;
+use crate::autostart;
+use crate::config::Hotkey;
+use crate::counters;
+use crate::dead_keys;
+use crate::input_backend::{HookMessage, MouseButton, RawKey};
+use crate::windows_input::{self, SYNTHETIC_INPUT_TAG};
+use crate::listening;
+use crate::search_popup;
+use crate::transforms::MAX_TRANSFORMS;
+use text_expander::keys::KeyId;
 
 // ---------------------------------------------------------------------------
 // Tray icon constants
 // ---------------------------------------------------------------------------
 
 const WM_TRAYICON: u32 = WM_APP + 1;
+/// Posted by the keyboard hook when the toggle hotkey is pressed.
+const WM_TOGGLE_PAUSE: u32 = WM_APP + 2;
+/// Posted by a second launch (see single_instance.rs) to the running
+/// instance's window, which reloads its config.
+const WM_RELOAD_REQUEST: u32 = WM_APP + 3;
+/// Posted when `REMOTE_FOCUSED` changes, to refresh the tooltip.
+const WM_REFRESH_TIP: u32 = WM_APP + 4;
+/// Posted by the keyboard hook when the search hotkey is pressed.
+const WM_SHOW_SEARCH: u32 = WM_APP + 5;
+/// Posted by `notify` to show what it queued in `NOTICES`.
+const WM_SHOW_NOTICES: u32 = WM_APP + 6;
+/// Posted by the keyboard hook when the repeat hotkey is pressed.
+const WM_REPEAT_EXPANSION: u32 = WM_APP + 7;
+/// Posted by the keyboard hook when a `[transforms]` hotkey is pressed, with
+/// its index as the `WPARAM`.
+const WM_TRANSFORM_SELECTION: u32 = WM_APP + 8;
+/// Found by a second launch with `FindWindowExW`.
+const TRAY_CLASS_NAME: &str = "TextExpanderTrayClass";
+/// `HWND_MESSAGE`: the parent of message-only windows.
+const HWND_MESSAGE: HWND = -3isize as HWND;
 const IDM_EXIT: usize = 1;
-
-fn encode_wide(s: &str) -> Vec<u16> {
+const IDM_PAUSE: usize = 2;
+const IDM_RELOAD: usize = 3;
+const IDM_RESET_COUNTERS: usize = 4;
+const IDM_AUTOSTART: usize = 5;
+const IDM_CLEAR_HISTORY: usize = 6;
+/// Groups menu items are numbered from here, in `GROUPS` order.
+const IDM_GROUP_FIRST: usize = 100;
+/// History menu items are numbered from here, newest first.
+const IDM_HISTORY_FIRST: usize = 200;
+
+pub(crate) fn encode_wide(s: &str) -> Vec<u16> {
     s.encode_utf16().chain(std::iter::once(0)).collect()
 }
 
 // ---------------------------------------------------------------------------
-// Public types
+// Shared state
 // ---------------------------------------------------------------------------
 
-#[derive(Debug, Clone, Copy, PartialEq, Eq)]
-pub enum KeyId {
-    Space,
-    Return,
-    Backspace,
-    Tab,
-    Escape,
-    Delete,
-    LeftArrow,
-    RightArrow,
-    UpArrow,
-    DownArrow,
-    Home,
-    End,
-    PageUp,
-    PageDown,
-    KeyA,
-    KeyB,
-    KeyC,
-    KeyD,
-    KeyE,
-    KeyF,
-    KeyG,
-    KeyH,
-    KeyI,
-    KeyJ,
-    KeyK,
-    KeyL,
-    KeyM,
-    KeyN,
-    KeyO,
-    KeyP,
-    KeyQ,
-    KeyR,
-    KeyS,
-    KeyT,
-    KeyU,
-    KeyV,
-    KeyW,
-    KeyX,
-    KeyY,
-    KeyZ,
-    Num0,
-    Num1,
-    Num2,
-    Num3,
-    Num4,
-    Num5,
-    Num6,
-    Num7,
-    Num8,
-    Num9,
-    Minus,
-    Equal,
-    LeftBracket,
-    RightBracket,
-    Quote,
-    Comma,
-    Dot,
-    Slash,
-    SemiColon,
-    BackSlash,
-    BackQuote,
-    Unknown(u32),
-}
-
-#[derive(Debug, Clone, Copy, PartialEq, Eq)]
-pub enum MouseButton {
-    Left,
-    Right,
-    Middle,
-}
-
-#[derive(Debug)]
-pub enum HookMessage {
-    KeyDown {
-        key: KeyId,
-        vk_code: u32,
-        scan_code: u32,
-    },
-    MouseDown(MouseButton),
+/// When set, unmodified Tab is swallowed like Space/Enter so the processing
+/// thread can use it for near-match completion or as a delimiter. Set from
+/// config at startup and on reload.
+pub static SWALLOW_TAB: AtomicBool = AtomicBool::new(false);
+
+/// Expansion switched off by the user from the tray. Unlike the transient
+/// `listening::is_listening`, this persists until toggled back, and while it is set
+/// every key passes straight through to the target app.
+pub static EXPANSION_PAUSED: AtomicBool = AtomicBool::new(false);
+
+/// A remote-desktop or VM client had focus at the last key, so expansion is
+/// suspended (`suppress_in_remote`). Only shown in the tooltip.
+static REMOTE_FOCUSED: AtomicBool = AtomicBool::new(false);
+
+/// A fill-in dialog or confirmation (`fill_dialog.rs`) is open. Keys go
+/// straight to it:
+/// not buffered for replay and not seen by the engine.
+pub static FILL_DIALOG_OPEN: AtomicBool = AtomicBool::new(false);
+
+/// The snippet groups listed in the tray's Groups menu and whether each is
+/// on, replaced by the processing thread on load and after every toggle.
+static GROUPS: Mutex<Vec<(String, bool)>> = Mutex::new(Vec::new());
+
+/// The History menu's lines, newest first, replaced by the processing
+/// thread after every expansion.
+static HISTORY: Mutex<Vec<String>> = Mutex::new(Vec::new());
+
+/// The pause/resume hotkey packed as `vk | modifier bits << 16`, 0 for none.
+/// Packed so the hook can read it without taking a lock.
+static TOGGLE_HOTKEY: AtomicU32 = AtomicU32::new(0);
+
+/// The quick-search hotkey, packed the same way.
+static SEARCH_HOTKEY: AtomicU32 = AtomicU32::new(0);
+
+/// The repeat-last-expansion hotkey, packed the same way.
+static REPEAT_HOTKEY: AtomicU32 = AtomicU32::new(0);
+
+/// The `[transforms]` hotkeys in order, packed the same way; unused ones are 0.
+static TRANSFORM_HOTKEYS: [AtomicU32; MAX_TRANSFORMS] = [const { AtomicU32::new(0) }; MAX_TRANSFORMS];
+
+const HOTKEY_CTRL: u32 = 1 << 16;
+const HOTKEY_ALT: u32 = 1 << 17;
+const HOTKEY_SHIFT: u32 = 1 << 18;
+const HOTKEY_WIN: u32 = 1 << 19;
+
+fn pack_hotkey(hotkey: Option<Hotkey>) -> u32 {
+    hotkey.map_or(0, |h| {
+        h.vk as u32
+            | if h.ctrl { HOTKEY_CTRL } else { 0 }
+            | if h.alt { HOTKEY_ALT } else { 0 }
+            | if h.shift { HOTKEY_SHIFT } else { 0 }
+            | if h.win { HOTKEY_WIN } else { 0 }
+    })
 }
 
-// ---------------------------------------------------------------------------
-// VK → KeyId mapping
-// ---------------------------------------------------------------------------
+pub fn set_groups(groups: Vec<(String, bool)>) {
+    *GROUPS.lock().unwrap() = groups;
+}
+
+pub fn set_history(lines: Vec<String>) {
+    *HISTORY.lock().unwrap() = lines;
+}
+
+/// Set from config at startup and on every reload.
+pub fn set_toggle_hotkey(hotkey: Option<Hotkey>) {
+    TOGGLE_HOTKEY.store(pack_hotkey(hotkey), Ordering::SeqCst);
+}
 
-fn vk_to_key_id(vk: u32) -> KeyId {
-    match vk {
-        0x20 => KeyId::Space,
-        0x0D => KeyId::Return,
-        0x08 => KeyId::Backspace,
-        0x09 => KeyId::Tab,
-        0x1B => KeyId::Escape,
-        0x2E => KeyId::Delete,
-        0x25 => KeyId::LeftArrow,
-        0x27 => KeyId::RightArrow,
-        0x26 => KeyId::UpArrow,
-        0x28 => KeyId::DownArrow,
-        0x24 => KeyId::Home,
-        0x23 => KeyId::End,
-        0x21 => KeyId::PageUp,
-        0x22 => KeyId::PageDown,
-        0x41 => KeyId::KeyA,
-        0x42 => KeyId::KeyB,
-        0x43 => KeyId::KeyC,
-        0x44 => KeyId::KeyD,
-        0x45 => KeyId::KeyE,
-        0x46 => KeyId::KeyF,
-        0x47 => KeyId::KeyG,
-        0x48 => KeyId::KeyH,
-        0x49 => KeyId::KeyI,
-        0x4A => KeyId::KeyJ,
-        0x4B => KeyId::KeyK,
-        0x4C => KeyId::KeyL,
-        0x4D => KeyId::KeyM,
-        0x4E => KeyId::KeyN,
-        0x4F => KeyId::KeyO,
-        0x50 => KeyId::KeyP,
-        0x51 => KeyId::KeyQ,
-        0x52 => KeyId::KeyR,
-        0x53 => KeyId::KeyS,
-        0x54 => KeyId::KeyT,
-        0x55 => KeyId::KeyU,
-        0x56 => KeyId::KeyV,
-        0x57 => KeyId::KeyW,
-        0x58 => KeyId::KeyX,
-        0x59 => KeyId::KeyY,
-        0x5A => KeyId::KeyZ,
-        0x30 => KeyId::Num0,
-        0x31 => KeyId::Num1,
-        0x32 => KeyId::Num2,
-        0x33 => KeyId::Num3,
-        0x34 => KeyId::Num4,
-        0x35 => KeyId::Num5,
-        0x36 => KeyId::Num6,
-        0x37 => KeyId::Num7,
-        0x38 => KeyId::Num8,
-        0x39 => KeyId::Num9,
-        0xBD => KeyId::Minus,       // VK_OEM_MINUS
-        0xBB => KeyId::Equal,       // VK_OEM_PLUS (=/+ key)
-        0xDB => KeyId::LeftBracket, // VK_OEM_4
-        0xDD => KeyId::RightBracket,// VK_OEM_6
-        0xDE => KeyId::Quote,       // VK_OEM_7
-        0xBC => KeyId::Comma,       // VK_OEM_COMMA
-        0xBE => KeyId::Dot,         // VK_OEM_PERIOD
-        0xBF => KeyId::Slash,       // VK_OEM_2
-        0xBA => KeyId::SemiColon,   // VK_OEM_1
-        0xDC => KeyId::BackSlash,   // VK_OEM_5
-        0xC0 => KeyId::BackQuote,   // VK_OEM_3
-        other => KeyId::Unknown(other),
+/// Set from config at startup and on every reload.
+pub fn set_search_hotkey(hotkey: Option<Hotkey>) {
+    SEARCH_HOTKEY.store(pack_hotkey(hotkey), Ordering::SeqCst);
+}
+
+/// Set from config at startup and on every reload.
+pub fn set_repeat_hotkey(hotkey: Option<Hotkey>) {
+    REPEAT_HOTKEY.store(pack_hotkey(hotkey), Ordering::SeqCst);
+}
+
+/// Set from config at startup and on every reload. Past `MAX_TRANSFORMS`
+/// are ignored; the config load refuses that many.
+pub fn set_transform_hotkeys(hotkeys: &[Hotkey]) {
+    for (index, slot) in TRANSFORM_HOTKEYS.iter().enumerate() {
+        slot.store(pack_hotkey(hotkeys.get(index).copied()), Ordering::SeqCst);
     }
 }
 
@@ -199,12 +174,45 @@ fn vk_to_key_id(vk: u32) -> KeyId {
 // Character resolution (called on processing thread, NOT in hook callback)
 // ---------------------------------------------------------------------------
 
+/// A dead key (e.g. `´` or `^`) waiting for the key it modifies.
+#[cfg_attr(feature = "rdev", allow(dead_code))]
+static PENDING_DEAD_KEY: Mutex<Option<char>> = Mutex::new(None);
+
+/// `ToUnicode` flag (Windows 10 1607+): translate without touching the
+/// kernel's dead-key state, which belongs to the app the user is typing into.
+#[cfg_attr(feature = "rdev", allow(dead_code))]
+const TOUNICODE_KEEP_STATE: u32 = 0x4;
+
+#[cfg_attr(feature = "rdev", allow(dead_code))]
 pub fn resolve_character(vk_code: u32, scan_code: u32) -> Option<String> {
     unsafe {
-        // If Ctrl or Alt are held, skip — these are control-key combos, not printable
-        if GetAsyncKeyState(VK_CONTROL) < 0 || GetAsyncKeyState(VK_MENU) < 0 {
+        // Ctrl or Alt alone makes a control-key combo, not text. Both together
+        // is AltGr (right Alt reports as Ctrl+Alt), which types `@`, `€`, etc.
+        // on many layouts.
+        let ctrl = GetAsyncKeyState(VK_CONTROL) < 0;
+        let alt = GetAsyncKeyState(VK_MENU) < 0;
+        let win = GetAsyncKeyState(VK_LWIN) < 0 || GetAsyncKeyState(VK_RWIN) < 0;
+        if ctrl != alt || win {
             return None;
         }
+        let altgr = ctrl && alt;
+
+        // Numpad digits and operators are the same under every layout and
+        // unaffected by Shift. The decimal key follows the layout (`.` or `,`).
+        let numpad = match vk_code {
+            0x60..=0x69 => char::from_digit(vk_code - 0x60, 10),
+            0x6A => Some('*'),
+            0x6B => Some('+'),
+            0x6D => Some('-'),
+            0x6F => Some('/'),
+            _ => None,
+        };
+        if let Some(c) = numpad {
+            if altgr {
+                return None;
+            }
+            return Some(c.to_string());
+        }
 
         // Build keyboard state manually
         let mut keyboard_state = [0u8; 256];
@@ -219,6 +227,13 @@ pub fn resolve_character(vk_code: u32, scan_code: u32) -> Option<String> {
             keyboard_state[VK_CAPITAL as usize] = 0x01;
         }
 
+        // AltGr: layouts look for Ctrl+Alt, some specifically right Alt
+        if altgr {
+            for vk in [VK_CONTROL, VK_LCONTROL, VK_MENU, VK_RMENU] {
+                keyboard_state[vk as usize] = 0x80;
+            }
+        }
+
         let mut buf = [0u16; 4];
         let result = ToUnicode(
             vk_code,
@@ -226,17 +241,30 @@ pub fn resolve_character(vk_code: u32, scan_code: u32) -> Option<String> {
             keyboard_state.as_ptr(),
             buf.as_mut_ptr(),
             buf.len() as i32,
-            0,
+            TOUNICODE_KEEP_STATE,
         );
 
-        if result == 1 {
-            String::from_utf16(&buf[..1]).ok()
-        } else if result > 1 {
-            // Multi-char output (rare)
-            String::from_utf16(&buf[..result as usize]).ok()
-        } else {
-            // result <= 0: dead key or no translation
-            None
+        let mut pending = PENDING_DEAD_KEY.lock().unwrap();
+        if result < 0 {
+            // Dead key: nothing typed yet. `buf` holds its spacing form.
+            *pending = char::from_u32(buf[0] as u32);
+            return None;
+        }
+        if result == 0 {
+            // Shift, Caps Lock, etc. don't use up a pending dead key
+            return None;
+        }
+
+        let text = String::from_utf16(&buf[..result as usize]).ok()?;
+        if text.chars().all(char::is_control) {
+            // Enter, Backspace, Tab, Escape: the app drops the dead key too
+            *pending = None;
+            return if altgr { None } else { Some(text) };
+        }
+
+        match pending.take() {
+            Some(dead) => Some(dead_keys::compose(dead, &text)),
+            None => Some(text),
         }
     }
 }
@@ -247,6 +275,9 @@ pub fn resolve_character(vk_code: u32, scan_code: u32) -> Option<String> {
 
 static HOOK_SENDER: OnceLock<Sender<HookMessage>> = OnceLock::new();
 
+/// The tray message window, for the hook to post hotkey toggles to.
+static TRAY_HWND: AtomicPtr<HWND__> = AtomicPtr::new(ptr::null_mut());
+
 // ---------------------------------------------------------------------------
 // Hook callbacks
 // ---------------------------------------------------------------------------
@@ -259,24 +290,58 @@ unsafe extern "system" fn keyboard_hook_proc(
     if n_code == HC_ACTION as i32 {
         let kb = unsafe { &*(l_param as *const KBDLLHOOKSTRUCT) };
 
-        // Always let our own synthetic events through to the target app
-        if kb.dwExtraInfo == SYNTHETIC_INPUT_TAG {
+        // Always let our own synthetic events through to the target app,
+        // and the user's typing through to an open fill-in dialog or search
+        // popup
+        let dialog_open = FILL_DIALOG_OPEN.load(Ordering::SeqCst) || search_popup::is_open();
+        if kb.dwExtraInfo == SYNTHETIC_INPUT_TAG || dialog_open {
             return unsafe { CallNextHookEx(ptr::null_mut(), n_code, w_param, l_param) };
         }
 
         let msg_type = w_param as u32;
 
+        // The hotkeys are handled on the tray window (tooltip update, the
+        // search popup) and never reach the focused app.
+        if msg_type == WM_KEYDOWN || msg_type == WM_SYSKEYDOWN {
+            let posted = if unsafe { is_hotkey(&TOGGLE_HOTKEY, kb.vkCode) } {
+                Some((WM_TOGGLE_PAUSE, 0))
+            } else if unsafe { is_hotkey(&SEARCH_HOTKEY, kb.vkCode) } {
+                Some((WM_SHOW_SEARCH, 0))
+            } else if unsafe { is_hotkey(&REPEAT_HOTKEY, kb.vkCode) } {
+                Some((WM_REPEAT_EXPANSION, 0))
+            } else {
+                TRANSFORM_HOTKEYS
+                    .iter()
+                    .position(|hotkey| unsafe { is_hotkey(hotkey, kb.vkCode) })
+                    .map(|index| (WM_TRANSFORM_SELECTION, index))
+            };
+            if let Some((message, index)) = posted {
+                unsafe { PostMessageW(TRAY_HWND.load(Ordering::SeqCst), message, index, 0) };
+                return 1;
+            }
+        }
+
         // When not listening (expansion in progress), buffer real keydown
         // events into the channel for later replay, but block them from
         // reaching the target app.
-        if !GLOBAL_LISTENING.load(Ordering::SeqCst) {
+        if !listening::is_listening() {
+            // Escape cancels the expansion being injected; it isn't replayed
+            if kb.vkCode == VK_ESCAPE as u32 {
+                if msg_type == WM_KEYDOWN {
+                    listening::cancel();
+                }
+                return 1;
+            }
             if msg_type == WM_KEYDOWN as u32 || msg_type == WM_SYSKEYDOWN as u32 {
                 if let Some(sender) = HOOK_SENDER.get() {
-                    let key = vk_to_key_id(kb.vkCode);
+                    let key = KeyId::from_vk(kb.vkCode);
                     let _ = sender.send(HookMessage::KeyDown {
                         key,
-                        vk_code: kb.vkCode,
-                        scan_code: kb.scanCode,
+                        raw: RawKey::Codes { vk_code: kb.vkCode, scan_code: kb.scanCode },
+                        swallowed: true,
+                        time: kb.time,
+                        shortcut: unsafe { is_shortcut(kb.vkCode) },
+                        shift: unsafe { GetAsyncKeyState(VK_SHIFT) } < 0,
                     });
                 }
                 return 1;
@@ -285,12 +350,7 @@ unsafe extern "system" fn keyboard_hook_proc(
 
         if msg_type == WM_KEYDOWN as u32 || msg_type == WM_SYSKEYDOWN as u32 {
             if let Some(sender) = HOOK_SENDER.get() {
-                let key = vk_to_key_id(kb.vkCode);
-                let _ = sender.send(HookMessage::KeyDown {
-                    key,
-                    vk_code: kb.vkCode,
-                    scan_code: kb.scanCode,
-                });
+                let key = KeyId::from_vk(kb.vkCode);
 
                 // Swallow Space and Enter so they never reach the target app.
                 // The processing thread will re-inject them if no expansion
@@ -298,7 +358,30 @@ unsafe extern "system" fn keyboard_hook_proc(
                 // Notepad++/Scintilla processes the character AFTER our
                 // backspaces (TranslateMessage posts WM_CHAR to the end
                 // of the queue, behind already-queued backspace events).
-                if key == KeyId::Space || key == KeyId::Return {
+                // Tab is swallowed the same way for Tab completion or when it
+                // is a delimiter, but never with Ctrl/Alt/Win held so Ctrl+Tab
+                // and Alt+Tab keep working.
+                // Nothing is swallowed while paused.
+                let swallowed = !EXPANSION_PAUSED.load(Ordering::SeqCst) && match key {
+                    KeyId::Space | KeyId::Return => true,
+                    KeyId::Tab => {
+                        SWALLOW_TAB.load(Ordering::SeqCst)
+                            && msg_type == WM_KEYDOWN
+                            && !unsafe { modifier_held() }
+                    }
+                    _ => false,
+                };
+
+                let _ = sender.send(HookMessage::KeyDown {
+                    key,
+                    raw: RawKey::Codes { vk_code: kb.vkCode, scan_code: kb.scanCode },
+                    swallowed,
+                    time: kb.time,
+                    shortcut: unsafe { is_shortcut(kb.vkCode) },
+                    shift: unsafe { GetAsyncKeyState(VK_SHIFT) } < 0,
+                });
+
+                if swallowed {
                     return 1;
                 }
             }
@@ -308,12 +391,64 @@ unsafe extern "system" fn keyboard_hook_proc(
     unsafe { CallNextHookEx(ptr::null_mut(), n_code, w_param, l_param) }
 }
 
+/// `vk` with exactly the modifiers of `hotkey` (packed) held.
+unsafe fn is_hotkey(hotkey: &AtomicU32, vk: u32) -> bool {
+    let bits = hotkey.load(Ordering::SeqCst);
+    if bits == 0 || vk != bits & 0xFFFF {
+        return false;
+    }
+
+    let held = unsafe {
+        (if GetAsyncKeyState(VK_CONTROL) < 0 { HOTKEY_CTRL } else { 0 })
+            | (if GetAsyncKeyState(VK_MENU) < 0 { HOTKEY_ALT } else { 0 })
+            | (if GetAsyncKeyState(VK_SHIFT) < 0 { HOTKEY_SHIFT } else { 0 })
+            | (if GetAsyncKeyState(VK_LWIN) < 0 || GetAsyncKeyState(VK_RWIN) < 0 { HOTKEY_WIN } else { 0 })
+    };
+    held == bits & !0xFFFF
+}
+
+/// Wait until Ctrl, Alt, and Win are up, so text sent after a hotkey isn't
+/// taken as shortcuts. Gives up after `limit`.
+pub fn wait_for_modifiers_released(limit: Duration) {
+    let start = Instant::now();
+    while unsafe { modifier_held() } && start.elapsed() < limit {
+        thread::sleep(Duration::from_millis(10));
+    }
+}
+
+/// Like `wait_for_modifiers_released`, but Shift too, for sending a chord
+/// of our own: Ctrl+Shift+C is not copy everywhere.
+pub fn wait_for_all_modifiers_released(limit: Duration) {
+    let start = Instant::now();
+    while unsafe { modifier_held() || GetAsyncKeyState(VK_SHIFT) < 0 } && start.elapsed() < limit {
+        thread::sleep(Duration::from_millis(10));
+    }
+}
+
+/// Ctrl, Alt, or Win is currently held down.
+unsafe fn modifier_held() -> bool {
+    unsafe {
+        GetAsyncKeyState(VK_CONTROL) < 0
+            || GetAsyncKeyState(VK_MENU) < 0
+            || GetAsyncKeyState(VK_LWIN) < 0
+            || GetAsyncKeyState(VK_RWIN) < 0
+    }
+}
+
+/// A non-modifier key pressed with Ctrl, Alt, or Win held. Shift alone
+/// doesn't count: it types capitals and symbols.
+unsafe fn is_shortcut(vk: u32) -> bool {
+    let modifier_key = matches!(vk as i32, VK_SHIFT | VK_CONTROL | VK_MENU | VK_LWIN | VK_RWIN)
+        || (0xA0..=0xA5).contains(&vk); // left/right Shift, Ctrl, Alt
+    !modifier_key && unsafe { modifier_held() }
+}
+
 unsafe extern "system" fn mouse_hook_proc(
     n_code: i32,
     w_param: WPARAM,
     l_param: LPARAM,
 ) -> LRESULT {
-    if n_code == HC_ACTION as i32 && GLOBAL_LISTENING.load(Ordering::SeqCst) {
+    if n_code == HC_ACTION as i32 && listening::is_listening() {
         let button = match w_param as u32 {
             WM_LBUTTONDOWN => Some(MouseButton::Left),
             WM_RBUTTONDOWN => Some(MouseButton::Right),
@@ -350,6 +485,43 @@ unsafe extern "system" fn window_proc(
                     GetCursorPos(&mut pt);
 
                     let hmenu = CreatePopupMenu();
+                    let paused = EXPANSION_PAUSED.load(Ordering::SeqCst);
+                    let pause_label = encode_wide("Pause expansion");
+                    let pause_flags = if paused { MF_STRING | MF_CHECKED } else { MF_STRING };
+                    AppendMenuW(hmenu, pause_flags, IDM_PAUSE, pause_label.as_ptr());
+                    let groups_menu = CreatePopupMenu();
+                    for (index, (group, enabled)) in GROUPS.lock().unwrap().iter().enumerate() {
+                        let group_label = encode_wide(&group.replace('&', "&&"));
+                        let group_flags = if *enabled { MF_STRING | MF_CHECKED } else { MF_STRING };
+                        AppendMenuW(groups_menu, group_flags, IDM_GROUP_FIRST + index, group_label.as_ptr());
+                    }
+                    let groups_label = encode_wide("Groups");
+                    AppendMenuW(hmenu, MF_POPUP, groups_menu as usize, groups_label.as_ptr());
+                    let history_menu = CreatePopupMenu();
+                    let history = HISTORY.lock().unwrap().clone();
+                    for (index, line) in history.iter().enumerate() {
+                        let line_label = encode_wide(&line.replace('&', "&&"));
+                        AppendMenuW(history_menu, MF_STRING, IDM_HISTORY_FIRST + index, line_label.as_ptr());
+                    }
+                    if history.is_empty() {
+                        let empty_label = encode_wide("(nothing expanded yet)");
+                        AppendMenuW(history_menu, MF_STRING | MF_GRAYED, 0, empty_label.as_ptr());
+                    }
+                    AppendMenuW(history_menu, MF_SEPARATOR, 0, ptr::null());
+                    let clear_label = encode_wide("Clear history");
+                    let clear_flags = if history.is_empty() { MF_STRING | MF_GRAYED } else { MF_STRING };
+                    AppendMenuW(history_menu, clear_flags, IDM_CLEAR_HISTORY, clear_label.as_ptr());
+                    let history_label = encode_wide("History");
+                    AppendMenuW(hmenu, MF_POPUP, history_menu as usize, history_label.as_ptr());
+                    let reload_label = encode_wide("Reload config");
+                    AppendMenuW(hmenu, MF_STRING, IDM_RELOAD, reload_label.as_ptr());
+                    let counters_label = encode_wide("Reset counters...");
+                    AppendMenuW(hmenu, MF_STRING, IDM_RESET_COUNTERS, counters_label.as_ptr());
+                    let autostart_label = encode_wide("Start with Windows");
+                    let autostart_flags =
+                        if autostart::registered_command().is_some() { MF_STRING | MF_CHECKED } else { MF_STRING };
+                    AppendMenuW(hmenu, autostart_flags, IDM_AUTOSTART, autostart_label.as_ptr());
+                    AppendMenuW(hmenu, MF_SEPARATOR, 0, ptr::null());
                     let label = encode_wide("Close");
                     AppendMenuW(hmenu, MF_STRING, IDM_EXIT, label.as_ptr());
 
@@ -363,14 +535,67 @@ unsafe extern "system" fn window_proc(
         }
         WM_COMMAND => {
             let cmd = (w_param as u32) & 0xFFFF;
-            if cmd as usize == IDM_EXIT {
-                unsafe {
+            match cmd as usize {
+                IDM_EXIT => unsafe {
                     remove_tray_icon(hwnd);
                     PostQuitMessage(0);
+                },
+                IDM_PAUSE => unsafe { toggle_paused(hwnd) },
+                IDM_RELOAD => request_reload(),
+                // Its own thread: a message box here would stall this thread's
+                // message loop, and with it the hooks
+                IDM_RESET_COUNTERS => {
+                    thread::spawn(confirm_reset_counters);
+                }
+                IDM_AUTOSTART => {
+                    thread::spawn(toggle_autostart);
+                }
+                IDM_CLEAR_HISTORY => send_to_processing(HookMessage::ClearHistory),
+                id if id >= IDM_HISTORY_FIRST => {
+                    send_to_processing(HookMessage::RepeatExpansion { index: id - IDM_HISTORY_FIRST, refocus: true })
                 }
+                id if id >= IDM_GROUP_FIRST => request_group_toggle(id - IDM_GROUP_FIRST),
+                _ => {}
             }
             0
         }
+        WM_TOGGLE_PAUSE => {
+            cancel_menu_activation(&TOGGLE_HOTKEY);
+            unsafe { toggle_paused(hwnd) };
+            0
+        }
+        WM_REPEAT_EXPANSION => {
+            cancel_menu_activation(&REPEAT_HOTKEY);
+            send_to_processing(HookMessage::RepeatExpansion { index: 0, refocus: false });
+            0
+        }
+        WM_TRANSFORM_SELECTION => {
+            if let Some(hotkey) = TRANSFORM_HOTKEYS.get(w_param) {
+                cancel_menu_activation(hotkey);
+                send_to_processing(HookMessage::TransformSelection { index: w_param });
+            }
+            0
+        }
+        WM_SHOW_SEARCH => {
+            unsafe { search_popup::show() };
+            0
+        }
+        WM_REFRESH_TIP => {
+            unsafe { update_tray_state(hwnd) };
+            0
+        }
+        WM_SHOW_NOTICES => {
+            let notices: Vec<Notice> = NOTICES.lock().unwrap().drain(..).collect();
+            for notice in notices {
+                unsafe { show_balloon(hwnd, &notice) };
+            }
+            0
+        }
+        WM_RELOAD_REQUEST => {
+            crate::log_info!("Launched again; reloading instead");
+            request_reload();
+            0
+        }
         WM_DESTROY => {
             unsafe {
                 remove_tray_icon(hwnd);
@@ -382,6 +607,51 @@ unsafe extern "system" fn window_proc(
     }
 }
 
+/// A hotkey's key was swallowed, so a held Alt or Win would be released
+/// with nothing in between and open the app's menu bar or the Start menu.
+/// An unassigned key in between prevents that.
+fn cancel_menu_activation(hotkey: &AtomicU32) {
+    if hotkey.load(Ordering::SeqCst) & (HOTKEY_ALT | HOTKEY_WIN) != 0 {
+        let _ = windows_input::send_key_tap(0xE8, 0); // unassigned VK
+    }
+}
+
+/// The processing thread owns the expansion state; asking it over the
+/// channel keeps the hook thread off that mutex.
+fn request_reload() {
+    send_to_processing(HookMessage::ReloadConfig);
+}
+
+fn send_to_processing(message: HookMessage) {
+    if let Some(sender) = HOOK_SENDER.get() {
+        let _ = sender.send(message);
+    }
+}
+
+/// Switch the `index`th group of the Groups menu off or on. The processing
+/// thread owns the disabled set and saves it.
+fn request_group_toggle(index: usize) {
+    let group = GROUPS.lock().unwrap().get(index).map(|(group, _)| group.clone());
+    if let Some(group) = group {
+        send_to_processing(HookMessage::ToggleGroup { group });
+    }
+}
+
+/// Insert the snippet picked in the search popup at the caret.
+pub fn request_insert(trigger: String) {
+    send_to_processing(HookMessage::InsertSnippet { trigger });
+}
+
+/// Ask an already running instance to reload its config. `false` if its
+/// window can't be found.
+pub fn ask_running_instance_to_reload() -> bool {
+    let class_name = encode_wide(TRAY_CLASS_NAME);
+    unsafe {
+        let hwnd = FindWindowExW(HWND_MESSAGE, ptr::null_mut(), class_name.as_ptr(), ptr::null());
+        !hwnd.is_null() && PostMessageW(hwnd, WM_RELOAD_REQUEST, 0, 0) != 0
+    }
+}
+
 unsafe fn add_tray_icon(hwnd: HWND) {
     unsafe {
         let mut nid: NOTIFYICONDATAW = mem::zeroed();
@@ -390,16 +660,124 @@ unsafe fn add_tray_icon(hwnd: HWND) {
         nid.uID = 1;
         nid.uFlags = NIF_ICON | NIF_MESSAGE | NIF_TIP;
         nid.uCallbackMessage = WM_TRAYICON;
-        nid.hIcon = LoadIconW(GetModuleHandleW(ptr::null()), 1 as *const u16);
-
-        let tip = encode_wide("Text Expander");
-        let len = tip.len().min(nid.szTip.len());
-        nid.szTip[..len].copy_from_slice(&tip[..len]);
+        nid.hIcon = tray_icon();
+        set_tray_tip(&mut nid);
 
         Shell_NotifyIconW(NIM_ADD, &mut nid);
     }
 }
 
+/// Flip the user's pause state (tray menu or hotkey).
+unsafe fn toggle_paused(hwnd: HWND) {
+    let paused = !EXPANSION_PAUSED.load(Ordering::SeqCst);
+    EXPANSION_PAUSED.store(paused, Ordering::SeqCst);
+    crate::debug_println!("Expansion {}", if paused { "paused" } else { "resumed" });
+    crate::log_info!("Expansion {}", if paused { "paused" } else { "resumed" });
+    unsafe { update_tray_state(hwnd) };
+}
+
+/// Record whether a remote session has focus (called by the processing
+/// thread on each key), updating the tooltip when that changes.
+pub fn set_remote_focused(focused: bool) {
+    if REMOTE_FOCUSED.swap(focused, Ordering::SeqCst) == focused {
+        return;
+    }
+    crate::log_info!("Expansion {} (remote session focus)", if focused { "suspended" } else { "resumed" });
+    unsafe { PostMessageW(TRAY_HWND.load(Ordering::SeqCst), WM_REFRESH_TIP, 0, 0) };
+}
+
+/// Refresh the icon and tooltip after the paused or remote state changes.
+unsafe fn update_tray_state(hwnd: HWND) {
+    unsafe {
+        let mut nid: NOTIFYICONDATAW = mem::zeroed();
+        nid.cbSize = mem::size_of::<NOTIFYICONDATAW>() as u32;
+        nid.hWnd = hwnd;
+        nid.uID = 1;
+        nid.uFlags = NIF_ICON | NIF_TIP;
+        nid.hIcon = tray_icon();
+        set_tray_tip(&mut nid);
+
+        Shell_NotifyIconW(NIM_MODIFY, &mut nid);
+    }
+}
+
+/// The app icon, or its greyed copy (resource 2, see build.rs) while paused
+/// or suspended. `LoadIconW` shares the handle, so it is never freed.
+fn tray_icon() -> HICON {
+    let idle = EXPANSION_PAUSED.load(Ordering::SeqCst) || REMOTE_FOCUSED.load(Ordering::SeqCst);
+    let id = if idle { 2 } else { 1 };
+    unsafe { LoadIconW(GetModuleHandleW(ptr::null()), id as *const u16) }
+}
+
+/// Tooltip text: the paused or suspended state and which expansions.toml
+/// is live. szTip truncates long paths.
+fn set_tray_tip(nid: &mut NOTIFYICONDATAW) {
+    let title = if EXPANSION_PAUSED.load(Ordering::SeqCst) {
+        "Text Expander (paused)"
+    } else if REMOTE_FOCUSED.load(Ordering::SeqCst) {
+        "Text Expander suspended (remote session focused)"
+    } else {
+        "Text Expander"
+    };
+    let tip = match crate::config::config_path() {
+        Some(path) => format!("{}\n{}", title, path.display()),
+        None => title.to_string(),
+    };
+    copy_truncated(&mut nid.szTip, &tip);
+}
+
+/// A balloon waiting for the tray window's thread, which owns the icon.
+struct Notice {
+    title: &'static str,
+    text: String,
+    error: bool,
+}
+
+static NOTICES: Mutex<Vec<Notice>> = Mutex::new(Vec::new());
+
+/// Show a quiet balloon from the tray icon (`notify_on_expand`). Safe to
+/// call from any thread.
+pub fn notify(text: &str) {
+    queue_notice(Notice { title: "Text Expander", text: text.to_string(), error: false });
+}
+
+/// Show an error balloon from the tray icon. Safe to call from any thread.
+pub fn notify_error(text: &str) {
+    queue_notice(Notice { title: "Text Expander: expansion failed", text: text.to_string(), error: true });
+}
+
+fn queue_notice(notice: Notice) {
+    let hwnd = TRAY_HWND.load(Ordering::SeqCst);
+    if hwnd.is_null() {
+        return;
+    }
+    NOTICES.lock().unwrap().push(notice);
+    unsafe { PostMessageW(hwnd, WM_SHOW_NOTICES, 0, 0) };
+}
+
+unsafe fn show_balloon(hwnd: HWND, notice: &Notice) {
+    unsafe {
+        let mut nid: NOTIFYICONDATAW = mem::zeroed();
+        nid.cbSize = mem::size_of::<NOTIFYICONDATAW>() as u32;
+        nid.hWnd = hwnd;
+        nid.uID = 1;
+        nid.uFlags = NIF_INFO;
+        nid.dwInfoFlags = if notice.error { NIIF_ERROR } else { NIIF_INFO | NIIF_NOSOUND };
+        copy_truncated(&mut nid.szInfoTitle, notice.title);
+        copy_truncated(&mut nid.szInfo, &notice.text);
+
+        Shell_NotifyIconW(NIM_MODIFY, &mut nid);
+    }
+}
+
+/// Copy `text` into a fixed-size, NUL-terminated `NOTIFYICONDATAW` field,
+/// cut to fit.
+fn copy_truncated(field: &mut [u16], text: &str) {
+    let text = encode_wide(text);
+    let len = text.len().min(field.len() - 1);
+    field[..len].copy_from_slice(&text[..len]);
+}
+
 unsafe fn remove_tray_icon(hwnd: HWND) {
     unsafe {
         let mut nid: NOTIFYICONDATAW = mem::zeroed();
@@ -414,7 +792,91 @@ unsafe fn remove_tray_icon(hwnd: HWND) {
 // Hook installation + message pump
 // ---------------------------------------------------------------------------
 
-pub fn install_hooks_and_run(sender: Sender<HookMessage>) -> Result<(), Box<dyn std::error::Error>> {
+/// Report a fatal error. Release builds have no console, so println alone
+/// would leave the user with a tray icon that never appears.
+/// Show the `{{counter:...}}` values and offer to start them all over.
+fn confirm_reset_counters() {
+    let values = counters::snapshot();
+    if values.is_empty() {
+        let text = encode_wide("No counter has been used yet.");
+        let caption = encode_wide("Text Expander");
+        unsafe { MessageBoxW(ptr::null_mut(), text.as_ptr(), caption.as_ptr(), MB_ICONINFORMATION | MB_OK) };
+        return;
+    }
+
+    let listing: Vec<String> = values.iter().map(|(name, value)| format!("{}: {}", name, value)).collect();
+    let text = encode_wide(&format!("Last values:\n{}\n\nReset every counter?", listing.join("\n")));
+    let caption = encode_wide("Text Expander");
+    let answer = unsafe { MessageBoxW(ptr::null_mut(), text.as_ptr(), caption.as_ptr(), MB_ICONQUESTION | MB_YESNO) };
+    if answer != IDYES {
+        return;
+    }
+    if let Err(e) = counters::reset_all() {
+        show_error_box(&format!("Could not reset counters: {}", e));
+    }
+}
+
+/// Tray "Start with Windows": unregister if registered, else register the
+/// running exe.
+fn toggle_autostart() {
+    let result = match autostart::registered_command() {
+        Some(_) => autostart::disable(),
+        None => autostart::enable(),
+    };
+    if let Err(e) = result {
+        show_error_box(&format!("Could not change Start with Windows: {}", e));
+    }
+}
+
+/// If Start with Windows launches some other copy or config (the exe was
+/// moved, say), offer to point it at this one. Blocks on the question, so
+/// call it from its own thread.
+pub fn offer_autostart_update() {
+    let (Some(registered), Ok(current)) = (autostart::registered_command(), autostart::current_command()) else {
+        return;
+    };
+    if registered.eq_ignore_ascii_case(&current) {
+        return;
+    }
+    let text = encode_wide(&format!(
+        "Start with Windows runs:\n{}\n\nbut this copy is:\n{}\n\nUpdate it to start this copy?",
+        registered, current
+    ));
+    let caption = encode_wide("Text Expander");
+    let answer = unsafe { MessageBoxW(ptr::null_mut(), text.as_ptr(), caption.as_ptr(), MB_ICONQUESTION | MB_YESNO) };
+    if answer != IDYES {
+        return;
+    }
+    if let Err(e) = autostart::enable() {
+        show_error_box(&format!("Could not update Start with Windows: {}", e));
+    }
+}
+
+pub fn show_error_box(text: &str) {
+    println!("Error: {}", text);
+    crate::log_error!("{}", text);
+    let text = encode_wide(text);
+    let caption = encode_wide("Text Expander");
+    unsafe {
+        MessageBoxW(ptr::null_mut(), text.as_ptr(), caption.as_ptr(), MB_ICONERROR | MB_OK);
+    }
+}
+
+/// Like `show_error_box`, for problems that didn't stop anything. Not
+/// printed; callers log the details themselves.
+pub fn show_warning_box(text: &str) {
+    let text = encode_wide(text);
+    let caption = encode_wide("Text Expander");
+    unsafe {
+        MessageBoxW(ptr::null_mut(), text.as_ptr(), caption.as_ptr(), MB_ICONWARNING | MB_OK);
+    }
+}
+
+/// Show the tray icon and run the message loop until Exit. With `hooks`, also
+/// install the low-level hooks, which feed `sender` every key and click and
+/// watch for the hotkeys; without them (the rdev backend), `sender` only gets
+/// what the tray menu asks for.
+pub fn run_tray(sender: Sender<HookMessage>, hooks: bool) -> Result<(), Box<dyn std::error::Error>> {
     HOOK_SENDER
         .set(sender)
         .map_err(|_| "HOOK_SENDER already initialized")?;
@@ -423,7 +885,7 @@ pub fn install_hooks_and_run(sender: Sender<HookMessage>) -> Result<(), Box<dyn
         let h_instance = GetModuleHandleW(ptr::null());
 
         // Register window class for tray icon message handling
-        let class_name = encode_wide("TextExpanderTrayClass");
+        let class_name = encode_wide(TRAY_CLASS_NAME);
         let wc = WNDCLASSW {
             lpfnWndProc: Some(window_proc),
             hInstance: h_instance,
@@ -439,7 +901,7 @@ pub fn install_hooks_and_run(sender: Sender<HookMessage>) -> Result<(), Box<dyn
             encode_wide("TextExpander").as_ptr(),
             0,
             0, 0, 0, 0,
-            -3isize as HWND, // HWND_MESSAGE
+            HWND_MESSAGE,
             ptr::null_mut(),
             h_instance,
             ptr::null_mut(),
@@ -449,38 +911,57 @@ pub fn install_hooks_and_run(sender: Sender<HookMessage>) -> Result<(), Box<dyn
             return Err("Failed to create tray message window".into());
         }
 
+        TRAY_HWND.store(hwnd, Ordering::SeqCst);
         add_tray_icon(hwnd);
 
-        let kb_hook: HHOOK =
-            SetWindowsHookExW(WH_KEYBOARD_LL, Some(keyboard_hook_proc), h_instance, 0);
-        if kb_hook.is_null() {
-            remove_tray_icon(hwnd);
-            DestroyWindow(hwnd);
-            return Err("Failed to install keyboard hook".into());
-        }
-
-        let mouse_hook: HHOOK =
-            SetWindowsHookExW(WH_MOUSE_LL, Some(mouse_hook_proc), h_instance, 0);
-        if mouse_hook.is_null() {
-            UnhookWindowsHookEx(kb_hook);
-            remove_tray_icon(hwnd);
-            DestroyWindow(hwnd);
-            return Err("Failed to install mouse hook".into());
-        }
-
-        crate::debug_println!("Hooks installed. Listening...");
+        let installed = if hooks {
+            match install_hooks(h_instance) {
+                Ok(installed) => Some(installed),
+                Err(error) => {
+                    remove_tray_icon(hwnd);
+                    DestroyWindow(hwnd);
+                    return Err(error);
+                }
+            }
+        } else {
+            None
+        };
 
         // Standard Windows message pump — handles both hooks and tray icon messages
         let mut msg: MSG = mem::zeroed();
         while GetMessageW(&mut msg, ptr::null_mut(), 0, 0) > 0 {
+            if search_popup::handle_key(&msg) {
+                continue;
+            }
             TranslateMessage(&msg);
             DispatchMessageW(&msg);
         }
 
-        UnhookWindowsHookEx(kb_hook);
-        UnhookWindowsHookEx(mouse_hook);
+        if let Some((kb_hook, mouse_hook)) = installed {
+            UnhookWindowsHookEx(kb_hook);
+            UnhookWindowsHookEx(mouse_hook);
+        }
         DestroyWindow(hwnd);
     }
 
     Ok(())
 }
+
+/// The keyboard and mouse hooks, for `run_tray`.
+fn install_hooks(h_instance: HINSTANCE) -> Result<(HHOOK, HHOOK), Box<dyn std::error::Error>> {
+    let kb_hook: HHOOK =
+        unsafe { SetWindowsHookExW(WH_KEYBOARD_LL, Some(keyboard_hook_proc), h_instance, 0) };
+    if kb_hook.is_null() {
+        return Err("Failed to install keyboard hook".into());
+    }
+
+    let mouse_hook: HHOOK =
+        unsafe { SetWindowsHookExW(WH_MOUSE_LL, Some(mouse_hook_proc), h_instance, 0) };
+    if mouse_hook.is_null() {
+        unsafe { UnhookWindowsHookEx(kb_hook) };
+        return Err("Failed to install mouse hook".into());
+    }
+
+    crate::debug_println!("Hooks installed. Listening...");
+    Ok((kb_hook, mouse_hook))
+}
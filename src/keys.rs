@@ -0,0 +1,265 @@
+// src/keys.rs
+//
+// `KeyId`: the keys every input backend reports and can send, named as rdev
+// names them. The Windows backend, and the hotkeys it watches for, need the
+// virtual-key code of each (`from_vk`/`vk`); rdev's `Key` maps one to one.
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum KeyId {
+    Space,
+    Return,
+    Backspace,
+    Tab,
+    Escape,
+    Delete,
+    Insert,
+    LeftArrow,
+    RightArrow,
+    UpArrow,
+    DownArrow,
+    Home,
+    End,
+    PageUp,
+    PageDown,
+    ShiftLeft,
+    ShiftRight,
+    ControlLeft,
+    ControlRight,
+    Alt,
+    AltGr,
+    MetaLeft,
+    MetaRight,
+    KeyA,
+    KeyB,
+    KeyC,
+    KeyD,
+    KeyE,
+    KeyF,
+    KeyG,
+    KeyH,
+    KeyI,
+    KeyJ,
+    KeyK,
+    KeyL,
+    KeyM,
+    KeyN,
+    KeyO,
+    KeyP,
+    KeyQ,
+    KeyR,
+    KeyS,
+    KeyT,
+    KeyU,
+    KeyV,
+    KeyW,
+    KeyX,
+    KeyY,
+    KeyZ,
+    Num0,
+    Num1,
+    Num2,
+    Num3,
+    Num4,
+    Num5,
+    Num6,
+    Num7,
+    Num8,
+    Num9,
+    Minus,
+    Equal,
+    LeftBracket,
+    RightBracket,
+    Quote,
+    Comma,
+    Dot,
+    Slash,
+    SemiColon,
+    BackSlash,
+    BackQuote,
+    /// VK_NUMPAD0–9, i.e. NumLock on. With NumLock off the keypad sends
+    /// the navigation keys (Home, End, arrows...) instead.
+    Numpad0,
+    Numpad1,
+    Numpad2,
+    Numpad3,
+    Numpad4,
+    Numpad5,
+    Numpad6,
+    Numpad7,
+    Numpad8,
+    Numpad9,
+    NumpadDecimal,
+    NumpadDivide,
+    NumpadMultiply,
+    NumpadSubtract,
+    NumpadAdd,
+    F1,
+    F2,
+    F3,
+    F4,
+    F5,
+    F6,
+    F7,
+    F8,
+    F9,
+    F10,
+    F11,
+    F12,
+    /// Any other key, by the backend's own code for it (a virtual-key code
+    /// on Windows, an X keycode on Linux), so it can still be re-sent.
+    Unknown(u32),
+}
+
+/// Every named key with its virtual-key code, for `from_vk` and `vk`.
+const VIRTUAL_KEYS: [(KeyId, u16); 97] = [
+    (KeyId::Space, 0x20),
+    (KeyId::Return, 0x0D),
+    (KeyId::Backspace, 0x08),
+    (KeyId::Tab, 0x09),
+    (KeyId::Escape, 0x1B),
+    (KeyId::Delete, 0x2E),
+    (KeyId::Insert, 0x2D),
+    (KeyId::LeftArrow, 0x25),
+    (KeyId::RightArrow, 0x27),
+    (KeyId::UpArrow, 0x26),
+    (KeyId::DownArrow, 0x28),
+    (KeyId::Home, 0x24),
+    (KeyId::End, 0x23),
+    (KeyId::PageUp, 0x21),
+    (KeyId::PageDown, 0x22),
+    (KeyId::ShiftLeft, 0xA0),
+    (KeyId::ShiftRight, 0xA1),
+    (KeyId::ControlLeft, 0xA2),
+    (KeyId::ControlRight, 0xA3),
+    (KeyId::Alt, 0xA4),
+    (KeyId::AltGr, 0xA5),
+    (KeyId::MetaLeft, 0x5B),
+    (KeyId::MetaRight, 0x5C),
+    (KeyId::KeyA, 0x41),
+    (KeyId::KeyB, 0x42),
+    (KeyId::KeyC, 0x43),
+    (KeyId::KeyD, 0x44),
+    (KeyId::KeyE, 0x45),
+    (KeyId::KeyF, 0x46),
+    (KeyId::KeyG, 0x47),
+    (KeyId::KeyH, 0x48),
+    (KeyId::KeyI, 0x49),
+    (KeyId::KeyJ, 0x4A),
+    (KeyId::KeyK, 0x4B),
+    (KeyId::KeyL, 0x4C),
+    (KeyId::KeyM, 0x4D),
+    (KeyId::KeyN, 0x4E),
+    (KeyId::KeyO, 0x4F),
+    (KeyId::KeyP, 0x50),
+    (KeyId::KeyQ, 0x51),
+    (KeyId::KeyR, 0x52),
+    (KeyId::KeyS, 0x53),
+    (KeyId::KeyT, 0x54),
+    (KeyId::KeyU, 0x55),
+    (KeyId::KeyV, 0x56),
+    (KeyId::KeyW, 0x57),
+    (KeyId::KeyX, 0x58),
+    (KeyId::KeyY, 0x59),
+    (KeyId::KeyZ, 0x5A),
+    (KeyId::Num0, 0x30),
+    (KeyId::Num1, 0x31),
+    (KeyId::Num2, 0x32),
+    (KeyId::Num3, 0x33),
+    (KeyId::Num4, 0x34),
+    (KeyId::Num5, 0x35),
+    (KeyId::Num6, 0x36),
+    (KeyId::Num7, 0x37),
+    (KeyId::Num8, 0x38),
+    (KeyId::Num9, 0x39),
+    (KeyId::Minus, 0xBD),        // VK_OEM_MINUS
+    (KeyId::Equal, 0xBB),        // VK_OEM_PLUS (=/+ key)
+    (KeyId::LeftBracket, 0xDB),  // VK_OEM_4
+    (KeyId::RightBracket, 0xDD), // VK_OEM_6
+    (KeyId::Quote, 0xDE),        // VK_OEM_7
+    (KeyId::Comma, 0xBC),        // VK_OEM_COMMA
+    (KeyId::Dot, 0xBE),          // VK_OEM_PERIOD
+    (KeyId::Slash, 0xBF),        // VK_OEM_2
+    (KeyId::SemiColon, 0xBA),    // VK_OEM_1
+    (KeyId::BackSlash, 0xDC),    // VK_OEM_5
+    (KeyId::BackQuote, 0xC0),    // VK_OEM_3
+    (KeyId::Numpad0, 0x60),
+    (KeyId::Numpad1, 0x61),
+    (KeyId::Numpad2, 0x62),
+    (KeyId::Numpad3, 0x63),
+    (KeyId::Numpad4, 0x64),
+    (KeyId::Numpad5, 0x65),
+    (KeyId::Numpad6, 0x66),
+    (KeyId::Numpad7, 0x67),
+    (KeyId::Numpad8, 0x68),
+    (KeyId::Numpad9, 0x69),
+    (KeyId::NumpadDecimal, 0x6E),
+    (KeyId::NumpadDivide, 0x6F),
+    (KeyId::NumpadMultiply, 0x6A),
+    (KeyId::NumpadSubtract, 0x6D),
+    (KeyId::NumpadAdd, 0x6B),
+    (KeyId::F1, 0x70),
+    (KeyId::F2, 0x71),
+    (KeyId::F3, 0x72),
+    (KeyId::F4, 0x73),
+    (KeyId::F5, 0x74),
+    (KeyId::F6, 0x75),
+    (KeyId::F7, 0x76),
+    (KeyId::F8, 0x77),
+    (KeyId::F9, 0x78),
+    (KeyId::F10, 0x79),
+    (KeyId::F11, 0x7A),
+    (KeyId::F12, 0x7B),
+];
+
+impl KeyId {
+    /// The key for a Windows virtual-key code. Numpad Enter is VK_RETURN
+    /// with the extended flag, so it comes out as `Return`.
+    pub fn from_vk(vk: u32) -> KeyId {
+        VIRTUAL_KEYS
+            .iter()
+            .find(|(_, code)| u32::from(*code) == vk)
+            .map_or(KeyId::Unknown(vk), |(key, _)| *key)
+    }
+
+    /// The key's Windows virtual-key code; `Unknown` keys keep theirs.
+    pub fn vk(self) -> u16 {
+        match self {
+            KeyId::Unknown(code) => code as u16,
+            key => VIRTUAL_KEYS.iter().find(|(named, _)| *named == key).map_or(0, |(_, code)| *code),
+        }
+    }
+
+    /// Shift, Ctrl, Alt, AltGr, or Win/Cmd.
+    pub fn is_modifier(self) -> bool {
+        matches!(
+            self,
+            KeyId::ShiftLeft
+                | KeyId::ShiftRight
+                | KeyId::ControlLeft
+                | KeyId::ControlRight
+                | KeyId::Alt
+                | KeyId::AltGr
+                | KeyId::MetaLeft
+                | KeyId::MetaRight
+        )
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn every_key_round_trips_through_its_virtual_key_code() {
+        for (key, vk) in VIRTUAL_KEYS {
+            assert_eq!(key.vk(), vk, "{:?}", key);
+            assert_eq!(KeyId::from_vk(u32::from(vk)), key, "{:#04X}", vk);
+        }
+        let codes: std::collections::HashSet<u16> = VIRTUAL_KEYS.iter().map(|(_, vk)| *vk).collect();
+        assert_eq!(codes.len(), VIRTUAL_KEYS.len(), "no code is listed twice");
+
+        // Keys without a name keep their code, e.g. for replaying them
+        assert_eq!(KeyId::from_vk(0xE8), KeyId::Unknown(0xE8));
+        assert_eq!(KeyId::Unknown(0xE8).vk(), 0xE8);
+    }
+}
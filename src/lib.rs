@@ -0,0 +1,43 @@
+// src/lib.rs
+//
+// The platform-independent half of the expander: expansions.toml, snippet
+// text processing, and the `engine` state machine. The binary (main.rs plus
+// the hook, input, clipboard, and foreground-window modules) feeds it hook
+// events and carries out the actions it returns.
+
+/// A macro that functions like `println!`, but only compiles in debug builds.
+#[macro_export]
+macro_rules! debug_println {
+    ($($arg:tt)*) => {
+        #[cfg(debug_assertions)]
+        {
+            print!("[DEBUG] ");
+            println!($($arg)*);
+        }
+        #[cfg(not(debug_assertions))]
+        {
+        }
+    };
+}
+
+pub mod cli;
+pub mod clipboard_burst;
+pub mod config;
+pub mod counters;
+pub mod dates;
+pub mod dead_keys;
+pub mod engine;
+pub mod groups;
+pub mod keys;
+pub mod listening;
+pub mod espanso;
+pub mod logging;
+pub mod macros;
+pub mod patterns;
+pub mod search;
+pub mod simulate;
+pub mod stats;
+pub mod template;
+pub mod transforms;
+pub mod trie;
+pub mod validate;
@@ -0,0 +1,170 @@
+// src/listening.rs
+//
+// Whether the keyboard hook hands keys to the engine, or holds them for
+// replay because an expansion is being injected. Listening only goes off
+// through a `ListeningOff` guard, so an injection that fails or panics still
+// turns it back on; with it stuck off the hook would swallow every key. A
+// watchdog turns it back on if it stays off too long regardless.
+//
+// Escape pressed while listening is off cancels the injection in progress:
+// the input loops check `cancelled` between chunks and stop there.
+//
+// Guards nest: replaying keys buffered during one injection can start
+// another. Only the outermost guard clears `cancelled`, and listening comes
+// back on when the last one is dropped.
+
+use std::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
+use std::sync::Mutex;
+use std::thread;
+use std::time::{Duration, Instant};
+
+/// Longest an injection may keep listening off, outside `expected_wait`.
+pub const WATCHDOG_TIMEOUT: Duration = Duration::from_secs(3);
+
+const WATCHDOG_POLL: Duration = Duration::from_millis(250);
+
+static LISTENING: AtomicBool = AtomicBool::new(true);
+
+/// When listening last went off.
+static OFF_SINCE: Mutex<Option<Instant>> = Mutex::new(None);
+
+/// `expected_wait` calls in progress.
+static EXPECTED_WAITS: AtomicUsize = AtomicUsize::new(0);
+
+/// Set by Escape during an injection; cleared when the next one starts.
+static CANCELLED: AtomicBool = AtomicBool::new(false);
+
+/// `ListeningOff` guards alive, outermost included.
+static DEPTH: AtomicUsize = AtomicUsize::new(0);
+
+pub fn is_listening() -> bool {
+    LISTENING.load(Ordering::SeqCst)
+}
+
+/// Listening is off for as long as this, or any guard around it, lives.
+pub struct ListeningOff(());
+
+impl ListeningOff {
+    #[allow(clippy::new_without_default)]
+    pub fn new() -> Self {
+        if DEPTH.fetch_add(1, Ordering::SeqCst) == 0 {
+            *OFF_SINCE.lock().unwrap() = Some(Instant::now());
+            CANCELLED.store(false, Ordering::SeqCst);
+        }
+        LISTENING.store(false, Ordering::SeqCst);
+        ListeningOff(())
+    }
+}
+
+impl Drop for ListeningOff {
+    fn drop(&mut self) {
+        // Already zero if the watchdog stepped in while this was alive
+        let outermost = DEPTH.fetch_update(Ordering::SeqCst, Ordering::SeqCst, |depth| depth.checked_sub(1)) == Ok(1);
+        if outermost {
+            LISTENING.store(true, Ordering::SeqCst);
+        }
+    }
+}
+
+/// Cancel the injection in progress, if there is one (Escape).
+pub fn cancel() {
+    if !is_listening() {
+        CANCELLED.store(true, Ordering::SeqCst);
+    }
+}
+
+/// Whether Escape has cancelled the current injection.
+pub fn cancelled() -> bool {
+    CANCELLED.load(Ordering::SeqCst)
+}
+
+/// Run `wait`, which may rightly keep listening off past `WATCHDOG_TIMEOUT`
+/// (a fill-in dialog, a macro's `sleep`), without the watchdog stepping in.
+/// The timeout starts over once it returns.
+pub fn expected_wait<T>(wait: impl FnOnce() -> T) -> T {
+    struct Waiting;
+    impl Drop for Waiting {
+        fn drop(&mut self) {
+            *OFF_SINCE.lock().unwrap() = Some(Instant::now());
+            EXPECTED_WAITS.fetch_sub(1, Ordering::SeqCst);
+        }
+    }
+
+    EXPECTED_WAITS.fetch_add(1, Ordering::SeqCst);
+    let _waiting = Waiting;
+    wait()
+}
+
+/// Check every `WATCHDOG_POLL` that listening hasn't been off for longer
+/// than `WATCHDOG_TIMEOUT`.
+pub fn start_watchdog() {
+    thread::spawn(|| loop {
+        thread::sleep(WATCHDOG_POLL);
+        if restore_if_stuck(WATCHDOG_TIMEOUT) {
+            println!("Error: listening was off for over {:?}, turned it back on", WATCHDOG_TIMEOUT);
+            crate::log_error!("Listening was off for over {:?}, turned it back on", WATCHDOG_TIMEOUT);
+        }
+    });
+}
+
+/// Turn listening back on if it has been off for longer than `timeout`
+/// outside an `expected_wait`, forgetting the guards still alive. Returns
+/// whether it did.
+fn restore_if_stuck(timeout: Duration) -> bool {
+    if is_listening() || EXPECTED_WAITS.load(Ordering::SeqCst) > 0 {
+        return false;
+    }
+    let stuck = OFF_SINCE.lock().unwrap().is_some_and(|since| since.elapsed() > timeout);
+    if stuck {
+        DEPTH.store(0, Ordering::SeqCst);
+        LISTENING.store(true, Ordering::SeqCst);
+    }
+    stuck
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // One test, since the state is global
+    #[test]
+    fn listening_recovers_and_escape_cancels_only_the_current_injection() {
+        let injection = std::panic::catch_unwind(|| {
+            let _listening_off = ListeningOff::new();
+            assert!(!is_listening());
+            panic!("SendInput sent 0 of 2 inputs");
+        });
+        assert!(injection.is_err());
+        assert!(is_listening());
+
+        cancel();
+        assert!(!cancelled(), "nothing to cancel while listening");
+        let listening_off = ListeningOff::new();
+        cancel();
+        assert!(cancelled());
+        drop(listening_off);
+        let next_injection = ListeningOff::new();
+        assert!(!cancelled(), "the next injection starts uncancelled");
+        drop(next_injection);
+
+        let outer = ListeningOff::new();
+        cancel();
+        let inner = ListeningOff::new();
+        assert!(cancelled(), "a nested guard keeps the Escape");
+        drop(inner);
+        assert!(!is_listening(), "off until the outermost guard drops");
+        drop(outer);
+        assert!(is_listening());
+
+        std::mem::forget(ListeningOff::new());
+        assert!(!restore_if_stuck(Duration::from_secs(60)));
+        assert!(!expected_wait(|| restore_if_stuck(Duration::ZERO)));
+        thread::sleep(Duration::from_millis(5));
+        assert!(restore_if_stuck(Duration::ZERO));
+        assert!(is_listening());
+
+        // The stuck guard no longer counts
+        drop(ListeningOff::new());
+        assert!(is_listening());
+    }
+}
@@ -0,0 +1,219 @@
+// src/logging.rs
+//
+// The rolling log file, text_expander.log next to the config file. Release
+// builds have no console, so this is what there is to go on when an
+// expansion misfires. Entries are queued and written by a background
+// thread, so the keystroke path never waits on the disk. Typed keys are
+// logged only at `trace`, which the user has to ask for.
+
+use std::fs::{self, File, OpenOptions};
+use std::io::{BufWriter, Write};
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicU8, Ordering};
+use std::sync::mpsc::{self, Receiver, Sender};
+use std::sync::OnceLock;
+use std::thread;
+
+use chrono::Local;
+use serde::Deserialize;
+
+/// Overrides `log_level` from the config file, e.g. `TEXT_EXPANDER_LOG=trace`.
+pub const LOG_ENV_VAR: &str = "TEXT_EXPANDER_LOG";
+
+pub const LOG_FILE_NAME: &str = "text_expander.log";
+
+/// Past this size the log is moved to `text_expander.log.old`, replacing the
+/// previous one, and a new log is started.
+const MAX_LOG_BYTES: u64 = 1024 * 1024;
+
+/// How much goes in the log; each level includes the ones before it.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum LogLevel {
+    Off,
+    /// Failed injections, commands, and clipboard restores.
+    Error,
+    /// Config problems.
+    Warn,
+    /// Expansions fired (by trigger), reloads, pausing.
+    Info,
+    Debug,
+    /// Every key, with the text it typed.
+    Trace,
+}
+
+impl LogLevel {
+    fn from_name(name: &str) -> Option<LogLevel> {
+        match name.trim().to_ascii_lowercase().as_str() {
+            "off" => Some(LogLevel::Off),
+            "error" => Some(LogLevel::Error),
+            "warn" => Some(LogLevel::Warn),
+            "info" => Some(LogLevel::Info),
+            "debug" => Some(LogLevel::Debug),
+            "trace" => Some(LogLevel::Trace),
+            _ => None,
+        }
+    }
+
+    fn label(self) -> &'static str {
+        match self {
+            LogLevel::Off => "OFF",
+            LogLevel::Error => "ERROR",
+            LogLevel::Warn => "WARN",
+            LogLevel::Info => "INFO",
+            LogLevel::Debug => "DEBUG",
+            LogLevel::Trace => "TRACE",
+        }
+    }
+}
+
+static LEVEL: AtomicU8 = AtomicU8::new(LogLevel::Off as u8);
+static SENDER: OnceLock<Sender<String>> = OnceLock::new();
+
+/// `configured` (the config file's `log_level`), unless `TEXT_EXPANDER_LOG`
+/// names a level.
+pub fn effective_level(configured: LogLevel) -> LogLevel {
+    std::env::var(LOG_ENV_VAR).ok().and_then(|name| LogLevel::from_name(&name)).unwrap_or(configured)
+}
+
+/// Start logging to `text_expander.log` in `dir`. Later calls only change
+/// the level.
+pub fn start(dir: &Path, level: LogLevel) {
+    set_level(level);
+    if SENDER.get().is_some() {
+        return;
+    }
+    let (sender, receiver) = mpsc::channel();
+    if SENDER.set(sender).is_ok() {
+        let path = dir.join(LOG_FILE_NAME);
+        thread::spawn(move || write_entries(path, receiver));
+    }
+}
+
+/// Change the level, e.g. after the config file is reloaded.
+pub fn set_level(level: LogLevel) {
+    LEVEL.store(level as u8, Ordering::Relaxed);
+}
+
+/// Whether an entry at `level` would be written. The log macros check this
+/// before formatting anything.
+pub fn enabled(level: LogLevel) -> bool {
+    level != LogLevel::Off && level as u8 <= LEVEL.load(Ordering::Relaxed)
+}
+
+/// Queue one entry, timestamped now. Use the `log_*!` macros instead.
+#[doc(hidden)]
+pub fn write(level: LogLevel, message: std::fmt::Arguments) {
+    if let Some(sender) = SENDER.get() {
+        let timestamp = Local::now().format("%Y-%m-%d %H:%M:%S%.3f");
+        let _ = sender.send(format!("{} {:<5} {}\n", timestamp, level.label(), message));
+    }
+}
+
+/// Append entries as they arrive, flushing after each batch. Failures to
+/// write have nowhere to be reported, so they are dropped.
+fn write_entries(path: PathBuf, receiver: Receiver<String>) {
+    let mut log = LogFile { path, writer: None, size: 0 };
+    while let Ok(entry) = receiver.recv() {
+        for entry in std::iter::once(entry).chain(receiver.try_iter()) {
+            log.append(&entry);
+        }
+        log.flush();
+    }
+}
+
+struct LogFile {
+    path: PathBuf,
+    writer: Option<BufWriter<File>>,
+    size: u64,
+}
+
+impl LogFile {
+    fn append(&mut self, entry: &str) {
+        if self.writer.is_some() && self.size + entry.len() as u64 > MAX_LOG_BYTES {
+            self.rotate();
+        }
+        if self.writer.is_none() {
+            self.open();
+        }
+        let written = self.writer.as_mut().is_some_and(|writer| writer.write_all(entry.as_bytes()).is_ok());
+        if written {
+            self.size += entry.len() as u64;
+        }
+    }
+
+    fn flush(&mut self) {
+        if let Some(writer) = &mut self.writer {
+            let _ = writer.flush();
+        }
+    }
+
+    fn open(&mut self) {
+        let Ok(file) = OpenOptions::new().create(true).append(true).open(&self.path) else {
+            return;
+        };
+        self.size = file.metadata().map_or(0, |metadata| metadata.len());
+        self.writer = Some(BufWriter::new(file));
+    }
+
+    /// Keep the full log as `.old` and start an empty one.
+    fn rotate(&mut self) {
+        self.flush();
+        self.writer = None;
+        let mut old = self.path.clone().into_os_string();
+        old.push(".old");
+        let _ = fs::rename(&self.path, old);
+    }
+}
+
+#[doc(hidden)]
+#[macro_export]
+macro_rules! log_at {
+    ($level:expr, $($arg:tt)*) => {
+        if $crate::logging::enabled($level) {
+            $crate::logging::write($level, format_args!($($arg)*));
+        }
+    };
+}
+
+/// Log at `error`: something the user saw go wrong.
+#[macro_export]
+macro_rules! log_error {
+    ($($arg:tt)*) => { $crate::log_at!($crate::logging::LogLevel::Error, $($arg)*) };
+}
+
+#[macro_export]
+macro_rules! log_warn {
+    ($($arg:tt)*) => { $crate::log_at!($crate::logging::LogLevel::Warn, $($arg)*) };
+}
+
+/// Log at `info`. Never pass typed text here; triggers only.
+#[macro_export]
+macro_rules! log_info {
+    ($($arg:tt)*) => { $crate::log_at!($crate::logging::LogLevel::Info, $($arg)*) };
+}
+
+#[macro_export]
+macro_rules! log_debug {
+    ($($arg:tt)*) => { $crate::log_at!($crate::logging::LogLevel::Debug, $($arg)*) };
+}
+
+/// Log at `trace`, the only level that may include what was typed.
+#[macro_export]
+macro_rules! log_trace {
+    ($($arg:tt)*) => { $crate::log_at!($crate::logging::LogLevel::Trace, $($arg)*) };
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn levels_include_the_ones_before_them() {
+        assert_eq!(LogLevel::from_name(" Trace"), Some(LogLevel::Trace));
+        assert_eq!(LogLevel::from_name("verbose"), None);
+        set_level(LogLevel::Info);
+        assert!(enabled(LogLevel::Error) && enabled(LogLevel::Info));
+        assert!(!enabled(LogLevel::Debug) && !enabled(LogLevel::Trace) && !enabled(LogLevel::Off));
+    }
+}
@@ -0,0 +1,154 @@
+// src/macros.rs
+//
+// `[macros]` triggers: instead of pasting text, a macro runs a list of
+// scripted input steps, e.g.
+//
+//   "ff" = ["delete(2)", "key(ShiftLeft+End)", "key(Delete)"]
+//
+// Steps are parsed when expansions.toml loads, so a typo is reported then
+// rather than when the trigger fires. main.rs runs them.
+
+use std::fmt::Write;
+
+use chrono::Local;
+use serde::Deserialize;
+
+use crate::keys::KeyId;
+
+/// One scripted step of a macro.
+#[derive(Debug, Clone, PartialEq, Deserialize)]
+#[serde(try_from = "String")]
+pub enum MacroStep {
+    /// `delete(N)`: send N backspaces.
+    Delete(usize),
+    /// `key(Mod+Mod+Key)`: press the keys in order, release in reverse.
+    Key(Vec<KeyId>),
+    /// `text("...")`: type literal text.
+    Text(String),
+    /// `date("FMT")`: type the current date/time in chrono strftime format.
+    Date(String),
+    /// `sleep(MS)`: pause, for apps that need time to catch up.
+    Sleep(u64),
+}
+
+impl TryFrom<String> for MacroStep {
+    type Error = String;
+
+    fn try_from(step: String) -> Result<Self, Self::Error> {
+        let step = step.trim();
+        let (name, arg) = step
+            .strip_suffix(')')
+            .and_then(|s| s.split_once('('))
+            .ok_or_else(|| format!("macro step {:?} should look like name(argument)", step))?;
+        let arg = arg.trim();
+
+        match name.trim() {
+            "delete" => Ok(MacroStep::Delete(parse_number(step, arg)?)),
+            "sleep" => Ok(MacroStep::Sleep(parse_number(step, arg)?)),
+            "key" => arg
+                .split('+')
+                .map(|key| key_by_name(key.trim()).ok_or_else(|| format!("unknown key {:?} in macro step {:?}", key.trim(), step)))
+                .collect::<Result<Vec<_>, _>>()
+                .map(MacroStep::Key),
+            "text" => Ok(MacroStep::Text(parse_quoted(step, arg)?)),
+            "date" => {
+                let format = parse_quoted(step, arg)?;
+                let mut probe = String::new();
+                write!(probe, "{}", Local::now().format(&format))
+                    .map_err(|_| format!("invalid date format in macro step {:?}", step))?;
+                Ok(MacroStep::Date(format))
+            }
+            other => Err(format!(
+                "unknown macro step {:?} in {:?} (expected delete, key, text, date, or sleep)",
+                other, step
+            )),
+        }
+    }
+}
+
+fn parse_number<T: std::str::FromStr>(step: &str, arg: &str) -> Result<T, String> {
+    arg.parse().map_err(|_| format!("macro step {:?} needs a whole number", step))
+}
+
+/// A double-quoted argument. Supports `\"`, `\\` and `\n`.
+fn parse_quoted(step: &str, arg: &str) -> Result<String, String> {
+    let inner = arg
+        .strip_prefix('"')
+        .and_then(|s| s.strip_suffix('"'))
+        .ok_or_else(|| format!("macro step {:?} needs a \"quoted\" argument", step))?;
+
+    let mut text = String::new();
+    let mut chars = inner.chars();
+    while let Some(c) = chars.next() {
+        if c != '\\' {
+            text.push(c);
+            continue;
+        }
+        match chars.next() {
+            Some('n') => text.push('\n'),
+            Some(escaped @ ('"' | '\\')) => text.push(escaped),
+            _ => return Err(format!("bad escape in macro step {:?}", step)),
+        }
+    }
+    Ok(text)
+}
+
+/// Key names accepted by `key(...)` (and the hotkeys), case-insensitive.
+/// Names follow the rdev/KeyId spelling (`ShiftLeft`, `LeftArrow`) with
+/// common short aliases. Punctuation is the key in that place on a US
+/// layout. "Win" is Cmd on macOS.
+pub fn key_by_name(name: &str) -> Option<KeyId> {
+    let lowered = name.to_ascii_lowercase();
+    let key = match lowered.as_str() {
+        "shift" | "shiftleft" => KeyId::ShiftLeft,
+        "shiftright" => KeyId::ShiftRight,
+        "ctrl" | "control" | "controlleft" => KeyId::ControlLeft,
+        "controlright" => KeyId::ControlRight,
+        "alt" | "altleft" => KeyId::Alt,
+        "altright" | "altgr" => KeyId::AltGr,
+        "win" | "metaleft" => KeyId::MetaLeft,
+        "backspace" => KeyId::Backspace,
+        "tab" => KeyId::Tab,
+        "return" | "enter" => KeyId::Return,
+        "escape" | "esc" => KeyId::Escape,
+        "space" => KeyId::Space,
+        "pageup" => KeyId::PageUp,
+        "pagedown" => KeyId::PageDown,
+        "end" => KeyId::End,
+        "home" => KeyId::Home,
+        "left" | "leftarrow" => KeyId::LeftArrow,
+        "up" | "uparrow" => KeyId::UpArrow,
+        "right" | "rightarrow" => KeyId::RightArrow,
+        "down" | "downarrow" => KeyId::DownArrow,
+        "insert" => KeyId::Insert,
+        "delete" => KeyId::Delete,
+        ";" | "semicolon" => KeyId::SemiColon,
+        "=" | "equal" => KeyId::Equal,
+        "," | "comma" => KeyId::Comma,
+        "-" | "minus" => KeyId::Minus,
+        "." | "dot" | "period" => KeyId::Dot,
+        "/" | "slash" => KeyId::Slash,
+        "`" | "backquote" => KeyId::BackQuote,
+        "[" | "leftbracket" => KeyId::LeftBracket,
+        "\\" | "backslash" => KeyId::BackSlash,
+        "]" | "rightbracket" => KeyId::RightBracket,
+        "'" | "quote" => KeyId::Quote,
+        _ => {
+            // Letters (A or KeyA), digits (1 or Num1), F1–F12. Their virtual
+            // keys are the uppercase letter or digit, and 0x70 on for F1–F12.
+            let single = lowered.strip_prefix("key").or_else(|| lowered.strip_prefix("num")).unwrap_or(&lowered);
+            let mut chars = single.chars();
+            match (chars.next(), chars.next()) {
+                (Some(c @ ('a'..='z' | '0'..='9')), None) => KeyId::from_vk(c.to_ascii_uppercase() as u32),
+                _ => {
+                    let n: u32 = lowered.strip_prefix('f')?.parse().ok()?;
+                    if !(1..=12).contains(&n) {
+                        return None;
+                    }
+                    KeyId::from_vk(0x70 + n - 1)
+                }
+            }
+        }
+    };
+    Some(key)
+}
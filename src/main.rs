@@ -1,495 +1,961 @@
 #![cfg_attr(not(debug_assertions), windows_subsystem = "windows")]
 
-use std::sync::atomic::{AtomicBool, Ordering};
-use std::sync::mpsc::Receiver;
-use std::sync::Arc;
-use std::env;
 use std::collections::HashMap;
+use std::path::Path;
+use std::sync::atomic::Ordering;
+use std::sync::mpsc::{Receiver, RecvTimeoutError};
+use std::sync::Arc;
 use std::sync::Mutex;
 use std::thread;
-use std::time::Duration;
-use serde::Deserialize;
-use arboard::Clipboard;
-use chrono::Local;
+use std::time::{Duration, Instant, SystemTime};
+use chrono::{DateTime, Local};
 
+// Only the clipboard's HTML writer is left in use with the rdev backend
+#[cfg(windows)]
+#[cfg_attr(feature = "rdev", allow(dead_code))]
 mod windows_input;
+mod input_backend;
+#[cfg(any(not(windows), feature = "rdev"))]
+mod rdev_backend;
+#[cfg(windows)]
+mod autostart;
+#[cfg(windows)]
 mod keyboard_hook;
+mod clipboard;
+#[cfg(windows)]
+mod foreground;
+#[cfg(windows)]
+mod fill_dialog;
+mod shell_command;
+#[cfg(windows)]
+mod search_popup;
+#[cfg(windows)]
+mod single_instance;
+#[cfg(not(windows))]
+mod non_windows;
+#[cfg(not(windows))]
+use non_windows::{fill_dialog, foreground, keyboard_hook, search_popup, single_instance};
+
+use text_expander::{cli, config, counters, debug_println, groups, listening, log_error, log_info, log_trace, log_warn, logging, search, stats, template, transforms};
+#[cfg(windows)]
+use text_expander::dead_keys;
+use text_expander::config::{ExpansionFile, Injection, Settings};
+use text_expander::keys::KeyId;
+use text_expander::engine::{Action, Delimiter, ExpansionEngine, HistoryEntry, InputEvent, Insertion, KeyPress};
+use text_expander::macros::MacroStep;
+use text_expander::template::FocusedWindow;
+use transforms::Transform;
+use input_backend::{HookMessage, MouseButton, BACKEND};
+
+
+/// Most config problems listed in the load warning; `text_expander check`
+/// prints them all.
+const MAX_PROBLEMS_SHOWN: usize = 10;
 
-use keyboard_hook::{KeyId, MouseButton, HookMessage};
+fn main() {
+    // Command-line modes do their job and exit. Release builds have no
+    // console of their own, so they report to the one they were run from.
+    let command = cli::parse_args(std::env::args().skip(1));
+    if command != Ok(cli::Command::Run) {
+        #[cfg(windows)]
+        unsafe { winapi::um::wincon::AttachConsole(winapi::um::wincon::ATTACH_PARENT_PROCESS) };
+        let result = command.map_err(Into::into).and_then(cli::run);
+        if let Err(error) = result {
+            println!("Error: {}", error);
+            std::process::exit(1);
+        }
+        return;
+    }
 
+    // A second instance would expand every trigger twice. Hand over to the
+    // running one, which reloads its config, and leave without a tray icon.
+    let Some(instance_lock) = single_instance::acquire() else {
+        keyboard_hook::ask_running_instance_to_reload();
+        return;
+    };
 
-/// A macro that functions like `println!`, but only compiles in debug builds.
-#[macro_export]
-macro_rules! debug_println {
-    ($($arg:tt)*) => {
-        #[cfg(debug_assertions)]
-        {
-            print!("[DEBUG] ");
-            println!($($arg)*);
-        }
-        #[cfg(not(debug_assertions))]
-        {
+    let expansion_table = match config::load_expansion_table() {
+        Ok(table) => table,
+        Err(error) => {
+            keyboard_hook::show_error_box(&format!("Could not load expansions: {}", error));
+            std::process::exit(1);
         }
     };
-}
+    start_logging(&expansion_table);
+    report_problems(&expansion_table);
+    thread::spawn(keyboard_hook::offer_autostart_update);
+    counters::load();
+    stats::load();
+    let mut engine = ExpansionEngine::new(expansion_table);
+    engine.set_disabled_groups(groups::read_disabled());
+    apply_hook_settings(&engine);
+    let engine = Arc::new(Mutex::new(engine));
+    listening::start_watchdog();
+
+    // Hot reload. A reload that lands while an expansion is being injected
+    // waits for the next poll.
+    let reload_engine = engine.clone();
+    config::watch_for_changes(move |result| {
+        if !listening::is_listening() {
+            return false;
+        }
+        apply_reloaded_table(&reload_engine, result);
+        true
+    });
 
+    let (sender, receiver) = std::sync::mpsc::channel();
 
-#[derive(Debug, Deserialize)]
-struct ExpansionFile {
-    case_sensitive: HashMap<String, String>,
-    case_insensitive: HashMap<String, String>,
-}
+    // Processing thread — uses explicit loop so we can pass &receiver for draining.
+    // While a clipboard burst is open, wait with a timeout so the user's
+    // clipboard is restored once expansions stop arriving.
+    thread::spawn(move || {
+        loop {
+            let message = match clipboard::time_until_restore() {
+                Some(wait) if wait.is_zero() => {
+                    clipboard::restore_after_burst();
+                    continue;
+                }
+                Some(wait) => match receiver.recv_timeout(wait) {
+                    Ok(msg) => msg,
+                    Err(RecvTimeoutError::Timeout) => {
+                        clipboard::restore_after_burst();
+                        continue;
+                    }
+                    Err(RecvTimeoutError::Disconnected) => break,
+                },
+                None => match receiver.recv() {
+                    Ok(msg) => msg,
+                    Err(_) => break, // sender dropped
+                },
+            };
 
-struct ExpansionData {
-    key_buffer: String,
-    expansion_table: ExpansionFile,
-    cursor_position: usize,
-    typing_state: TypingState,
-    global_listening: bool,
-}
+            match message {
+                HookMessage::KeyDown { key, raw, swallowed, time, shortcut, shift } => {
+                    let event_name = BACKEND.resolve_character(&raw);
+                    let event = input_event(key, event_name, shortcut, shift);
+                    handle_key_press(engine.clone(), key, event, swallowed, time, &receiver);
+                }
+                HookMessage::MouseDown(button) => {
+                    handle_mouse_press(engine.clone(), button);
+                }
+                HookMessage::ReloadConfig => {
+                    apply_reloaded_table(&engine, config::reload_expansion_table());
+                }
+                HookMessage::InsertSnippet { trigger } => {
+                    insert_from_search(&engine, &trigger, &receiver);
+                }
+                HookMessage::ToggleGroup { group } => {
+                    toggle_group(&engine, &group);
+                }
+                HookMessage::RepeatExpansion { index, refocus } => {
+                    repeat_expansion(&engine, index, refocus, &receiver);
+                }
+                HookMessage::ClearHistory => {
+                    clear_history(&engine);
+                }
+                HookMessage::TransformSelection { index } => {
+                    transform_selection(&engine, index, &receiver);
+                }
+            }
+        }
+    });
+
+    // Install hooks and run message pump (blocks main thread)
+    if let Err(error) = BACKEND.run(sender) {
+        println!("Error: {:?}", error);
+        log_error!("Hooks failed: {:?}", error);
+    }
 
-enum TypingState {
-    Typing,
-    Empty,
-    NoMatch,
+    // Don't leave the last expansion on the clipboard if we exit mid-burst
+    clipboard::restore_after_burst();
+    stats::save();
+    drop(instance_lock);
 }
 
-impl ExpansionData {
-    fn new(expansion_table: ExpansionFile) -> Self {
-        ExpansionData {
-            key_buffer: String::new(),
-            expansion_table,
-            cursor_position: 0,
-            typing_state: TypingState::Empty,
-            global_listening: true,
-        }
+/// Log to text_expander.log next to the config file, at the level it (or
+/// `TEXT_EXPANDER_LOG`) asks for. On reload this only changes the level.
+fn start_logging(expansion_table: &ExpansionFile) {
+    let level = logging::effective_level(expansion_table.settings.log_level);
+    if let Some(dir) = config::config_path().and_then(|path| path.parent()) {
+        logging::start(dir, level);
+        log_info!("Started, logging at {:?}", level);
     }
+}
 
-    fn clear_buffer(&mut self) {
-        self.key_buffer.clear();
+/// The focused app is in `excluded_apps` (or `only_apps` is set and it isn't
+/// there), its title contains one of `excluded_titles`, its window class is
+/// in `excluded_classes`, or a password box has focus. `recheck` asks the
+/// password check not to trust what it found for the same control before.
+fn foreground_excluded(settings: &Settings, recheck: bool) -> bool {
+    if settings.detect_password_fields && foreground::password_field_focused(recheck) {
+        return true;
     }
 
-    fn push_to_buffer(&mut self, c: &str) {
-        let index = (self.cursor_position as usize).min(self.key_buffer.len());
-        self.key_buffer.insert_str(index, c);
-        self.cursor_position += c.len();
+    if !settings.excluded_apps.is_empty() || !settings.only_apps.is_empty() {
+        let exe = foreground::exe_name();
+        let excluded = exe.as_ref().is_some_and(|exe| settings.excluded_apps.contains(exe));
+        let allowed = settings.only_apps.is_empty() || exe.is_some_and(|exe| settings.only_apps.contains(&exe));
+        if excluded || !allowed {
+            return true;
+        }
     }
 
-    fn pop_from_buffer(&mut self) {
-        if self.cursor_position > 0 && !self.key_buffer.is_empty() {
-            let remove_index = self.cursor_position - 1;
-            if self.key_buffer.is_char_boundary(remove_index as usize) {
-                self.key_buffer.remove(remove_index as usize);
-                self.cursor_position -= 1;
-            }
+    if !settings.excluded_classes.is_empty() {
+        let class = foreground::class_name().to_lowercase();
+        if settings.excluded_classes.contains(&class) {
+            return true;
         }
     }
 
-    fn set_typing_state(&mut self, state: TypingState) {
-        self.typing_state = state;
+    if settings.excluded_titles.is_empty() {
+        return false;
     }
+    let title = foreground::window_title().to_lowercase();
+    settings.excluded_titles.iter().any(|pattern| title.contains(pattern.as_str()))
+}
 
-    fn reset(&mut self) {
-        self.clear_buffer();
-        self.typing_state = TypingState::Empty;
-        self.cursor_position = 0;
-        self.global_listening = true;
+/// `suppress_in_remote` is on and a remote-desktop or VM client has focus,
+/// by exe name or window class.
+fn foreground_remote(settings: &Settings) -> bool {
+    if !settings.suppress_in_remote {
+        return false;
     }
+    let listed = |builtin: &[&str], extra: &[String], name: &str| {
+        builtin.contains(&name) || extra.iter().any(|listed| listed == name)
+    };
+    let remote_app = foreground::exe_name().is_some_and(|exe| listed(config::REMOTE_APPS, &settings.remote_apps, &exe));
+    remote_app || listed(config::REMOTE_WINDOW_CLASSES, &settings.remote_window_classes, &foreground::class_name().to_lowercase())
+}
 
-    fn decrement_cursor_position(&mut self) {
-        if self.cursor_position > 0 {
-            self.cursor_position -= 1;
-        }
-        if self.cursor_position == 0 {
-            self.reset();
-        }
+/// Send a key the hook swallowed on to the target app.
+fn reinject_key(key: KeyId) {
+    if let Err(error) = BACKEND.resend_key(key) {
+        log_error!("Re-sending {:?} failed: {}", key, error);
     }
+}
+
+/// Settings the keyboard hook reads directly, without the engine lock.
+fn apply_hook_settings(engine: &ExpansionEngine) {
+    sync_tab_swallowing(engine);
+    keyboard_hook::set_toggle_hotkey(engine.settings().toggle_hotkey);
+    keyboard_hook::set_search_hotkey(engine.settings().search_hotkey);
+    keyboard_hook::set_repeat_hotkey(engine.settings().repeat_hotkey);
+    search_popup::set_entries(engine.search_entries());
+    keyboard_hook::set_groups(engine.groups());
+    let transform_hotkeys: Vec<config::Hotkey> =
+        engine.selection_transforms().iter().map(|transform| transform.hotkey).collect();
+    keyboard_hook::set_transform_hotkeys(&transform_hotkeys);
+    let settings = engine.settings();
+    clipboard::set_timing(
+        settings.clipboard_ready_timeout_ms,
+        settings.paste_settle_ms,
+        settings.clipboard_restore_delay_ms,
+    );
+}
 
-    fn increment_cursor_position(&mut self) {
-        self.cursor_position += 1;
+/// Switch a snippet group off or back on from the tray, and save the choice.
+fn toggle_group(engine_arc: &Arc<Mutex<ExpansionEngine>>, group: &str) {
+    let mut engine = engine_arc.lock().unwrap();
+    let enabled = engine.toggle_group(group);
+    log_info!("Group {:?} {}", group, if enabled { "enabled" } else { "disabled" });
+    keyboard_hook::set_groups(engine.groups());
+    if let Err(error) = groups::save_disabled(engine.disabled_groups()) {
+        println!("Error: could not save groups: {}", error);
+        log_error!("Could not save groups: {}", error);
     }
 }
 
+/// Tab is swallowed while the engine has a use for it, which changes as
+/// tab-stop sessions start and end.
+fn sync_tab_swallowing(engine: &ExpansionEngine) {
+    keyboard_hook::SWALLOW_TAB.store(engine.wants_tab(), Ordering::SeqCst);
+}
 
-// Atomic boolean for listening state
-static GLOBAL_LISTENING: AtomicBool = AtomicBool::new(true);
+/// Swap in a freshly loaded table, or keep the old one and report why. The
+/// error box gets its own thread so neither caller blocks on it.
+fn apply_reloaded_table(
+    engine_arc: &Arc<Mutex<ExpansionEngine>>,
+    result: Result<ExpansionFile, Box<dyn std::error::Error>>,
+) {
+    match result {
+        Ok(expansion_table) => {
+            start_logging(&expansion_table);
+            report_problems(&expansion_table);
+            let mut engine = engine_arc.lock().unwrap();
+            engine.replace_expansion_table(expansion_table);
+            apply_hook_settings(&engine);
+            println!("Expansions reloaded");
+            log_info!("Expansions reloaded");
+        }
+        Err(error) => {
+            println!("Error: reload failed, keeping the previous expansions: {}", error);
+            log_error!("Reload failed, keeping the previous expansions: {}", error);
+            let text = format!("Keeping the previous expansions: {}", error);
+            thread::spawn(move || keyboard_hook::show_error_box(&text));
+        }
+    }
+}
 
-fn main() {
-    let expansion_table = load_expansion_table().unwrap();
-    let expansion_data = Arc::new(Mutex::new(ExpansionData::new(expansion_table)));
+/// Tell the user what a load skipped or found suspicious. Off the calling
+/// thread, so neither startup nor a reload waits on the OK button.
+fn report_problems(expansion_table: &ExpansionFile) {
+    let problems = &expansion_table.problems;
+    if problems.is_empty() {
+        return;
+    }
+    let path = config::config_path().map_or_else(String::new, |path| path.display().to_string());
+    let mut text = format!("Problems in {}:\n\n", path);
+    for problem in problems.iter().take(MAX_PROBLEMS_SHOWN) {
+        text.push_str(&format!("{}\n", problem));
+    }
+    if problems.len() > MAX_PROBLEMS_SHOWN {
+        text.push_str(&format!("...and {} more\n", problems.len() - MAX_PROBLEMS_SHOWN));
+    }
+    text.push_str("\nEntries marked (skipped) were not loaded. Run `text_expander check` to list every problem.");
+    thread::spawn(move || keyboard_hook::show_warning_box(&text));
+}
 
-    let (sender, receiver) = std::sync::mpsc::channel();
+/// The engine's view of a hook key. `event_name` is the text the key
+/// produced, for printable keys. A modifier combo that still produced text
+/// was AltGr, so it counts as typing. Shift+Home/End/Up/Down select, but
+/// reset the buffer just like the plain keys.
+fn input_event(key: KeyId, event_name: Option<String>, shortcut: bool, shift: bool) -> InputEvent {
+    if shortcut && event_name.is_none() {
+        return InputEvent::Shortcut;
+    }
 
-    // Processing thread — uses explicit loop so we can pass &receiver for draining
-    thread::spawn(move || {
-        loop {
-            let message = match receiver.recv() {
-                Ok(msg) => msg,
-                Err(_) => break, // sender dropped
-            };
+    match key {
+        KeyId::Space => InputEvent::Delimiter(Delimiter::Space),
+        KeyId::Return => InputEvent::Delimiter(Delimiter::Enter),
+        KeyId::Tab => InputEvent::Tab,
+        KeyId::Backspace => InputEvent::Backspace,
+        KeyId::Delete => InputEvent::Delete,
+        KeyId::LeftArrow | KeyId::RightArrow if shift => InputEvent::Select,
+        KeyId::LeftArrow => InputEvent::ArrowLeft,
+        KeyId::RightArrow => InputEvent::ArrowRight,
+
+        KeyId::UpArrow | KeyId::DownArrow | KeyId::Escape
+        | KeyId::PageDown | KeyId::PageUp | KeyId::Home | KeyId::End => InputEvent::Navigation,
 
-            match message {
-                HookMessage::KeyDown { key, vk_code, scan_code } => {
-                    let event_name = keyboard_hook::resolve_character(vk_code, scan_code);
-                    handle_key_press(expansion_data.clone(), key, event_name, &receiver);
-                }
-                HookMessage::MouseDown(button) => {
-                    handle_mouse_press(expansion_data.clone(), button);
-                }
-            }
-        }
-    });
+        KeyId::KeyA | KeyId::KeyB | KeyId::KeyC | KeyId::KeyD | KeyId::KeyE | KeyId::KeyF
+        | KeyId::KeyG | KeyId::KeyH | KeyId::KeyI | KeyId::KeyJ | KeyId::KeyK | KeyId::KeyL | KeyId::KeyM
+        | KeyId::KeyN | KeyId::KeyO | KeyId::KeyP | KeyId::KeyQ | KeyId::KeyR | KeyId::KeyS | KeyId::KeyT
+        | KeyId::KeyU | KeyId::KeyV | KeyId::KeyW | KeyId::KeyX | KeyId::KeyY | KeyId::KeyZ
+        | KeyId::Num0 | KeyId::Num1 | KeyId::Num2 | KeyId::Num3 | KeyId::Num4 | KeyId::Num5
+        | KeyId::Num6 | KeyId::Num7 | KeyId::Num8 | KeyId::Num9
+        | KeyId::Minus | KeyId::Equal | KeyId::LeftBracket | KeyId::RightBracket
+        | KeyId::Quote | KeyId::Comma | KeyId::Dot | KeyId::Slash
+        | KeyId::SemiColon | KeyId::BackSlash | KeyId::BackQuote
+        | KeyId::Numpad0 | KeyId::Numpad1 | KeyId::Numpad2 | KeyId::Numpad3 | KeyId::Numpad4
+        | KeyId::Numpad5 | KeyId::Numpad6 | KeyId::Numpad7 | KeyId::Numpad8 | KeyId::Numpad9
+        | KeyId::NumpadDecimal | KeyId::NumpadDivide | KeyId::NumpadMultiply
+        | KeyId::NumpadSubtract | KeyId::NumpadAdd => InputEvent::Char(event_name),
 
-    // Install hooks and run message pump (blocks main thread)
-    if let Err(error) = keyboard_hook::install_hooks_and_run(sender) {
-        println!("Error: {:?}", error);
+        _ => InputEvent::Other,
     }
 }
 
 fn handle_key_press(
-    expansion_data_arc: Arc<Mutex<ExpansionData>>,
+    engine_arc: Arc<Mutex<ExpansionEngine>>,
     key: KeyId,
-    event_name: Option<String>,
+    event: InputEvent,
+    swallowed: bool,
+    time: u32,
     receiver: &Receiver<HookMessage>,
 ) {
-    if !GLOBAL_LISTENING.load(Ordering::SeqCst) {
+    if !listening::is_listening() {
         return;
     }
 
-    let mut expansion_data = expansion_data_arc.lock().unwrap();
+    let mut engine = engine_arc.lock().unwrap();
+
+    // Paused, or typing into an excluded app or a remote session: nothing is
+    // tracked or expanded. The hook stops swallowing while paused, but a key
+    // swallowed just before the toggle (or any delimiter in an excluded app)
+    // still has to reach the app. Untracked typing means the buffer is stale
+    // later, so keep it empty.
+    let remote = foreground_remote(engine.settings());
+    keyboard_hook::set_remote_focused(remote);
+    // Tab and delimiters are where focus may have moved inside a browser page
+    let recheck = matches!(event, InputEvent::Delimiter(_) | InputEvent::Tab);
+    let paused = keyboard_hook::EXPANSION_PAUSED.load(Ordering::SeqCst);
+    if paused || remote || foreground_excluded(engine.settings(), recheck) {
+        engine.reset();
+        drop(engine);
+        if swallowed {
+            reinject_key(key);
+        }
+        return;
+    }
 
     debug_println!("Key pressed: {:?}", key);
+    log_trace!("Key {:?} {:?}", key, event);
 
-    match key {
-        KeyId::Space | KeyId::Return => {
-            // Space/Enter are swallowed by the hook to prevent WM_CHAR
-            // ordering issues. We must re-inject them if no expansion fires.
-            let (reinject_vk, reinject_scan) = match key {
-                KeyId::Space => (0x20u16, 0x39u16),
-                _ => (0x0Du16, 0x1Cu16), // VK_RETURN, scan 0x1C
-            };
-            let separator = if key == KeyId::Space { " " } else { "\n" };
-
-            match expansion_data.typing_state {
-                TypingState::Typing => {
-                    // Check for expansion match
-                    if let Some((trigger_length, completion)) = check_for_completion(&expansion_data) {
-                        debug_println!("Found match: {}", completion);
-                        expansion_data.reset();
-                        drop(expansion_data);
-                        expand_trigger_phrase(trigger_length, completion, separator, receiver, &expansion_data_arc)
-                            .expect("Error in expand_trigger_phrase");
-                        return;
-                    }
+    let press = KeyPress { event, time, swallowed };
+    let actions = engine.handle(press, Instant::now(), &foreground::exe_name);
+    drop(engine);
 
-                    // Check for ff trigger
-                    if expansion_data.key_buffer == "ff" {
-                        expansion_data.reset();
-                        drop(expansion_data);
+    run_actions(actions, key, receiver, &engine_arc);
+}
 
-                        disable_keyboard_listening();
+/// Insert a snippet picked in the search popup. The popup has already given
+/// focus back to the window it was opened over; let that settle first.
+fn insert_from_search(engine_arc: &Arc<Mutex<ExpansionEngine>>, trigger: &str, receiver: &Receiver<HookMessage>) {
+    thread::sleep(Duration::from_millis(50));
+    let actions = engine_arc.lock().unwrap().insert_by_trigger(trigger);
+    run_actions(actions, KeyId::Unknown(0), receiver, engine_arc);
+}
 
-                        // Delete "ff" only (separator was swallowed)
-                        windows_input::send_backspaces_fast(2)
-                            .expect("Error sending backspaces for ff");
-                        thread::sleep(Duration::from_millis(30));
+/// Insert an expansion from the history again. A pick from the tray's History
+/// menu first gives focus back to the window the last expansion went into;
+/// the hotkey's modifiers have to be up before anything is sent.
+fn repeat_expansion(
+    engine_arc: &Arc<Mutex<ExpansionEngine>>,
+    index: usize,
+    refocus: bool,
+    receiver: &Receiver<HookMessage>,
+) {
+    if refocus {
+        foreground::refocus_last_target();
+    }
+    keyboard_hook::wait_for_modifiers_released(Duration::from_secs(2));
+    thread::sleep(Duration::from_millis(50));
+    let actions = engine_arc.lock().unwrap().repeat_from_history(index);
+    run_actions(actions, KeyId::Unknown(0), receiver, engine_arc);
+}
 
-                        // Select to end of line and delete
-                        windows_input::send_shift_end()
-                            .expect("Error sending Shift+End for ff");
-                        thread::sleep(Duration::from_millis(30));
-                        windows_input::send_delete_key()
-                            .expect("Error sending Delete for ff");
+fn clear_history(engine_arc: &Arc<Mutex<ExpansionEngine>>) {
+    engine_arc.lock().unwrap().clear_history();
+    keyboard_hook::set_history(Vec::new());
+    log_info!("History cleared");
+}
 
-                        replay_buffered_keystrokes(receiver, &expansion_data_arc);
-                        enable_keyboard_listening();
-                        return;
-                    }
+/// Replace the selection in the focused app with the `index`th
+/// `[transforms]` entry applied to it. Nothing happens if nothing is
+/// selected, so old clipboard contents are never pasted.
+fn transform_selection(engine_arc: &Arc<Mutex<ExpansionEngine>>, index: usize, receiver: &Receiver<HookMessage>) {
+    let Some(selection_transform) = engine_arc.lock().unwrap().selection_transforms().get(index).cloned() else {
+        return;
+    };
+    // Ctrl+C with the hotkey's Alt or Shift still down is another shortcut
+    keyboard_hook::wait_for_all_modifiers_released(Duration::from_secs(2));
+    // Only clipboard text is put back afterwards
+    if clipboard::holds_non_text() {
+        keyboard_hook::notify_error("Transforms need text, or nothing, on the clipboard; it holds something else");
+        return;
+    }
 
-                    // Check for nn (date) trigger
-                    if expansion_data.key_buffer == "nn" {
-                        let now = Local::now();
-                        let date_string = now.format("%-m/%-d/%y:").to_string();
-                        expansion_data.reset();
-                        drop(expansion_data);
-                        expand_trigger_phrase(2, date_string, separator, receiver, &expansion_data_arc)
-                            .expect("Error in expanding date phrase");
-                        return;
-                    }
+    let listening_off = listening::ListeningOff::new();
+    match replace_selection(&selection_transform.transform) {
+        Ok(true) => log_info!("Transformed the selection ({})", selection_transform.name),
+        Ok(false) => {
+            debug_println!("Nothing selected for {}", selection_transform.name);
+        }
+        Err(error) => {
+            println!("Error: transform {} failed: {}", selection_transform.name, error);
+            log_error!("Transform {} failed: {}", selection_transform.name, error);
+            keyboard_hook::notify_error(&format!("Transform {} failed at {}", selection_transform.name, error));
+        }
+    }
+    // The word being typed, if any, went with the selection
+    engine_arc.lock().unwrap().reset();
 
-                    // Check for /wksN and /daysN triggers
-                    if let Some(date_string) = handle_date_expansion(&expansion_data.key_buffer) {
-                        let trigger_length = expansion_data.key_buffer.len();
-                        debug_println!("Date expansion triggered: {}", date_string);
-                        expansion_data.reset();
-                        drop(expansion_data);
-                        expand_trigger_phrase(trigger_length, date_string, separator, receiver, &expansion_data_arc)
-                            .expect("Error in date expansion");
-                        return;
-                    }
+    replay_buffered_keystrokes(receiver, engine_arc);
+    drop(listening_off);
+}
 
-                    // No match — re-inject the swallowed key and transition
-                    drop(expansion_data);
-                    let _ = windows_input::send_key_tap(reinject_vk, reinject_scan);
-                    let mut expansion_data = expansion_data_arc.lock().unwrap();
-                    if let KeyId::Space = key {
-                        expansion_data.push_to_buffer(" ");
-                        expansion_data.set_typing_state(TypingState::NoMatch);
-                    } else {
-                        expansion_data.reset();
-                    }
-                }
+/// Copy the selection, and paste `transform` of it in its place. False if
+/// nothing was selected.
+fn replace_selection(transform: &Transform) -> Result<bool, Box<dyn std::error::Error>> {
+    let mut clipboard = at_stage("clipboard", clipboard::open())?;
+    let selection = at_stage("copy", clipboard::copy_selection(&mut clipboard, || BACKEND.copy()))?;
+    let Some(selection) = selection.filter(|selection| !selection.is_empty()) else {
+        return Ok(false);
+    };
+    drop(clipboard);
+    let transformed = transform.apply(&selection);
+    paste_via_clipboard(|clipboard| clipboard::set_expansion_text(clipboard, &transformed, None))?;
+    Ok(true)
+}
 
-                TypingState::Empty | TypingState::NoMatch => {
-                    if matches!(expansion_data.typing_state, TypingState::NoMatch) {
-                        expansion_data.reset();
-                    }
-                    // Re-inject the swallowed key
-                    drop(expansion_data);
-                    let _ = windows_input::send_key_tap(reinject_vk, reinject_scan);
-                }
-            }
-        }
+/// The tray's History menu lines: time, trigger, and text on one line.
+fn history_lines(engine: &ExpansionEngine) -> Vec<String> {
+    engine
+        .history()
+        .iter()
+        .map(|entry| {
+            let time = DateTime::<Local>::from(entry.time).format("%H:%M");
+            format!("{}  {}  {}", time, entry.trigger, search::preview(&entry.text))
+        })
+        .collect()
+}
 
-        KeyId::Backspace => {
-            expansion_data.pop_from_buffer();
-            expansion_data.set_typing_state(TypingState::Typing);
-            debug_println!("{:?}", &expansion_data.key_buffer);
+fn handle_mouse_press(engine_arc: Arc<Mutex<ExpansionEngine>>, button: MouseButton) {
+    match button {
+        MouseButton::Left | MouseButton::Right | MouseButton::Middle => {
+            let click = KeyPress { event: InputEvent::MouseClick, time: 0, swallowed: false };
+            let mut engine = engine_arc.lock().unwrap();
+            engine.handle(click, Instant::now(), &|| None);
+            sync_tab_swallowing(&engine);
+            foreground::invalidate();
+            debug_println!("Mouse button pressed, buffer cleared");
         }
+    }
+}
 
-        // Cursor movement
-        KeyId::LeftArrow => {
-            expansion_data.decrement_cursor_position();
-        }
-        KeyId::RightArrow => {
-            if expansion_data.key_buffer.len() == expansion_data.cursor_position {
-                expansion_data.reset();
-                return;
-            } else {
-                expansion_data.increment_cursor_position();
-            }
-        }
+/// Carry out the engine's actions for `key`. Anything beyond passing the key
+/// through runs with listening off, then keys typed meanwhile are replayed.
+/// A failed step abandons the rest but still restores listening; a dead
+/// processing thread would swallow every later Space/Enter for good.
+fn run_actions(
+    actions: Vec<Action>,
+    key: KeyId,
+    receiver: &Receiver<HookMessage>,
+    engine_arc: &Arc<Mutex<ExpansionEngine>>,
+) {
+    let actions: Vec<Action> = actions.into_iter().flat_map(resolve_confirmation).flat_map(resolve_command).collect();
+    let trigger = actions.iter().find_map(|action| match action {
+        Action::InsertText(insertion) => insertion.trigger.clone(),
+        _ => None,
+    });
+    let injecting = actions.iter().any(|action| *action != Action::PassThrough);
+    let listening_off = injecting.then(listening::ListeningOff::new);
 
-        // Navigation keys — reset
-        KeyId::UpArrow | KeyId::DownArrow | KeyId::Escape | KeyId::Tab
-        | KeyId::PageDown | KeyId::PageUp | KeyId::Home | KeyId::End => {
-            expansion_data.reset();
-            return;
+    for action in actions {
+        if listening::cancelled() {
+            break;
         }
-
-        // Printable characters
-        KeyId::KeyA | KeyId::KeyB | KeyId::KeyC | KeyId::KeyD | KeyId::KeyE | KeyId::KeyF
-        | KeyId::KeyG | KeyId::KeyH | KeyId::KeyI | KeyId::KeyJ | KeyId::KeyK | KeyId::KeyL | KeyId::KeyM
-        | KeyId::KeyN | KeyId::KeyO | KeyId::KeyP | KeyId::KeyQ | KeyId::KeyR | KeyId::KeyS | KeyId::KeyT
-        | KeyId::KeyU | KeyId::KeyV | KeyId::KeyW | KeyId::KeyX | KeyId::KeyY | KeyId::KeyZ
-        | KeyId::Num0 | KeyId::Num1 | KeyId::Num2 | KeyId::Num3 | KeyId::Num4 | KeyId::Num5
-        | KeyId::Num6 | KeyId::Num7 | KeyId::Num8 | KeyId::Num9
-        | KeyId::Minus | KeyId::Equal | KeyId::LeftBracket | KeyId::RightBracket
-        | KeyId::Quote | KeyId::Comma | KeyId::Dot | KeyId::Slash
-        | KeyId::SemiColon | KeyId::BackSlash | KeyId::BackQuote => {
-            if matches!(expansion_data.typing_state, TypingState::NoMatch) {
-                expansion_data.reset();
+        let result = match action {
+            Action::PassThrough => {
+                reinject_key(key);
+                Ok(())
             }
-            expansion_data.set_typing_state(TypingState::Typing);
-            if let Some(c) = event_name {
-                debug_println!("{:?}", c);
-                debug_println!(
-                    "Char to push: '{}', len: {}, bytes: {:?}",
-                    c,
-                    c.len(),
-                    c.as_bytes()
-                );
-                expansion_data.push_to_buffer(&c);
-                debug_println!("{:?}", &expansion_data.key_buffer);
+            Action::DeleteChars(count) => at_stage("delete", delete_chars(count)),
+            Action::InsertText(insertion) => insert_text(insertion, engine_arc),
+            Action::TypeText(text) => at_stage("typing", BACKEND.inject_text(&text)),
+            Action::RunMacro(steps) => at_stage("macro", run_macro(&steps)),
+            Action::RunCommand { .. } | Action::Confirm { .. } => {
+                unreachable!("commands and confirmations are resolved before injecting")
             }
+            Action::CaretLeft(count) => at_stage("caret", BACKEND.caret_left(count)),
+            Action::CaretRight(count) => at_stage("caret", BACKEND.caret_right(count)),
+        };
+        if let Err(error) = result {
+            if listening::cancelled() {
+                println!("Expansion cancelled with Escape: {}", error);
+                log_info!("Expansion cancelled with Escape: {}", error);
+            } else {
+                println!("Error: expansion failed: {}", error);
+                log_error!("Expansion failed: {}", error);
+                let what = trigger.as_ref().map_or_else(|| "The expansion".to_string(), |trigger| format!("{:?}", trigger));
+                keyboard_hook::notify_error(&format!("{} failed at {}", what, error));
+            }
+            break;
         }
+    }
+    // Whatever was on the clipboard before goes back right away
+    if listening::cancelled() {
+        clipboard::restore_after_burst();
+    }
 
-        _ => {}
+    if injecting {
+        replay_buffered_keystrokes(receiver, engine_arc);
     }
+    drop(listening_off);
+    sync_tab_swallowing(&engine_arc.lock().unwrap());
 }
 
-fn handle_mouse_press(buffer: Arc<Mutex<ExpansionData>>, button: MouseButton) {
-    match button {
-        MouseButton::Left | MouseButton::Right | MouseButton::Middle => {
-            buffer.lock().unwrap().reset();
-            debug_println!("Mouse button pressed, buffer cleared");
-        }
+/// Ask before a `confirm` snippet expands, before listening goes off so the
+/// prompt gets the keys, and pick the actions for the answer.
+fn resolve_confirmation(action: Action) -> Vec<Action> {
+    let Action::Confirm { trigger, then, otherwise } = action else {
+        return vec![action];
+    };
+    let length = then.iter().find_map(|action| match action {
+        Action::InsertText(insertion) => Some(format!(" ({} characters)", insertion.text.chars().count())),
+        _ => None,
+    });
+    if fill_dialog::confirm(&format!("Expand {:?}{} here?", trigger, length.unwrap_or_default())) {
+        then
+    } else {
+        log_info!("Declined to expand {:?}", trigger);
+        otherwise
     }
 }
 
-fn load_expansion_table() -> Result<ExpansionFile, Box<dyn std::error::Error>> {
-    let path = env::current_exe()?
-        .parent()
-        .ok_or("Failed to get executable directory")?
-        .join("expansions.toml");
-
-    println!("Loading expansions from: {:?}", path);
+/// Run a `cmd` snippet's command, before listening goes off so keys typed
+/// while it runs aren't held up, and pick the actions for how it went. A
+/// failed command with a `fallback` inserts that instead.
+fn resolve_command(action: Action) -> Vec<Action> {
+    let Action::RunCommand { command, then, otherwise } = action else {
+        return vec![action];
+    };
+    let output = shell_command::run(&command).or_else(|error| {
+        println!("Error: command {:?} failed: {}", command.cmd, error);
+        log_error!("Command {:?} failed: {}", command.cmd, error);
+        command.fallback.clone().ok_or_else(|| {
+            keyboard_hook::notify_error(&format!("Command {:?} failed: {}", command.cmd, error));
+        })
+    });
+    match output {
+        Ok(output) => then
+            .into_iter()
+            .map(|action| match action {
+                Action::InsertText(insertion) => Action::InsertText(Insertion {
+                    text: insertion.text.replace(template::COMMAND_OUTPUT, &output),
+                    ..insertion
+                }),
+                action => action,
+            })
+            .collect(),
+        Err(()) => otherwise,
+    }
+}
 
-    let contents = std::fs::read_to_string(&path)?;
-    let expansion_file: ExpansionFile = toml::from_str(&contents)?;
+/// Prefix an error with the step of the expansion it came from (delete,
+/// clipboard, paste, typing, ...), for the log and the error balloon.
+fn at_stage<T, E: std::fmt::Display>(stage: &str, result: Result<T, E>) -> Result<T, Box<dyn std::error::Error>> {
+    result.map_err(|error| format!("{}: {}", stage, error).into())
+}
 
-    Ok(expansion_file)
+/// Send exactly `count` backspaces (the engine has already decided whether
+/// the delimiter is on screen), then give the target app time to process
+/// them.
+fn delete_chars(count: usize) -> Result<(), Box<dyn std::error::Error>> {
+    BACKEND.delete_chars(count)?;
+    debug_println!("deleted {} characters", count);
+    thread::sleep(Duration::from_millis(30));
+    Ok(())
 }
 
-fn check_for_completion(expansion_data: &ExpansionData) -> Option<(usize, String)> {
-    let buffer = &expansion_data.key_buffer;
+/// Insert snippet text through the selected backend: clipboard set + paste
+/// (the user's clipboard is restored once the burst of expansions goes idle,
+/// see clipboard.rs), or typed as Unicode key events.
+fn insert_text(insertion: Insertion, engine_arc: &Arc<Mutex<ExpansionEngine>>) -> Result<(), Box<dyn std::error::Error>> {
+    if let Some(path) = &insertion.image {
+        return insert_image(path, &insertion.text, insertion.undo_text.as_deref());
+    }
+    if insertion.resolved {
+        let completion = insertion.text.replace("\n", "\r\n");
+        return inject(&completion, None, insertion.injection, insertion.typing_delay_ms);
+    }
 
-    // Case-sensitive lookup first
-    if let Some(expansion) = expansion_data.expansion_table.case_sensitive.get(buffer) {
-        return Some((buffer.len(), expansion.clone()));
+    // Read the focused window before the fill-in dialog takes focus
+    let uses_window = template::uses_window(&insertion.text)
+        || insertion.html.as_deref().is_some_and(template::uses_window);
+    let window = if uses_window {
+        FocusedWindow { title: foreground::window_title(), app: foreground::exe_name().unwrap_or_default() }
+    } else {
+        FocusedWindow::default()
+    };
+
+    // Ask for `{{fill:...}}` fields first. Cancelling types back what the
+    // expansion replaced, as Backspace-to-undo would.
+    let fields = template::fill_fields(&insertion.text);
+    let fills = if fields.is_empty() {
+        Vec::new()
+    } else {
+        match at_stage("fill-in", listening::expected_wait(|| fill_dialog::prompt(&fields)))? {
+            Some(values) => fields.into_iter().zip(values).collect(),
+            None => {
+                debug_println!("Fill-in cancelled");
+                if let Some(undo_text) = &insertion.undo_text {
+                    at_stage("typing", BACKEND.inject_text(undo_text))?;
+                }
+                return Ok(());
+            }
+        }
+    };
+
+    // Resolve placeholders, then the fields and window, whose values are
+    // taken as they are; then strip the cursor marker or tab stops so the
+    // Left-arrow counts are taken on the text as it will appear. The
+    // clipboard text goes in last (before our own clipboard use below), so a
+    // copied `$|` stays text
+    let cursor_marker = engine_arc.lock().unwrap().settings().cursor_marker.clone();
+    let mut counted = HashMap::new();
+    let next_counter = |name: &str, start, step| {
+        let value = counters::next_value(name, start, step);
+        counted.insert(name.to_string(), value);
+        value
+    };
+    let standin = || template::CLIPBOARD_STANDIN.to_string();
+    let text = template::resolve_placeholders(&insertion.text, next_counter, standin);
+    let text = template::substitute_fills(&text, &fills);
+    let text = template::substitute_window(&text, &window);
+    let (text, stops) = if insertion.tab_stops {
+        template::take_tab_stops(&text)
+    } else {
+        let (text, caret_left) = template::take_cursor_marker(&text, &cursor_marker);
+        (text, vec![caret_left])
+    };
+    let (text, stops) = template::put_clipboard(&text, &stops, clipboard::user_text);
+    let (caret_left, later_stops) = (stops[0], stops[1..].to_vec());
+    let completion = text.replace("\n", "\r\n");
+
+    let html = insertion.html.as_deref().filter(|_| insertion.injection == Injection::Clipboard).map(|html| {
+        resolve_html(html, &counted, &fills, &window, insertion.tab_stops, &cursor_marker)
+    });
+    inject(&completion, html.as_deref(), insertion.injection, insertion.typing_delay_ms)?;
+
+    // Move the caret back to the cursor marker or first tab stop, if there
+    // was one. Left only — Up/Down would land in the wrong column on
+    // soft-wrapped lines.
+    if caret_left > 0 {
+        at_stage("caret", BACKEND.caret_left(caret_left))?;
     }
 
-    // Case-insensitive section — also matched by exact case (all triggers stored lowercase)
-    if let Some(expansion) = expansion_data.expansion_table.case_insensitive.get(buffer) {
-        return Some((buffer.len(), expansion.clone()));
+    // Tab visits the remaining stops. Otherwise arm Backspace-to-undo, but
+    // not after a cursor marker: the caret is no longer at the end of the
+    // inserted text.
+    let inserted_chars = template::typed_length(&completion);
+    let mut engine = engine_arc.lock().unwrap();
+    if !later_stops.is_empty() {
+        engine.start_tab_stops(later_stops, caret_left);
+    } else if let Some(undo_text) = insertion.undo_text.filter(|_| caret_left == 0) {
+        engine.record_expansion(inserted_chars, undo_text, Instant::now());
+    }
+    if let Some(trigger) = insertion.trigger {
+        if engine.settings().notify_on_expand {
+            keyboard_hook::notify(&format!("Expanded {:?} ({} chars)", trigger, inserted_chars));
+        }
+        // Kept as inserted, so a repeat gives the same dates and fill-ins
+        engine.record_history(HistoryEntry {
+            trigger,
+            text,
+            injection: insertion.injection,
+            typing_delay_ms: insertion.typing_delay_ms,
+            time: SystemTime::now(),
+        });
+        keyboard_hook::set_history(history_lines(&engine));
+        foreground::remember_target();
     }
 
-    None
+    Ok(())
 }
 
-/// Backspaces first, then clipboard set, then paste, then restore.
-/// Receives &Receiver to drain synthetic events before re-enabling listening.
-fn expand_trigger_phrase(
-    length: usize,
-    completion: String,
-    separator: &str,
-    receiver: &Receiver<HookMessage>,
-    expansion_data_arc: &Arc<Mutex<ExpansionData>>,
+/// Send expansion text (`\r\n` line breaks) through `injection`. Clipboard
+/// injection falls back to typing if pasting fails, or if the user's
+/// clipboard holds something a text snapshot can't restore.
+fn inject(
+    completion: &str,
+    html: Option<&str>,
+    injection: Injection,
+    typing_delay_ms: u64,
 ) -> Result<(), Box<dyn std::error::Error>> {
-    disable_keyboard_listening();
-
-    let completion = format!("{}{}", completion, separator);
-    let completion = completion.replace("\n", "\r\n");
-
-    // Step 1: Send backspaces (separator was swallowed by hook, so just trigger length)
-    windows_input::send_backspaces_fast(length)?;
-    debug_println!("deleted {} characters", length);
+    // A clipboard holding an image, a formatted range, etc. can't be
+    // restored from a text snapshot, so leave it alone.
+    let injection = if injection == Injection::Clipboard && clipboard::holds_non_text() {
+        debug_println!("Clipboard holds non-text data, typing the expansion instead");
+        Injection::Unicode
+    } else {
+        injection
+    };
+    match injection {
+        Injection::Clipboard => {
+            // The trigger is already gone, so a clipboard that stays busy
+            // still gets the expansion typed in
+            let pasted = paste_via_clipboard(|clipboard| clipboard::set_expansion_text(clipboard, completion, html));
+            if let Err(error) = pasted {
+                if listening::cancelled() {
+                    return Err(error);
+                }
+                println!("Warning: pasting failed ({}), typing the expansion instead", error);
+                log_warn!("Pasting failed ({}), typing the expansion instead", error);
+                at_stage("typing", BACKEND.inject_text(completion))?;
+            }
+        }
+        Injection::Unicode => at_stage("typing", BACKEND.inject_text(completion))?,
+        // Can rightly take longer than the listening watchdog allows
+        Injection::Typed => at_stage(
+            "typing",
+            listening::expected_wait(|| BACKEND.inject_text_slowly(completion, typing_delay_ms)),
+        )?,
+    }
 
-    // Step 2: Wait for target app to finish processing backspaces
-    thread::sleep(Duration::from_millis(30));
+    Ok(())
+}
 
-    // Step 3: Save old clipboard, set expansion text, verify it took
-    let mut clipboard = Clipboard::new()?;
-    let old_clipboard = clipboard.get_text().unwrap_or_default();
-    clipboard.set_text(completion.to_owned())?;
-
-    // Step 4: Verify clipboard actually updated before pasting.
-    // OleSetClipboard can return before the data is globally visible.
-    // Poll up to 25 times (2ms each = 50ms max), then proceed anyway.
-    for _ in 0..25 {
-        if let Ok(current) = clipboard.get_text() {
-            if current == completion {
-                break;
+/// Paste an `image` snippet's picture, then type `text` (the kept
+/// delimiter). A file that can't be loaded pastes nothing: `undo_text` puts
+/// back the trigger already deleted. There is no Backspace-to-undo.
+fn insert_image(path: &Path, text: &str, undo_text: Option<&str>) -> Result<(), Box<dyn std::error::Error>> {
+    // Relative paths are from the config file's folder
+    let path = config::config_path().and_then(Path::parent).map_or(path.to_path_buf(), |dir| dir.join(path));
+    let image = match image::open(&path) {
+        Ok(image) => image.into_rgba8(),
+        Err(error) => {
+            println!("Error: could not load image {}: {}", path.display(), error);
+            log_error!("Could not load image {}: {}", path.display(), error);
+            keyboard_hook::notify_error(&format!("Could not load image {}: {}", path.display(), error));
+            if let Some(undo_text) = undo_text {
+                at_stage("typing", BACKEND.inject_text(undo_text))?;
             }
+            return Ok(());
         }
-        thread::sleep(Duration::from_millis(2));
+    };
+    if clipboard::holds_non_text() {
+        log_warn!("Pasting an image over a clipboard that holds more than text; only its text will be restored");
     }
 
-    // Step 5: Paste
-    windows_input::send_ctrl_v()?;
+    let (width, height) = (image.width() as usize, image.height() as usize);
+    let image = arboard::ImageData { width, height, bytes: image.into_raw().into() };
+    paste_via_clipboard(|clipboard| clipboard::set_expansion_image(clipboard, image))?;
+    if !text.is_empty() {
+        at_stage("typing", BACKEND.inject_text(text))?;
+    }
+    Ok(())
+}
+
+/// A snippet's `html` with the same placeholder values as its text: the
+/// counters it already took, and escaped fill-in values, window title, and
+/// clipboard text (put in after the markers, as for the text).
+/// Markers are stripped; the caret is placed by the plain text.
+fn resolve_html(
+    html: &str,
+    counted: &HashMap<String, i64>,
+    fills: &[(String, String)],
+    window: &FocusedWindow,
+    tab_stops: bool,
+    cursor_marker: &str,
+) -> String {
+    let next_counter =
+        |name: &str, start, step| counted.get(name).copied().unwrap_or_else(|| counters::next_value(name, start, step));
+    let html = template::resolve_placeholders(html, next_counter, || template::CLIPBOARD_STANDIN.to_string());
+    let fills: Vec<(String, String)> =
+        fills.iter().map(|(name, value)| (name.clone(), template::escape_html(value))).collect();
+    let html = template::substitute_fills(&html, &fills);
+    let window = FocusedWindow {
+        title: template::escape_html(&window.title),
+        app: template::escape_html(&window.app),
+    };
+    let html = template::substitute_window(&html, &window);
+    let html = if tab_stops {
+        template::take_tab_stops(&html).0
+    } else {
+        template::take_cursor_marker(&html, cursor_marker).0
+    };
+    template::put_clipboard(&html, &[], || template::escape_html(&clipboard::user_text())).0
+}
+
+/// Clipboard backend: set the expansion with `set` (the user's clipboard is
+/// snapshotted only on the first expansion of a burst), paste it, and wait
+/// for the target app to take it.
+fn paste_via_clipboard(
+    set: impl FnOnce(&mut arboard::Clipboard) -> Result<(), Box<dyn std::error::Error>>,
+) -> Result<(), Box<dyn std::error::Error>> {
+    // Set the expansion; returns once the clipboard sequence number shows
+    // it is visible
+    let mut clipboard = at_stage("clipboard", clipboard::open())?;
+    at_stage("clipboard", set(&mut clipboard))?;
 
-    // Step 6: Wait for paste to complete — target app must process Ctrl+V
-    // from its message queue and read clipboard before we restore it.
-    // Chrome extension text boxes route paste through multi-process IPC
-    // (browser → renderer → extension) which can occasionally exceed 100ms.
-    thread::sleep(Duration::from_millis(150));
+    if listening::cancelled() {
+        return Err("cancelled before pasting".into());
+    }
+    at_stage("paste", BACKEND.paste())?;
 
-    // Step 7: Restore old clipboard
-    clipboard.set_text(old_clipboard)?;
+    // Wait for paste to complete — target app must process Ctrl+V from its
+    // message queue and read clipboard before we overwrite it. Reading
+    // doesn't change the sequence number, so there is no signal for this;
+    // `paste_settle_ms` is a fixed wait, and `clipboard_restore_delay_ms`
+    // before restoring adds further margin. Chrome extension text boxes
+    // route paste through multi-process IPC (browser → renderer →
+    // extension) which can occasionally exceed 100ms.
+    thread::sleep(clipboard::paste_settle());
 
-    // Step 8: Replay any keystrokes the user typed during expansion
-    replay_buffered_keystrokes(receiver, expansion_data_arc);
+    // Restore of the user's clipboard is deferred until the burst of
+    // expansions goes idle (see clipboard.rs)
+    clipboard::finish_job();
+
+    Ok(())
+}
 
-    // Step 9: Re-enable listening
-    enable_keyboard_listening();
+/// Run a `[macros]` entry's steps in order. The swallowed delimiter is
+/// consumed, as with expansions; a macro that wants it back types it with
+/// `text(...)`.
+fn run_macro(steps: &[MacroStep]) -> Result<(), Box<dyn std::error::Error>> {
+    for step in steps {
+        debug_println!("Macro step: {:?}", step);
+        match step {
+            MacroStep::Delete(count) => BACKEND.delete_chars(*count)?,
+            MacroStep::Key(keys) => BACKEND.send_chord(keys)?,
+            MacroStep::Text(text) => BACKEND.inject_text(text)?,
+            MacroStep::Date(format) => {
+                BACKEND.inject_text(&Local::now().format(format).to_string())?
+            }
+            MacroStep::Sleep(ms) => listening::expected_wait(|| thread::sleep(Duration::from_millis(*ms))),
+        }
+    }
 
     Ok(())
 }
 
-/// Replay keystrokes that were buffered during expansion.
-/// Re-injects them as synthetic key taps so the hook passes them to the target
-/// app, and also feeds printable characters through the state machine so the
-/// buffer tracks what was typed. Separators (Space/Enter) are only re-injected
-/// to avoid recursive expansion during replay.
+/// Replay keystrokes that were buffered during an injection by re-injecting
+/// them as synthetic key taps, which the hook passes on to the target app.
+/// All but Space and Enter are fed through the engine too, so a trigger typed
+/// while the expansion was going out is still tracked.
 fn replay_buffered_keystrokes(
     receiver: &Receiver<HookMessage>,
-    expansion_data_arc: &Arc<Mutex<ExpansionData>>,
+    engine_arc: &Arc<Mutex<ExpansionEngine>>,
 ) {
     let buffered: Vec<HookMessage> =
         std::iter::from_fn(|| receiver.try_recv().ok()).collect();
 
     for msg in buffered {
         match msg {
-            HookMessage::KeyDown { key, vk_code, scan_code } => {
-                // Re-inject to target app
-                let _ = windows_input::send_key_tap(vk_code as u16, scan_code as u16);
+            HookMessage::KeyDown { key, raw, time, shortcut, shift, .. } => {
+                // A double-tapped delimiter usually lands here, since the
+                // second press arrives while the expansion is still running
+                let mut engine = engine_arc.lock().unwrap();
+                let collapsed = match input_event(key, None, false, false) {
+                    InputEvent::Delimiter(delimiter) => engine.take_debounced_delimiter(delimiter, time),
+                    _ => false,
+                };
+                if collapsed {
+                    debug_println!("Collapsed repeated {:?} into the previous expansion", key);
+                    continue;
+                }
 
-                // Feed printable keys through the state machine so the buffer
-                // tracks them. Skip separators to avoid recursive expansion.
+                // Typing past the expansion ends the chance to undo it
+                engine.forget_last_expansion();
+                drop(engine);
+
+                if let Err(error) = BACKEND.resend_key(key) {
+                    log_error!("Replaying {:?} failed: {}", key, error);
+                }
+
+                // A trigger fired by Space or Enter here would expand inside
+                // the expansion still being finished, so those are only re-sent
                 if !matches!(key, KeyId::Space | KeyId::Return) {
-                    let event_name = keyboard_hook::resolve_character(vk_code, scan_code);
-                    handle_key_press(expansion_data_arc.clone(), key, event_name, receiver);
+                    let event_name = BACKEND.resolve_character(&raw);
+                    let event = input_event(key, event_name, shortcut, shift);
+                    let press = KeyPress { event, time, swallowed: false };
+                    let actions = engine_arc.lock().unwrap().handle(press, Instant::now(), &foreground::exe_name);
+                    run_actions(actions, key, receiver, engine_arc);
                 }
             }
             HookMessage::MouseDown(button) => {
-                handle_mouse_press(expansion_data_arc.clone(), button);
+                handle_mouse_press(engine_arc.clone(), button);
             }
-        }
-    }
-}
-
-/// Checks for date expansion triggers like "/days40", "/wks8", or "/mo3".
-fn handle_date_expansion(buffer: &str) -> Option<String> {
-    debug_println!("doing the date expansion thing!");
-
-    let (prefix, num_str) = if buffer.starts_with("/days") {
-        ("/days", &buffer[5..])
-    } else if buffer.starts_with("/wks") {
-        ("/wks", &buffer[4..])
-    } else if buffer.starts_with("/mo") {
-        ("/mo", &buffer[3..])
-    } else {
-        return None;
-    };
-
-    debug_println!("made it through 1st if: {prefix}, {num_str}");
-
-    if let Ok(num) = num_str.parse::<i64>() {
-        let current_date = Local::now();
-
-        let future_date = if prefix == "/mo" {
-            if num >= 0 {
-                current_date.checked_add_months(chrono::Months::new(num as u32))
-            } else {
-                current_date.checked_sub_months(chrono::Months::new((-num) as u32))
+            HookMessage::ReloadConfig => {
+                // Requested mid-expansion; the injection is done by now
+                apply_reloaded_table(engine_arc, config::reload_expansion_table());
+            }
+            HookMessage::InsertSnippet { trigger } => {
+                insert_from_search(engine_arc, &trigger, receiver);
+            }
+            HookMessage::ToggleGroup { group } => {
+                toggle_group(engine_arc, &group);
+            }
+            HookMessage::RepeatExpansion { index, refocus } => {
+                repeat_expansion(engine_arc, index, refocus, receiver);
+            }
+            HookMessage::ClearHistory => {
+                clear_history(engine_arc);
+            }
+            HookMessage::TransformSelection { index } => {
+                transform_selection(engine_arc, index, receiver);
             }
-        } else if prefix == "/days" {
-            current_date.checked_add_signed(chrono::Duration::days(num))
-        } else {
-            current_date.checked_add_signed(chrono::Duration::weeks(num))
-        };
-
-        if let Some(date) = future_date {
-            let formatted_with_padding = date.format("%m/%d/%y").to_string();
-            let parts: Vec<&str> = formatted_with_padding.split('/').collect();
-            let formatted = format!(
-                "{}/{}/{}",
-                parts[0].parse::<u32>().unwrap(),
-                parts[1].parse::<u32>().unwrap(),
-                parts[2]
-            );
-
-            debug_println!("formatted date str, returning: {formatted}");
-            return Some(formatted);
         }
     }
-
-    None
-}
-
-fn disable_keyboard_listening() {
-    GLOBAL_LISTENING.store(false, Ordering::SeqCst);
-}
-fn enable_keyboard_listening() {
-    GLOBAL_LISTENING.store(true, Ordering::SeqCst);
 }
@@ -0,0 +1,135 @@
+// src/non_windows.rs
+//
+// Stand-ins for the Windows-only modules main.rs talks to, for Linux and
+// macOS builds. Those get expansion through the rdev backend and nothing
+// around it: no tray icon or hotkeys, no fill-in dialog or search popup, and
+// no per-app rules. Errors and notices go to stdout and the log instead.
+
+pub mod keyboard_hook {
+    use std::sync::atomic::AtomicBool;
+    use std::time::Duration;
+
+    use text_expander::config::Hotkey;
+    use text_expander::{log_error, log_info, log_warn};
+
+    /// Only read by the Windows hook; rdev can't swallow Tab.
+    pub static SWALLOW_TAB: AtomicBool = AtomicBool::new(false);
+
+    /// Never set: there is no tray to pause from.
+    pub static EXPANSION_PAUSED: AtomicBool = AtomicBool::new(false);
+
+    pub fn set_toggle_hotkey(_hotkey: Option<Hotkey>) {}
+
+    pub fn set_search_hotkey(_hotkey: Option<Hotkey>) {}
+
+    pub fn set_repeat_hotkey(_hotkey: Option<Hotkey>) {}
+
+    pub fn set_transform_hotkeys(_hotkeys: &[Hotkey]) {}
+
+    pub fn set_groups(_groups: Vec<(String, bool)>) {}
+
+    pub fn set_history(_lines: Vec<String>) {}
+
+    pub fn set_remote_focused(_focused: bool) {}
+
+    pub fn show_error_box(text: &str) {
+        println!("Error: {}", text);
+        log_error!("{}", text);
+    }
+
+    pub fn show_warning_box(text: &str) {
+        println!("Warning: {}", text);
+        log_warn!("{}", text);
+    }
+
+    pub fn notify(text: &str) {
+        log_info!("{}", text);
+    }
+
+    pub fn notify_error(text: &str) {
+        println!("Error: {}", text);
+        log_error!("{}", text);
+    }
+
+    /// Only hotkeys need this, and there are none.
+    pub fn wait_for_modifiers_released(_limit: Duration) {}
+
+    pub fn wait_for_all_modifiers_released(_limit: Duration) {}
+
+    pub fn offer_autostart_update() {}
+
+    /// There is no window to post to; the second launch just exits.
+    pub fn ask_running_instance_to_reload() -> bool {
+        false
+    }
+}
+
+/// Nothing is known about the focused window, so `excluded_apps`,
+/// `excluded_classes`, `excluded_titles` and profiles never match, and
+/// `only_apps` matches nothing.
+pub mod foreground {
+    pub fn exe_name() -> Option<String> {
+        None
+    }
+
+    pub fn class_name() -> String {
+        String::new()
+    }
+
+    pub fn window_title() -> String {
+        String::new()
+    }
+
+    pub fn password_field_focused(_recheck: bool) -> bool {
+        false
+    }
+
+    pub fn remember_target() {}
+
+    pub fn refocus_last_target() {}
+
+    pub fn invalidate() {}
+}
+
+pub mod fill_dialog {
+    use text_expander::log_warn;
+
+    pub fn prompt(_fields: &[String]) -> Result<Option<Vec<String>>, Box<dyn std::error::Error>> {
+        Err("fill-in fields need the Windows dialog".into())
+    }
+
+    /// With no way to ask, a `confirm` snippet doesn't expand.
+    pub fn confirm(question: &str) -> bool {
+        log_warn!("Not expanding, there is no dialog to ask: {}", question);
+        false
+    }
+}
+
+pub mod search_popup {
+    use text_expander::search::SearchEntry;
+
+    pub fn set_entries(_entries: Vec<SearchEntry>) {}
+}
+
+/// A lock file in the temp directory instead of a named mutex. The OS drops
+/// the lock when the process exits, however it exits.
+pub mod single_instance {
+    use std::fs::{File, TryLockError};
+
+    /// Held by the running instance, like the Windows one.
+    pub struct InstanceLock {
+        _file: Option<File>,
+    }
+
+    /// Claim the lock file, or `None` if another instance holds it. Should
+    /// the file not open, this instance runs anyway.
+    pub fn acquire() -> Option<InstanceLock> {
+        let Ok(file) = File::create(std::env::temp_dir().join("text_expander_rs.lock")) else {
+            return Some(InstanceLock { _file: None });
+        };
+        match file.try_lock() {
+            Err(TryLockError::WouldBlock) => None,
+            _ => Some(InstanceLock { _file: Some(file) }),
+        }
+    }
+}
@@ -0,0 +1,114 @@
+// src/patterns.rs
+//
+// `[regex]` triggers: a pattern the typed word must match in full when a
+// delimiter is pressed, and a template its capture groups are put into
+// (`$1`, `$2`, ...; `$$` is a dollar sign). Patterns are compiled once at
+// load, together into a `RegexSet` that tells in one pass which of them
+// match, and only tried after the exact trigger tables miss.
+
+use std::collections::HashMap;
+
+use regex::{Captures, Regex, RegexSet};
+
+/// Longest word a pattern is tried on. A pattern can match any length, so
+/// while there are any the engine keeps this much of the typed word.
+pub const MAX_MATCH_CHARS: usize = 64;
+
+/// One `[regex]` entry, compiled.
+#[derive(Debug, Clone)]
+pub struct RegexTrigger {
+    /// As written in the file; stats count the trigger under it.
+    pub pattern: String,
+    /// `pattern`, anchored at both ends.
+    regex: Regex,
+    template: String,
+}
+
+/// Every `[regex]` entry, compiled, in pattern order.
+#[derive(Debug, Clone, Default)]
+pub struct RegexTriggers {
+    triggers: Vec<RegexTrigger>,
+    /// The anchored patterns of `triggers`, in the same order.
+    set: RegexSet,
+}
+
+/// Compile every `[regex]` entry, in pattern order. A pattern that doesn't
+/// compile fails the load.
+pub fn compile(entries: &HashMap<String, String>) -> Result<RegexTriggers, Box<dyn std::error::Error>> {
+    let mut entries: Vec<(&String, &String)> = entries.iter().collect();
+    entries.sort();
+    let triggers = entries
+        .into_iter()
+        .map(|(pattern, template)| {
+            let regex = Regex::new(&format!("^(?:{})$", pattern))
+                .map_err(|e| format!("[regex] {:?} is not a valid pattern: {}", pattern, e))?;
+            Ok(RegexTrigger { pattern: pattern.clone(), regex, template: template.clone() })
+        })
+        .collect::<Result<Vec<_>, Box<dyn std::error::Error>>>()?;
+    let set = RegexSet::new(triggers.iter().map(|trigger| trigger.regex.as_str()))?;
+    Ok(RegexTriggers { triggers, set })
+}
+
+impl RegexTriggers {
+    pub fn is_empty(&self) -> bool {
+        self.triggers.is_empty()
+    }
+
+    /// The first trigger that matches all of `typed`, and its expansion.
+    /// Only that one pattern is run again, for its groups.
+    pub fn find(&self, typed: &str) -> Option<(&RegexTrigger, String)> {
+        let index = self.set.matches(typed).into_iter().next()?;
+        let trigger = &self.triggers[index];
+        let captures = trigger.regex.captures(typed)?;
+        Some((trigger, expand(&trigger.template, &captures)))
+    }
+}
+
+/// `template` with each `$N` replaced by capture group N (empty if it took
+/// no part in the match) and `$$` by `$`. Any other `$` is kept.
+fn expand(template: &str, captures: &Captures) -> String {
+    let mut out = String::with_capacity(template.len());
+    let mut rest = template;
+    while let Some(dollar) = rest.find('$') {
+        out.push_str(&rest[..dollar]);
+        let after = &rest[dollar + 1..];
+        if let Some(after) = after.strip_prefix('$') {
+            out.push('$');
+            rest = after;
+            continue;
+        }
+        let digits = after.len() - after.trim_start_matches(|c: char| c.is_ascii_digit()).len();
+        match after[..digits].parse::<usize>() {
+            Ok(group) => out.push_str(captures.get(group).map_or("", |m| m.as_str())),
+            Err(_) => out.push('$'),
+        }
+        rest = &after[digits..];
+    }
+    out.push_str(rest);
+    out
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn patterns_match_the_whole_word_and_fill_their_groups() {
+        let entries = HashMap::from([
+            (r"rx(\d+)x(\d+)".to_string(), "Take $1 tablets $2 times daily".to_string()),
+            (r"^\$(\d+)(k)?$".to_string(), "$$$1,000 ($2)".to_string()),
+        ]);
+        let triggers = compile(&entries).unwrap();
+        let expand = |typed| triggers.find(typed).map(|(_, text)| text);
+
+        assert_eq!(expand("rx2x3").as_deref(), Some("Take 2 tablets 3 times daily"));
+        assert_eq!(expand("xrx2x3"), None, "anchored at the start");
+        assert_eq!(expand("rx2x3y"), None, "anchored at the end");
+        assert_eq!(expand("$5k").as_deref(), Some("$5,000 (k)"));
+        assert_eq!(expand("$5").as_deref(), Some("$5,000 ()"), "an unmatched group is empty");
+        assert!(compile(&HashMap::new()).unwrap().find("rx2x3").is_none());
+
+        let bad = HashMap::from([("rx(\\d+".to_string(), String::new())]);
+        assert!(compile(&bad).unwrap_err().to_string().contains("rx(\\\\d+"));
+    }
+}
@@ -0,0 +1,412 @@
+// src/rdev_backend.rs
+//
+// The input backend for Linux and macOS, and for Windows built with the
+// `rdev` feature: rdev's `listen` for keys and clicks, `simulate` for
+// everything sent.
+//
+// rdev can't block a key, so nothing is swallowed. A Space or Enter reaches
+// the app before the expansion and is deleted with the trigger, and keys
+// typed while an expansion goes out land as typed instead of being replayed
+// after it. `simulate` only presses physical keys, so typed injection is
+// limited to what a US layout has keys for; clipboard injection isn't.
+//
+// `listen` also reports the keys we simulate. Every press sent is counted in
+// `UNSEEN` and dropped when it comes back. A key the user types meanwhile can
+// be dropped in place of one of ours, so the count is given up after a quiet
+// spell rather than hiding the user's typing for long.
+
+use std::sync::atomic::{AtomicU64, AtomicUsize, Ordering};
+use std::sync::mpsc::Sender;
+use std::thread;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+use rdev::{Button, Event, EventType, Key};
+use text_expander::keys::KeyId;
+use text_expander::listening;
+
+use crate::input_backend::{HookMessage, InputBackend, MouseButton, RawKey};
+
+/// Pause after every simulated event. Apps, and macOS itself, drop or
+/// reorder events sent back to back.
+const EVENT_DELAY: Duration = Duration::from_millis(2);
+
+/// Presses still expected back are forgotten once nothing has been
+/// simulated for this long.
+const UNSEEN_TIMEOUT_MS: u64 = 500;
+
+/// Simulated presses `listen` hasn't reported back yet.
+static UNSEEN: AtomicUsize = AtomicUsize::new(0);
+
+/// When the last press was simulated, in milliseconds since the epoch.
+static LAST_SIMULATED_MS: AtomicU64 = AtomicU64::new(0);
+
+/// The modifier of the paste and copy shortcuts: Cmd on macOS, else Ctrl.
+#[cfg(target_os = "macos")]
+const SHORTCUT_MODIFIER: Key = Key::MetaLeft;
+#[cfg(not(target_os = "macos"))]
+const SHORTCUT_MODIFIER: Key = Key::ControlLeft;
+
+/// Every named key with rdev's name for it, for `key_id` and `rdev_key`.
+const RDEV_KEYS: [(KeyId, Key); 97] = [
+    (KeyId::Space, Key::Space),
+    (KeyId::Return, Key::Return),
+    (KeyId::Backspace, Key::Backspace),
+    (KeyId::Tab, Key::Tab),
+    (KeyId::Escape, Key::Escape),
+    (KeyId::Delete, Key::Delete),
+    (KeyId::Insert, Key::Insert),
+    (KeyId::LeftArrow, Key::LeftArrow),
+    (KeyId::RightArrow, Key::RightArrow),
+    (KeyId::UpArrow, Key::UpArrow),
+    (KeyId::DownArrow, Key::DownArrow),
+    (KeyId::Home, Key::Home),
+    (KeyId::End, Key::End),
+    (KeyId::PageUp, Key::PageUp),
+    (KeyId::PageDown, Key::PageDown),
+    (KeyId::ShiftLeft, Key::ShiftLeft),
+    (KeyId::ShiftRight, Key::ShiftRight),
+    (KeyId::ControlLeft, Key::ControlLeft),
+    (KeyId::ControlRight, Key::ControlRight),
+    (KeyId::Alt, Key::Alt),
+    (KeyId::AltGr, Key::AltGr),
+    (KeyId::MetaLeft, Key::MetaLeft),
+    (KeyId::MetaRight, Key::MetaRight),
+    (KeyId::KeyA, Key::KeyA),
+    (KeyId::KeyB, Key::KeyB),
+    (KeyId::KeyC, Key::KeyC),
+    (KeyId::KeyD, Key::KeyD),
+    (KeyId::KeyE, Key::KeyE),
+    (KeyId::KeyF, Key::KeyF),
+    (KeyId::KeyG, Key::KeyG),
+    (KeyId::KeyH, Key::KeyH),
+    (KeyId::KeyI, Key::KeyI),
+    (KeyId::KeyJ, Key::KeyJ),
+    (KeyId::KeyK, Key::KeyK),
+    (KeyId::KeyL, Key::KeyL),
+    (KeyId::KeyM, Key::KeyM),
+    (KeyId::KeyN, Key::KeyN),
+    (KeyId::KeyO, Key::KeyO),
+    (KeyId::KeyP, Key::KeyP),
+    (KeyId::KeyQ, Key::KeyQ),
+    (KeyId::KeyR, Key::KeyR),
+    (KeyId::KeyS, Key::KeyS),
+    (KeyId::KeyT, Key::KeyT),
+    (KeyId::KeyU, Key::KeyU),
+    (KeyId::KeyV, Key::KeyV),
+    (KeyId::KeyW, Key::KeyW),
+    (KeyId::KeyX, Key::KeyX),
+    (KeyId::KeyY, Key::KeyY),
+    (KeyId::KeyZ, Key::KeyZ),
+    (KeyId::Num0, Key::Num0),
+    (KeyId::Num1, Key::Num1),
+    (KeyId::Num2, Key::Num2),
+    (KeyId::Num3, Key::Num3),
+    (KeyId::Num4, Key::Num4),
+    (KeyId::Num5, Key::Num5),
+    (KeyId::Num6, Key::Num6),
+    (KeyId::Num7, Key::Num7),
+    (KeyId::Num8, Key::Num8),
+    (KeyId::Num9, Key::Num9),
+    (KeyId::Minus, Key::Minus),
+    (KeyId::Equal, Key::Equal),
+    (KeyId::LeftBracket, Key::LeftBracket),
+    (KeyId::RightBracket, Key::RightBracket),
+    (KeyId::Quote, Key::Quote),
+    (KeyId::Comma, Key::Comma),
+    (KeyId::Dot, Key::Dot),
+    (KeyId::Slash, Key::Slash),
+    (KeyId::SemiColon, Key::SemiColon),
+    (KeyId::BackSlash, Key::BackSlash),
+    (KeyId::BackQuote, Key::BackQuote),
+    (KeyId::Numpad0, Key::Kp0),
+    (KeyId::Numpad1, Key::Kp1),
+    (KeyId::Numpad2, Key::Kp2),
+    (KeyId::Numpad3, Key::Kp3),
+    (KeyId::Numpad4, Key::Kp4),
+    (KeyId::Numpad5, Key::Kp5),
+    (KeyId::Numpad6, Key::Kp6),
+    (KeyId::Numpad7, Key::Kp7),
+    (KeyId::Numpad8, Key::Kp8),
+    (KeyId::Numpad9, Key::Kp9),
+    (KeyId::NumpadDecimal, Key::KpDelete),
+    (KeyId::NumpadDivide, Key::KpDivide),
+    (KeyId::NumpadMultiply, Key::KpMultiply),
+    (KeyId::NumpadSubtract, Key::KpMinus),
+    (KeyId::NumpadAdd, Key::KpPlus),
+    (KeyId::F1, Key::F1),
+    (KeyId::F2, Key::F2),
+    (KeyId::F3, Key::F3),
+    (KeyId::F4, Key::F4),
+    (KeyId::F5, Key::F5),
+    (KeyId::F6, Key::F6),
+    (KeyId::F7, Key::F7),
+    (KeyId::F8, Key::F8),
+    (KeyId::F9, Key::F9),
+    (KeyId::F10, Key::F10),
+    (KeyId::F11, Key::F11),
+    (KeyId::F12, Key::F12),
+];
+
+/// The letter keys, a to z, for `us_key`.
+const LETTERS: [Key; 26] = [
+    Key::KeyA, Key::KeyB, Key::KeyC, Key::KeyD, Key::KeyE, Key::KeyF, Key::KeyG, Key::KeyH, Key::KeyI,
+    Key::KeyJ, Key::KeyK, Key::KeyL, Key::KeyM, Key::KeyN, Key::KeyO, Key::KeyP, Key::KeyQ, Key::KeyR,
+    Key::KeyS, Key::KeyT, Key::KeyU, Key::KeyV, Key::KeyW, Key::KeyX, Key::KeyY, Key::KeyZ,
+];
+
+/// The digit keys, 0 to 9, and what they type with Shift on a US layout.
+const DIGITS: [Key; 10] =
+    [Key::Num0, Key::Num1, Key::Num2, Key::Num3, Key::Num4, Key::Num5, Key::Num6, Key::Num7, Key::Num8, Key::Num9];
+const SHIFTED_DIGITS: &str = ")!@#$%^&*(";
+
+/// The other keys a US layout types with: unshifted, shifted.
+const PUNCTUATION: [(char, char, Key); 11] = [
+    ('`', '~', Key::BackQuote),
+    ('-', '_', Key::Minus),
+    ('=', '+', Key::Equal),
+    ('[', '{', Key::LeftBracket),
+    (']', '}', Key::RightBracket),
+    ('\\', '|', Key::BackSlash),
+    (';', ':', Key::SemiColon),
+    ('\'', '"', Key::Quote),
+    (',', '<', Key::Comma),
+    ('.', '>', Key::Dot),
+    ('/', '?', Key::Slash),
+];
+
+/// rdev's `listen` and `simulate`.
+pub struct RdevBackend;
+
+impl InputBackend for RdevBackend {
+    #[cfg(not(windows))]
+    fn run(&self, sender: Sender<HookMessage>) -> Result<(), Box<dyn std::error::Error>> {
+        listen(sender)
+    }
+
+    /// rdev's hooks run on their own thread; the tray stays for its menu.
+    #[cfg(windows)]
+    fn run(&self, sender: Sender<HookMessage>) -> Result<(), Box<dyn std::error::Error>> {
+        let keys = sender.clone();
+        thread::spawn(move || {
+            if let Err(error) = listen(keys) {
+                crate::keyboard_hook::show_error_box(&format!("Listening for keys failed: {}", error));
+            }
+        });
+        crate::keyboard_hook::run_tray(sender, false)
+    }
+
+    fn resolve_character(&self, raw: &RawKey) -> Option<String> {
+        match raw {
+            RawKey::Text(text) => text.clone(),
+            RawKey::Codes { .. } => None,
+        }
+    }
+
+    fn delete_chars(&self, count: usize) -> Result<(), Box<dyn std::error::Error>> {
+        (0..count).try_for_each(|_| chord(&[Key::Backspace]))
+    }
+
+    fn inject_text(&self, text: &str) -> Result<(), Box<dyn std::error::Error>> {
+        type_text(text, Duration::ZERO)
+    }
+
+    fn inject_text_slowly(&self, text: &str, delay_ms: u64) -> Result<(), Box<dyn std::error::Error>> {
+        type_text(text, Duration::from_millis(delay_ms))
+    }
+
+    fn caret_left(&self, count: usize) -> Result<(), Box<dyn std::error::Error>> {
+        (0..count).try_for_each(|_| chord(&[Key::LeftArrow]))
+    }
+
+    fn caret_right(&self, count: usize) -> Result<(), Box<dyn std::error::Error>> {
+        (0..count).try_for_each(|_| chord(&[Key::RightArrow]))
+    }
+
+    fn paste(&self) -> Result<(), Box<dyn std::error::Error>> {
+        chord(&[SHORTCUT_MODIFIER, Key::KeyV])
+    }
+
+    fn copy(&self) -> Result<(), Box<dyn std::error::Error>> {
+        chord(&[SHORTCUT_MODIFIER, Key::KeyC])
+    }
+
+    fn resend_key(&self, key: KeyId) -> Result<(), Box<dyn std::error::Error>> {
+        chord(&[rdev_key(key)])
+    }
+
+    fn send_chord(&self, keys: &[KeyId]) -> Result<(), Box<dyn std::error::Error>> {
+        let keys: Vec<Key> = keys.iter().map(|&key| rdev_key(key)).collect();
+        chord(&keys)
+    }
+}
+
+/// Send every key and click to `sender` until the app quits. On macOS this
+/// has to run on the main thread.
+fn listen(sender: Sender<HookMessage>) -> Result<(), Box<dyn std::error::Error>> {
+    let mut held: Vec<Key> = Vec::new();
+    rdev::listen(move |event| {
+        if let Some(message) = hook_message(event, &mut held) {
+            let _ = sender.send(message);
+        }
+    })
+    .map_err(|error| format!("rdev could not listen: {:?}", error))?;
+    Ok(())
+}
+
+/// What the processing thread gets to hear of `event`. `held` tracks the
+/// modifiers that are down, which rdev has no way to ask for.
+fn hook_message(event: Event, held: &mut Vec<Key>) -> Option<HookMessage> {
+    let key = match event.event_type {
+        EventType::KeyPress(key) => key,
+        EventType::KeyRelease(key) => {
+            held.retain(|&down| down != key);
+            return None;
+        }
+        EventType::ButtonPress(Button::Left) => return Some(HookMessage::MouseDown(MouseButton::Left)),
+        EventType::ButtonPress(Button::Right) => return Some(HookMessage::MouseDown(MouseButton::Right)),
+        EventType::ButtonPress(Button::Middle) => return Some(HookMessage::MouseDown(MouseButton::Middle)),
+        _ => return None,
+    };
+    if take_unseen() {
+        return None;
+    }
+
+    // Keys typed during an expansion have reached the app already; only
+    // Escape means anything, and cancels it
+    if !listening::is_listening() {
+        if key == Key::Escape {
+            listening::cancel();
+        }
+        return None;
+    }
+
+    let id = key_id(key);
+    if id.is_modifier() && !held.contains(&key) {
+        held.push(key);
+    }
+    let is_held = |keys: &[Key]| held.iter().any(|down| keys.contains(down));
+    let shortcut = !id.is_modifier()
+        && is_held(&[Key::ControlLeft, Key::ControlRight, Key::Alt, Key::MetaLeft, Key::MetaRight]);
+    let shift = is_held(&[Key::ShiftLeft, Key::ShiftRight]);
+
+    // Backspace, Return and Ctrl combos come with control characters
+    let text = event.name.filter(|name| !name.chars().all(char::is_control));
+    let time = event.time.duration_since(UNIX_EPOCH).map_or(0, |since| since.as_millis() as u32);
+    Some(HookMessage::KeyDown { key: id, raw: RawKey::Text(text), swallowed: false, time, shortcut, shift })
+}
+
+/// True if a press `listen` reports is one of ours coming back.
+fn take_unseen() -> bool {
+    if now_ms().saturating_sub(LAST_SIMULATED_MS.load(Ordering::SeqCst)) > UNSEEN_TIMEOUT_MS {
+        UNSEEN.store(0, Ordering::SeqCst);
+        return false;
+    }
+    UNSEEN.fetch_update(Ordering::SeqCst, Ordering::SeqCst, |unseen| unseen.checked_sub(1)).is_ok()
+}
+
+fn now_ms() -> u64 {
+    SystemTime::now().duration_since(UNIX_EPOCH).map_or(0, |since| since.as_millis() as u64)
+}
+
+/// Our name for an rdev key. Keys without one (CapsLock, say) come out as
+/// `Unknown(0)`; nothing sends those back.
+fn key_id(key: Key) -> KeyId {
+    match key {
+        Key::Unknown(code) => KeyId::Unknown(code),
+        // Numpad Enter is Return on Windows too
+        Key::KpReturn => KeyId::Return,
+        key => RDEV_KEYS.iter().find(|(_, rdev)| *rdev == key).map_or(KeyId::Unknown(0), |(id, _)| *id),
+    }
+}
+
+/// rdev's name for one of ours. Every named key is in `RDEV_KEYS`.
+fn rdev_key(key: KeyId) -> Key {
+    match key {
+        KeyId::Unknown(code) => Key::Unknown(code),
+        key => RDEV_KEYS.iter().find(|(id, _)| *id == key).map_or(Key::Unknown(0), |(_, rdev)| *rdev),
+    }
+}
+
+/// The key that types `c` on a US layout, and whether it needs Shift.
+fn us_key(c: char) -> Option<(Key, bool)> {
+    if c.is_ascii_lowercase() {
+        return Some((LETTERS[(c as u8 - b'a') as usize], false));
+    }
+    if c.is_ascii_uppercase() {
+        return Some((LETTERS[(c as u8 - b'A') as usize], true));
+    }
+    if let Some(digit) = c.to_digit(10) {
+        return Some((DIGITS[digit as usize], false));
+    }
+    if let Some(digit) = SHIFTED_DIGITS.find(c) {
+        return Some((DIGITS[digit], true));
+    }
+    match c {
+        ' ' => Some((Key::Space, false)),
+        '\n' => Some((Key::Return, false)),
+        '\t' => Some((Key::Tab, false)),
+        c => PUNCTUATION.iter().find_map(|&(plain, shifted, key)| {
+            if c == plain {
+                Some((key, false))
+            } else {
+                (c == shifted).then_some((key, true))
+            }
+        }),
+    }
+}
+
+/// Type `text` key by key, `delay` apart. `\r` is dropped, so `\r\n` and
+/// `\n` both give one line break. Nothing is typed if any character has no
+/// key; Escape stops it between keys.
+fn type_text(text: &str, delay: Duration) -> Result<(), Box<dyn std::error::Error>> {
+    let keys = text
+        .chars()
+        .filter(|&c| c != '\r')
+        .map(|c| {
+            us_key(c).ok_or_else(|| {
+                format!("{:?} can't be typed here, only US keyboard characters; use clipboard injection", c)
+            })
+        })
+        .collect::<Result<Vec<_>, _>>()?;
+
+    for (key, shift) in keys {
+        if listening::cancelled() {
+            break;
+        }
+        if shift {
+            chord(&[Key::ShiftLeft, key])?;
+        } else {
+            chord(&[key])?;
+        }
+        thread::sleep(delay);
+    }
+    Ok(())
+}
+
+/// Press `keys` in order, then release them in reverse.
+fn chord(keys: &[Key]) -> Result<(), Box<dyn std::error::Error>> {
+    for &key in keys {
+        send(EventType::KeyPress(key))?;
+    }
+    for &key in keys.iter().rev() {
+        send(EventType::KeyRelease(key))?;
+    }
+    Ok(())
+}
+
+/// Simulate one event, counting presses in `UNSEEN`.
+fn send(event: EventType) -> Result<(), Box<dyn std::error::Error>> {
+    let press = matches!(event, EventType::KeyPress(_));
+    if press {
+        LAST_SIMULATED_MS.store(now_ms(), Ordering::SeqCst);
+        UNSEEN.fetch_add(1, Ordering::SeqCst);
+    }
+    if let Err(error) = rdev::simulate(&event) {
+        if press {
+            let _ = UNSEEN.fetch_update(Ordering::SeqCst, Ordering::SeqCst, |unseen| unseen.checked_sub(1));
+        }
+        return Err(format!("{} ({:?})", error, event).into());
+    }
+    thread::sleep(EVENT_DELAY);
+    Ok(())
+}
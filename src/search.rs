@@ -0,0 +1,129 @@
+// src/search.rs
+//
+// Ranking for the quick-search popup (`search_hotkey`): which snippets match
+// what has been typed into it, best first. Matching is fuzzy: the query's
+// characters must appear in order in the trigger or the expansion, and
+// matches in the trigger, near the start, on word starts, and in runs rank
+// higher.
+
+use crate::config::{ExpansionFile, Snippet};
+
+/// Expansions are shown on one line, cut to this many characters.
+const PREVIEW_CHARS: usize = 60;
+
+/// One line of the popup's list.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct SearchEntry {
+    /// As stored: case_insensitive triggers are lowercase.
+    pub trigger: String,
+    /// What the snippet inserts, as written (or its command or picture).
+    pub expansion: String,
+}
+
+/// The snippets of the global tables, by trigger. Per-app profiles and
+/// macros aren't listed.
+pub fn entries(file: &ExpansionFile) -> Vec<SearchEntry> {
+    let mut entries: Vec<SearchEntry> = file
+        .case_sensitive
+        .iter()
+        .chain(&file.case_insensitive)
+        .map(|(trigger, snippet)| SearchEntry { trigger: trigger.clone(), expansion: describe(snippet) })
+        .collect();
+    entries.sort_by(|a, b| a.trigger.cmp(&b.trigger));
+    entries.dedup_by(|a, b| a.trigger == b.trigger);
+    entries
+}
+
+fn describe(snippet: &Snippet) -> String {
+    if let Some(command) = &snippet.options.command {
+        return format!("$ {}", command.cmd);
+    }
+    match &snippet.options.image {
+        Some(image) => format!("[image] {}", image.display()),
+        None => snippet.replace.clone(),
+    }
+}
+
+/// Indexes into `entries` that match `query`, best first; ties keep their
+/// order. An empty query matches everything.
+pub fn search(entries: &[SearchEntry], query: &str) -> Vec<usize> {
+    let query: Vec<char> = query.trim().to_lowercase().chars().collect();
+    let mut scored: Vec<(i64, usize)> = entries
+        .iter()
+        .enumerate()
+        .filter_map(|(index, entry)| {
+            // A trigger match counts double: that's what the user remembers
+            let in_trigger = fuzzy_score(&entry.trigger, &query).map(|score| score * 2);
+            let in_expansion = fuzzy_score(&entry.expansion, &query);
+            Some((in_trigger.max(in_expansion)?, index))
+        })
+        .collect();
+    scored.sort_by_key(|&(score, index)| (-score, index));
+    scored.into_iter().map(|(_, index)| index).collect()
+}
+
+/// How well `query` (lowercase) matches `text` as a subsequence, taking the
+/// first occurrence of each character; `None` if it doesn't.
+fn fuzzy_score(text: &str, query: &[char]) -> Option<i64> {
+    let mut score = 0;
+    let mut wanted = query.iter().peekable();
+    let mut previous: Option<char> = None;
+    let mut previous_matched = false;
+    for (position, c) in text.chars().flat_map(char::to_lowercase).enumerate() {
+        let Some(&&next) = wanted.peek() else {
+            break;
+        };
+        let matched = c == next;
+        if matched {
+            wanted.next();
+            score += 10;
+            if previous_matched {
+                score += 15;
+            }
+            if previous.is_none_or(|previous| !previous.is_alphanumeric()) {
+                score += 10;
+            }
+            score -= position.min(20) as i64;
+        }
+        previous_matched = matched;
+        previous = Some(c);
+    }
+    wanted.peek().is_none().then_some(score)
+}
+
+/// `expansion` on one line, cut to `PREVIEW_CHARS`, for the popup's list and
+/// the tray's History menu.
+pub fn preview(expansion: &str) -> String {
+    let line = expansion.split_whitespace().collect::<Vec<_>>().join(" ");
+    match line.char_indices().nth(PREVIEW_CHARS) {
+        Some((cut, _)) => format!("{}…", &line[..cut]),
+        None => line,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn search_ranks_trigger_and_contiguous_matches_first() {
+        let entry = |trigger: &str, expansion: &str| SearchEntry {
+            trigger: trigger.to_string(),
+            expansion: expansion.to_string(),
+        };
+        let entries = [
+            entry("addr", "1 Main St"),
+            entry("btw", "by the way"),
+            entry("sig", "Best regards, Zach"),
+            entry("zsig", "Zach, Example Corp"),
+        ];
+
+        let found = |query| search(&entries, query).into_iter().map(|i| entries[i].trigger.as_str()).collect::<Vec<_>>();
+        assert_eq!(found(""), ["addr", "btw", "sig", "zsig"]);
+        assert_eq!(found("sig"), ["sig", "zsig"]);
+        assert_eq!(found("zach"), ["zsig", "sig"], "a match at the start beats one mid-text");
+        assert_eq!(found("bytheway"), ["btw"], "spaces in the text may be skipped");
+        assert_eq!(found("MAIN"), ["addr"]);
+        assert!(found("qq").is_empty());
+    }
+}
@@ -0,0 +1,274 @@
+// src/search_popup.rs
+//
+// The quick-search popup (`search_hotkey`): a search box over the snippet
+// list, narrowed as you type (`search::search`). Up/Down move the selection,
+// Enter inserts it into the window that had focus before, and Escape or
+// clicking away closes it. It lives on the hook thread beside the tray window
+// and shares its message loop, which hands it the keys it acts on
+// (`handle_key`); while it is open the hook lets every key through to it.
+
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::{Mutex, Once};
+use std::{mem, ptr};
+
+use winapi::shared::minwindef::{FALSE, HIWORD, LOWORD, LPARAM, LRESULT, UINT, WPARAM};
+use winapi::shared::windef::{HWND, RECT};
+use winapi::um::libloaderapi::GetModuleHandleW;
+use winapi::um::winuser::{
+    AdjustWindowRectEx, CreateWindowExW, DefWindowProcW, DestroyWindow, GetForegroundWindow, GetParent,
+    GetSystemMetrics, GetWindowTextLengthW, GetWindowTextW, PostMessageW, RegisterClassW, SendMessageW, SetFocus,
+    ShowWindow, COLOR_BTNFACE, EN_CHANGE, ES_AUTOHSCROLL, LBN_DBLCLK, LBS_NOINTEGRALHEIGHT, LBS_NOTIFY,
+    LB_ADDSTRING, LB_GETCOUNT, LB_GETCURSEL, LB_RESETCONTENT, LB_SETCURSEL, MSG, SM_CXSCREEN, SM_CYSCREEN,
+    SW_SHOW, VK_DOWN, VK_ESCAPE, VK_RETURN, VK_UP, WA_INACTIVE, WM_ACTIVATE, WM_CLOSE, WM_COMMAND, WM_DESTROY,
+    WM_KEYDOWN, WNDCLASSW, WS_CAPTION, WS_EX_CLIENTEDGE, WS_EX_DLGMODALFRAME, WS_EX_TOOLWINDOW, WS_EX_TOPMOST,
+    WS_POPUP, WS_SYSMENU, WS_TABSTOP, WS_VSCROLL,
+};
+
+use crate::fill_dialog::{add_control, bring_to_front};
+use crate::keyboard_hook::{self, encode_wide};
+use crate::search::{self, SearchEntry};
+
+const CLASS_NAME: &str = "TextExpanderSearchPopup";
+
+// Layout, in pixels
+const MARGIN: i32 = 10;
+const WIDTH: i32 = 460;
+const EDIT_HEIGHT: i32 = 24;
+const LIST_HEIGHT: i32 = 280;
+
+const ID_EDIT: i32 = 100;
+const ID_LIST: i32 = 101;
+
+/// The snippets to search, replaced by the processing thread whenever the
+/// table is loaded.
+static ENTRIES: Mutex<Vec<SearchEntry>> = Mutex::new(Vec::new());
+
+/// The open popup. HWNDs as integers so this can live in a static.
+struct Popup {
+    hwnd: usize,
+    edit: usize,
+    list: usize,
+    /// The window to insert into.
+    target: usize,
+    /// Trigger of each list line, in order.
+    shown: Vec<String>,
+}
+
+static POPUP: Mutex<Option<Popup>> = Mutex::new(None);
+
+/// Read by the keyboard hook, which can't wait on the `POPUP` lock.
+static OPEN: AtomicBool = AtomicBool::new(false);
+
+static REGISTER_CLASS: Once = Once::new();
+
+pub fn set_entries(entries: Vec<SearchEntry>) {
+    *ENTRIES.lock().unwrap() = entries;
+}
+
+pub fn is_open() -> bool {
+    OPEN.load(Ordering::SeqCst)
+}
+
+/// Open the popup over the focused window, or bring it back to the front if
+/// it is already open. Called on the hook thread.
+pub unsafe fn show() {
+    let open = POPUP.lock().unwrap().as_ref().map(|popup| popup.hwnd);
+    if let Some(hwnd) = open {
+        unsafe { bring_to_front(hwnd as HWND) };
+        return;
+    }
+
+    unsafe {
+        let target = GetForegroundWindow();
+        let h_instance = GetModuleHandleW(ptr::null());
+        let class_name = encode_wide(CLASS_NAME);
+        REGISTER_CLASS.call_once(|| {
+            let wc = WNDCLASSW {
+                lpfnWndProc: Some(popup_proc),
+                hInstance: h_instance,
+                lpszClassName: class_name.as_ptr(),
+                hbrBackground: (COLOR_BTNFACE + 1) as usize as _,
+                ..mem::zeroed()
+            };
+            RegisterClassW(&wc);
+        });
+
+        let client_width = WIDTH;
+        let list_top = MARGIN * 2 + EDIT_HEIGHT;
+        let client_height = list_top + LIST_HEIGHT + MARGIN;
+        let style = WS_POPUP | WS_CAPTION | WS_SYSMENU;
+        let ex_style = WS_EX_DLGMODALFRAME | WS_EX_TOPMOST | WS_EX_TOOLWINDOW;
+        let mut rect = RECT { left: 0, top: 0, right: client_width, bottom: client_height };
+        AdjustWindowRectEx(&mut rect, style, FALSE, ex_style);
+        let (width, height) = (rect.right - rect.left, rect.bottom - rect.top);
+
+        let hwnd = CreateWindowExW(
+            ex_style,
+            class_name.as_ptr(),
+            encode_wide("Insert snippet").as_ptr(),
+            style,
+            (GetSystemMetrics(SM_CXSCREEN) - width) / 2,
+            (GetSystemMetrics(SM_CYSCREEN) - height) / 3,
+            width,
+            height,
+            ptr::null_mut(),
+            ptr::null_mut(),
+            h_instance,
+            ptr::null_mut(),
+        );
+        if hwnd.is_null() {
+            println!("Error: failed to create the search popup");
+            crate::log_error!("Failed to create the search popup");
+            return;
+        }
+
+        let inner_width = client_width - MARGIN * 2;
+        let edit_rect = (MARGIN, MARGIN, inner_width, EDIT_HEIGHT);
+        let edit = add_control(hwnd, WS_EX_CLIENTEDGE, "EDIT", "", WS_TABSTOP | ES_AUTOHSCROLL, edit_rect, ID_EDIT);
+        let list_style = WS_TABSTOP | WS_VSCROLL | LBS_NOTIFY | LBS_NOINTEGRALHEIGHT;
+        let list_rect = (MARGIN, list_top, inner_width, LIST_HEIGHT);
+        let list = add_control(hwnd, WS_EX_CLIENTEDGE, "LISTBOX", "", list_style, list_rect, ID_LIST);
+
+        *POPUP.lock().unwrap() = Some(Popup {
+            hwnd: hwnd as usize,
+            edit: edit as usize,
+            list: list as usize,
+            target: target as usize,
+            shown: Vec::new(),
+        });
+        OPEN.store(true, Ordering::SeqCst);
+        refresh();
+
+        ShowWindow(hwnd, SW_SHOW);
+        bring_to_front(hwnd);
+        SetFocus(edit);
+    }
+}
+
+/// Act on Up, Down, Enter, and Escape pressed in the popup. Called by the
+/// hook thread's message loop for every message; true if it was handled.
+pub unsafe fn handle_key(msg: &MSG) -> bool {
+    if msg.message != WM_KEYDOWN || !is_open() {
+        return false;
+    }
+    let Some((hwnd, list)) = POPUP.lock().unwrap().as_ref().map(|popup| (popup.hwnd as HWND, popup.list as HWND))
+    else {
+        return false;
+    };
+    if msg.hwnd != hwnd && unsafe { GetParent(msg.hwnd) } != hwnd {
+        return false;
+    }
+
+    unsafe {
+        match msg.wParam as i32 {
+            VK_UP | VK_DOWN => {
+                let count = SendMessageW(list, LB_GETCOUNT, 0, 0);
+                let selected = SendMessageW(list, LB_GETCURSEL, 0, 0);
+                let step = if msg.wParam as i32 == VK_UP { -1 } else { 1 };
+                if count > 0 {
+                    SendMessageW(list, LB_SETCURSEL, (selected + step).clamp(0, count - 1) as WPARAM, 0);
+                }
+                true
+            }
+            VK_RETURN => {
+                choose();
+                true
+            }
+            VK_ESCAPE => {
+                DestroyWindow(hwnd);
+                true
+            }
+            _ => false,
+        }
+    }
+}
+
+/// Fill the list with the snippets matching the search box, best first,
+/// and select the first.
+unsafe fn refresh() {
+    let Some((edit, list)) = POPUP.lock().unwrap().as_ref().map(|popup| (popup.edit as HWND, popup.list as HWND))
+    else {
+        return;
+    };
+
+    let query = unsafe {
+        let mut buf = vec![0u16; GetWindowTextLengthW(edit) as usize + 1];
+        let len = GetWindowTextW(edit, buf.as_mut_ptr(), buf.len() as i32);
+        String::from_utf16_lossy(&buf[..len.max(0) as usize])
+    };
+    let lines: Vec<(String, String)> = {
+        let entries = ENTRIES.lock().unwrap();
+        search::search(&entries, &query)
+            .into_iter()
+            .map(|index| {
+                let entry = &entries[index];
+                (entry.trigger.clone(), format!("{}    {}", entry.trigger, search::preview(&entry.expansion)))
+            })
+            .collect()
+    };
+
+    unsafe {
+        SendMessageW(list, LB_RESETCONTENT, 0, 0);
+        for (_, line) in &lines {
+            SendMessageW(list, LB_ADDSTRING, 0, encode_wide(line).as_ptr() as LPARAM);
+        }
+        SendMessageW(list, LB_SETCURSEL, 0, 0);
+    }
+    if let Some(popup) = POPUP.lock().unwrap().as_mut() {
+        popup.shown = lines.into_iter().map(|(trigger, _)| trigger).collect();
+    }
+}
+
+/// Close the popup, give focus back to the window it was opened over, and
+/// have the processing thread insert the selected snippet there.
+unsafe fn choose() {
+    let Some((hwnd, list, target, shown)) = POPUP
+        .lock()
+        .unwrap()
+        .as_ref()
+        .map(|popup| (popup.hwnd as HWND, popup.list as HWND, popup.target as HWND, popup.shown.clone()))
+    else {
+        return;
+    };
+
+    unsafe {
+        let selected = SendMessageW(list, LB_GETCURSEL, 0, 0);
+        let trigger = usize::try_from(selected).ok().and_then(|selected| shown.get(selected).cloned());
+        DestroyWindow(hwnd);
+        let Some(trigger) = trigger else {
+            return;
+        };
+        if !target.is_null() {
+            bring_to_front(target);
+        }
+        keyboard_hook::request_insert(trigger);
+    }
+}
+
+unsafe extern "system" fn popup_proc(hwnd: HWND, msg: UINT, w_param: WPARAM, l_param: LPARAM) -> LRESULT {
+    unsafe {
+        match msg {
+            WM_COMMAND => {
+                let (id, code) = (LOWORD(w_param as u32) as i32, HIWORD(w_param as u32));
+                if id == ID_EDIT && code == EN_CHANGE {
+                    refresh();
+                } else if id == ID_LIST && code == LBN_DBLCLK {
+                    choose();
+                }
+                0
+            }
+            // Clicking elsewhere closes it, after the activation is done
+            WM_ACTIVATE if LOWORD(w_param as u32) == WA_INACTIVE => {
+                PostMessageW(hwnd, WM_CLOSE, 0, 0);
+                0
+            }
+            WM_DESTROY => {
+                *POPUP.lock().unwrap() = None;
+                OPEN.store(false, Ordering::SeqCst);
+                0
+            }
+            // WM_CLOSE (the title bar X) destroys the window
+            _ => DefWindowProcW(hwnd, msg, w_param, l_param),
+        }
+    }
+}
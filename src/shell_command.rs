@@ -0,0 +1,79 @@
+// src/shell_command.rs
+//
+// Runs `cmd` snippets. The command goes through `cmd /C` without a console
+// window (`sh -c` off Windows), and its stdout becomes the expansion. It runs on the processing
+// thread before anything is deleted, with listening still on, so a slow
+// command holds up only its own expansion.
+
+use std::io::Read;
+#[cfg(windows)]
+use std::os::windows::process::CommandExt;
+use std::process::{Command, Stdio};
+use std::thread;
+use std::time::{Duration, Instant};
+
+use text_expander::config::ShellCommand;
+use text_expander::debug_println;
+
+/// Keeps `cmd /C` from flashing a console window.
+#[cfg(windows)]
+const CREATE_NO_WINDOW: u32 = 0x0800_0000;
+
+/// How often a running command is checked on.
+const POLL_INTERVAL: Duration = Duration::from_millis(10);
+
+/// Run `command` and return its stdout without the trailing newline. A
+/// non-zero exit is an error, and a command still running after its
+/// `timeout_ms` is killed.
+pub fn run(command: &ShellCommand) -> Result<String, Box<dyn std::error::Error>> {
+    debug_println!("Running command: {:?}", command.cmd);
+    let mut child = shell(&command.cmd)
+        .stdin(Stdio::null())
+        .stdout(Stdio::piped())
+        .stderr(Stdio::null())
+        .spawn()?;
+
+    // Drain stdout while waiting, or a chatty command fills the pipe and
+    // never exits
+    let mut stdout = child.stdout.take().ok_or("command has no stdout")?;
+    let reader = thread::spawn(move || {
+        let mut output = Vec::new();
+        stdout.read_to_end(&mut output).map(|_| output)
+    });
+
+    let deadline = Instant::now() + Duration::from_millis(command.timeout_ms);
+    let status = loop {
+        if let Some(status) = child.try_wait()? {
+            break status;
+        }
+        if Instant::now() >= deadline {
+            let _ = child.kill();
+            let _ = child.wait();
+            return Err(format!("timed out after {} ms", command.timeout_ms).into());
+        }
+        thread::sleep(POLL_INTERVAL);
+    };
+    if !status.success() {
+        return Err(format!("exited with {}", status).into());
+    }
+
+    let output = reader.join().map_err(|_| "reading the command's output failed")??;
+    let output = String::from_utf8_lossy(&output);
+    let output = output.strip_suffix('\n').map_or(&*output, |line| line.strip_suffix('\r').unwrap_or(line));
+    Ok(output.to_string())
+}
+
+/// `cmd /C cmd`, passed on as typed: cmd has its own quoting rules.
+#[cfg(windows)]
+fn shell(cmd: &str) -> Command {
+    let mut shell = Command::new("cmd");
+    shell.arg("/C").raw_arg(cmd).creation_flags(CREATE_NO_WINDOW);
+    shell
+}
+
+#[cfg(not(windows))]
+fn shell(cmd: &str) -> Command {
+    let mut shell = Command::new("sh");
+    shell.arg("-c").arg(cmd);
+    shell
+}
@@ -0,0 +1,142 @@
+// src/simulate.rs
+//
+// `--simulate <script>`: replay a keystroke script through the same engine
+// the hooks drive and print what each step does, without hooks or any input
+// sent. A script is one event per line (`char a`, `backspace`, `left`,
+// `space`, `enter`, `click`, ...); blank lines and `#` comments are skipped.
+
+use std::time::{Duration, Instant};
+
+use crate::engine::{Action, Delimiter, ExpansionEngine, InputEvent, KeyPress};
+use crate::template;
+
+/// One script line: the event and how it was written, for the report.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Step {
+    pub name: String,
+    pub event: InputEvent,
+}
+
+/// The steps of `script`. `type <text>` is one `char` step per character.
+/// The error names the line.
+pub fn parse_script(script: &str) -> Result<Vec<Step>, String> {
+    let mut steps = Vec::new();
+    for (number, line) in script.lines().enumerate() {
+        let line = line.trim();
+        if line.is_empty() || line.starts_with('#') {
+            continue;
+        }
+        let (word, rest) = line.split_once(char::is_whitespace).unwrap_or((line, ""));
+        let rest = rest.trim();
+        let step = |event| Step { name: word.to_string(), event };
+        match (word, rest) {
+            ("char", text) if !text.is_empty() => {
+                steps.push(Step { name: format!("char {}", text), event: InputEvent::Char(Some(text.to_string())) })
+            }
+            ("type", text) if !text.is_empty() => steps.extend(text.chars().map(|c| Step {
+                name: format!("char {}", c),
+                event: InputEvent::Char(Some(c.to_string())),
+            })),
+            ("space", "") => steps.push(step(InputEvent::Delimiter(Delimiter::Space))),
+            ("enter", "") => steps.push(step(InputEvent::Delimiter(Delimiter::Enter))),
+            ("tab", "") => steps.push(step(InputEvent::Tab)),
+            ("backspace", "") => steps.push(step(InputEvent::Backspace)),
+            ("delete", "") => steps.push(step(InputEvent::Delete)),
+            ("left", "") => steps.push(step(InputEvent::ArrowLeft)),
+            ("right", "") => steps.push(step(InputEvent::ArrowRight)),
+            ("up" | "down" | "home" | "end" | "pageup" | "pagedown" | "escape", "") => {
+                steps.push(step(InputEvent::Navigation))
+            }
+            ("select", "") => steps.push(step(InputEvent::Select)),
+            ("shortcut", "") => steps.push(step(InputEvent::Shortcut)),
+            ("click", "") => steps.push(step(InputEvent::MouseClick)),
+            _ => return Err(format!("line {}: unknown event {:?}", number + 1, line)),
+        }
+    }
+    Ok(steps)
+}
+
+/// Feed `steps` to `engine` a second apart, swallowing keys the way the hook
+/// does, and describe each: its actions, then the buffer. Commands aren't
+/// run and confirmations are taken as yes; both are reported.
+pub fn run(engine: &mut ExpansionEngine, steps: &[Step]) -> Vec<String> {
+    let start = Instant::now();
+    let width = steps.iter().map(|step| step.name.chars().count()).max().unwrap_or(0);
+    let mut lines = Vec::new();
+    for (i, step) in steps.iter().enumerate() {
+        let now = start + Duration::from_secs(i as u64);
+        let swallowed = match step.event {
+            InputEvent::Delimiter(_) => true,
+            InputEvent::Tab => engine.wants_tab(),
+            _ => false,
+        };
+        let press = KeyPress { event: step.event.clone(), time: 1000 * i as u32, swallowed };
+        let mut described = Vec::new();
+        let actions = engine.handle(press, now, &|| None);
+        describe(engine, actions, &step.name, now, &mut described);
+        described.push(format!("buffer {:?}", engine.buffer()));
+        lines.push(format!("{:<width$}  {}", step.name, described.join(", "), width = width));
+    }
+    lines
+}
+
+/// Append `actions` to `described`, arming undo and tab stops after an
+/// insertion as the live expander does.
+fn describe(engine: &mut ExpansionEngine, actions: Vec<Action>, key: &str, now: Instant, described: &mut Vec<String>) {
+    for action in actions {
+        match action {
+            Action::PassThrough => described.push(format!("PASSTHROUGH {}", key)),
+            Action::DeleteChars(count) => described.push(format!("DELETE {}", count)),
+            Action::InsertText(insertion) => {
+                if insertion.tab_stops {
+                    let (_, stops) = template::take_tab_stops(&insertion.text);
+                    engine.start_tab_stops(stops[1..].to_vec(), stops[0]);
+                } else if let Some(undo_text) = insertion.undo_text {
+                    engine.record_expansion(insertion.text.chars().count(), undo_text, now);
+                }
+                described.push(format!("INSERT {:?}", insertion.text));
+            }
+            Action::TypeText(text) => described.push(format!("TYPE {:?}", text)),
+            Action::RunMacro(steps) => described.push(format!("MACRO of {} steps", steps.len())),
+            Action::RunCommand { command, then, .. } => {
+                described.push(format!("COMMAND {:?} (not run)", command.cmd));
+                describe(engine, then, key, now, described);
+            }
+            Action::Confirm { trigger, then, .. } => {
+                described.push(format!("CONFIRM {:?} (yes)", trigger));
+                describe(engine, then, key, now, described);
+            }
+            Action::CaretLeft(count) => described.push(format!("LEFT {}", count)),
+            Action::CaretRight(count) => described.push(format!("RIGHT {}", count)),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::config;
+
+    #[test]
+    fn scripts_replay_through_the_engine() {
+        let file = config::parse_expansion_file("[case_sensitive]\n\"btw\" = \"by the way\"\n").unwrap();
+        let mut engine = ExpansionEngine::new(file);
+        let script = "# off by one?\ntype bt\nchar x\nbackspace\n\nchar w\nspace\nclick\nchar q\nenter\n";
+        assert_eq!(
+            run(&mut engine, &parse_script(script).unwrap()),
+            [
+                "char b     buffer \"b\"",
+                "char t     buffer \"bt\"",
+                "char x     buffer \"btx\"",
+                "backspace  buffer \"bt\"",
+                "char w     buffer \"btw\"",
+                "space      DELETE 3, INSERT \"by the way \", buffer \"\"",
+                "click      buffer \"\"",
+                "char q     buffer \"q\"",
+                "enter      PASSTHROUGH enter, buffer \"\"",
+            ]
+        );
+        assert_eq!(parse_script("char a\njump\n").unwrap_err(), "line 2: unknown event \"jump\"");
+        assert!(parse_script("char\n").is_err());
+    }
+}
@@ -0,0 +1,49 @@
+// src/single_instance.rs
+//
+// Keeps a second launch from installing a second set of hooks, which would
+// expand every trigger twice. The first instance holds a named mutex for as
+// long as it runs; a later launch finds it taken, asks the running instance
+// to reload its config instead, and exits.
+
+use std::ptr;
+
+use winapi::shared::winerror::ERROR_ALREADY_EXISTS;
+use winapi::um::errhandlingapi::GetLastError;
+use winapi::um::handleapi::CloseHandle;
+use winapi::um::synchapi::CreateMutexW;
+use winapi::um::winnt::HANDLE;
+
+use crate::keyboard_hook::encode_wide;
+
+/// `Local\`: the hooks only see the session they run in, so another user's
+/// session can run its own instance.
+const MUTEX_NAME: &str = "Local\\text_expander_rs";
+
+/// Held by the running instance. Dropping it releases the name, so a
+/// relaunch after exiting starts normally.
+pub struct InstanceLock(HANDLE);
+
+impl Drop for InstanceLock {
+    fn drop(&mut self) {
+        if !self.0.is_null() {
+            unsafe { CloseHandle(self.0) };
+        }
+    }
+}
+
+/// Claim the instance name, or `None` if another instance holds it. Should
+/// creating the mutex fail outright, this instance runs anyway.
+pub fn acquire() -> Option<InstanceLock> {
+    let name = encode_wide(MUTEX_NAME);
+    unsafe {
+        let handle = CreateMutexW(ptr::null_mut(), 0, name.as_ptr());
+        if handle.is_null() {
+            return Some(InstanceLock(handle));
+        }
+        if GetLastError() == ERROR_ALREADY_EXISTS {
+            CloseHandle(handle);
+            return None;
+        }
+        Some(InstanceLock(handle))
+    }
+}
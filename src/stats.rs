@@ -0,0 +1,114 @@
+// src/stats.rs
+//
+// Per-trigger usage: how often each trigger expanded and roughly how many
+// keystrokes that saved, kept in stats.toml next to the config file.
+// Expansions only update the in-memory table; a background thread writes it
+// out every `SAVE_INTERVAL` when it has changed, and main saves once more on
+// exit. Triggers removed from the config keep their counts.
+
+use std::collections::BTreeMap;
+use std::fs;
+use std::path::PathBuf;
+use std::sync::Mutex;
+use std::thread;
+use std::time::Duration;
+
+use chrono::Local;
+use serde::{Deserialize, Serialize};
+
+use crate::config;
+
+const STATS_FILE: &str = "stats.toml";
+
+/// How often changed stats are written out.
+const SAVE_INTERVAL: Duration = Duration::from_secs(60);
+
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+pub struct Stats {
+    /// The day counting started, as YYYY-MM-DD.
+    pub since: Option<String>,
+    #[serde(default)]
+    pub triggers: BTreeMap<String, TriggerStats>,
+}
+
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub struct TriggerStats {
+    pub expansions: u64,
+    /// Expansion length minus trigger length, summed. Placeholders count as
+    /// written, so this is an estimate.
+    pub chars_saved: i64,
+}
+
+struct State {
+    stats: Stats,
+    /// Changed since the last save.
+    dirty: bool,
+}
+
+/// `None` until first loaded.
+static STATE: Mutex<Option<State>> = Mutex::new(None);
+
+fn state_path() -> Option<PathBuf> {
+    Some(config::config_path()?.parent()?.join(STATS_FILE))
+}
+
+/// Read stats.toml and start saving changes in the background. Called at
+/// startup, after the config is loaded.
+pub fn load() {
+    *STATE.lock().unwrap() = Some(State { stats: read(), dirty: false });
+    thread::spawn(|| loop {
+        thread::sleep(SAVE_INTERVAL);
+        save();
+    });
+}
+
+/// The saved stats. A missing file means nothing has expanded yet, and an
+/// unreadable one is reported and started over.
+pub fn read() -> Stats {
+    let Some(path) = state_path() else {
+        return Stats::default();
+    };
+    let Ok(contents) = fs::read_to_string(&path) else {
+        return Stats::default();
+    };
+    toml::from_str(&contents).unwrap_or_else(|e| {
+        println!("Warning: ignoring unreadable {}: {}", path.display(), e);
+        Stats::default()
+    })
+}
+
+/// Count one expansion of `trigger`, which replaced `trigger_chars` typed
+/// characters with `expansion_chars`. Memory only; see `save`.
+pub fn record(trigger: &str, trigger_chars: usize, expansion_chars: usize) {
+    let mut state = STATE.lock().unwrap();
+    let state = state.get_or_insert_with(|| State { stats: read(), dirty: false });
+    state.stats.since.get_or_insert_with(|| Local::now().format("%Y-%m-%d").to_string());
+    let entry = state.stats.triggers.entry(trigger.to_string()).or_default();
+    entry.expansions += 1;
+    entry.chars_saved += expansion_chars as i64 - trigger_chars as i64;
+    state.dirty = true;
+}
+
+/// Write the stats out if anything was counted since the last save, through
+/// a temporary file and a rename like counters.toml.
+pub fn save() {
+    let mut state = STATE.lock().unwrap();
+    let Some(state) = state.as_mut().filter(|state| state.dirty) else {
+        return;
+    };
+    match write(&state.stats) {
+        Ok(()) => state.dirty = false,
+        Err(e) => {
+            println!("Error: could not save stats: {}", e);
+            crate::log_error!("Could not save stats: {}", e);
+        }
+    }
+}
+
+fn write(stats: &Stats) -> Result<(), Box<dyn std::error::Error>> {
+    let path = state_path().ok_or("No config file has been loaded yet")?;
+    let temp = path.with_extension("toml.tmp");
+    fs::write(&temp, toml::to_string(stats)?)?;
+    fs::rename(&temp, &path)?;
+    Ok(())
+}
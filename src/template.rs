@@ -0,0 +1,820 @@
+// src/template.rs
+//
+// Expansion text processing that happens at expansion time, just before the
+// text is handed to the injector.
+
+use std::env;
+use std::fmt::Write;
+use std::hash::{BuildHasher, RandomState};
+use std::time::SystemTime;
+
+use chrono::{DateTime, Local, TimeZone, Utc};
+use chrono_tz::Tz;
+use unicode_segmentation::UnicodeSegmentation;
+
+/// Marks where the caret should end up after the expansion is inserted,
+/// unless `cursor_marker` in [settings] names another.
+pub const CURSOR_MARKER: &str = "$|";
+
+/// Replaced with the user's clipboard text.
+pub const CLIPBOARD_PLACEHOLDER: &str = "%clip%";
+/// The same, in the `{{...}}` style of the other placeholders.
+const CLIPBOARD_BRACED: &str = "{{clipboard}}";
+/// Holds the clipboard's place while the cursor marker or tab stops are
+/// taken, so copied text is never read as either. A private-use character.
+pub const CLIPBOARD_STANDIN: &str = "\u{E000}";
+
+/// The expansion of a `cmd` snippet, replaced with the command's output once
+/// it has run.
+pub const COMMAND_OUTPUT: &str = "{{output}}";
+
+/// Resolve every placeholder in an expansion. Date/time go first so text
+/// pulled in from the clipboard is never itself treated as a placeholder.
+/// `next_counter` hands out `{{counter:...}}` values (see counters.rs).
+/// Pass `CLIPBOARD_STANDIN` as the clipboard text to keep it out of the
+/// cursor marker and tab stops too (`put_clipboard`).
+pub fn resolve_placeholders(
+    text: &str,
+    next_counter: impl FnMut(&str, i64, i64) -> i64,
+    clipboard_text: impl FnOnce() -> String,
+) -> String {
+    let text = substitute_datetime(text, &Local::now());
+    let text = substitute_counters(&text, next_counter);
+    let text = substitute_system(&text, |name| env::var(name).ok(), random_u64);
+    substitute_clipboard(&text, clipboard_text)
+}
+
+/// Replace `{date:FMT}` and `{time:FMT}` with `now` formatted by chrono's
+/// strftime syntax. The format is optional: `{date}` is `M/D/YY` like the
+/// `nn` trigger and `{time}` is `HH:MM`; `{year}` is the four-digit year.
+/// `{utc:FMT}` is the time in UTC (`HH:MM UTC`) and `{tz:ZONE:FMT}` in an
+/// IANA zone such as `America/New_York` (`HH:MM` and the zone's
+/// abbreviation). Each may also be written with double braces,
+/// `{{date:FMT}}`. A placeholder with an invalid format or an unknown zone
+/// is left in the text as-is.
+pub fn substitute_datetime(text: &str, now: &DateTime<Local>) -> String {
+    let mut out = String::with_capacity(text.len());
+    let mut rest = text;
+
+    while let Some(start) = rest.find('{') {
+        out.push_str(&rest[..start]);
+        let candidate = &rest[start..];
+
+        let (open, close) = if candidate.starts_with("{{") { ("{{", "}}") } else { ("{", "}") };
+        let Some(close_at) = candidate.find(close) else {
+            rest = candidate;
+            break;
+        };
+        let end = close_at + close.len() - 1;
+        let inner = &candidate[open.len()..close_at];
+        let (name, format) = match inner.split_once(':') {
+            Some((name, format)) => (name, Some(format)),
+            None => (inner, None),
+        };
+        let formatted = match (name, format) {
+            ("date", format) => format_time(now, format.unwrap_or("%-m/%-d/%y")),
+            ("time", format) => format_time(now, format.unwrap_or("%H:%M")),
+            ("year", None) => format_time(now, "%Y"),
+            ("utc", format) => format_time(&now.with_timezone(&Utc), format.unwrap_or("%H:%M UTC")),
+            ("tz", Some(zone)) => {
+                let (zone, format) = zone.split_once(':').unwrap_or((zone, "%H:%M %Z"));
+                zone.parse::<Tz>().ok().and_then(|zone| format_time(&now.with_timezone(&zone), format))
+            }
+            _ => {
+                out.push('{');
+                rest = &candidate[1..];
+                continue;
+            }
+        };
+
+        match formatted {
+            Some(formatted) => out.push_str(&formatted),
+            None => {
+                crate::debug_println!("Invalid date/time format or zone in placeholder: {}", &candidate[..=end]);
+                out.push_str(&candidate[..=end]);
+            }
+        }
+        rest = &candidate[end + 1..];
+    }
+
+    out.push_str(rest);
+    out
+}
+
+/// `time` in chrono strftime `format`, or `None` if the format is invalid.
+fn format_time<Zone: TimeZone>(time: &DateTime<Zone>, format: &str) -> Option<String>
+where
+    Zone::Offset: std::fmt::Display,
+{
+    let mut formatted = String::new();
+    write!(formatted, "{}", time.format(format)).ok().map(|_| formatted)
+}
+
+/// Replace every `%clip%` and `{{clipboard}}` in `text`. `clipboard_text` is
+/// only called when one is present, so snippets without them never touch
+/// the clipboard.
+pub fn substitute_clipboard(text: &str, clipboard_text: impl FnOnce() -> String) -> String {
+    if !text.contains(CLIPBOARD_PLACEHOLDER) && !text.contains(CLIPBOARD_BRACED) {
+        return text.to_string();
+    }
+    let clipboard = clipboard_text();
+    text.replace(CLIPBOARD_PLACEHOLDER, &clipboard).replace(CLIPBOARD_BRACED, &clipboard)
+}
+
+/// Starts of a fill-in field, `{{fill:Name}}` or `{{field:Name}}`, asked
+/// for in a dialog when the snippet fires. Both spellings of a name are the
+/// same field.
+const FILL_OPENS: [&str; 2] = ["{{fill:", "{{field:"];
+const FILL_CLOSE: &str = "}}";
+
+/// Names of the fill-in fields in `text`, in order of first appearance. A
+/// name used more than once is listed once.
+pub fn fill_fields(text: &str) -> Vec<String> {
+    let mut fields: Vec<String> = Vec::new();
+    let mut rest = text;
+    let next_field = |rest: &str| FILL_OPENS.iter().filter_map(|open| Some((rest.find(open)?, open.len()))).min();
+    while let Some((start, open_len)) = next_field(rest) {
+        let after = &rest[start + open_len..];
+        let Some(end) = after.find(FILL_CLOSE) else {
+            break;
+        };
+        let name = &after[..end];
+        if !fields.iter().any(|field| field == name) {
+            fields.push(name.to_string());
+        }
+        rest = &after[end + FILL_CLOSE.len()..];
+    }
+    fields
+}
+
+/// Replace every `{{fill:Name}}` and `{{field:Name}}` with the value
+/// entered for `Name`. `values` pairs each name from `fill_fields` with its
+/// value.
+pub fn substitute_fills(text: &str, values: &[(String, String)]) -> String {
+    let placeholders = values.iter().flat_map(|(name, value)| {
+        FILL_OPENS.iter().map(move |open| (format!("{}{}{}", open, name, FILL_CLOSE), value))
+    });
+    placeholders.fold(text.to_string(), |text, (placeholder, value)| text.replace(&placeholder, value))
+}
+
+/// A field name as the dialog labels it: `patient_name` as "Patient name".
+pub fn field_label(name: &str) -> String {
+    let spaced = name.replace('_', " ");
+    let mut chars = spaced.chars();
+    match chars.next() {
+        Some(first) => first.to_uppercase().chain(chars).collect(),
+        None => spaced,
+    }
+}
+
+/// `text` with `&`, `<`, `>` and quotes escaped, for putting fill-in values
+/// and clipboard text into a snippet's `html`.
+pub fn escape_html(text: &str) -> String {
+    let mut out = String::with_capacity(text.len());
+    for c in text.chars() {
+        match c {
+            '&' => out.push_str("&amp;"),
+            '<' => out.push_str("&lt;"),
+            '>' => out.push_str("&gt;"),
+            '"' => out.push_str("&quot;"),
+            '\'' => out.push_str("&#39;"),
+            c => out.push(c),
+        }
+    }
+    out
+}
+
+/// `{{choose:a|b|c}}` picks an option at random each time; `{{cycle:a|b|c}}`
+/// takes them in turn; `{{choose_daily:a|b|c}}` picks at random once a day.
+/// `\` escapes the next character, so `\|` and `\}}` put a literal `|` or
+/// `}}` in an option.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Choice {
+    Choose,
+    Cycle,
+    Daily,
+}
+
+const CHOICE_OPENS: [(&str, Choice); 3] =
+    [("{{choose:", Choice::Choose), ("{{cycle:", Choice::Cycle), ("{{choose_daily:", Choice::Daily)];
+
+/// Replace every `{{choose:...}}` and `{{cycle:...}}` in `text` with one of
+/// its options. `pick` gets the kind, which choice placeholder of `text` this
+/// is (counting from 0), and the number of options, and returns the index
+/// to use. A placeholder without its closing `}}` is left as written.
+pub fn substitute_choices(text: &str, mut pick: impl FnMut(Choice, usize, usize) -> usize) -> String {
+    let mut out = String::with_capacity(text.len());
+    let mut rest = text;
+    let mut ordinal = 0;
+
+    loop {
+        let next = CHOICE_OPENS
+            .iter()
+            .filter_map(|&(open, kind)| rest.find(open).map(|start| (start, open, kind)))
+            .min_by_key(|&(start, _, _)| start);
+        let Some((start, open, kind)) = next else {
+            break;
+        };
+        out.push_str(&rest[..start]);
+        let body = &rest[start + open.len()..];
+
+        match parse_options(body) {
+            Some((options, consumed)) => {
+                let index = pick(kind, ordinal, options.len()).min(options.len() - 1);
+                out.push_str(&options[index]);
+                ordinal += 1;
+                rest = &body[consumed..];
+            }
+            None => {
+                out.push_str(open);
+                rest = body;
+            }
+        }
+    }
+
+    out.push_str(rest);
+    out
+}
+
+/// Split a choice placeholder's body at unescaped `|` up to the unescaped
+/// `}}` that closes it. Returns the options (at least one, possibly empty)
+/// and the bytes consumed including the `}}`.
+fn parse_options(body: &str) -> Option<(Vec<String>, usize)> {
+    let mut options = vec![String::new()];
+    let mut chars = body.char_indices().peekable();
+    while let Some((index, c)) = chars.next() {
+        match c {
+            '\\' => {
+                if let Some((_, escaped)) = chars.next() {
+                    options.last_mut()?.push(escaped);
+                }
+            }
+            '|' => options.push(String::new()),
+            '}' if chars.peek().is_some_and(|&(_, next)| next == '}') => {
+                return Some((options, index + FILL_CLOSE.len()));
+            }
+            c => options.last_mut()?.push(c),
+        }
+    }
+    None
+}
+
+/// `{{env:NAME}}`: an environment variable, empty if unset.
+const ENV_OPEN: &str = "{{env:";
+/// The machine name (`COMPUTERNAME`, or `HOSTNAME` elsewhere).
+const HOSTNAME_PLACEHOLDER: &str = "{{hostname}}";
+/// The login name (`USERNAME`, or `USER` elsewhere).
+const USER_PLACEHOLDER: &str = "{{user}}";
+/// A fresh random UUID each time.
+const UUID_PLACEHOLDER: &str = "{{uuid}}";
+/// The first eight hex digits of a fresh UUID.
+const UUID_SHORT_PLACEHOLDER: &str = "{{uuid_short}}";
+/// `{{rand:hex:16}}`: that many random characters, from `hex`, `alnum`, or
+/// `digits`.
+const RAND_OPEN: &str = "{{rand:";
+/// Longest `{{rand:...}}` string.
+const MAX_RAND_CHARS: usize = 256;
+
+/// Replace `{{env:NAME}}`, `{{hostname}}`, `{{user}}`, `{{uuid}}`,
+/// `{{uuid_short}}`, and `{{rand:KIND:N}}`. `var` reads an environment
+/// variable; `random` gives the random bits, a fresh value each call. A `{{rand:...}}` with an
+/// unknown kind or a bad length is left as written.
+pub fn substitute_system(text: &str, var: impl Fn(&str) -> Option<String>, mut random: impl FnMut() -> u64) -> String {
+    let mut out = String::with_capacity(text.len());
+    let mut rest = text;
+    while let Some(start) = rest.find(ENV_OPEN) {
+        let after = &rest[start + ENV_OPEN.len()..];
+        let Some(end) = after.find(FILL_CLOSE) else {
+            break;
+        };
+        out.push_str(&rest[..start]);
+        let name = &after[..end];
+        out.push_str(&var(name).unwrap_or_else(|| {
+            crate::debug_println!("Environment variable {} is not set", name);
+            String::new()
+        }));
+        rest = &after[end + FILL_CLOSE.len()..];
+    }
+    out.push_str(rest);
+
+    if out.contains(HOSTNAME_PLACEHOLDER) {
+        let hostname = var("COMPUTERNAME").or_else(|| var("HOSTNAME")).unwrap_or_default();
+        out = out.replace(HOSTNAME_PLACEHOLDER, &hostname);
+    }
+    if out.contains(USER_PLACEHOLDER) {
+        let user = var("USERNAME").or_else(|| var("USER")).unwrap_or_default();
+        out = out.replace(USER_PLACEHOLDER, &user);
+    }
+    while let Some(start) = out.find(UUID_PLACEHOLDER) {
+        out.replace_range(start..start + UUID_PLACEHOLDER.len(), &format_uuid(random(), random()));
+    }
+    while let Some(start) = out.find(UUID_SHORT_PLACEHOLDER) {
+        out.replace_range(start..start + UUID_SHORT_PLACEHOLDER.len(), &format_uuid(random(), random())[..8]);
+    }
+
+    let mut text = String::with_capacity(out.len());
+    let mut rest = out.as_str();
+    while let Some(start) = rest.find(RAND_OPEN) {
+        let after = &rest[start + RAND_OPEN.len()..];
+        let Some(end) = after.find(FILL_CLOSE) else {
+            break;
+        };
+        text.push_str(&rest[..start]);
+        match parse_rand(&after[..end]) {
+            Some((alphabet, length)) => {
+                text.extend((0..length).map(|_| alphabet[(random() % alphabet.len() as u64) as usize] as char))
+            }
+            None => text.push_str(&rest[start..start + RAND_OPEN.len() + end + FILL_CLOSE.len()]),
+        }
+        rest = &after[end + FILL_CLOSE.len()..];
+    }
+    text.push_str(rest);
+    text
+}
+
+/// The characters and length of a `{{rand:KIND:N}}`, from `KIND:N`.
+fn parse_rand(spec: &str) -> Option<(&'static [u8], usize)> {
+    let (kind, length) = spec.split_once(':')?;
+    let alphabet: &[u8] = match kind {
+        "hex" => b"0123456789abcdef",
+        "alnum" => b"ABCDEFGHIJKLMNOPQRSTUVWXYZabcdefghijklmnopqrstuvwxyz0123456789",
+        "digits" => b"0123456789",
+        _ => return None,
+    };
+    let length = length.parse().ok().filter(|&length| length <= MAX_RAND_CHARS)?;
+    Some((alphabet, length))
+}
+
+/// 64 unpredictable bits. Each RandomState gets fresh random keys, so a
+/// hash of anything will do.
+pub fn random_u64() -> u64 {
+    RandomState::new().hash_one(SystemTime::now())
+}
+
+/// `high` and `low` as a random (version 4) UUID, lowercase and hyphenated.
+fn format_uuid(high: u64, low: u64) -> String {
+    let bits = (u128::from(high) << 64) | u128::from(low);
+    let mut bytes = bits.to_be_bytes();
+    bytes[6] = (bytes[6] & 0x0f) | 0x40;
+    bytes[8] = (bytes[8] & 0x3f) | 0x80;
+
+    let hex: String = bytes.iter().map(|byte| format!("{:02x}", byte)).collect();
+    format!("{}-{}-{}-{}-{}", &hex[..8], &hex[8..12], &hex[12..16], &hex[16..20], &hex[20..])
+}
+
+/// The title of the window the expansion goes into.
+const WINDOW_TITLE_PLACEHOLDER: &str = "{{window_title}}";
+/// Its program's exe name, lowercased, e.g. `outlook.exe`.
+const APP_PLACEHOLDER: &str = "{{app}}";
+
+/// What `{{window_title}}` and `{{app}}` insert, read when the snippet fires.
+#[derive(Debug, Clone, Default)]
+pub struct FocusedWindow {
+    pub title: String,
+    pub app: String,
+}
+
+/// True if `text` has a `{{window_title}}` or `{{app}}`, so the focused
+/// window needs looking up.
+pub fn uses_window(text: &str) -> bool {
+    text.contains(WINDOW_TITLE_PLACEHOLDER) || text.contains(APP_PLACEHOLDER)
+}
+
+/// Replace `{{window_title}}` and `{{app}}` with `window`'s, in one pass so
+/// a title is never read for placeholders.
+pub fn substitute_window(text: &str, window: &FocusedWindow) -> String {
+    let parts: Vec<String> =
+        text.split(WINDOW_TITLE_PLACEHOLDER).map(|part| part.replace(APP_PLACEHOLDER, &window.app)).collect();
+    parts.join(&window.title)
+}
+
+/// Start of a persistent counter, `{{counter:name}}` or
+/// `{{counter:name:start=100:step=5}}` (both default to 1).
+const COUNTER_OPEN: &str = "{{counter:";
+
+/// Replace each `{{counter:...}}` with `next(name, start, step)`. A counter
+/// with an option that isn't `start=N` or `step=N` is left as written.
+pub fn substitute_counters(text: &str, mut next: impl FnMut(&str, i64, i64) -> i64) -> String {
+    let mut out = String::with_capacity(text.len());
+    let mut rest = text;
+    while let Some(start) = rest.find(COUNTER_OPEN) {
+        let after = &rest[start + COUNTER_OPEN.len()..];
+        let Some(end) = after.find(FILL_CLOSE) else {
+            break;
+        };
+        out.push_str(&rest[..start]);
+
+        match parse_counter(&after[..end]) {
+            Some((name, first, step)) => out.push_str(&next(name, first, step).to_string()),
+            None => {
+                crate::debug_println!("Invalid counter placeholder: {}{}}}}}", COUNTER_OPEN, &after[..end]);
+                out.push_str(&rest[start..start + COUNTER_OPEN.len() + end + FILL_CLOSE.len()]);
+            }
+        }
+        rest = &after[end + FILL_CLOSE.len()..];
+    }
+
+    out.push_str(rest);
+    out
+}
+
+/// `name:start=N:step=N` → name, start, step.
+fn parse_counter(spec: &str) -> Option<(&str, i64, i64)> {
+    let mut parts = spec.split(':');
+    let name = parts.next().filter(|name| !name.is_empty())?;
+    let (mut first, mut step) = (1, 1);
+    for option in parts {
+        match option.split_once('=')? {
+            ("start", value) => first = value.trim().parse().ok()?,
+            ("step", value) => step = value.trim().parse().ok()?,
+            _ => return None,
+        }
+    }
+    Some((name, first, step))
+}
+
+/// Start of a reference to another snippet's expansion, `{{snippet:trigger}}`.
+pub const SNIPPET_REF_OPEN: &str = "{{snippet:";
+
+/// How many references deep a snippet may pull in other snippets.
+const MAX_SNIPPET_DEPTH: usize = 10;
+
+/// Replace each `{{snippet:name}}` in `trigger`'s expansion `text` with the
+/// text of snippet `name`, recursively. `lookup` returns a snippet's text as
+/// written. A reference to no snippet is left in the text and added to
+/// `unknown`. A cycle or nesting deeper than `MAX_SNIPPET_DEPTH` is an error
+/// naming the chain of triggers.
+pub fn resolve_snippet_refs<'a>(
+    trigger: &str,
+    text: &str,
+    lookup: &dyn Fn(&str) -> Option<&'a str>,
+    unknown: &mut Vec<String>,
+) -> Result<String, String> {
+    expand_snippet_refs(text, lookup, &mut vec![trigger.to_string()], unknown)
+}
+
+/// `chain` is the triggers being expanded, outermost first.
+fn expand_snippet_refs<'a>(
+    text: &str,
+    lookup: &dyn Fn(&str) -> Option<&'a str>,
+    chain: &mut Vec<String>,
+    unknown: &mut Vec<String>,
+) -> Result<String, String> {
+    let mut out = String::with_capacity(text.len());
+    let mut rest = text;
+    while let Some(start) = rest.find(SNIPPET_REF_OPEN) {
+        let after = &rest[start + SNIPPET_REF_OPEN.len()..];
+        let Some(end) = after.find(FILL_CLOSE) else {
+            break;
+        };
+        let name = &after[..end];
+        let reference = &rest[start..start + SNIPPET_REF_OPEN.len() + end + FILL_CLOSE.len()];
+        out.push_str(&rest[..start]);
+        rest = &after[end + FILL_CLOSE.len()..];
+
+        let Some(referenced) = lookup(name) else {
+            unknown.push(reference.to_string());
+            out.push_str(reference);
+            continue;
+        };
+        if chain.iter().any(|trigger| trigger == name) {
+            return Err(format!("snippet references loop: {} -> {}", chain.join(" -> "), name));
+        }
+        if chain.len() > MAX_SNIPPET_DEPTH {
+            return Err(format!("snippet references nest more than {} deep: {}", MAX_SNIPPET_DEPTH, chain.join(" -> ")));
+        }
+
+        chain.push(name.to_string());
+        out.push_str(&expand_snippet_refs(referenced, lookup, chain, unknown)?);
+        chain.pop();
+    }
+
+    out.push_str(rest);
+    Ok(out)
+}
+
+/// Strip each `marker` (the cursor marker) from `text` and normalize line
+/// endings to `\n`. Returns the text and the number of Left presses that
+/// move the caret from the end of the inserted text back to the first
+/// marker. Markers after the first are removed but ignored.
+pub fn take_cursor_marker(text: &str, marker: &str) -> (String, usize) {
+    let (text, markers) = strip_markers(text, |rest| rest.find(marker).map(|index| (index, marker.len(), ())));
+    let caret_left = markers.first().map_or(0, |&(_, left)| left);
+    (text, caret_left)
+}
+
+/// Put the clipboard text in place of each `CLIPBOARD_STANDIN` in `text`,
+/// once the cursor marker or tab stops are out, with its line endings
+/// normalized to `\n` like the rest. `stops` (Left presses from the end)
+/// grow by the text it adds after them. `clipboard_text` is only called if
+/// there is a stand-in.
+pub fn put_clipboard(text: &str, stops: &[usize], clipboard_text: impl FnOnce() -> String) -> (String, Vec<usize>) {
+    if !text.contains(CLIPBOARD_STANDIN) {
+        return (text.to_string(), stops.to_vec());
+    }
+    let clipboard = clipboard_text().replace("\r\n", "\n");
+    let added = clipboard.graphemes(true).count() as isize - 1;
+    let graphemes: Vec<&str> = text.graphemes(true).collect();
+    let stops = stops
+        .iter()
+        .map(|&left| {
+            let after = graphemes[graphemes.len() - left.min(graphemes.len())..].iter();
+            let standins = after.filter(|grapheme| grapheme.contains(CLIPBOARD_STANDIN)).count() as isize;
+            (left as isize + standins * added) as usize
+        })
+        .collect();
+    (text.replace(CLIPBOARD_STANDIN, &clipboard), stops)
+}
+
+/// Strip `$1`..`$9` and `$0` tab stops from `text` and normalize line
+/// endings to `\n`. Returns the text and each stop as Left presses from the
+/// end of it, in the order Tab visits them: `$1`, `$2`, ..., then `$0`, or
+/// the end of the text if there is no `$0`. Only the first occurrence of a
+/// number is a stop.
+pub fn take_tab_stops(text: &str) -> (String, Vec<usize>) {
+    let (text, mut markers) = strip_markers(text, |rest| {
+        rest.match_indices('$').find_map(|(index, _)| {
+            let number = rest[index + 1..].chars().next()?.to_digit(10)?;
+            Some((index, 2, number))
+        })
+    });
+
+    // `$0` sorts last; a stable sort keeps each number's first occurrence
+    // ahead of its repeats
+    markers.sort_by_key(|&(number, _)| if number == 0 { 10 } else { number });
+    markers.dedup_by_key(|&mut (number, _)| number);
+    let has_final_stop = markers.last().is_some_and(|&(number, _)| number == 0);
+    let mut stops: Vec<usize> = markers.into_iter().map(|(_, left)| left).collect();
+    if !has_final_stop {
+        stops.push(0);
+    }
+    (text, stops)
+}
+
+/// Remove the markers `next_marker` finds (byte index and length of the
+/// first one in a string, plus a tag) and normalize line endings to `\n`.
+/// Returns the text and each marker's tag with the number of Left presses
+/// from the end of the text back to it. Left steps over a whole grapheme
+/// (`é` typed as e + accent, an emoji with its skin tone), and a line break
+/// is a single caret stop in Windows edit controls, so `\r\n` counts once.
+fn strip_markers<T>(text: &str, next_marker: impl Fn(&str) -> Option<(usize, usize, T)>) -> (String, Vec<(T, usize)>) {
+    let mut rest = text.replace("\r\n", "\n");
+    let mut out = String::with_capacity(rest.len());
+    let mut found = Vec::new();
+    while let Some((index, len, tag)) = next_marker(&rest) {
+        out.push_str(&rest[..index]);
+        found.push((tag, out.len()));
+        rest = rest[index + len..].to_string();
+    }
+    out.push_str(&rest);
+
+    let markers = found.into_iter().map(|(tag, at)| (tag, out[at..].graphemes(true).count())).collect();
+    (out, markers)
+}
+
+/// What is wrong with the placeholders in an expansion as written, one
+/// message each: a `{{...}}` that is unknown, unclosed, or missing its
+/// argument, a counter with bad options, or a `{date:...}`/`{time:...}`
+/// format (either brace style) chrono rejects. For `text_expander check`.
+pub fn placeholder_errors(text: &str) -> Vec<String> {
+    let mut errors = Vec::new();
+
+    let mut rest = text;
+    while let Some(start) = rest.find("{{") {
+        let body = &rest[start + 2..];
+        let name_end = body.find([':', '}']).unwrap_or(body.len());
+        let (name, has_argument) = (&body[..name_end], body[name_end..].starts_with(':'));
+
+        let close = if matches!(name, "choose" | "cycle" | "choose_daily") && has_argument {
+            parse_options(&body[name_end + 1..]).map(|(_, consumed)| name_end + 1 + consumed - FILL_CLOSE.len())
+        } else {
+            body.find(FILL_CLOSE)
+        };
+        let Some(close) = close else {
+            errors.push(format!("{{{{{} is never closed with }}}}", name));
+            break;
+        };
+        let argument = body.get(name_end + 1..close).filter(|_| has_argument);
+
+        let placeholder = &rest[start..start + 2 + close + FILL_CLOSE.len()];
+        match (name, argument) {
+            ("fill" | "field" | "env" | "snippet" | "choose" | "cycle" | "choose_daily", Some(argument))
+                if !argument.is_empty() => {}
+            (
+                "hostname" | "user" | "window_title" | "app" | "uuid" | "uuid_short" | "year" | "clipboard" | "date"
+                | "time" | "utc",
+                None,
+            ) => {}
+            ("date" | "time" | "utc", Some(_)) => {}
+            ("tz", Some(zone)) if zone.split(':').next().is_some_and(|zone| zone.parse::<Tz>().is_ok()) => {}
+            ("tz", Some(argument)) if !argument.is_empty() => {
+                errors.push(format!("{} doesn't start with a known time zone", placeholder))
+            }
+            ("rand", Some(spec)) if parse_rand(spec).is_some() => {}
+            ("rand", Some(_)) => {
+                let kinds = "hex, alnum, or digits";
+                errors.push(format!("{} needs {} and a length up to {}", placeholder, kinds, MAX_RAND_CHARS))
+            }
+            ("counter", Some(spec)) if parse_counter(spec).is_some() => {}
+            ("counter", Some(_)) => errors.push(format!("{} has options other than start=N and step=N", placeholder)),
+            (
+                "fill" | "field" | "env" | "snippet" | "choose" | "cycle" | "choose_daily" | "counter" | "tz" | "rand",
+                _,
+            ) => {
+                errors.push(format!("{} needs a value after the colon", placeholder))
+            }
+            _ => errors.push(format!("{} is not a known placeholder", placeholder)),
+        }
+        rest = &body[close + FILL_CLOSE.len()..];
+    }
+
+    for open in ["{date:", "{time:"] {
+        for (start, _) in text.match_indices(open) {
+            let Some(end) = text[start..].find('}') else {
+                continue;
+            };
+            let format = &text[start + open.len()..start + end];
+            let mut probe = String::new();
+            if write!(probe, "{}", Local::now().format(format)).is_err() {
+                errors.push(format!("{} has an invalid date/time format", &text[start..=start + end]));
+            }
+        }
+    }
+
+    errors
+}
+
+/// Characters `text` puts on screen once pasted, i.e. how many Backspaces
+/// remove it again. A `\r\n` line break is one character to the editor.
+pub fn typed_length(text: &str) -> usize {
+    text.chars().count() - text.matches("\r\n").count()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn fill_fields_are_asked_once_and_substituted_everywhere() {
+        let text = "Hi {{fill:Name}}, see you {{fill:Date}}. Bye {{fill:Name}}! {date}";
+        assert_eq!(fill_fields(text), ["Name", "Date"]);
+
+        let values = [("Name".to_string(), "Ann".to_string()), ("Date".to_string(), "Friday".to_string())];
+        assert_eq!(substitute_fills(text, &values), "Hi Ann, see you Friday. Bye Ann! {date}");
+
+        let note = "Pt: {{field:patient_name}}, seen {{fill:Date}}; {{fill:patient_name}} consents";
+        assert_eq!(fill_fields(note), ["patient_name", "Date"]);
+        let values = [("patient_name".to_string(), "Ann".to_string()), ("Date".to_string(), "today".to_string())];
+        assert_eq!(substitute_fills(note, &values), "Pt: Ann, seen today; Ann consents");
+        assert_eq!(field_label("patient_name"), "Patient name");
+        assert_eq!(escape_html("<O'Brien & \"Sons\">"), "&lt;O&#39;Brien &amp; &quot;Sons&quot;&gt;");
+    }
+
+    #[test]
+    fn snippet_references_resolve_recursively_and_reject_loops() {
+        let lookup = |name: &str| match name {
+            "sig" => Some("Dr. Smith {{snippet:phone}}"),
+            "phone" => Some("555-0100"),
+            "a" => Some("{{snippet:b}}"),
+            "b" => Some("{{snippet:a}}"),
+            _ => None,
+        };
+
+        let mut unknown = Vec::new();
+        let mut resolve = |trigger, text| resolve_snippet_refs(trigger, text, &lookup, &mut unknown);
+        assert_eq!(resolve("email", "Thanks,\n{{snippet:sig}} {date}").unwrap(), "Thanks,\nDr. Smith 555-0100 {date}");
+        assert_eq!(resolve("x", "{{snippet:nope}}").unwrap(), "{{snippet:nope}}");
+        assert_eq!(resolve("a", "{{snippet:b}}").unwrap_err(), "snippet references loop: a -> b -> a");
+        assert_eq!(unknown, ["{{snippet:nope}}"]);
+    }
+
+    #[test]
+    fn choice_options_split_on_unescaped_bars_and_close_on_unescaped_braces() {
+        let first = |_, _, _| 0;
+        let last = |_, _, count: usize| count - 1;
+
+        assert_eq!(substitute_choices("{{choose:Hey|Hi there|Hello}}!", last), "Hello!");
+        assert_eq!(substitute_choices(r"{{cycle:a\}}b|c\|d|}} {date}", first), "a}}b {date}");
+        assert_eq!(substitute_choices(r"{{cycle:a\}}b|c\|d|}}", |_, _, _| 1), "c|d");
+        assert_eq!(substitute_choices("[{{choose:|x}}]", first), "[]");
+        assert_eq!(substitute_choices("{{choose:a|b", first), "{{choose:a|b");
+
+        let mut seen = Vec::new();
+        substitute_choices("{{cycle:a|b}} {{choose:c|d|e}}", |kind, ordinal, count| {
+            seen.push((kind, ordinal, count));
+            0
+        });
+        assert_eq!(seen, [(Choice::Cycle, 0, 2), (Choice::Choose, 1, 3)]);
+    }
+
+    #[test]
+    fn counters_parse_their_start_and_step() {
+        let mut calls = Vec::new();
+        let text = substitute_counters("#{{counter:inv}} fig {{counter:fig:start=100:step=5}} {{counter:x:by=2}}", |name, start, step| {
+            calls.push((name.to_string(), start, step));
+            start
+        });
+        assert_eq!(text, "#1 fig 100 {{counter:x:by=2}}");
+        assert_eq!(calls, [("inv".to_string(), 1, 1), ("fig".to_string(), 100, 5)]);
+    }
+
+    #[test]
+    fn environment_hostname_and_uuid_placeholders() {
+        let var = |name: &str| match name {
+            "USERNAME" => Some("asmith".to_string()),
+            "COMPUTERNAME" => Some("CLINIC-PC".to_string()),
+            _ => None,
+        };
+        let mut next = 0;
+        let counting = || {
+            next += 1;
+            next
+        };
+        let text = substitute_system("{{env:USERNAME}}@{{hostname}} [{{env:UNSET}}] {{user}} {{uuid}}", var, counting);
+        assert_eq!(text, "asmith@CLINIC-PC [] asmith 00000000-0000-4001-8000-000000000002");
+
+        let mut next = 2;
+        let counting = || {
+            next += 1;
+            next
+        };
+        let placeholders = "{{uuid_short}} {{rand:hex:4}} {{rand:digits:3}} {{rand:hex:x}} {{rand:b64:2}}";
+        let text = substitute_system(placeholders, var, counting);
+        assert_eq!(text, "00000000 5678 901 {{rand:hex:x}} {{rand:b64:2}}");
+
+        let window = FocusedWindow { title: "Lab results - {{app}}".to_string(), app: "outlook.exe".to_string() };
+        assert!(uses_window("Re: {{window_title}}") && !uses_window("{{user}}"));
+        assert_eq!(
+            substitute_window("Re: {{window_title}} ({{app}})", &window),
+            "Re: Lab results - {{app}} (outlook.exe)"
+        );
+
+        let uuid = format_uuid(random_u64(), random_u64());
+        assert_eq!(uuid.len(), 36);
+        assert_eq!(&uuid[14..15], "4");
+        assert_ne!(uuid, format_uuid(random_u64(), random_u64()));
+    }
+
+    #[test]
+    fn date_time_and_clipboard_placeholders_take_either_brace_style() {
+        let now = Local.with_ymd_and_hms(2026, 3, 7, 9, 5, 0).unwrap();
+        let text = substitute_datetime("{date} {{date:%m/%d/%y}} {time} {{time}} {{year}} {{fill:Name}} {x}", &now);
+        assert_eq!(text, "3/7/26 03/07/26 09:05 09:05 2026 {{fill:Name}} {x}");
+        assert_eq!(substitute_datetime("{{date:%Q}}", &now), "{{date:%Q}}", "an invalid format is kept");
+
+        let now = Utc.with_ymd_and_hms(2026, 3, 7, 14, 5, 0).unwrap().with_timezone(&Local);
+        let zones = "{{utc}} {{utc:%d %H%MZ}} {{tz:America/New_York:%H:%M}} {{tz:Asia/Tokyo}}";
+        let text = substitute_datetime(zones, &now);
+        assert_eq!(text, "14:05 UTC 07 1405Z 09:05 23:05 JST");
+        assert_eq!(substitute_datetime("{{tz:Mars/Olympus}}", &now), "{{tz:Mars/Olympus}}", "an unknown zone is kept");
+
+        assert_eq!(substitute_clipboard("%clip% / {{clipboard}}", || "copied".to_string()), "copied / copied");
+        assert_eq!(substitute_clipboard("none", || unreachable!()), "none");
+
+        // Copied text holding a marker or stop is inserted as it is
+        let text = substitute_clipboard("[code]{{clipboard}}$|[/code]", || CLIPBOARD_STANDIN.to_string());
+        let (text, caret_left) = take_cursor_marker(&text, CURSOR_MARKER);
+        let put = put_clipboard(&text, &[caret_left], || "a $| b\r\n$1".to_string());
+        assert_eq!(put, ("[code]a $| b\n$1[/code]".to_string(), vec![7]));
+        let text = substitute_clipboard("$1 %clip% $2", || CLIPBOARD_STANDIN.to_string());
+        let (text, stops) = take_tab_stops(&text);
+        assert_eq!(put_clipboard(&text, &stops, || "$3yz".to_string()), (" $3yz ".to_string(), vec![6, 0, 0]));
+        assert_eq!(put_clipboard("none", &[3], || unreachable!()), ("none".to_string(), vec![3]));
+    }
+
+    #[test]
+    fn placeholder_errors_name_the_bad_placeholder() {
+        assert!(placeholder_errors("{{fill:Name}} {{choose:a|b\\}}}} {{counter:n:start=5}} {{uuid}} {date:%Y}").is_empty());
+        assert!(placeholder_errors("{{date:%m/%d}} {{time}} {{year}} {{clipboard}}").is_empty());
+        assert!(placeholder_errors("{{utc}} {{utc:%H%MZ}} {{tz:Europe/London}} {{tz:UTC:%H:%M}}").is_empty());
+        assert!(placeholder_errors("{{choose_daily:Hi|Hello \\| there}}").is_empty());
+        assert!(placeholder_errors("{{uuid_short}} {{rand:hex:16}} {{rand:alnum:8}}").is_empty());
+        assert_eq!(
+            placeholder_errors("{{rand:hex:1000}}"),
+            ["{{rand:hex:1000}} needs hex, alnum, or digits and a length up to 256"]
+        );
+        assert_eq!(
+            placeholder_errors("{{fil:Name}} {{env:}} {{tz:Mars/Olympus}} {{counter:n:by=2}} {time:%Q} {{choose:a"),
+            [
+                "{{fil:Name}} is not a known placeholder",
+                "{{env:}} needs a value after the colon",
+                "{{tz:Mars/Olympus}} doesn't start with a known time zone",
+                "{{counter:n:by=2}} has options other than start=N and step=N",
+                "{{choose is never closed with }}",
+                "{time:%Q} has an invalid date/time format",
+            ]
+        );
+    }
+
+    #[test]
+    fn tab_stops_are_visited_in_number_order_with_zero_last() {
+        let (text, stops) = take_tab_stops("for ($1 = 0; $1 < $2; $1++) {\r\n$0\r\n}");
+        assert_eq!(text, "for ( = 0;  < ; ++) {\n\n}");
+        assert_eq!(stops, [19, 10, 2]);
+
+        // Without `$0` the last stop is the end of the text
+        assert_eq!(take_tab_stops("$2 and $1"), (" and ".to_string(), vec![0, 5, 0]));
+        assert_eq!(take_cursor_marker("a$|b\r\nc$|d", CURSOR_MARKER), ("ab\ncd".to_string(), 4));
+        assert_eq!(take_cursor_marker("(^^)e\u{301}👍🏽", "^^"), ("()e\u{301}👍🏽".to_string(), 3), "Left per grapheme");
+    }
+}
@@ -0,0 +1,141 @@
+// src/transforms.rs
+//
+// `[transforms]`: hotkeys that replace the selection in the focused app with
+// a changed copy of it, e.g. `"Ctrl+Alt+U" = "upper"` or
+// `"Ctrl+Alt+B" = "**{selection}**"`. This module parses the table and
+// applies a transform to text; main.rs copies the selection, and pastes the
+// result back through the clipboard.
+
+use std::collections::HashMap;
+
+use crate::config::Hotkey;
+
+/// Placeholder a template transform puts the selection in.
+pub const SELECTION: &str = "{selection}";
+/// The same, in the `{{...}}` style of snippet placeholders.
+const SELECTION_BRACED: &str = "{{selection}}";
+
+/// How many transforms can have a hotkey; the hook checks a fixed set.
+pub const MAX_TRANSFORMS: usize = 16;
+
+/// What a transform does to the selected text.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Transform {
+    Upper,
+    Lower,
+    /// Each word's first letter uppercase, the rest lowercase.
+    Title,
+    /// The template with each `{selection}` (or `{{selection}}`) replaced by
+    /// the selection.
+    Template(String),
+}
+
+/// One `[transforms]` entry, parsed.
+#[derive(Debug, Clone)]
+pub struct SelectionTransform {
+    /// The hotkey as written, for messages.
+    pub name: String,
+    pub hotkey: Hotkey,
+    pub transform: Transform,
+}
+
+impl Transform {
+    /// "upper", "lower", "title", or a template containing `{selection}`.
+    pub fn parse(text: &str) -> Result<Self, String> {
+        match text {
+            "upper" => Ok(Transform::Upper),
+            "lower" => Ok(Transform::Lower),
+            "title" => Ok(Transform::Title),
+            template if template.contains(SELECTION) => Ok(Transform::Template(template.to_string())),
+            other => Err(format!("{:?} is not upper, lower, title, or a template with {}", other, SELECTION)),
+        }
+    }
+
+    pub fn apply(&self, selection: &str) -> String {
+        match self {
+            Transform::Upper => selection.to_uppercase(),
+            Transform::Lower => selection.to_lowercase(),
+            Transform::Title => title_case(selection),
+            // One pass, so a selection holding a placeholder stays as it is
+            Transform::Template(template) => template
+                .split(SELECTION_BRACED)
+                .map(|part| part.replace(SELECTION, selection))
+                .collect::<Vec<_>>()
+                .join(selection),
+        }
+    }
+}
+
+fn title_case(text: &str) -> String {
+    let mut out = String::with_capacity(text.len());
+    let mut word_start = true;
+    for c in text.chars() {
+        if word_start {
+            out.extend(c.to_uppercase());
+        } else {
+            out.extend(c.to_lowercase());
+        }
+        word_start = c.is_whitespace() || matches!(c, '-' | '/' | '(' | '"');
+    }
+    out
+}
+
+/// Parse every `[transforms]` entry, in hotkey order. A bad hotkey or
+/// transform, a chord used twice, or more than `MAX_TRANSFORMS` entries
+/// fails the load.
+pub fn compile(entries: &HashMap<String, String>) -> Result<Vec<SelectionTransform>, Box<dyn std::error::Error>> {
+    if entries.len() > MAX_TRANSFORMS {
+        return Err(format!("[transforms] has {} entries; at most {} are allowed", entries.len(), MAX_TRANSFORMS).into());
+    }
+    let mut entries: Vec<(&String, &String)> = entries.iter().collect();
+    entries.sort();
+
+    let mut transforms: Vec<SelectionTransform> = Vec::new();
+    for (name, transform) in entries {
+        let hotkey = Hotkey::parse(name)
+            .map_err(|e| format!("[transforms] {}", e))?
+            .ok_or_else(|| format!("[transforms] {:?}: a transform needs a hotkey", name))?;
+        if let Some(earlier) = transforms.iter().find(|earlier| earlier.hotkey == hotkey) {
+            return Err(format!("[transforms] {:?} is the same hotkey as {:?}", name, earlier.name).into());
+        }
+        let transform = Transform::parse(transform).map_err(|e| format!("[transforms] {:?}: {}", name, e))?;
+        transforms.push(SelectionTransform { name: name.clone(), hotkey, transform });
+    }
+    Ok(transforms)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn transforms_parse_and_apply() {
+        let entries = HashMap::from([
+            ("Ctrl+Alt+U".to_string(), "upper".to_string()),
+            ("Ctrl+Alt+T".to_string(), "title".to_string()),
+            ("Ctrl+Alt+B".to_string(), "**{selection}**".to_string()),
+        ]);
+        let transforms = compile(&entries).unwrap();
+        let apply = |name: &str, text| {
+            transforms.iter().find(|t| t.name == name).unwrap().transform.apply(text)
+        };
+
+        assert_eq!(apply("Ctrl+Alt+U", "straße ok"), "STRASSE OK");
+        assert_eq!(apply("Ctrl+Alt+T", "the QUICK brown-fox\nis (here)"), "The Quick Brown-Fox\nIs (Here)");
+        assert_eq!(apply("Ctrl+Alt+B", "bold"), "**bold**");
+        let wrap = Transform::parse("[code]{{selection}}[/code] {selection}").unwrap();
+        assert_eq!(wrap.apply("x {{selection}}"), "[code]x {{selection}}[/code] x {{selection}}");
+        assert_eq!(Transform::Lower.apply("MiXeD"), "mixed");
+
+        let bad = |name: &str, transform: &str| {
+            compile(&HashMap::from([(name.to_string(), transform.to_string())])).unwrap_err().to_string()
+        };
+        assert!(bad("Ctrl+Alt+U", "shout").contains("not upper"));
+        assert!(bad("", "upper").contains("needs a hotkey"));
+        let twice = HashMap::from([
+            ("Ctrl+Alt+U".to_string(), "upper".to_string()),
+            ("alt+ctrl+u".to_string(), "lower".to_string()),
+        ]);
+        assert!(compile(&twice).unwrap_err().to_string().contains("same hotkey"));
+    }
+}
@@ -0,0 +1,228 @@
+// src/trie.rs
+//
+// Every exact trigger (the global tables, the app profiles, and `[macros]`)
+// in a trie, walked one typed character at a time. A `Scan` follows one walk
+// per word start at once, so a single pass over the text before the caret
+// finds every trigger ending there (longest first) and every word start that
+// could still grow into one. The tables stay the source of the snippets;
+// the trie only says where to look them up. Instant and `word = false`
+// snippets keep their last-character index (config.rs).
+
+/// Two tries: case_sensitive triggers and macros as written, and
+/// case_insensitive ones folded (`fold`).
+#[derive(Debug, Default)]
+pub struct TriggerTrie {
+    exact: Trie,
+    folded: Trie,
+    /// The longest trigger in either, in characters.
+    longest_chars: usize,
+}
+
+#[derive(Debug)]
+struct Trie {
+    /// `nodes[0]` is the root.
+    nodes: Vec<Node>,
+}
+
+#[derive(Debug, Default)]
+struct Node {
+    /// Sorted by character, for binary search.
+    children: Vec<(char, usize)>,
+    /// A trigger ends here.
+    end: bool,
+}
+
+/// A trigger that ends where the scan stopped.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Candidate {
+    /// Byte offset of the word start it was typed from.
+    pub start: usize,
+    /// Found among the case_sensitive triggers and macros, not the folded
+    /// case_insensitive ones.
+    pub case_sensitive: bool,
+}
+
+#[derive(Debug, Default, PartialEq, Eq)]
+pub struct MatchResult {
+    /// Triggers ending at the end of the text, longest first; exact before
+    /// folded for the same start.
+    pub candidates: Vec<Candidate>,
+    /// Word starts whose text so far is all or the beginning of some
+    /// trigger, earliest first.
+    pub partial: Vec<usize>,
+}
+
+/// One walk from a word start.
+#[derive(Debug, Clone, Copy)]
+struct Walk {
+    start: usize,
+    node: usize,
+    case_sensitive: bool,
+}
+
+/// A walk per word start, advanced a character at a time. Walks that leave
+/// the trie are dropped.
+pub struct Scan<'a> {
+    trie: &'a TriggerTrie,
+    walks: Vec<Walk>,
+}
+
+impl Default for Trie {
+    fn default() -> Self {
+        Trie { nodes: vec![Node::default()] }
+    }
+}
+
+impl Trie {
+    fn insert(&mut self, key: impl Iterator<Item = char>) {
+        let mut node = 0;
+        for c in key {
+            node = match self.nodes[node].children.binary_search_by_key(&c, |&(child, _)| child) {
+                Ok(found) => self.nodes[node].children[found].1,
+                Err(at) => {
+                    let next = self.nodes.len();
+                    self.nodes.push(Node::default());
+                    self.nodes[node].children.insert(at, (c, next));
+                    next
+                }
+            };
+        }
+        self.nodes[node].end = true;
+    }
+
+    fn child(&self, node: usize, c: char) -> Option<usize> {
+        let children = &self.nodes[node].children;
+        children.binary_search_by_key(&c, |&(child, _)| child).ok().map(|found| children[found].1)
+    }
+}
+
+impl TriggerTrie {
+    /// Build from the exact and the case_insensitive triggers (already
+    /// lowercased at load).
+    pub fn new<'a>(
+        exact: impl IntoIterator<Item = &'a String>,
+        case_insensitive: impl IntoIterator<Item = &'a String>,
+    ) -> Self {
+        let mut trie = TriggerTrie::default();
+        for trigger in exact {
+            trie.exact.insert(trigger.chars());
+            trie.longest_chars = trie.longest_chars.max(trigger.chars().count());
+        }
+        for trigger in case_insensitive {
+            trie.folded.insert(trigger.chars().flat_map(fold));
+            trie.longest_chars = trie.longest_chars.max(trigger.chars().count());
+        }
+        trie
+    }
+
+    pub fn longest_chars(&self) -> usize {
+        self.longest_chars
+    }
+
+    /// A scan with no walks yet.
+    pub fn scanner(&self) -> Scan<'_> {
+        Scan { trie: self, walks: Vec::new() }
+    }
+
+    /// True if some trigger starts with `typed` (or equals it).
+    pub fn could_start(&self, typed: &str) -> bool {
+        !self.scan(typed, &[0]).partial.is_empty()
+    }
+
+    /// Walk `text` from each of `starts` (byte offsets, ascending) to its
+    /// end.
+    pub fn scan(&self, text: &str, starts: &[usize]) -> MatchResult {
+        let mut scan = self.scanner();
+        let mut starts = starts.iter().peekable();
+        for (index, c) in text.char_indices() {
+            while starts.next_if(|&&start| start <= index).is_some() {
+                scan.start_word(index);
+            }
+            scan.advance(c);
+        }
+        if starts.next().is_some() {
+            scan.start_word(text.len());
+        }
+        scan.result()
+    }
+}
+
+impl Scan<'_> {
+    /// Begin walks in both tries at byte offset `start`.
+    pub fn start_word(&mut self, start: usize) {
+        self.walks.push(Walk { start, node: 0, case_sensitive: true });
+        self.walks.push(Walk { start, node: 0, case_sensitive: false });
+    }
+
+    /// Follow the next typed character in every walk.
+    pub fn advance(&mut self, c: char) {
+        let trie = self.trie;
+        self.walks.retain_mut(|walk| {
+            let next = if walk.case_sensitive {
+                trie.exact.child(walk.node, c)
+            } else {
+                fold(c).try_fold(walk.node, |node, c| trie.folded.child(node, c))
+            };
+            next.map(|node| walk.node = node).is_some()
+        });
+    }
+
+    pub fn result(&self) -> MatchResult {
+        let trie = |walk: &Walk| if walk.case_sensitive { &self.trie.exact } else { &self.trie.folded };
+        let candidates = self
+            .walks
+            .iter()
+            .filter(|walk| trie(walk).nodes[walk.node].end)
+            .map(|walk| Candidate { start: walk.start, case_sensitive: walk.case_sensitive })
+            .collect();
+        // Only an empty trie's root has nowhere to go
+        let live = |walk: &&Walk| {
+            let node = &trie(walk).nodes[walk.node];
+            node.end || !node.children.is_empty()
+        };
+        let mut partial: Vec<usize> = self.walks.iter().filter(live).map(|walk| walk.start).collect();
+        partial.dedup();
+        MatchResult { candidates, partial }
+    }
+}
+
+/// Lowercase `c` the way case_insensitive triggers were at load, with final
+/// sigma, which only `str::to_lowercase` produces, read as sigma.
+fn fold(c: char) -> impl Iterator<Item = char> {
+    c.to_lowercase().map(|c| if c == 'ς' { 'σ' } else { c })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn a_scan_finds_every_trigger_ending_at_the_end_and_every_live_start() {
+        let exact = ["btw".to_string(), ";btw".to_string(), "BTW!".to_string()];
+        let folded = ["omw".to_string(), "οδος".to_string()];
+        let trie = TriggerTrie::new(&exact, &folded);
+        assert_eq!(trie.longest_chars(), 4);
+
+        let result = trie.scan("x ;btw", &[0, 1, 2, 3]);
+        let exact_at = |start| Candidate { start, case_sensitive: true };
+        assert_eq!(result.candidates, vec![exact_at(2), exact_at(3)], "longest first");
+        assert_eq!(result.partial, vec![2, 3]);
+
+        let result = trie.scan("OmW", &[0]);
+        assert_eq!(result.candidates, vec![Candidate { start: 0, case_sensitive: false }]);
+        assert_eq!(trie.scan("ΟΔΟΣ", &[0]).candidates.len(), 1, "folded like str::to_lowercase");
+
+        assert!(trie.could_start("BT"));
+        assert!(trie.could_start("om"));
+        assert!(!trie.could_start("bx"));
+        assert!(trie.could_start(""), "every trigger starts with nothing");
+        assert!(trie.scan("", &[]).partial.is_empty());
+        assert!(!TriggerTrie::default().could_start(""));
+
+        let many: Vec<String> = (0..5000).map(|n| format!(";s{}", n)).collect();
+        let trie = TriggerTrie::new(&many, &[]);
+        let result = trie.scan("see ;s4999", &[0, 3, 4]);
+        assert_eq!(result.candidates, vec![exact_at(4)]);
+        assert!(trie.could_start(";s49") && !trie.could_start(";s5000"));
+    }
+}
@@ -0,0 +1,360 @@
+// src/validate.rs
+//
+// Problems in expansions.toml, with line numbers: TOML errors, plus
+// triggers and placeholders that load fine but can never work as intended.
+// `text_expander check` prints them all; every load logs the latter as
+// warnings.
+
+use std::collections::{BTreeMap, HashMap};
+use std::fmt;
+use std::path::PathBuf;
+
+use serde::Deserialize;
+use toml::Spanned;
+
+use crate::config;
+use crate::template;
+
+/// One thing wrong with the file. `line` and `column` count from 1.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Problem {
+    /// Set once loaded from disk, since an included file can have problems
+    /// of its own.
+    pub file: Option<PathBuf>,
+    pub line: Option<usize>,
+    pub column: Option<usize>,
+    pub message: String,
+    /// The entry this problem leaves out of the loaded table, if any.
+    pub skipped: Option<Entry>,
+}
+
+/// A trigger as written in the file, and where.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Entry {
+    /// The `[app."..."]` profile, as written; `None` for the global tables.
+    pub app: Option<String>,
+    /// "case_sensitive", "case_insensitive", or "macros".
+    pub table: &'static str,
+    pub trigger: String,
+}
+
+impl fmt::Display for Problem {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        if let Some(file) = &self.file {
+            write!(f, "{}: ", file.display())?;
+        }
+        match (self.line, self.column) {
+            (Some(line), Some(column)) => write!(f, "line {}, column {}: {}", line, column, self.message)?,
+            (Some(line), None) => write!(f, "line {}: {}", line, self.message)?,
+            _ => write!(f, "{}", self.message)?,
+        }
+        if self.skipped.is_some() {
+            write!(f, " (skipped)")?;
+        }
+        Ok(())
+    }
+}
+
+/// Every problem with `contents`. A file that doesn't parse reports only
+/// why, since nothing after the error can be checked.
+pub fn check(contents: &str) -> Vec<Problem> {
+    match config::parse_expansion_file(contents) {
+        Ok(expansion_file) => expansion_file.problems,
+        Err(error) => vec![parse_problem(contents, error.as_ref())],
+    }
+}
+
+/// Every problem with an included file, whose triggers end at the main
+/// file's punctuation `delimiters`. It is checked on its own, before merging.
+pub fn check_included(contents: &str, delimiters: &[char]) -> Vec<Problem> {
+    match config::parse_entries(contents, Some(delimiters)) {
+        Ok(expansion_file) => expansion_file.problems,
+        Err(error) => vec![parse_problem(contents, error.as_ref())],
+    }
+}
+
+/// A load error as a `Problem`, located when it is a TOML error.
+pub fn parse_problem(contents: &str, error: &(dyn std::error::Error + 'static)) -> Problem {
+    match error.downcast_ref::<toml::de::Error>() {
+        Some(error) => {
+            let (line, column) = error.span().map(|span| line_and_column(contents, span.start)).unzip();
+            Problem { file: None, line, column, message: error.message().trim_end().to_string(), skipped: None }
+        }
+        None => Problem { file: None, line: None, column: None, message: error.to_string(), skipped: None },
+    }
+}
+
+type RawSnippets = BTreeMap<Spanned<String>, toml::Value>;
+
+/// Just the trigger tables, with where each trigger is in the file.
+#[derive(Deserialize)]
+struct RawFile {
+    #[serde(default)]
+    case_sensitive: RawSnippets,
+    #[serde(default)]
+    case_insensitive: RawSnippets,
+    #[serde(default)]
+    macros: RawSnippets,
+    #[serde(default)]
+    app: BTreeMap<String, RawProfile>,
+    #[serde(default)]
+    snippet: Vec<RawListed>,
+}
+
+/// A `[[snippet]]` entry; the rest of its fields are the snippet table.
+#[derive(Deserialize)]
+struct RawListed {
+    trigger: Spanned<String>,
+    #[serde(default)]
+    case_sensitive: bool,
+    #[serde(flatten)]
+    snippet: toml::Table,
+}
+
+#[derive(Deserialize)]
+struct RawProfile {
+    #[serde(default)]
+    case_sensitive: RawSnippets,
+    #[serde(default)]
+    case_insensitive: RawSnippets,
+}
+
+/// Problems in a file that parses, sorted by line. Triggers that can't be
+/// typed (given the punctuation `delimiters`) and expansions with broken
+/// placeholders are marked `skipped`; duplicate triggers and ones an instant
+/// trigger cuts short are only reported, since matching already picks one.
+pub fn lint(contents: &str, delimiters: &[char]) -> Vec<Problem> {
+    let Ok(mut raw) = toml::from_str::<RawFile>(contents) else {
+        return Vec::new();
+    };
+    let at = |trigger: &Spanned<String>, message: String, skipped: Option<Entry>| {
+        let (line, column) = line_and_column(contents, trigger.span().start);
+        Problem { file: None, line: Some(line), column: Some(column), message, skipped }
+    };
+    let entry = |app: Option<&String>, table, trigger: &Spanned<String>| {
+        Some(Entry { app: app.cloned(), table, trigger: trigger.get_ref().clone() })
+    };
+    let mut problems = Vec::new();
+
+    // `[[snippet]]` entries are checked as part of their table, as they load
+    for listed in std::mem::take(&mut raw.snippet) {
+        let snippets = if listed.case_sensitive { &mut raw.case_sensitive } else { &mut raw.case_insensitive };
+        if snippets.remove(listed.trigger.get_ref().as_str()).is_some() {
+            let message = format!("{:?} is defined again by a [[snippet]] entry, which wins", listed.trigger.get_ref());
+            problems.push(at(&listed.trigger, message, None));
+        }
+        snippets.insert(listed.trigger, toml::Value::Table(listed.snippet));
+    }
+
+    let profiles = raw.app.iter().map(|(app, profile)| (Some(app), &profile.case_sensitive, &profile.case_insensitive));
+    let scopes = [(None, &raw.case_sensitive, &raw.case_insensitive)].into_iter().chain(profiles);
+    for (app, case_sensitive, case_insensitive) in scopes {
+        let tables = [("case_sensitive", case_sensitive), ("case_insensitive", case_insensitive)];
+        for (table, snippets) in tables {
+            for (trigger, value) in snippets {
+                if let Some(message) = trigger_problem(trigger.get_ref(), delimiters) {
+                    problems.push(at(trigger, message, entry(app, table, trigger)));
+                    continue;
+                }
+                let text = match value {
+                    toml::Value::String(text) => Some(text.as_str()),
+                    toml::Value::Table(snippet) => snippet.get("replace").and_then(toml::Value::as_str),
+                    _ => None,
+                };
+                let errors = text.map(template::placeholder_errors).unwrap_or_default();
+                if !errors.is_empty() {
+                    let message = format!("{:?}: {}", trigger.get_ref(), errors.join("; "));
+                    problems.push(at(trigger, message, entry(app, table, trigger)));
+                }
+            }
+        }
+
+        let mut folded: HashMap<String, &String> = HashMap::new();
+        for trigger in case_insensitive.keys() {
+            let lowered = trigger.get_ref().to_lowercase();
+            if case_sensitive.contains_key(lowered.as_str()) {
+                let message = format!("{:?} is in both case_sensitive and case_insensitive", lowered);
+                problems.push(at(trigger, message, None));
+            }
+            if let Some(first) = folded.insert(lowered, trigger.get_ref()) {
+                let message = format!("{:?} and {:?} are the same case_insensitive trigger", first, trigger.get_ref());
+                problems.push(at(trigger, message, None));
+            }
+        }
+
+        // An instant trigger fires as soon as it is typed, so a longer one
+        // starting with it never gets the chance
+        let instant: Vec<(&str, bool)> = tables
+            .iter()
+            .flat_map(|&(table, snippets)| {
+                let instant = snippets.iter().filter(|(_, value)| is_instant(value));
+                instant.map(move |(trigger, _)| (trigger.get_ref().as_str(), table == "case_sensitive"))
+            })
+            .collect();
+        for trigger in tables.iter().flat_map(|(_, snippets)| snippets.keys()) {
+            let typed = trigger.get_ref();
+            let shadowed_by = instant.iter().find(|&&(instant, case_sensitive)| {
+                typed.len() > instant.len()
+                    && if case_sensitive {
+                        typed.starts_with(instant)
+                    } else {
+                        typed.to_lowercase().starts_with(&instant.to_lowercase())
+                    }
+            });
+            if let Some((instant, _)) = shadowed_by {
+                let message = format!("{:?} can never fire: the instant trigger {:?} fires first", typed, instant);
+                problems.push(at(trigger, message, None));
+            }
+        }
+    }
+
+    for trigger in raw.macros.keys() {
+        if let Some(message) = trigger_problem(trigger.get_ref(), delimiters) {
+            problems.push(at(trigger, message, entry(None, "macros", trigger)));
+        } else if raw.case_sensitive.contains_key(trigger.get_ref().as_str()) {
+            problems.push(at(trigger, format!("{:?} is both a macro and a snippet", trigger.get_ref()), None));
+        }
+    }
+
+    problems.sort_by_key(|problem| (problem.line, problem.column));
+    problems
+}
+
+fn is_instant(value: &toml::Value) -> bool {
+    let flag = |name| value.get(name).and_then(toml::Value::as_bool);
+    flag("instant").or_else(|| flag("immediate")).unwrap_or(false)
+}
+
+/// Why `trigger` can never fire, if it can't: it is empty, it has
+/// whitespace other than single spaces between words (a phrase trigger), or
+/// it contains one of the punctuation `delimiters`, which end the word
+/// before the trigger is complete.
+pub fn trigger_problem(trigger: &str, delimiters: &[char]) -> Option<String> {
+    if trigger.is_empty() {
+        return Some("empty trigger".to_string());
+    }
+    let phrase = trigger.split(' ').all(|word| !word.is_empty() && !word.contains(char::is_whitespace));
+    if !phrase {
+        return Some(format!("{:?} has whitespace other than single spaces between words", trigger));
+    }
+    trigger.chars().find(|c| delimiters.contains(c)).map(|delimiter| {
+        format!("{:?} contains the delimiter {:?}, which fires before the trigger is complete", trigger, delimiter)
+    })
+}
+
+/// 1-based line and column (in characters) of byte `offset`.
+fn line_and_column(contents: &str, offset: usize) -> (usize, usize) {
+    let before = &contents[..offset.min(contents.len())];
+    let line_start = before.rfind('\n').map_or(0, |newline| newline + 1);
+    (before.matches('\n').count() + 1, before[line_start..].chars().count() + 1)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn problems_point_at_their_line_and_broken_entries_are_skipped() {
+        let contents = r#"
+[settings]
+delimiters = ["space", "."]
+
+[case_sensitive]
+"e.g" = "for example"
+"btw" = "by the way"
+"b  tw" = "oops"
+"d" = "{{dat}}"
+
+[case_insensitive]
+"btw" = "By the way"
+"Omw" = "on my way"
+"OMW" = "ON MY WAY"
+
+[macros]
+"d" = ["delete(1)"]
+"#;
+        let file = config::parse_expansion_file(contents).unwrap();
+        assert!(!file.case_sensitive.contains_key("b  tw") && !file.case_sensitive.contains_key("d"));
+        let problems: Vec<String> = file.problems.iter().map(Problem::to_string).collect();
+        assert_eq!(
+            problems,
+            [
+                "line 6, column 1: \"e.g\" contains the delimiter '.', which fires before the trigger is complete (skipped)",
+                "line 8, column 1: \"b  tw\" has whitespace other than single spaces between words (skipped)",
+                "line 9, column 1: \"d\": {{dat}} is not a known placeholder (skipped)",
+                "line 12, column 1: \"btw\" is in both case_sensitive and case_insensitive",
+                "line 13, column 1: \"OMW\" and \"Omw\" are the same case_insensitive trigger",
+                "line 17, column 1: \"d\" is both a macro and a snippet",
+            ]
+        );
+
+        let broken = "[case_sensitive]\n\"btw\" = \"by the way\n";
+        let problem = &check(broken)[0];
+        assert_eq!((problem.line, problem.column), (Some(2), Some(20)));
+    }
+
+    #[test]
+    fn snippet_array_entries_join_their_table_with_their_options() {
+        let contents = r#"
+[case_insensitive]
+"omw" = "on my way"
+
+[[snippet]]
+trigger = "q4h"
+replace = "every 4 hours"
+case_sensitive = true
+word_boundary = false
+keep_delimiter = false
+
+[[snippet]]
+trigger = "omw"
+replace = "On my way!"
+
+[[snippet]]
+trigger = "b\ttw"
+replace = "oops"
+"#;
+        let file = config::parse_expansion_file(contents).unwrap();
+        let q4h = &file.case_sensitive["q4h"];
+        assert_eq!(q4h.replace, "every 4 hours");
+        assert!(!q4h.options.word && !q4h.options.keep_delimiter);
+        assert_eq!(file.case_insensitive["omw"].replace, "On my way!");
+        assert!(!file.case_insensitive.contains_key("b\ttw"));
+
+        let problems: Vec<String> = file.problems.iter().map(Problem::to_string).collect();
+        assert_eq!(
+            problems,
+            [
+                "line 13, column 11: \"omw\" is defined again by a [[snippet]] entry, which wins",
+                "line 17, column 11: \"b\\ttw\" has whitespace other than single spaces between words (skipped)",
+            ]
+        );
+    }
+
+    #[test]
+    fn triggers_an_instant_trigger_starts_are_reported() {
+        let contents = r#"
+[case_sensitive]
+"sig" = { replace = "Regards", instant = true }
+"sig2" = "Best"
+"signal" = "signal"
+
+[case_insensitive]
+";ADDR" = { replace = "1 Main St", immediate = true }
+";addr2" = "2 Main St"
+"si" = "so"
+"#;
+        let file = config::parse_expansion_file(contents).unwrap();
+        assert!(file.case_sensitive.contains_key("sig2"), "only reported");
+        let problems: Vec<String> = file.problems.iter().map(Problem::to_string).collect();
+        assert_eq!(
+            problems,
+            [
+                "line 4, column 1: \"sig2\" can never fire: the instant trigger \"sig\" fires first",
+                "line 5, column 1: \"signal\" can never fire: the instant trigger \"sig\" fires first",
+                "line 9, column 1: \";addr2\" can never fire: the instant trigger \";ADDR\" fires first",
+            ]
+        );
+    }
+
+}
@@ -1,14 +1,21 @@
 // src/windows_input.rs
+use winapi::um::winbase::{GlobalAlloc, GlobalFree, GlobalLock, GlobalUnlock, GMEM_MOVEABLE};
 use winapi::um::winuser::{
-    SendInput, INPUT, INPUT_KEYBOARD,
-    KEYEVENTF_KEYUP, KEYEVENTF_EXTENDEDKEY, VK_BACK, VK_CONTROL, VK_SHIFT,
-    VK_END, VK_DELETE,
+    CloseClipboard, EmptyClipboard, MapVirtualKeyW, RegisterClipboardFormatW, SendInput, SetClipboardData,
+    CF_UNICODETEXT, INPUT, INPUT_KEYBOARD,
+    KEYEVENTF_KEYUP, KEYEVENTF_EXTENDEDKEY, KEYEVENTF_UNICODE, MAPVK_VK_TO_VSC,
+    VK_BACK, VK_CONTROL, VK_LEFT, VK_RETURN, VK_RIGHT,
 };
-use winapi::shared::minwindef::WORD;
-use std::mem;
+use winapi::shared::minwindef::{UINT, WORD};
+use std::{mem, ptr};
 use std::thread;
 use std::time::Duration;
 
+use crate::clipboard;
+use crate::keyboard_hook::encode_wide;
+use crate::listening;
+use text_expander::keys::KeyId;
+
 /// Delay in milliseconds between each backspace key down+up pair.
 /// Increase if target apps (e.g. EHR software) drop keystrokes.
 pub const BACKSPACE_DELAY_MS: u64 = 5;
@@ -17,10 +24,22 @@ pub const BACKSPACE_DELAY_MS: u64 = 5;
 /// Allows the rdev hook to distinguish self-generated input.
 pub const SYNTHETIC_INPUT_TAG: usize = 0x5445_5854; // "TEXT" in hex
 
-/// Send `count` backspaces as individual key down+up pairs with delays.
+/// Characters per SendInput batch when typing text as Unicode events.
+pub const UNICODE_CHUNK_CHARS: usize = 64;
+
+/// Pause between Unicode batches. Increase if target apps drop characters
+/// from long expansions.
+pub const UNICODE_CHUNK_DELAY_MS: u64 = 10;
+
+/// Send exactly `count` backspaces as individual key down+up pairs with
+/// delays, stopping early on Escape.
 /// Each event includes the hardware scan code (0x0E) and dwExtraInfo tag.
 pub fn send_backspaces_fast(count: usize) -> Result<(), Box<dyn std::error::Error>> {
-    for _ in 0..count {
+    for i in 0..count {
+        if listening::cancelled() {
+            return Err(format!("cancelled after {} of {} backspaces", i, count).into());
+        }
+
         // Key down
         let mut key_down: INPUT = unsafe { mem::zeroed() };
         unsafe {
@@ -62,6 +81,16 @@ pub fn send_backspaces_fast(count: usize) -> Result<(), Box<dyn std::error::Erro
 /// Send Ctrl+V as a single batched SendInput call (atomic modifier chord).
 /// Includes hardware scan codes and dwExtraInfo tag.
 pub fn send_ctrl_v() -> Result<(), Box<dyn std::error::Error>> {
+    send_ctrl_chord('V', 0x2F) // scan code for V
+}
+
+/// Send Ctrl+C the same way, to copy the selection.
+pub fn send_ctrl_c() -> Result<(), Box<dyn std::error::Error>> {
+    send_ctrl_chord('C', 0x2E) // scan code for C
+}
+
+/// Ctrl down, `key` down and up, Ctrl up, in one SendInput call.
+fn send_ctrl_chord(key: char, scan: WORD) -> Result<(), Box<dyn std::error::Error>> {
     let mut inputs: Vec<INPUT> = Vec::with_capacity(4);
 
     // Ctrl down
@@ -76,29 +105,29 @@ pub fn send_ctrl_v() -> Result<(), Box<dyn std::error::Error>> {
     }
     inputs.push(ctrl_down);
 
-    // V down
-    let mut v_down: INPUT = unsafe { mem::zeroed() };
+    // Key down
+    let mut key_down: INPUT = unsafe { mem::zeroed() };
     unsafe {
-        v_down.type_ = INPUT_KEYBOARD;
-        let ki = v_down.u.ki_mut();
-        ki.wVk = 'V' as WORD;
-        ki.wScan = 0x2F; // scan code for V
+        key_down.type_ = INPUT_KEYBOARD;
+        let ki = key_down.u.ki_mut();
+        ki.wVk = key as WORD;
+        ki.wScan = scan;
         ki.dwFlags = 0;
         ki.dwExtraInfo = SYNTHETIC_INPUT_TAG;
     }
-    inputs.push(v_down);
+    inputs.push(key_down);
 
-    // V up
-    let mut v_up: INPUT = unsafe { mem::zeroed() };
+    // Key up
+    let mut key_up: INPUT = unsafe { mem::zeroed() };
     unsafe {
-        v_up.type_ = INPUT_KEYBOARD;
-        let ki = v_up.u.ki_mut();
-        ki.wVk = 'V' as WORD;
-        ki.wScan = 0x2F;
+        key_up.type_ = INPUT_KEYBOARD;
+        let ki = key_up.u.ki_mut();
+        ki.wVk = key as WORD;
+        ki.wScan = scan;
         ki.dwFlags = KEYEVENTF_KEYUP;
         ki.dwExtraInfo = SYNTHETIC_INPUT_TAG;
     }
-    inputs.push(v_up);
+    inputs.push(key_up);
 
     // Ctrl up
     let mut ctrl_up: INPUT = unsafe { mem::zeroed() };
@@ -121,63 +150,42 @@ pub fn send_ctrl_v() -> Result<(), Box<dyn std::error::Error>> {
     };
 
     if sent != inputs.len() as u32 {
-        return Err(format!("Failed to send Ctrl+V. Sent: {}/{}", sent, inputs.len()).into());
+        return Err(format!("Failed to send Ctrl+{}. Sent: {}/{}", key, sent, inputs.len()).into());
     }
 
     Ok(())
 }
 
-/// Send Shift+End to select from cursor to end of line.
-pub fn send_shift_end() -> Result<(), Box<dyn std::error::Error>> {
-    let mut inputs: Vec<INPUT> = Vec::with_capacity(4);
+/// Send `count` Left Arrow presses in one batched SendInput call. Used to
+/// place the caret after an expansion.
+pub fn send_left_arrows(count: usize) -> Result<(), Box<dyn std::error::Error>> {
+    send_arrows(VK_LEFT as WORD, 0x4B, count).map_err(|sent| format!("Failed to send Left arrows. Sent: {}", sent).into())
+}
 
-    // Shift down
-    let mut shift_down: INPUT = unsafe { mem::zeroed() };
-    unsafe {
-        shift_down.type_ = INPUT_KEYBOARD;
-        let ki = shift_down.u.ki_mut();
-        ki.wVk = VK_SHIFT as WORD;
-        ki.wScan = 0x2A; // scan code for Left Shift
-        ki.dwFlags = 0;
-        ki.dwExtraInfo = SYNTHETIC_INPUT_TAG;
-    }
-    inputs.push(shift_down);
+/// Send `count` Right Arrow presses, for moving forward to a tab stop.
+pub fn send_right_arrows(count: usize) -> Result<(), Box<dyn std::error::Error>> {
+    send_arrows(VK_RIGHT as WORD, 0x4D, count).map_err(|sent| format!("Failed to send Right arrows. Sent: {}", sent).into())
+}
 
-    // End down (extended key — nav cluster, not numpad)
-    let mut end_down: INPUT = unsafe { mem::zeroed() };
-    unsafe {
-        end_down.type_ = INPUT_KEYBOARD;
-        let ki = end_down.u.ki_mut();
-        ki.wVk = VK_END as WORD;
-        ki.wScan = 0x4F; // scan code for End
-        ki.dwFlags = KEYEVENTF_EXTENDEDKEY;
-        ki.dwExtraInfo = SYNTHETIC_INPUT_TAG;
-    }
-    inputs.push(end_down);
+/// Arrow key taps (extended key — nav cluster, not numpad). On failure,
+/// returns how many of how many inputs went out.
+fn send_arrows(vk: WORD, scan: WORD, count: usize) -> Result<(), String> {
+    let mut inputs: Vec<INPUT> = Vec::with_capacity(count * 2);
 
-    // End up
-    let mut end_up: INPUT = unsafe { mem::zeroed() };
-    unsafe {
-        end_up.type_ = INPUT_KEYBOARD;
-        let ki = end_up.u.ki_mut();
-        ki.wVk = VK_END as WORD;
-        ki.wScan = 0x4F;
-        ki.dwFlags = KEYEVENTF_KEYUP | KEYEVENTF_EXTENDEDKEY;
-        ki.dwExtraInfo = SYNTHETIC_INPUT_TAG;
-    }
-    inputs.push(end_up);
-
-    // Shift up
-    let mut shift_up: INPUT = unsafe { mem::zeroed() };
-    unsafe {
-        shift_up.type_ = INPUT_KEYBOARD;
-        let ki = shift_up.u.ki_mut();
-        ki.wVk = VK_SHIFT as WORD;
-        ki.wScan = 0x2A;
-        ki.dwFlags = KEYEVENTF_KEYUP;
-        ki.dwExtraInfo = SYNTHETIC_INPUT_TAG;
+    for _ in 0..count {
+        for flags in [KEYEVENTF_EXTENDEDKEY, KEYEVENTF_EXTENDEDKEY | KEYEVENTF_KEYUP] {
+            let mut input: INPUT = unsafe { mem::zeroed() };
+            unsafe {
+                input.type_ = INPUT_KEYBOARD;
+                let ki = input.u.ki_mut();
+                ki.wVk = vk;
+                ki.wScan = scan;
+                ki.dwFlags = flags;
+                ki.dwExtraInfo = SYNTHETIC_INPUT_TAG;
+            }
+            inputs.push(input);
+        }
     }
-    inputs.push(shift_up);
 
     let sent = unsafe {
         SendInput(
@@ -188,44 +196,7 @@ pub fn send_shift_end() -> Result<(), Box<dyn std::error::Error>> {
     };
 
     if sent != inputs.len() as u32 {
-        return Err(format!("Failed to send Shift+End. Sent: {}/{}", sent, inputs.len()).into());
-    }
-
-    Ok(())
-}
-
-/// Send a single Delete key press+release (extended key — nav cluster).
-pub fn send_delete_key() -> Result<(), Box<dyn std::error::Error>> {
-    // Key down
-    let mut key_down: INPUT = unsafe { mem::zeroed() };
-    unsafe {
-        key_down.type_ = INPUT_KEYBOARD;
-        let ki = key_down.u.ki_mut();
-        ki.wVk = VK_DELETE as WORD;
-        ki.wScan = 0x53; // scan code for Delete
-        ki.dwFlags = KEYEVENTF_EXTENDEDKEY;
-        ki.dwExtraInfo = SYNTHETIC_INPUT_TAG;
-    }
-
-    let sent = unsafe { SendInput(1, &mut key_down, mem::size_of::<INPUT>() as i32) };
-    if sent != 1 {
-        return Err("Failed to send Delete key down".into());
-    }
-
-    // Key up
-    let mut key_up: INPUT = unsafe { mem::zeroed() };
-    unsafe {
-        key_up.type_ = INPUT_KEYBOARD;
-        let ki = key_up.u.ki_mut();
-        ki.wVk = VK_DELETE as WORD;
-        ki.wScan = 0x53;
-        ki.dwFlags = KEYEVENTF_KEYUP | KEYEVENTF_EXTENDEDKEY;
-        ki.dwExtraInfo = SYNTHETIC_INPUT_TAG;
-    }
-
-    let sent = unsafe { SendInput(1, &mut key_up, mem::size_of::<INPUT>() as i32) };
-    if sent != 1 {
-        return Err("Failed to send Delete key up".into());
+        return Err(format!("{}/{}", sent, inputs.len()));
     }
 
     Ok(())
@@ -260,3 +231,220 @@ pub fn send_key_tap(vk: u16, scan: u16) -> Result<(), Box<dyn std::error::Error>
     }
     Ok(())
 }
+
+/// Press `keys` in order, then release them in reverse, as one batched
+/// SendInput call (e.g. Shift, End → Shift+End). A single key is a tap.
+/// Scan codes come from the active layout.
+pub fn send_key_chord(keys: &[KeyId]) -> Result<(), Box<dyn std::error::Error>> {
+    let mut inputs: Vec<INPUT> = Vec::with_capacity(keys.len() * 2);
+
+    let presses = keys.iter().map(|&key| (key, 0));
+    let releases = keys.iter().rev().map(|&key| (key, KEYEVENTF_KEYUP));
+    for (key, up) in presses.chain(releases) {
+        let vk = key.vk();
+        let mut input: INPUT = unsafe { mem::zeroed() };
+        unsafe {
+            input.type_ = INPUT_KEYBOARD;
+            let ki = input.u.ki_mut();
+            ki.wVk = vk;
+            ki.wScan = MapVirtualKeyW(vk as u32, MAPVK_VK_TO_VSC) as WORD;
+            ki.dwFlags = up | if is_extended(key) { KEYEVENTF_EXTENDEDKEY } else { 0 };
+            ki.dwExtraInfo = SYNTHETIC_INPUT_TAG;
+        }
+        inputs.push(input);
+    }
+
+    let sent = unsafe {
+        SendInput(
+            inputs.len() as u32,
+            inputs.as_mut_ptr(),
+            mem::size_of::<INPUT>() as i32,
+        )
+    };
+
+    if sent != inputs.len() as u32 {
+        return Err(format!("Failed to send key chord. Sent: {}/{}", sent, inputs.len()).into());
+    }
+
+    Ok(())
+}
+
+/// Keys that need KEYEVENTF_EXTENDEDKEY, or apps read them as their numpad
+/// twins (End as numpad 1, say).
+fn is_extended(key: KeyId) -> bool {
+    matches!(
+        key,
+        KeyId::ControlRight
+            | KeyId::AltGr
+            | KeyId::MetaLeft
+            | KeyId::MetaRight
+            | KeyId::Insert
+            | KeyId::Delete
+            | KeyId::Home
+            | KeyId::End
+            | KeyId::PageUp
+            | KeyId::PageDown
+            | KeyId::LeftArrow
+            | KeyId::RightArrow
+            | KeyId::UpArrow
+            | KeyId::DownArrow
+    )
+}
+
+/// Type `text` directly as KEYEVENTF_UNICODE events, without touching the
+/// clipboard. `\n` is sent as a Return tap and `\r` is dropped, so `\r\n`
+/// and `\n` both give one line break. Long text goes out in batches of
+/// `UNICODE_CHUNK_CHARS` with a short pause between them so slow apps keep
+/// up; Escape stops it between batches.
+pub fn send_text_via_unicode(text: &str) -> Result<(), Box<dyn std::error::Error>> {
+    let chars: Vec<char> = text.chars().filter(|&c| c != '\r').collect();
+
+    for (i, chunk) in chars.chunks(UNICODE_CHUNK_CHARS).enumerate() {
+        if i > 0 {
+            thread::sleep(Duration::from_millis(UNICODE_CHUNK_DELAY_MS));
+        }
+        if listening::cancelled() {
+            return Err(format!("cancelled after {} of {} characters", i * UNICODE_CHUNK_CHARS, chars.len()).into());
+        }
+
+        let mut inputs: Vec<INPUT> = chunk.iter().flat_map(|&c| char_inputs(c)).collect();
+        let sent = unsafe {
+            SendInput(
+                inputs.len() as u32,
+                inputs.as_mut_ptr(),
+                mem::size_of::<INPUT>() as i32,
+            )
+        };
+
+        if sent != inputs.len() as u32 {
+            return Err(format!("Failed to send unicode text. Sent: {}/{}", sent, inputs.len()).into());
+        }
+    }
+
+    Ok(())
+}
+
+/// `injection = "typed"`: like `send_text_via_unicode`, but one character
+/// (press and release) per SendInput call, `delay_ms` apart. Stops at the
+/// first character that doesn't go out, or on Escape, saying how far it got.
+pub fn send_text_typed(text: &str, delay_ms: u64) -> Result<(), Box<dyn std::error::Error>> {
+    let chars: Vec<char> = text.chars().filter(|&c| c != '\r').collect();
+
+    for (i, &c) in chars.iter().enumerate() {
+        if i > 0 {
+            thread::sleep(Duration::from_millis(delay_ms));
+        }
+        if listening::cancelled() {
+            return Err(format!("cancelled after {} of {} characters", i, chars.len()).into());
+        }
+
+        let mut inputs = char_inputs(c);
+        let sent = unsafe {
+            SendInput(
+                inputs.len() as u32,
+                inputs.as_mut_ptr(),
+                mem::size_of::<INPUT>() as i32,
+            )
+        };
+
+        if sent != inputs.len() as u32 {
+            return Err(format!("Typing stopped after {} of {} characters", i, chars.len()).into());
+        }
+    }
+
+    Ok(())
+}
+
+/// Press and release for one character: a Return tap for a line break,
+/// otherwise a unicode event per UTF-16 unit (two for a surrogate pair).
+fn char_inputs(c: char) -> Vec<INPUT> {
+    let mut units = [0u16; 2];
+    // (vk, scan, flags)
+    let keys: Vec<(WORD, WORD, u32)> = if c == '\n' {
+        vec![(VK_RETURN as WORD, 0x1C, 0)] // scan code for Return
+    } else {
+        c.encode_utf16(&mut units).iter().map(|&unit| (0, unit, KEYEVENTF_UNICODE)).collect()
+    };
+
+    let mut inputs = Vec::with_capacity(keys.len() * 2);
+    for (vk, scan, flags) in keys {
+        for up in [0, KEYEVENTF_KEYUP] {
+            let mut input: INPUT = unsafe { mem::zeroed() };
+            unsafe {
+                input.type_ = INPUT_KEYBOARD;
+                let ki = input.u.ki_mut();
+                ki.wVk = vk;
+                ki.wScan = scan;
+                ki.dwFlags = flags | up;
+                ki.dwExtraInfo = SYNTHETIC_INPUT_TAG;
+            }
+            inputs.push(input);
+        }
+    }
+    inputs
+}
+
+/// Put `text` (CF_UNICODETEXT) and `html` (CF_HTML) on the clipboard in one
+/// go, so apps that take HTML paste it formatted and the rest paste `text`.
+/// Everything else on the clipboard is cleared, so restoring the user's text
+/// afterwards clears the HTML too.
+pub fn set_clipboard_text_and_html(text: &str, html: &str) -> Result<(), Box<dyn std::error::Error>> {
+    let html_format = unsafe { RegisterClipboardFormatW(encode_wide("HTML Format").as_ptr()) };
+    if html_format == 0 {
+        return Err("could not register the HTML clipboard format".into());
+    }
+    let unicode: Vec<u8> = text.encode_utf16().chain(std::iter::once(0)).flat_map(u16::to_le_bytes).collect();
+    let cf_html = format!("{}\0", cf_html(html));
+
+    if !clipboard::open_raw() {
+        return Err("the clipboard is in use by another app".into());
+    }
+    let result = unsafe {
+        EmptyClipboard();
+        set_clipboard_data(CF_UNICODETEXT, &unicode).and_then(|()| set_clipboard_data(html_format, cf_html.as_bytes()))
+    };
+    unsafe { CloseClipboard() };
+    result
+}
+
+/// Hand a copy of `data` to the open clipboard as `format`.
+unsafe fn set_clipboard_data(format: UINT, data: &[u8]) -> Result<(), Box<dyn std::error::Error>> {
+    unsafe {
+        let memory = GlobalAlloc(GMEM_MOVEABLE, data.len());
+        if memory.is_null() {
+            return Err("GlobalAlloc failed".into());
+        }
+        let target = GlobalLock(memory) as *mut u8;
+        if target.is_null() {
+            GlobalFree(memory);
+            return Err("GlobalLock failed".into());
+        }
+        ptr::copy_nonoverlapping(data.as_ptr(), target, data.len());
+        GlobalUnlock(memory);
+        // The clipboard owns the memory once this succeeds
+        if SetClipboardData(format, memory).is_null() {
+            GlobalFree(memory);
+            return Err(format!("SetClipboardData failed for format {}", format).into());
+        }
+    }
+    Ok(())
+}
+
+/// Wrap an HTML fragment in the CF_HTML header. Its offsets count UTF-8
+/// bytes from the start of the header, which is fixed-width (10-digit
+/// offsets) so its own length is known before they are filled in.
+fn cf_html(fragment: &str) -> String {
+    const BEFORE: &str = "<html><body>\r\n<!--StartFragment-->";
+    const AFTER: &str = "<!--EndFragment-->\r\n</body></html>";
+    let header = |start_html: usize, end_html: usize, start_fragment: usize, end_fragment: usize| {
+        format!(
+            "Version:0.9\r\nStartHTML:{:010}\r\nEndHTML:{:010}\r\nStartFragment:{:010}\r\nEndFragment:{:010}\r\n",
+            start_html, end_html, start_fragment, end_fragment
+        )
+    };
+    let start_html = header(0, 0, 0, 0).len();
+    let start_fragment = start_html + BEFORE.len();
+    let end_fragment = start_fragment + fragment.len();
+    let end_html = end_fragment + AFTER.len();
+    format!("{}{}{}{}", header(start_html, end_html, start_fragment, end_fragment), BEFORE, fragment, AFTER)
+}